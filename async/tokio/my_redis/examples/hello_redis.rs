@@ -1,14 +1,14 @@
-use mini_redis::{client, Result};
+use my_redis::{Client, Result};
 
 /// This is a simple example of a client application.
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    // Open a connection to the mini-redis server
-    let mut client = client::connect("127.0.0.1:6379").await?;
+    // Open a connection to the my_redis server
+    let client = Client::connect("127.0.0.1:6379").await?;
 
     // Set the key "hello" to the value "world"
-    client.set("hello", "world".into()).await?;
+    client.set("hello", "world").await?;
 
     // Get the value of the key "hello"
     let result = client.get("hello").await?;
@@ -0,0 +1,235 @@
+//! The audit log: one JSON line per mutating command, in a file that rotates by size.
+//!
+//! ```text
+//! {"time":1700000000.123456,"client":"127.0.0.1:52144","command":"SET","args":["key","value"],"ok":true}
+//! ```
+//!
+//! `ok` is false when the command replied with an error. Arguments that are not valid UTF-8
+//! are logged with U+FFFD in place of the invalid bytes.
+//!
+//! When the file would grow past `max_size`, it is renamed to `<path>.1`, the previous
+//! `<path>.1` to `<path>.2` and so on, and the oldest beyond `max_files` is deleted, like
+//! logrotate does.
+
+use crate::cmd::Command;
+use crate::json;
+use std::fmt::Write as _;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Settings of an `AuditLog`.
+#[derive(Debug, Clone)]
+pub struct AuditConfig {
+    pub path: PathBuf,
+    /// The size in bytes past which the file is rotated.
+    pub max_size: u64,
+    /// How many rotated files are kept next to the current one.
+    pub max_files: usize,
+}
+
+impl AuditConfig {
+    pub fn new(path: impl Into<PathBuf>) -> AuditConfig {
+        AuditConfig { path: path.into(), max_size: 100 * 1024 * 1024, max_files: 5 }
+    }
+}
+
+/// The audit log, shared by every connection task.
+///
+/// Why a thread?
+/// Writing a file blocks, and a blocked connection task would hold up every other task of
+/// its runtime thread. The connection tasks only send the lines over a channel; a thread
+/// owns the file, writes whatever is queued and flushes once the queue is empty.
+///
+/// Note: the channel is unbounded, so a disk slower than the traffic makes memory grow.
+#[derive(Clone)]
+pub struct AuditLog {
+    lines: mpsc::Sender<String>,
+}
+
+impl AuditLog {
+    /// Opens (or creates) the file and starts the writer thread. The thread stops, flushing
+    /// what is left, once every clone of the log is dropped.
+    pub fn open(config: AuditConfig) -> io::Result<AuditLog> {
+        let mut writer = Writer::open(config)?;
+        let (tx, rx) = mpsc::channel::<String>();
+        thread::spawn(move || {
+            while let Ok(line) = rx.recv() {
+                let mut result = writer.write(&line);
+                while result.is_ok() {
+                    match rx.try_recv() {
+                        Ok(line) => result = writer.write(&line),
+                        Err(_) => break,
+                    }
+                }
+                if let Err(e) = result.and_then(|_| writer.file.flush()) {
+                    eprintln!("audit log error: {}", e);
+                }
+            }
+        });
+        Ok(AuditLog { lines: tx })
+    }
+
+    /// Logs `cmd` if it is a write.
+    pub fn log(&self, cmd: &Command, client: SocketAddr, ok: bool) {
+        if cmd.is_write() {
+            // An error means the writer thread died; it already reported why.
+            let _ = self.lines.send(format_entry(SystemTime::now(), client, cmd, ok));
+        }
+    }
+}
+
+/// Formats one line of the log, with its newline.
+pub fn format_entry(time: SystemTime, client: SocketAddr, cmd: &Command, ok: bool) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let mut line = format!(
+        "{{\"time\":{}.{:06},\"client\":\"{}\",\"command\":",
+        since_epoch.as_secs(),
+        since_epoch.subsec_micros(),
+        client
+    );
+    json::write_string(&mut line, cmd.name());
+    line.push_str(",\"args\":[");
+    for (i, arg) in cmd.args().iter().enumerate() {
+        if i > 0 {
+            line.push(',');
+        }
+        json::write_string(&mut line, &String::from_utf8_lossy(arg));
+    }
+    let _ = writeln!(line, "],\"ok\":{}}}", ok);
+    line
+}
+
+/// The file side, owned by the writer thread.
+struct Writer {
+    config: AuditConfig,
+    file: BufWriter<File>,
+    size: u64,
+}
+
+impl Writer {
+    fn open(config: AuditConfig) -> io::Result<Writer> {
+        let file = OpenOptions::new().create(true).append(true).open(&config.path)?;
+        let size = file.metadata()?.len();
+        Ok(Writer { config, file: BufWriter::new(file), size })
+    }
+
+    fn write(&mut self, line: &str) -> io::Result<()> {
+        // A line longer than `max_size` still goes in a file of its own.
+        if self.size > 0 && self.size + line.len() as u64 > self.config.max_size {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        let rotated = |n: usize| {
+            let mut name = self.config.path.clone().into_os_string();
+            name.push(format!(".{}", n));
+            PathBuf::from(name)
+        };
+        if self.config.max_files == 0 {
+            fs::remove_file(&self.config.path)?;
+        } else {
+            // Missing files are fine: there are fewer than `max_files` so far.
+            let _ = fs::remove_file(rotated(self.config.max_files));
+            for n in (1..self.config.max_files).rev() {
+                let _ = fs::rename(rotated(n), rotated(n + 1));
+            }
+            fs::rename(&self.config.path, rotated(1))?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&self.config.path)?;
+        self.file = BufWriter::new(file);
+        self.size = 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::Frame;
+    use std::time::Duration;
+
+    fn command(parts: &[&str]) -> Command {
+        Command::from_frame(Frame::command(parts.iter().map(|p| p.to_string()))).unwrap()
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("my_redis-audit-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_format_entry() {
+        let time = UNIX_EPOCH + Duration::from_micros(1_700_000_000_123_456);
+        let client: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        let cmd = command(&["set", "key", "a \"quoted\"\nvalue\u{1}"]);
+        assert_eq!(
+            format_entry(time, client, &cmd, true),
+            "{\"time\":1700000000.123456,\"client\":\"127.0.0.1:5000\",\"command\":\"SET\",\
+             \"args\":[\"key\",\"a \\\"quoted\\\"\\nvalue\\u0001\"],\"ok\":true}\n"
+        );
+    }
+
+    #[test]
+    fn test_rotation_keeps_max_files() {
+        let dir = temp_dir("rotation");
+        let path = dir.join("audit.log");
+        let config = AuditConfig { path: path.clone(), max_size: 25, max_files: 2 };
+        let mut writer = Writer::open(config).unwrap();
+        for i in 0..5 {
+            writer.write(&format!("line {} of the log\n", i)).unwrap();
+        }
+        writer.file.flush().unwrap();
+
+        let read = |name: &str| fs::read_to_string(dir.join(name)).unwrap();
+        assert_eq!(read("audit.log"), "line 4 of the log\n");
+        assert_eq!(read("audit.log.1"), "line 3 of the log\n");
+        assert_eq!(read("audit.log.2"), "line 2 of the log\n");
+        assert!(!dir.join("audit.log.3").exists());
+
+        // reopening appends to the current file
+        let config = AuditConfig { path: path.clone(), max_size: 1000, max_files: 2 };
+        let mut writer = Writer::open(config).unwrap();
+        writer.write("line 5\n").unwrap();
+        writer.file.flush().unwrap();
+        assert_eq!(read("audit.log"), "line 4 of the log\nline 5\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_only_writes_are_logged() {
+        let dir = temp_dir("writes");
+        let path = dir.join("audit.log");
+        let log = AuditLog::open(AuditConfig::new(&path)).unwrap();
+        let client: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        log.log(&command(&["GET", "key"]), client, true);
+        log.log(&command(&["SET", "key", "value"]), client, true);
+        log.log(&command(&["INCR", "key"]), client, false);
+        drop(log);
+
+        // the writer thread flushes on its own time
+        let mut lines = Vec::new();
+        for _ in 0..100 {
+            lines = fs::read_to_string(&path).unwrap().lines().map(str::to_string).collect();
+            if lines.len() == 2 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].contains("\"command\":\"SET\"") && lines[0].ends_with("\"ok\":true}"));
+        assert!(lines[1].contains("\"command\":\"INCR\"") && lines[1].ends_with("\"ok\":false}"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,344 @@
+//! The engine of `my-redis-benchmark`: runs a command mix over N connections and measures
+//! the latency of every request.
+
+use crate::connection::Connection;
+use crate::frame::Frame;
+use bytes::Bytes;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+
+/// A command the benchmark knows how to generate.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BenchCommand {
+    Get,
+    Set,
+    Incr,
+    Lpush,
+}
+
+impl BenchCommand {
+    pub fn parse(name: &str) -> Result<BenchCommand, String> {
+        match name.to_lowercase().as_str() {
+            "get" => Ok(BenchCommand::Get),
+            "set" => Ok(BenchCommand::Set),
+            "incr" => Ok(BenchCommand::Incr),
+            "lpush" => Ok(BenchCommand::Lpush),
+            _ => Err(format!("unknown command {}, expected get, set, incr or lpush", name)),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            BenchCommand::Get => "GET",
+            BenchCommand::Set => "SET",
+            BenchCommand::Incr => "INCR",
+            BenchCommand::Lpush => "LPUSH",
+        }
+    }
+
+    fn frame(&self, key: u64, value: &Bytes) -> Frame {
+        // Counters and lists get their own keys, so INCR doesn't hit a SET value (WRONGTYPE).
+        let key = match self {
+            BenchCommand::Get | BenchCommand::Set => format!("key:{:012}", key),
+            BenchCommand::Incr => format!("counter:{:012}", key),
+            BenchCommand::Lpush => format!("list:{:012}", key),
+        };
+        match self {
+            BenchCommand::Get => Frame::command([Bytes::from("GET"), Bytes::from(key)]),
+            BenchCommand::Incr => Frame::command([Bytes::from("INCR"), Bytes::from(key)]),
+            BenchCommand::Set | BenchCommand::Lpush => {
+                Frame::command([Bytes::from(self.name()), Bytes::from(key), value.clone()])
+            }
+        }
+    }
+}
+
+/// Commands with their weights, e.g. `get=80,set=20` sends four GETs per SET.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Workload {
+    pub commands: Vec<(BenchCommand, u32)>,
+}
+
+impl Workload {
+    /// Parses `get=80,set=20`. A command without a weight counts once, so `get,set` is 50/50.
+    pub fn parse(spec: &str) -> Result<Workload, String> {
+        let mut commands = Vec::new();
+        for part in spec.split(',').filter(|part| !part.is_empty()) {
+            let (name, weight) = match part.split_once('=') {
+                Some((name, weight)) => (name, weight.parse().map_err(|_| format!("invalid weight in {}", part))?),
+                None => (part, 1),
+            };
+            if weight > 0 {
+                commands.push((BenchCommand::parse(name)?, weight));
+            }
+        }
+        if commands.is_empty() {
+            return Err("empty command mix".to_string());
+        }
+        Ok(Workload { commands })
+    }
+
+    /// The name used in reports, e.g. `GET` or `GET=80,SET=20`.
+    pub fn name(&self) -> String {
+        match &self.commands[..] {
+            [(command, _)] => command.name().to_string(),
+            commands => {
+                let parts: Vec<String> = commands.iter().map(|(c, w)| format!("{}={}", c.name(), w)).collect();
+                parts.join(",")
+            }
+        }
+    }
+
+    /// Picks a command, `n` is a random number.
+    fn pick(&self, n: u64) -> BenchCommand {
+        let total: u64 = self.commands.iter().map(|(_, w)| *w as u64).sum();
+        let mut n = n % total;
+        for (command, weight) in &self.commands {
+            if n < *weight as u64 {
+                return *command;
+            }
+            n -= *weight as u64;
+        }
+        unreachable!("n is below the total weight")
+    }
+}
+
+/// Settings of a benchmark run.
+#[derive(Debug, Clone)]
+pub struct BenchConfig {
+    pub addr: String,
+    /// Number of concurrent connections.
+    pub clients: usize,
+    /// Total number of requests, over all connections.
+    pub requests: u64,
+    /// Requests sent at once on a connection before waiting for the replies.
+    pub pipeline: usize,
+    /// Size of the values of SET and LPUSH, in bytes.
+    pub value_size: usize,
+    /// Keys are picked at random among this many.
+    pub keyspace: u64,
+}
+
+/// What a run measured.
+#[derive(Debug, Clone)]
+pub struct Report {
+    pub name: String,
+    pub requests: u64,
+    pub errors: u64,
+    pub elapsed: Duration,
+    // every latency in microseconds, sorted
+    latencies_us: Vec<u64>,
+}
+
+impl Report {
+    pub fn throughput(&self) -> f64 {
+        self.requests as f64 / self.elapsed.as_secs_f64()
+    }
+
+    /// Returns the latency below which `p` (0.0..=1.0) of the requests completed.
+    pub fn percentile(&self, p: f64) -> Duration {
+        if self.latencies_us.is_empty() {
+            return Duration::ZERO;
+        }
+        // nearest-rank method: the smallest sample with at least p of the samples at or below it
+        let rank = (p * self.latencies_us.len() as f64).ceil().max(1.0) as usize;
+        Duration::from_micros(self.latencies_us[rank.min(self.latencies_us.len()) - 1])
+    }
+
+    pub fn average(&self) -> Duration {
+        match self.latencies_us.len() {
+            0 => Duration::ZERO,
+            n => Duration::from_micros(self.latencies_us.iter().sum::<u64>() / n as u64),
+        }
+    }
+
+    /// Formats the report like `redis-benchmark` does.
+    pub fn to_text(&self, config: &BenchConfig) -> String {
+        let mut out = String::new();
+        writeln!(out, "====== {} ======", self.name).unwrap();
+        writeln!(out, "  {} requests completed in {:.2} seconds", self.requests, self.elapsed.as_secs_f64()).unwrap();
+        writeln!(out, "  {} parallel clients", config.clients).unwrap();
+        writeln!(out, "  {} bytes payload", config.value_size).unwrap();
+        writeln!(out, "  pipeline depth: {}", config.pipeline).unwrap();
+        if self.errors > 0 {
+            writeln!(out, "  {} error replies", self.errors).unwrap();
+        }
+        writeln!(out, "  throughput: {:.2} requests per second", self.throughput()).unwrap();
+        writeln!(
+            out,
+            "  latency (msec): avg={:.3} p50={:.3} p99={:.3} p99.9={:.3} max={:.3}",
+            millis(self.average()),
+            millis(self.percentile(0.50)),
+            millis(self.percentile(0.99)),
+            millis(self.percentile(0.999)),
+            millis(self.percentile(1.0)),
+        )
+        .unwrap();
+        out
+    }
+
+    pub const CSV_HEADER: &'static str =
+        "\"test\",\"rps\",\"avg_latency_ms\",\"p50_latency_ms\",\"p99_latency_ms\",\"p999_latency_ms\",\"max_latency_ms\",\"errors\"";
+
+    /// One line of CSV, matching `CSV_HEADER`.
+    pub fn to_csv(&self) -> String {
+        format!(
+            "\"{}\",\"{:.2}\",\"{:.3}\",\"{:.3}\",\"{:.3}\",\"{:.3}\",\"{:.3}\",\"{}\"",
+            self.name,
+            self.throughput(),
+            millis(self.average()),
+            millis(self.percentile(0.50)),
+            millis(self.percentile(0.99)),
+            millis(self.percentile(0.999)),
+            millis(self.percentile(1.0)),
+            self.errors,
+        )
+    }
+}
+
+fn millis(d: Duration) -> f64 {
+    d.as_secs_f64() * 1000.0
+}
+
+/// Runs `workload` and measures it. Connections are opened before the clock starts.
+pub async fn run(config: &BenchConfig, workload: &Workload) -> crate::Result<Report> {
+    let mut connections = Vec::with_capacity(config.clients);
+    for _ in 0..config.clients {
+        connections.push(Connection::new(TcpStream::connect(&config.addr).await?));
+    }
+
+    let remaining = Arc::new(AtomicU64::new(config.requests));
+    let value = Bytes::from(vec![b'x'; config.value_size]);
+    let start = Instant::now();
+    let mut tasks = Vec::new();
+    for (i, connection) in connections.into_iter().enumerate() {
+        let remaining = remaining.clone();
+        let (workload, value) = (workload.clone(), value.clone());
+        let (pipeline, keyspace) = (config.pipeline.max(1) as u64, config.keyspace.max(1));
+        tasks.push(tokio::spawn(async move {
+            client(connection, &remaining, &workload, &value, pipeline, keyspace, i as u64).await
+        }));
+    }
+
+    let (mut latencies_us, mut errors) = (Vec::with_capacity(config.requests as usize), 0);
+    for task in tasks {
+        let (latencies, task_errors) = task.await??;
+        latencies_us.extend(latencies);
+        errors += task_errors;
+    }
+    let elapsed = start.elapsed();
+    latencies_us.sort_unstable();
+    Ok(Report { name: workload.name(), requests: latencies_us.len() as u64, errors, elapsed, latencies_us })
+}
+
+/// One connection: takes `pipeline` requests at a time from the shared counter until none
+/// are left. Returns the latencies and the number of error replies.
+async fn client(
+    mut connection: Connection,
+    remaining: &AtomicU64,
+    workload: &Workload,
+    value: &Bytes,
+    pipeline: u64,
+    keyspace: u64,
+    seed: u64,
+) -> crate::Result<(Vec<u64>, u64)> {
+    let mut rng = XorShift::new(seed);
+    let (mut latencies, mut errors) = (Vec::new(), 0);
+    loop {
+        let take = |n: u64| if n == 0 { None } else { Some(n - n.min(pipeline)) };
+        let batch = match remaining.fetch_update(Ordering::Relaxed, Ordering::Relaxed, take) {
+            Ok(before) => before.min(pipeline),
+            Err(_) => return Ok((latencies, errors)),
+        };
+        let frames: Vec<Frame> = (0..batch)
+            .map(|_| workload.pick(rng.next()).frame(rng.next() % keyspace, value))
+            .collect();
+
+        // Every request of a pipeline is sent at the same time, so its latency is the time
+        // from the write until its own reply arrived.
+        let sent = Instant::now();
+        connection.write_frames(&frames).await?;
+        for _ in 0..batch {
+            match connection.read_frame().await? {
+                Some(Frame::Error(_)) => errors += 1,
+                Some(_) => {}
+                None => return Err("server closed the connection".into()),
+            }
+            latencies.push(sent.elapsed().as_micros() as u64);
+        }
+    }
+}
+
+/// A tiny pseudo random generator, good enough to spread keys (and no extra dependency).
+struct XorShift(u64);
+
+impl XorShift {
+    fn new(seed: u64) -> XorShift {
+        // the state must not be 0
+        XorShift(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.0 = x;
+        x
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TestServer;
+
+    #[test]
+    fn test_parse_workload() {
+        let workload = Workload::parse("get=80,SET=20").unwrap();
+        assert_eq!(workload.commands, vec![(BenchCommand::Get, 80), (BenchCommand::Set, 20)]);
+        assert_eq!(workload.name(), "GET=80,SET=20");
+        assert_eq!(Workload::parse("incr").unwrap().name(), "INCR");
+        assert!(Workload::parse("get=x").is_err());
+        assert!(Workload::parse("flushall").is_err());
+        assert!(Workload::parse("").is_err());
+    }
+
+    #[test]
+    fn test_pick_follows_weights() {
+        let workload = Workload::parse("get=3,set=1").unwrap();
+        let picks: Vec<BenchCommand> = (0..4).map(|n| workload.pick(n)).collect();
+        assert_eq!(picks, [BenchCommand::Get, BenchCommand::Get, BenchCommand::Get, BenchCommand::Set]);
+    }
+
+    #[test]
+    fn test_percentiles() {
+        let report = Report {
+            name: "GET".to_string(),
+            requests: 1000,
+            errors: 0,
+            elapsed: Duration::from_secs(1),
+            latencies_us: (1..=1000).collect(),
+        };
+        assert_eq!(report.percentile(0.5), Duration::from_micros(500));
+        assert_eq!(report.percentile(0.99), Duration::from_micros(990));
+        assert_eq!(report.percentile(0.999), Duration::from_micros(999));
+        assert_eq!(report.percentile(1.0), Duration::from_micros(1000));
+        assert_eq!(report.throughput(), 1000.0);
+        assert!(report.to_csv().starts_with("\"GET\",\"1000.00\",\"0.500\""));
+    }
+
+    #[tokio::test]
+    async fn test_run_sends_every_request() {
+        let server = TestServer::start().await.unwrap();
+        let addr = server.addr().to_string();
+        let config = BenchConfig { addr, clients: 4, requests: 1001, pipeline: 16, value_size: 8, keyspace: 1 };
+        let report = run(&config, &Workload::parse("incr").unwrap()).await.unwrap();
+        assert_eq!((report.requests, report.errors), (1001, 0));
+        // a single key, so the counter saw every request
+        assert_eq!(server.shared().db.get("counter:000000000000"), Some(Bytes::from("1001")));
+    }
+}
@@ -0,0 +1,89 @@
+use my_redis::benchmark::{self, BenchConfig, Report, Workload};
+use std::env;
+use std::process;
+
+/// A `redis-benchmark` look-alike, e.g.
+/// `cargo run --release --bin my-redis-benchmark -- -c 50 -n 100000 -P 16 -t get,set`
+/// runs GET and SET one after the other, and `--mix get=80,set=20` runs both in one test.
+struct Config {
+    bench: BenchConfig,
+    workloads: Vec<Workload>,
+    csv: bool,
+}
+
+impl Config {
+    fn build(args: &[String]) -> Result<Config, String> {
+        let mut host = "127.0.0.1".to_string();
+        let mut port = 6379u16;
+        let mut bench = BenchConfig {
+            addr: String::new(),
+            clients: 50,
+            requests: 100_000,
+            pipeline: 1,
+            value_size: 3,
+            keyspace: 100_000,
+        };
+        let mut workloads = Vec::new();
+        let mut csv = false;
+
+        let mut iter = args.iter().skip(1);
+        while let Some(flag) = iter.next() {
+            if flag == "--csv" {
+                csv = true;
+                continue;
+            }
+            let value = iter.next().ok_or(format!("missing value for {}", flag))?;
+            let invalid = |_| format!("invalid value for {}: {}", flag, value);
+            match flag.as_str() {
+                "-h" => host = value.clone(),
+                "-p" => port = value.parse().map_err(invalid)?,
+                "-c" => bench.clients = value.parse().map_err(invalid)?,
+                "-n" => bench.requests = value.parse().map_err(invalid)?,
+                "-P" => bench.pipeline = value.parse().map_err(invalid)?,
+                "-d" => bench.value_size = value.parse().map_err(invalid)?,
+                "-r" => bench.keyspace = value.parse().map_err(invalid)?,
+                // one test per command
+                "-t" => {
+                    for name in value.split(',') {
+                        workloads.push(Workload::parse(name)?);
+                    }
+                }
+                // a single test mixing the commands
+                "--mix" => workloads.push(Workload::parse(value)?),
+                _ => return Err(format!("unknown option {}", flag)),
+            }
+        }
+        if bench.clients == 0 || bench.pipeline == 0 {
+            return Err("-c and -P must be at least 1".to_string());
+        }
+        if workloads.is_empty() {
+            workloads = ["set", "get", "incr", "lpush"].iter().map(|name| Workload::parse(name)).collect::<Result<_, _>>()?;
+        }
+        bench.addr = format!("{}:{}", host, port);
+        Ok(Config { bench, workloads, csv })
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = env::args().collect();
+    let config = Config::build(&args).unwrap_or_else(|err| {
+        eprintln!("Problem parsing arguments: {}", err);
+        process::exit(1);
+    });
+
+    if config.csv {
+        println!("{}", Report::CSV_HEADER);
+    }
+    for workload in &config.workloads {
+        let report = benchmark::run(&config.bench, workload).await.unwrap_or_else(|err| {
+            eprintln!("Benchmark {} failed: {}", workload.name(), err);
+            process::exit(1);
+        });
+        if config.csv {
+            println!("{}", report.to_csv());
+        } else {
+            println!("{}", report.to_text(&config.bench));
+        }
+    }
+}
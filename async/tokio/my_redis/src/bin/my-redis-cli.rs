@@ -0,0 +1,339 @@
+use bytes::{Buf, BytesMut};
+use my_redis::cli::{self, format_reply};
+use my_redis::{Connection, Frame};
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, Helper};
+use std::env;
+use std::io::{self, BufRead, Cursor, IsTerminal};
+use std::process;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::runtime::Runtime;
+
+/// A `redis-cli` look-alike:
+/// - `my-redis-cli` starts an interactive prompt (with history and tab completion),
+/// - `my-redis-cli SET key value` runs a single command,
+/// - `echo "GET key" | my-redis-cli` runs one command per line of stdin,
+/// - `my-redis-cli --pipe < data.resp` sends raw RESP commands as fast as possible (mass insertion).
+struct Config {
+    host: String,
+    port: u16,
+    pipe: bool,
+    // the command to run in one-shot mode, empty otherwise
+    command: Vec<String>,
+}
+
+impl Config {
+    fn build(args: &[String]) -> Result<Config, String> {
+        let mut config = Config { host: "127.0.0.1".to_string(), port: 6379, pipe: false, command: Vec::new() };
+
+        let mut iter = args.iter().skip(1);
+        while let Some(arg) = iter.next() {
+            match arg.as_str() {
+                "-h" => config.host = iter.next().ok_or("missing value for -h")?.clone(),
+                "-p" => {
+                    let value = iter.next().ok_or("missing value for -p")?;
+                    config.port = value.parse().map_err(|_| format!("invalid value for -p: {}", value))?;
+                }
+                "--pipe" => config.pipe = true,
+                // Everything from the first non-option on is the command, like redis-cli.
+                _ => {
+                    config.command.push(arg.clone());
+                    config.command.extend(iter.by_ref().cloned());
+                }
+            }
+        }
+        Ok(config)
+    }
+
+    fn addr(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let config = Config::build(&args).unwrap_or_else(|err| {
+        eprintln!("Problem parsing arguments: {}", err);
+        process::exit(1);
+    });
+
+    // Why not #[tokio::main]?
+    // Because rustyline blocks the thread while waiting for input. With our own runtime, the
+    // prompt runs on the main thread and only sending a command goes through `block_on`.
+    let rt = Runtime::new().unwrap();
+    let result = if config.pipe {
+        rt.block_on(pipe(&config.addr()))
+    } else if !config.command.is_empty() {
+        rt.block_on(one_shot(&config))
+    } else if io::stdin().is_terminal() {
+        repl(&rt, &config)
+    } else {
+        from_stdin(&rt, &config)
+    };
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
+
+async fn connect(addr: &str) -> my_redis::Result<Connection> {
+    let socket = TcpStream::connect(addr)
+        .await
+        .map_err(|e| format!("Could not connect to my_redis at {}: {}", addr, e))?;
+    Ok(Connection::new(socket))
+}
+
+/// Sends a command and returns its reply.
+///
+/// Push frames (e.g. invalidation messages after CLIENT TRACKING in RESP3) can arrive before
+/// the reply, they are printed as they come.
+async fn send(connection: &mut Connection, args: Vec<Vec<u8>>) -> my_redis::Result<Frame> {
+    connection.write_frame(&Frame::command(args)).await?;
+    loop {
+        match connection.read_frame().await? {
+            Some(Frame::Push(items)) => println!("{}", format_reply(&Frame::Push(items))),
+            Some(frame) => return Ok(frame),
+            None => return Err("Server closed the connection".into()),
+        }
+    }
+}
+
+/// Prints a reply, except for INFO which is text meant to be read as it is (like redis-cli).
+fn print_reply(args: &[Vec<u8>], reply: &Frame) {
+    match reply {
+        Frame::Bulk(text) if args[0].eq_ignore_ascii_case(b"info") => print!("{}", String::from_utf8_lossy(text)),
+        reply => println!("{}", format_reply(reply)),
+    }
+}
+
+/// After MONITOR, prints the commands the server runs until it goes away (or Ctrl-C, like
+/// redis-cli).
+async fn follow_monitor(connection: &mut Connection) -> my_redis::Result<()> {
+    while let Some(frame) = connection.read_frame().await? {
+        match frame {
+            Frame::Simple(line) => println!("{}", line),
+            frame => println!("{}", format_reply(&frame)),
+        }
+    }
+    Ok(())
+}
+
+fn is_monitor(args: &[Vec<u8>], reply: &Frame) -> bool {
+    args[0].eq_ignore_ascii_case(b"monitor") && !matches!(reply, Frame::Error(_))
+}
+
+async fn one_shot(config: &Config) -> my_redis::Result<()> {
+    let mut connection = connect(&config.addr()).await?;
+    let args: Vec<Vec<u8>> = config.command.iter().map(|arg| arg.as_bytes().to_vec()).collect();
+    let reply = send(&mut connection, args.clone()).await?;
+    print_reply(&args, &reply);
+    if is_monitor(&args, &reply) {
+        follow_monitor(&mut connection).await?;
+    }
+    Ok(())
+}
+
+fn from_stdin(rt: &Runtime, config: &Config) -> my_redis::Result<()> {
+    let mut connection = rt.block_on(connect(&config.addr()))?;
+    for line in io::stdin().lock().lines() {
+        let args = match cli::split_args(&line?) {
+            Ok(args) if args.is_empty() => continue,
+            Ok(args) => args,
+            Err(e) => {
+                eprintln!("Invalid argument(s): {}", e);
+                continue;
+            }
+        };
+        let reply = rt.block_on(send(&mut connection, args.clone()))?;
+        print_reply(&args, &reply);
+    }
+    Ok(())
+}
+
+/// Completes the command name, i.e. the first word of the line.
+struct CommandCompleter;
+
+impl Completer for CommandCompleter {
+    type Candidate = String;
+
+    fn complete(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> rustyline::Result<(usize, Vec<String>)> {
+        let prefix = &line[..pos];
+        if prefix.trim_start().contains(char::is_whitespace) {
+            return Ok((pos, Vec::new()));
+        }
+        let start = prefix.len() - prefix.trim_start().len();
+        let candidates = cli::complete(&prefix[start..]).into_iter().map(String::from).collect();
+        Ok((start, candidates))
+    }
+}
+
+// The defaults of these traits do nothing, which is fine for a prompt.
+impl Hinter for CommandCompleter {
+    type Hint = String;
+}
+impl Highlighter for CommandCompleter {}
+impl Validator for CommandCompleter {}
+impl Helper for CommandCompleter {}
+
+fn repl(rt: &Runtime, config: &Config) -> my_redis::Result<()> {
+    let addr = config.addr();
+    let mut editor = Editor::new()?;
+    editor.set_helper(Some(CommandCompleter));
+    let history = env::var("HOME").map(|home| format!("{}/.my_redis_cli_history", home)).ok();
+    if let Some(path) = &history {
+        // No history yet on the first run, that's fine.
+        let _ = editor.load_history(path);
+    }
+
+    // If the server goes away we keep the prompt, and reconnect on the next command.
+    let mut connection = rt.block_on(connect(&addr)).map_err(|e| eprintln!("{}", e)).ok();
+    loop {
+        let prompt = match connection {
+            Some(_) => format!("{}> ", addr),
+            None => "not connected> ".to_string(),
+        };
+        let line = match editor.readline(&prompt) {
+            Ok(line) => line,
+            // Ctrl-C clears the line, Ctrl-D quits.
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(e.into()),
+        };
+        let args = match cli::split_args(&line) {
+            Ok(args) if args.is_empty() => continue,
+            Ok(args) => args,
+            Err(e) => {
+                println!("Invalid argument(s): {}", e);
+                continue;
+            }
+        };
+        editor.add_history_entry(line.as_str())?;
+        if matches!(&args[0][..], b"quit" | b"exit" | b"QUIT" | b"EXIT") {
+            break;
+        }
+
+        if connection.is_none() {
+            connection = rt.block_on(connect(&addr)).map_err(|e| eprintln!("{}", e)).ok();
+        }
+        let Some(conn) = connection.as_mut() else { continue };
+        match rt.block_on(send(conn, args.clone())) {
+            Ok(reply) if is_monitor(&args, &reply) => {
+                print_reply(&args, &reply);
+                rt.block_on(follow_monitor(conn))?;
+                break;
+            }
+            Ok(reply) => print_reply(&args, &reply),
+            Err(e) => {
+                println!("Error: {}", e);
+                connection = None;
+            }
+        }
+    }
+
+    if let Some(path) = &history {
+        editor.save_history(path)?;
+    }
+    Ok(())
+}
+
+/// Mass insertion: streams the RESP commands of stdin to the server while reading the replies.
+///
+/// Why two tasks?
+/// If we wrote everything before reading, the server would eventually block writing replies
+/// nobody reads, stop reading our commands, and both sides would wait forever.
+async fn pipe(addr: &str) -> my_redis::Result<()> {
+    let socket = TcpStream::connect(addr).await?;
+    let (mut reader, mut writer) = socket.into_split();
+
+    // The writer counts the commands it sends, so the reader knows how many replies to expect.
+    let sender = tokio::spawn(async move {
+        let mut stdin = tokio::io::stdin();
+        let mut pending = BytesMut::with_capacity(64 * 1024);
+        let mut sent = 0u64;
+        loop {
+            let n = stdin.read_buf(&mut pending).await?;
+            let complete = count_frames(&pending)?;
+            writer.write_all(&pending[..complete.bytes]).await?;
+            pending.advance(complete.bytes);
+            sent += complete.frames;
+            if n == 0 {
+                if !pending.is_empty() {
+                    return Err("stdin ends in the middle of a command".into());
+                }
+                eprintln!("All data transferred. Waiting for the last reply...");
+                return Ok::<u64, my_redis::Error>(sent);
+            }
+        }
+    });
+
+    let mut buf = BytesMut::with_capacity(64 * 1024);
+    let (mut replies, mut errors) = (0u64, 0u64);
+    let mut expected = None;
+    let mut sender = Some(sender);
+    loop {
+        if expected == Some(replies) {
+            break;
+        }
+        tokio::select! {
+            result = async { sender.as_mut().unwrap().await }, if sender.is_some() => {
+                sender = None;
+                expected = Some(result??);
+            }
+            n = reader.read_buf(&mut buf) => {
+                if n? == 0 {
+                    return Err("Server closed the connection".into());
+                }
+                while let Some(frame) = next_frame(&mut buf)? {
+                    replies += 1;
+                    if let Frame::Error(e) = frame {
+                        errors += 1;
+                        eprintln!("{}", e);
+                    }
+                }
+            }
+        }
+    }
+    println!("errors: {}, replies: {}", errors, replies);
+    Ok(())
+}
+
+struct Complete {
+    frames: u64,
+    bytes: usize,
+}
+
+/// Counts the whole frames at the start of `data`.
+fn count_frames(data: &[u8]) -> my_redis::Result<Complete> {
+    let mut cursor = Cursor::new(data);
+    let mut complete = Complete { frames: 0, bytes: 0 };
+    loop {
+        match Frame::check(&mut cursor) {
+            Ok(()) => {
+                complete.frames += 1;
+                complete.bytes = cursor.position() as usize;
+            }
+            Err(my_redis::frame::Error::Incomplete) => return Ok(complete),
+            Err(e) => return Err(format!("stdin is not valid RESP: {}", e).into()),
+        }
+    }
+}
+
+fn next_frame(buf: &mut BytesMut) -> my_redis::Result<Option<Frame>> {
+    let mut cursor = Cursor::new(&buf[..]);
+    match Frame::check(&mut cursor) {
+        Ok(()) => {
+            let len = cursor.position() as usize;
+            cursor.set_position(0);
+            let frame = Frame::parse(&mut cursor)?;
+            buf.advance(len);
+            Ok(Some(frame))
+        }
+        Err(my_redis::frame::Error::Incomplete) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
@@ -0,0 +1,95 @@
+use my_redis::export::{self, Format};
+use my_redis::Client;
+use std::env;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::process;
+
+/// Copies a keyspace to a file and back, e.g.
+/// `my-redis-dump export --format json --match 'user:*' --file users.jsonl` and
+/// `my-redis-dump import --format json --file users.jsonl -p 6380`.
+/// Without `--file`, export writes to stdout and import reads stdin.
+struct Config {
+    addr: String,
+    import: bool,
+    format: Format,
+    pattern: Option<String>,
+    file: Option<String>,
+    replace: bool,
+}
+
+impl Config {
+    fn build(args: &[String]) -> Result<Config, String> {
+        let mut host = "127.0.0.1".to_string();
+        let mut port = 6379u16;
+        let mut format = Format::Json;
+        let mut pattern = None;
+        let mut file = None;
+        let mut replace = false;
+
+        let mut iter = args.iter().skip(1);
+        let import = match iter.next().map(String::as_str) {
+            Some("export") => false,
+            Some("import") => true,
+            _ => return Err("usage: my-redis-dump export|import [options]".to_string()),
+        };
+        while let Some(flag) = iter.next() {
+            if flag == "--replace" {
+                replace = true;
+                continue;
+            }
+            let value = iter.next().ok_or(format!("missing value for {}", flag))?;
+            match flag.as_str() {
+                "-h" => host = value.clone(),
+                "-p" => port = value.parse().map_err(|_| format!("invalid value for -p: {}", value))?,
+                "--format" => format = Format::parse(value).ok_or(format!("unknown format {}", value))?,
+                "--match" => pattern = Some(value.clone()),
+                "--file" => file = Some(value.clone()),
+                _ => return Err(format!("unknown option {}", flag)),
+            }
+        }
+        Ok(Config { addr: format!("{}:{}", host, port), import, format, pattern, file, replace })
+    }
+}
+
+async fn run(config: &Config) -> my_redis::Result<()> {
+    let client = Client::connect(&config.addr).await?;
+    let pattern = config.pattern.as_deref();
+    if config.import {
+        let data = match &config.file {
+            Some(path) => fs::read(path)?,
+            None => {
+                let mut data = Vec::new();
+                io::stdin().read_to_end(&mut data)?;
+                data
+            }
+        };
+        let records = export::read_records(config.format, &data)?;
+        let imported = export::import(&client, &records, pattern, config.replace).await?;
+        eprintln!("imported {} of {} keys", imported, records.len());
+    } else {
+        let records = export::export(&client, pattern).await?;
+        let mut out: Box<dyn Write> = match &config.file {
+            Some(path) => Box::new(io::BufWriter::new(fs::File::create(path)?)),
+            None => Box::new(io::BufWriter::new(io::stdout().lock())),
+        };
+        export::write_records(config.format, &records, &mut out)?;
+        out.flush()?;
+        eprintln!("exported {} keys", records.len());
+    }
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = env::args().collect();
+    let config = Config::build(&args).unwrap_or_else(|err| {
+        eprintln!("Problem parsing arguments: {}", err);
+        process::exit(1);
+    });
+
+    if let Err(e) = run(&config).await {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
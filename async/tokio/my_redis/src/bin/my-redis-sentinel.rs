@@ -0,0 +1,56 @@
+use my_redis::{Sentinel, SentinelConfig};
+use std::env;
+use std::process;
+use std::time::Duration;
+use tokio::net::TcpListener;
+
+/// A `redis-sentinel` look-alike. Three of them watching a primary on 6379:
+/// `my-redis-sentinel --port 26379 --primary 127.0.0.1:6379 --peer 127.0.0.1:26380 --peer 127.0.0.1:26381`
+/// and the same on 26380 and 26381 with the other two as peers. The replicas are found
+/// through the primary, after `my-redis-cli -p 6380 REPLICAOF 127.0.0.1 6379`.
+struct Config {
+    port: u16,
+    sentinel: SentinelConfig,
+}
+
+impl Config {
+    fn build(args: &[String]) -> Result<Config, String> {
+        let mut port = 26379u16;
+        let mut sentinel = SentinelConfig::new("mymaster", "127.0.0.1:6379");
+
+        let mut iter = args.iter().skip(1);
+        while let Some(flag) = iter.next() {
+            let value = iter.next().ok_or(format!("missing value for {}", flag))?;
+            let invalid = |_| format!("invalid value for {}: {}", flag, value);
+            match flag.as_str() {
+                "--port" => port = value.parse().map_err(invalid)?,
+                "--name" => sentinel.name = value.clone(),
+                "--primary" => sentinel.primary = value.clone(),
+                "--quorum" => sentinel.quorum = value.parse().map_err(invalid)?,
+                "--peer" => sentinel.peers.push(value.clone()),
+                // milliseconds, like the settings of Redis Sentinel
+                "--down-after" => sentinel.down_after = Duration::from_millis(value.parse().map_err(invalid)?),
+                "--failover-timeout" => sentinel.failover_timeout = Duration::from_millis(value.parse().map_err(invalid)?),
+                _ => return Err(format!("unknown option {}", flag)),
+            }
+        }
+        if sentinel.quorum == 0 || sentinel.quorum > sentinel.peers.len() + 1 {
+            return Err(format!("--quorum must be between 1 and {} (this sentinel and its peers)", sentinel.peers.len() + 1));
+        }
+        Ok(Config { port, sentinel })
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = env::args().collect();
+    let config = Config::build(&args).unwrap_or_else(|err| {
+        eprintln!("Problem parsing arguments: {}", err);
+        process::exit(1);
+    });
+
+    let listener = TcpListener::bind(("127.0.0.1", config.port)).await.unwrap();
+    let _sentinel = Sentinel::start(config.sentinel, listener).unwrap();
+    // The sentinel runs in background tasks until the process is stopped.
+    std::future::pending::<()>().await;
+}
@@ -1,52 +1,121 @@
-use tokio::net::{TcpListener, TcpStream};
-use mini_redis::{Connection, Frame};
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use my_redis::audit::{AuditConfig, AuditLog};
+use my_redis::metrics;
+use my_redis::server::{self, Shared};
+use my_redis::storage::{bitcask, BitcaskConfig};
 use my_redis::ShardedDatabase;
 
+/// Command line options of the server, e.g.
+/// `cargo run --bin server -- --slowlog-log-slower-than 5000 --metrics-port 9121`
+struct Config {
+    port: u16,
+    // microseconds, a negative value disables the slow log (same as Redis)
+    slowlog_log_slower_than: i64,
+    slowlog_max_len: usize,
+    // the Prometheus endpoint is off unless a port is given
+    metrics_port: Option<u16>,
+    // milliseconds an EVAL may run before it is aborted
+    script_time_limit: u64,
+    // where the data is kept: "memory" (lost on exit) or "bitcask" (files in `dir`)
+    storage: String,
+    dir: PathBuf,
+    // JSON lines of every mutating command, off unless a path is given
+    audit_log: Option<PathBuf>,
+    audit_log_max_size: u64,
+    audit_log_max_files: usize,
+}
+
+impl Config {
+    fn build(args: &[String]) -> Result<Config, String> {
+        let mut config = Config {
+            port: 6379,
+            slowlog_log_slower_than: 10_000,
+            slowlog_max_len: 128,
+            metrics_port: None,
+            script_time_limit: 5_000,
+            storage: "memory".to_string(),
+            dir: PathBuf::from("data"),
+            audit_log: None,
+            audit_log_max_size: 100 * 1024 * 1024,
+            audit_log_max_files: 5,
+        };
+
+        let mut iter = args.iter().skip(1);
+        while let Some(flag) = iter.next() {
+            let value = iter.next().ok_or(format!("missing value for {}", flag))?;
+            let invalid = |_| format!("invalid value for {}: {}", flag, value);
+            match flag.as_str() {
+                "--port" => config.port = value.parse().map_err(invalid)?,
+                "--slowlog-log-slower-than" => config.slowlog_log_slower_than = value.parse().map_err(invalid)?,
+                "--slowlog-max-len" => config.slowlog_max_len = value.parse().map_err(invalid)?,
+                "--metrics-port" => config.metrics_port = Some(value.parse().map_err(invalid)?),
+                "--script-time-limit" => config.script_time_limit = value.parse().map_err(invalid)?,
+                "--storage" if value == "memory" || value == "bitcask" => config.storage = value.clone(),
+                "--storage" => return Err(format!("invalid value for --storage: {} (memory or bitcask)", value)),
+                "--dir" => config.dir = PathBuf::from(value),
+                "--audit-log" => config.audit_log = Some(PathBuf::from(value)),
+                "--audit-log-max-size" => config.audit_log_max_size = value.parse().map_err(invalid)?,
+                "--audit-log-max-files" => config.audit_log_max_files = value.parse().map_err(invalid)?,
+                _ => return Err(format!("unknown option {}", flag)),
+            }
+        }
+        Ok(config)
+    }
+}
+
 #[tokio::main]
 async fn main() {
+    let args: Vec<String> = env::args().collect();
+    let config = Config::build(&args).unwrap_or_else(|err| {
+        eprintln!("Problem parsing arguments: {}", err);
+        process::exit(1);
+    });
+
     // Bind the listener to the address
-    let listener = TcpListener::bind("127.0.0.1:6379").await.unwrap();
-    
+    let listener = TcpListener::bind(("127.0.0.1", config.port)).await.unwrap();
+
     // Create a sharded database with 16 shards
-    let db = ShardedDatabase::new(16);
-
-    loop {
-        let (socket, _) = listener.accept().await.unwrap();
-        // Why do we need to clone here?
-        // Because the db is wrapped in an Arc internally, cloning it only increments
-        // the reference count, allowing multiple tasks to share the same database.
-        let db = db.clone();
-        // Spawn a new task to handle the connection
-        tokio::spawn(async move {
-            process(socket, db).await
-        });
+    let db = open_database(&config.storage, &config.dir, 16).unwrap_or_else(|err| {
+        eprintln!("Could not open the data in {}: {}", config.dir.display(), err);
+        process::exit(1);
+    });
+    let mut shared = Shared::new(db);
+    if let Some(path) = &config.audit_log {
+        let audit = AuditConfig {
+            path: path.clone(),
+            max_size: config.audit_log_max_size,
+            max_files: config.audit_log_max_files,
+        };
+        shared.audit = Some(AuditLog::open(audit).unwrap_or_else(|err| {
+            eprintln!("Could not open the audit log {}: {}", path.display(), err);
+            process::exit(1);
+        }));
     }
-}
+    let threshold = u64::try_from(config.slowlog_log_slower_than).ok().map(Duration::from_micros);
+    shared.metrics.set_slowlog_threshold(threshold);
+    shared.metrics.set_slowlog_max_len(config.slowlog_max_len);
+    shared.scripts.set_time_limit(Duration::from_millis(config.script_time_limit));
 
-async fn process(socket: TcpStream, db: ShardedDatabase) {
-    use mini_redis::Command::{self, Get, Set};
-
-    // Connection, provided by `mini-redis`, handles parsing frames from the socket
-    let mut connection = Connection::new(socket);
+    if let Some(port) = config.metrics_port {
+        // The metrics endpoint only listens locally, it is meant for a scraper on the same host.
+        let metrics_listener = TcpListener::bind(("127.0.0.1", port)).await.unwrap();
+        tokio::spawn(metrics::serve_prometheus(
+            metrics_listener,
+            shared.metrics.clone(),
+            shared.db.clone(),
+        ));
+    }
 
-    // Use `read_frame` to receive a command from the connection.
-    while let Some(frame) = connection.read_frame().await.unwrap() {
-        let response = match Command::from_frame(frame).unwrap() {
-            Set(cmd) => {
-                db.insert(cmd.key(), cmd.value().clone());
-                Frame::Simple("OK".to_string())
-            }
-            Get(cmd) => {
-                if let Some(value) = db.get(cmd.key()) {
-                    Frame::Bulk(value.clone())
-                } else {
-                    Frame::Null
-                }
-            }
-            cmd => panic!("unimplemented {:?}", cmd),
-        };
+    server::run(listener, shared).await;
+}
 
-        // Write the response to the client
-        connection.write_frame(&response).await.unwrap();
+fn open_database(storage: &str, dir: &Path, num_shards: usize) -> std::io::Result<ShardedDatabase> {
+    match storage {
+        "bitcask" => Ok(ShardedDatabase::with_storage(bitcask::open_shards(dir, num_shards, &BitcaskConfig::default())?)),
+        _ => Ok(ShardedDatabase::new(num_shards)),
     }
 }
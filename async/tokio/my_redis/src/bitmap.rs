@@ -0,0 +1,207 @@
+//! Strings used as arrays of bits (SETBIT/GETBIT/BITCOUNT/BITOP).
+//!
+//! Bits are numbered from the most significant bit of the first byte, like in Redis: bit 0
+//! is `0x80` of byte 0, bit 7 is `0x01` of byte 0, bit 8 is `0x80` of byte 1. A bitmap is an
+//! ordinary string value, so GET returns the raw bytes and SET can load one.
+
+use crate::{ShardedDatabase, Value, WrongType};
+use bytes::{Bytes, BytesMut};
+
+/// The largest offset SETBIT accepts: strings are limited to 512MB, like in Redis.
+pub const MAX_BIT_OFFSET: u64 = 512 * 1024 * 1024 * 8 - 1;
+
+/// The operation of BITOP.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BitOp {
+    And,
+    Or,
+    Xor,
+    Not,
+}
+
+impl BitOp {
+    pub fn parse(s: &str) -> Option<BitOp> {
+        match s.to_uppercase().as_str() {
+            "AND" => Some(BitOp::And),
+            "OR" => Some(BitOp::Or),
+            "XOR" => Some(BitOp::Xor),
+            "NOT" => Some(BitOp::Not),
+            _ => None,
+        }
+    }
+}
+
+/// Whether the range of BITCOUNT counts bytes (the default) or bits.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BitUnit {
+    Byte,
+    Bit,
+}
+
+impl ShardedDatabase {
+    /// Sets or clears the bit at `offset`, growing the string with zero bytes if needed
+    /// (SETBIT). Returns the previous bit.
+    pub fn setbit(&self, key: &str, offset: u64, bit: bool) -> Result<bool, WrongType> {
+        let mut shard = self.lock_shard(key);
+        if !shard.entries.contains_key(key) {
+            shard.entries.insert(key.to_string(), Value::String(Bytes::new()));
+        }
+        let Some(Value::String(data)) = shard.entries.get_mut(key) else {
+            return Err(WrongType);
+        };
+
+        // Why `try_into_mut`?
+        // `Bytes` is immutable. When nobody else holds this value, we get the buffer back
+        // without a copy, so setting bits one by one in a large bitmap stays cheap.
+        let mut buf = std::mem::take(data).try_into_mut().unwrap_or_else(|shared| BytesMut::from(&shared[..]));
+        let byte = (offset / 8) as usize;
+        let mask = 0x80u8 >> (offset % 8);
+        if buf.len() <= byte {
+            buf.resize(byte + 1, 0);
+        }
+        let old = buf[byte] & mask != 0;
+        if bit {
+            buf[byte] |= mask;
+        } else {
+            buf[byte] &= !mask;
+        }
+        *data = buf.freeze();
+        Ok(old)
+    }
+
+    /// Returns the bit at `offset`, 0 past the end of the string (GETBIT).
+    pub fn getbit(&self, key: &str, offset: u64) -> Result<bool, WrongType> {
+        let data = self.get_string(key)?.unwrap_or_default();
+        let byte = (offset / 8) as usize;
+        Ok(data.get(byte).is_some_and(|b| b & (0x80 >> (offset % 8)) != 0))
+    }
+
+    /// Counts the bits set to 1 (BITCOUNT), in the whole string or in a range of bytes or
+    /// bits. Both ends are inclusive, and negative indexes count from the end.
+    pub fn bitcount(&self, key: &str, range: Option<(i64, i64, BitUnit)>) -> Result<u64, WrongType> {
+        let data = self.get_string(key)?.unwrap_or_default();
+        let Some((start, end, unit)) = range else {
+            return Ok(count_ones(&data));
+        };
+
+        let len = match unit {
+            BitUnit::Byte => data.len() as i64,
+            BitUnit::Bit => data.len() as i64 * 8,
+        };
+        let start = if start < 0 { (len + start).max(0) } else { start };
+        let end = if end < 0 { (len + end).max(0) } else { end.min(len - 1) };
+        if start > end || len == 0 {
+            return Ok(0);
+        }
+        let (start, end) = (start as usize, end as usize);
+        match unit {
+            BitUnit::Byte => Ok(count_ones(&data[start..=end])),
+            BitUnit::Bit => {
+                let (first, last) = (start / 8, end / 8);
+                let mut count = count_ones(&data[first..=last]);
+                // Take out the bits of the first byte before `start`, and of the last byte
+                // after `end`.
+                let before = !(0xffu8 >> (start % 8));
+                let after = 0xffu8.checked_shr(end as u32 % 8 + 1).unwrap_or(0);
+                count -= u64::from((data[first] & before).count_ones());
+                count -= u64::from((data[last] & after).count_ones());
+                Ok(count)
+            }
+        }
+    }
+
+    /// Combines the strings at `keys` bit by bit and stores the result at `destination`
+    /// (BITOP). Shorter strings are padded with zero bytes, missing keys are empty strings.
+    /// Returns the length of the result; an empty result deletes `destination`.
+    ///
+    /// Like LMOVE, the keys may live in different shards, so this is not atomic.
+    pub fn bitop(&self, op: BitOp, destination: &str, keys: &[String]) -> Result<usize, WrongType> {
+        let mut sources = Vec::with_capacity(keys.len());
+        for key in keys {
+            sources.push(self.get_string(key)?.unwrap_or_default());
+        }
+        let len = sources.iter().map(|source| source.len()).max().unwrap_or(0);
+        let byte = |source: &Bytes, i: usize| source.get(i).copied().unwrap_or(0);
+
+        let mut result = vec![0u8; len];
+        for (i, out) in result.iter_mut().enumerate() {
+            let mut bytes = sources.iter().map(|source| byte(source, i));
+            let first = bytes.next().unwrap_or(0);
+            *out = match op {
+                BitOp::And => bytes.fold(first, |acc, b| acc & b),
+                BitOp::Or => bytes.fold(first, |acc, b| acc | b),
+                BitOp::Xor => bytes.fold(first, |acc, b| acc ^ b),
+                BitOp::Not => !first,
+            };
+        }
+
+        if result.is_empty() {
+            self.remove(destination);
+        } else {
+            self.insert(destination, Bytes::from(result));
+        }
+        Ok(len)
+    }
+}
+
+fn count_ones(data: &[u8]) -> u64 {
+    data.iter().map(|b| u64::from(b.count_ones())).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_setbit_getbit() {
+        let db = ShardedDatabase::new(4);
+        assert_eq!(db.setbit("bits", 7, true), Ok(false));
+        assert_eq!(db.setbit("bits", 7, true), Ok(true));
+        assert_eq!(db.get("bits"), Some(Bytes::from_static(&[0x01])));
+        // grows the string
+        db.setbit("bits", 8, true).unwrap();
+        assert_eq!(db.get("bits"), Some(Bytes::from_static(&[0x01, 0x80])));
+        assert_eq!(db.getbit("bits", 8), Ok(true));
+        assert_eq!(db.getbit("bits", 9), Ok(false));
+        assert_eq!(db.getbit("bits", 1000), Ok(false));
+        assert_eq!(db.setbit("bits", 7, false), Ok(true));
+
+        db.push("list", &[Bytes::from("a")], crate::list::End::Left).unwrap();
+        assert_eq!(db.setbit("list", 0, true), Err(WrongType));
+    }
+
+    #[test]
+    fn test_bitcount_ranges() {
+        let db = ShardedDatabase::new(4);
+        db.insert("key", Bytes::from("foobar"));
+        assert_eq!(db.bitcount("key", None), Ok(26));
+        assert_eq!(db.bitcount("key", Some((0, 0, BitUnit::Byte))), Ok(4));
+        assert_eq!(db.bitcount("key", Some((1, 1, BitUnit::Byte))), Ok(6));
+        assert_eq!(db.bitcount("key", Some((1, 1, BitUnit::Bit))), Ok(1));
+        assert_eq!(db.bitcount("key", Some((5, 30, BitUnit::Bit))), Ok(17));
+        assert_eq!(db.bitcount("key", Some((-2, -1, BitUnit::Byte))), Ok(7));
+        assert_eq!(db.bitcount("key", Some((3, 1, BitUnit::Byte))), Ok(0));
+        assert_eq!(db.bitcount("missing", None), Ok(0));
+    }
+
+    #[test]
+    fn test_bitop() {
+        let db = ShardedDatabase::new(4);
+        db.insert("a", Bytes::from_static(&[0b1100, 0xff]));
+        db.insert("b", Bytes::from_static(&[0b1010]));
+        let keys = |names: &[&str]| names.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+
+        assert_eq!(db.bitop(BitOp::And, "and", &keys(&["a", "b"])), Ok(2));
+        assert_eq!(db.get("and"), Some(Bytes::from_static(&[0b1000, 0])));
+        db.bitop(BitOp::Or, "or", &keys(&["a", "b"])).unwrap();
+        assert_eq!(db.get("or"), Some(Bytes::from_static(&[0b1110, 0xff])));
+        db.bitop(BitOp::Xor, "xor", &keys(&["a", "b"])).unwrap();
+        assert_eq!(db.get("xor"), Some(Bytes::from_static(&[0b0110, 0xff])));
+        db.bitop(BitOp::Not, "not", &keys(&["b"])).unwrap();
+        assert_eq!(db.get("not"), Some(Bytes::from_static(&[!0b1010])));
+
+        // nothing to combine: the destination is deleted
+        assert_eq!(db.bitop(BitOp::Or, "or", &keys(&["missing"])), Ok(0));
+        assert!(!db.contains_key("or"));
+    }
+}
@@ -0,0 +1,293 @@
+//! Client-side caching on top of CLIENT TRACKING, see `CachingClient`.
+
+use crate::client::{request_frame, PushHandler};
+use crate::frame::Frame;
+use crate::{Client, ClientConfig};
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// A `Client` that keeps the values it reads with GET in a local cache, and drops them when
+/// the server says they changed.
+///
+/// On every (re)connection it sends `HELLO 3` and `CLIENT TRACKING ON`, so the server
+/// remembers the keys this client reads and pushes an invalidation when one changes, whoever
+/// changed it. A hot key is then read from the server once per change instead of once per
+/// GET.
+///
+/// ```no_run
+/// # async fn example() -> my_redis::Result<()> {
+/// let client = my_redis::CachingClient::connect("127.0.0.1:6379").await?;
+/// client.get("config").await?; // from the server
+/// client.get("config").await?; // from the cache, until someone changes "config"
+/// # Ok(())
+/// # }
+/// ```
+///
+/// Note: the cache is only as fresh as the invalidations: a value may be served for the
+/// short time an invalidation takes to arrive. After a reconnection the whole cache is
+/// dropped, since invalidations sent to the old connection are lost.
+#[derive(Clone)]
+pub struct CachingClient {
+    client: Client,
+    cache: Arc<Cache>,
+}
+
+/// Settings of a `CachingClient`, see `CachingClient::connect_with`.
+#[derive(Debug, Clone)]
+pub struct CacheConfig {
+    /// How many keys are cached at most. Past that, an arbitrary key is dropped.
+    pub max_entries: usize,
+    /// Use broadcast mode with these prefixes (`CLIENT TRACKING ON BCAST PREFIX ...`): the
+    /// server sends every change of a matching key instead of remembering reads. Cheaper for
+    /// the server when many clients read the same keys. `Some(vec![])` matches every key.
+    pub broadcast: Option<Vec<String>>,
+}
+
+impl Default for CacheConfig {
+    fn default() -> CacheConfig {
+        CacheConfig { max_entries: 10_000, broadcast: None }
+    }
+}
+
+/// Counters of a `CachingClient`.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Keys dropped because the server said they changed.
+    pub invalidations: u64,
+    /// Keys cached right now.
+    pub entries: usize,
+}
+
+struct Cache {
+    config: CacheConfig,
+    entries: Mutex<HashMap<String, Entry>>,
+    next_token: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    invalidations: AtomicU64,
+}
+
+enum Entry {
+    /// A GET is on its way to the server. The token tells it apart from a later GET of
+    /// the same key.
+    Pending(u64),
+    /// The value, `None` for a missing key.
+    Ready(Option<Bytes>),
+}
+
+impl CachingClient {
+    /// Connects to `addr` with the default settings.
+    pub async fn connect(addr: &str) -> crate::Result<CachingClient> {
+        CachingClient::connect_with(addr, ClientConfig::default(), CacheConfig::default()).await
+    }
+
+    /// Connects to `addr`. Fails if the server doesn't support RESP3 and CLIENT TRACKING.
+    pub async fn connect_with(addr: &str, config: ClientConfig, cache: CacheConfig) -> crate::Result<CachingClient> {
+        let cache = Arc::new(Cache {
+            config: cache,
+            entries: Mutex::new(HashMap::new()),
+            next_token: AtomicU64::new(0),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            invalidations: AtomicU64::new(0),
+        });
+        let client = Client::connect_with_handler(addr, config, cache.clone()).await?;
+        Ok(CachingClient { client, cache })
+    }
+
+    /// GET, served from the cache when possible.
+    pub async fn get(&self, key: &str) -> crate::Result<Option<Bytes>> {
+        let token = {
+            let mut entries = self.cache.entries.lock().unwrap();
+            if let Some(Entry::Ready(value)) = entries.get(key) {
+                self.cache.hits.fetch_add(1, Ordering::Relaxed);
+                return Ok(value.clone());
+            }
+            let token = self.cache.next_token.fetch_add(1, Ordering::Relaxed);
+            entries.insert(key.to_string(), Entry::Pending(token));
+            token
+        };
+        self.cache.misses.fetch_add(1, Ordering::Relaxed);
+        let value = self.client.get(key).await?;
+
+        // Why the pending entry?
+        // The reply and the invalidation travel on the same connection, but reach us on
+        // different paths: the reply through this task, the invalidation through the
+        // connection task. If the key changed after the server read it, the invalidation
+        // has removed the pending entry by now, and caching the reply would keep a stale
+        // value forever.
+        let mut entries = self.cache.entries.lock().unwrap();
+        if matches!(entries.get(key), Some(Entry::Pending(t)) if *t == token) {
+            entries.insert(key.to_string(), Entry::Ready(value.clone()));
+            self.cache.evict(&mut entries, key);
+        }
+        Ok(value)
+    }
+
+    /// SET. The server's invalidation drops the cached value, like for any other writer.
+    pub async fn set(&self, key: &str, value: impl AsRef<[u8]>) -> crate::Result<()> {
+        self.client.set(key, value).await
+    }
+
+    pub async fn del(&self, keys: &[&str]) -> crate::Result<i64> {
+        self.client.del(keys).await
+    }
+
+    /// The underlying client, for the commands that are not cached.
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.cache.hits.load(Ordering::Relaxed),
+            misses: self.cache.misses.load(Ordering::Relaxed),
+            invalidations: self.cache.invalidations.load(Ordering::Relaxed),
+            entries: self.cache.entries.lock().unwrap().len(),
+        }
+    }
+}
+
+impl Cache {
+    /// Drops keys other than `keep` until the cache fits in `max_entries`.
+    fn evict(&self, entries: &mut HashMap<String, Entry>, keep: &str) {
+        while entries.len() > self.config.max_entries.max(1) {
+            let Some(victim) = entries.keys().find(|key| *key != keep).cloned() else { break };
+            entries.remove(&victim);
+        }
+    }
+}
+
+impl PushHandler for Cache {
+    fn handshake(&self) -> Vec<Frame> {
+        let mut tracking = vec!["CLIENT".to_string(), "TRACKING".to_string(), "ON".to_string()];
+        if let Some(prefixes) = &self.config.broadcast {
+            tracking.push("BCAST".to_string());
+            for prefix in prefixes {
+                tracking.push("PREFIX".to_string());
+                tracking.push(prefix.clone());
+            }
+        }
+        vec![request_frame(["HELLO", "3"]), request_frame(tracking)]
+    }
+
+    fn connected(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+
+    fn push(&self, frame: Frame) {
+        let Frame::Push(mut parts) = frame else { return };
+        if parts.len() != 2 || !matches!(&parts[0], Frame::Bulk(kind) if kind == "invalidate") {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        match parts.pop() {
+            Some(Frame::Array(keys)) => {
+                for key in keys {
+                    if let Frame::Bulk(key) = key {
+                        if entries.remove(String::from_utf8_lossy(&key).as_ref()).is_some() {
+                            self.invalidations.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
+                }
+            }
+            // Redis sends a null list when the whole database is flushed.
+            Some(Frame::Null) => {
+                self.invalidations.fetch_add(entries.len() as u64, Ordering::Relaxed);
+                entries.clear();
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TestServer;
+    use std::time::Duration;
+
+    /// Waits for the invalidation of a write to reach `client`.
+    async fn wait_for_invalidations(client: &CachingClient, count: u64) {
+        for _ in 0..100 {
+            if client.stats().invalidations >= count {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("no invalidation after 1s: {:?}", client.stats());
+    }
+
+    #[tokio::test]
+    async fn test_cached_until_another_client_writes() {
+        let server = TestServer::start().await.unwrap();
+        let addr = server.addr().to_string();
+        let cached = CachingClient::connect(&addr).await.unwrap();
+        let writer = server.client().await.unwrap();
+
+        writer.set("hot", "v1").await.unwrap();
+        for _ in 0..3 {
+            assert_eq!(cached.get("hot").await.unwrap(), Some(Bytes::from("v1")));
+        }
+        assert_eq!(cached.get("missing").await.unwrap(), None);
+        assert_eq!(cached.get("missing").await.unwrap(), None);
+        let stats = cached.stats();
+        assert_eq!((stats.hits, stats.misses, stats.entries), (3, 2, 2));
+
+        writer.set("hot", "v2").await.unwrap();
+        wait_for_invalidations(&cached, 1).await;
+        assert_eq!(cached.get("hot").await.unwrap(), Some(Bytes::from("v2")));
+        writer.set("missing", "now here").await.unwrap();
+        wait_for_invalidations(&cached, 2).await;
+        assert_eq!(cached.get("missing").await.unwrap(), Some(Bytes::from("now here")));
+
+        // its own writes invalidate too
+        cached.del(&["hot"]).await.unwrap();
+        wait_for_invalidations(&cached, 3).await;
+        assert_eq!(cached.get("hot").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_mode() {
+        let server = TestServer::start().await.unwrap();
+        let addr = server.addr().to_string();
+        let config = CacheConfig { broadcast: Some(vec!["user:".to_string()]), ..Default::default() };
+        let cached = CachingClient::connect_with(&addr, ClientConfig::default(), config).await.unwrap();
+        let writer = server.client().await.unwrap();
+
+        assert_eq!(cached.get("user:1").await.unwrap(), None);
+        // not matching the prefix: no invalidation, the cache keeps the old value
+        cached.get("other").await.unwrap();
+        writer.set("other", "x").await.unwrap();
+        writer.set("user:1", "alice").await.unwrap();
+        wait_for_invalidations(&cached, 1).await;
+        assert_eq!(cached.get("user:1").await.unwrap(), Some(Bytes::from("alice")));
+        assert_eq!(cached.get("other").await.unwrap(), None);
+        assert_eq!(cached.stats().invalidations, 1);
+    }
+
+    #[tokio::test]
+    async fn test_eviction_and_protocol_errors() {
+        let server = TestServer::start().await.unwrap();
+        let addr = server.addr().to_string();
+        let config = CacheConfig { max_entries: 2, ..Default::default() };
+        let cached = CachingClient::connect_with(&addr, ClientConfig::default(), config).await.unwrap();
+        for key in ["a", "b", "c"] {
+            cached.get(key).await.unwrap();
+        }
+        assert_eq!(cached.stats().entries, 2);
+
+        // push messages need RESP3
+        let plain = server.client().await.unwrap();
+        assert!(plain.command(["CLIENT", "TRACKING", "ON"]).await.is_err());
+        assert!(plain.command(["HELLO", "4"]).await.is_err());
+        assert!(matches!(plain.command(["HELLO", "3"]).await.unwrap(), Frame::Map(_)));
+        assert!(plain.command(["CLIENT", "TRACKING", "ON", "PREFIX", "a"]).await.is_err());
+        assert!(plain.command(["CLIENT", "TRACKING", "ON", "BCAST", "PREFIX", "a"]).await.is_ok());
+        assert!(plain.command(["CLIENT", "TRACKING", "OFF"]).await.is_ok());
+    }
+}
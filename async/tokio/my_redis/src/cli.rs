@@ -0,0 +1,216 @@
+//! The parts of `my-redis-cli` that are worth testing: splitting a typed line into arguments
+//! and printing replies the way `redis-cli` does.
+
+use crate::frame::Frame;
+
+/// Command names offered by tab completion, and the ones the metrics keep stats for.
+///
+/// Note: keep this in sync with `server::dispatch`, the server has no COMMAND command yet
+/// that the cli could ask instead.
+pub const COMMANDS: &[&str] = &[
+    "BITCOUNT", "BITOP", "BLMOVE", "BLPOP", "BRPOP", "CLIENT", "DECR", "DECRBY", "DEL", "DUMP", "EVAL", "EVALSHA",
+    "EXISTS", "EXPIRE", "GEOADD", "GEODIST", "GEOSEARCH", "GET", "GETBIT", "HELLO", "INCR", "INCRBY", "INFO",
+    "KEYS", "LLEN", "LMOVE", "LPOP", "LPUSH", "LRANGE", "MONITOR", "PERSIST", "PEXPIRE", "PFADD", "PFCOUNT",
+    "PFMERGE", "PING", "PTTL", "REPLICAOF", "RESTORE", "RPOP", "RPUSH", "SCAN", "SCRIPT", "SENTINEL", "SET",
+    "SETBIT", "SLOWLOG", "TTL", "XACK", "XADD", "XCLAIM", "XGROUP", "XLEN", "XPENDING", "XRANGE", "XREAD",
+    "XREADGROUP", "XREVRANGE", "ZADD", "ZCARD", "ZRANGE", "ZREM", "ZSCORE",
+];
+
+/// Returns the command names starting with `prefix`, ignoring case.
+pub fn complete(prefix: &str) -> Vec<&'static str> {
+    let prefix = prefix.to_uppercase();
+    COMMANDS.iter().copied().filter(|name| name.starts_with(&prefix)).collect()
+}
+
+/// Splits a line into arguments like `redis-cli` does: on whitespace, except inside quotes.
+///
+/// Double quotes understand the escapes `\n`, `\r`, `\t`, `\"`, `\\` and `\xHH`, single quotes
+/// only `\'`. A closing quote must be followed by a space or the end of the line.
+pub fn split_args(line: &str) -> Result<Vec<Vec<u8>>, String> {
+    let mut args = Vec::new();
+    let mut chars = line.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let Some(&first) = chars.peek() else { return Ok(args) };
+
+        let mut arg = Vec::new();
+        if first == '"' || first == '\'' {
+            chars.next();
+            loop {
+                match chars.next() {
+                    None => return Err("unbalanced quotes".to_string()),
+                    Some(c) if c == first => break,
+                    Some('\\') if first == '\'' => match chars.next_if_eq(&'\'') {
+                        Some(_) => arg.push(b'\''),
+                        None => arg.push(b'\\'),
+                    },
+                    Some('\\') => match chars.next() {
+                        Some('n') => arg.push(b'\n'),
+                        Some('r') => arg.push(b'\r'),
+                        Some('t') => arg.push(b'\t'),
+                        Some('x') => {
+                            let hex: String = chars.by_ref().take(2).collect();
+                            let byte = u8::from_str_radix(&hex, 16).map_err(|_| format!("invalid escape \\x{}", hex))?;
+                            arg.push(byte);
+                        }
+                        Some(c) => push_char(&mut arg, c),
+                        None => return Err("unbalanced quotes".to_string()),
+                    },
+                    Some(c) => push_char(&mut arg, c),
+                }
+            }
+            if chars.peek().is_some_and(|c| !c.is_whitespace()) {
+                return Err("closing quote must be followed by a space".to_string());
+            }
+        } else {
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                push_char(&mut arg, c);
+            }
+        }
+        args.push(arg);
+    }
+}
+
+fn push_char(arg: &mut Vec<u8>, c: char) {
+    let mut buf = [0; 4];
+    arg.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+}
+
+/// Formats a reply for humans, in the style of `redis-cli`:
+///
+/// ```text
+/// 1) "mystream"
+/// 2) 1) 1) "1700000000000-0"
+///       2) 1) "field"
+///          2) "value"
+/// ```
+///
+/// RESP3 types get their own markers, e.g. `1# "key" => "value"` for a map entry,
+/// `(double) 1.5` or `(true)`.
+pub fn format_reply(frame: &Frame) -> String {
+    let mut out = String::new();
+    format_into(&mut out, frame, 0);
+    out
+}
+
+fn format_into(out: &mut String, frame: &Frame, indent: usize) {
+    match frame {
+        Frame::Simple(s) => out.push_str(s),
+        Frame::Error(e) => {
+            out.push_str("(error) ");
+            out.push_str(e);
+        }
+        Frame::Integer(n) => out.push_str(&format!("(integer) {}", n)),
+        Frame::Bulk(data) => out.push_str(&quote(data)),
+        Frame::Null => out.push_str("(nil)"),
+        Frame::Double(f) => out.push_str(&format!("(double) {}", f)),
+        Frame::Boolean(b) => out.push_str(&format!("({})", b)),
+        Frame::BigNumber(n) => out.push_str(&format!("(big number) {}", n)),
+        // Verbatim strings are meant to be shown as they are, e.g. the text of INFO.
+        Frame::Verbatim(_, text) => out.push_str(text),
+        Frame::Array(items) => format_items(out, items, indent, ")", "(empty array)"),
+        Frame::Set(items) => format_items(out, items, indent, "~", "(empty set)"),
+        Frame::Push(items) => format_items(out, items, indent, ")", "(empty push)"),
+        Frame::Map(entries) => {
+            if entries.is_empty() {
+                out.push_str("(empty hash)");
+            }
+            let width = entries.len().to_string().len();
+            for (i, (key, value)) in entries.iter().enumerate() {
+                let prefix = format!("{:>width$}# ", i + 1, width = width);
+                if i > 0 {
+                    out.push('\n');
+                    out.push_str(&" ".repeat(indent));
+                }
+                out.push_str(&prefix);
+                format_into(out, key, indent + prefix.len());
+                out.push_str(" => ");
+                // the value is nested under the key, past the " => "
+                let key_width = out.len() - out.rfind('\n').map_or(0, |i| i + 1);
+                format_into(out, value, key_width);
+            }
+        }
+    }
+}
+
+fn format_items(out: &mut String, items: &[Frame], indent: usize, marker: &str, empty: &str) {
+    if items.is_empty() {
+        out.push_str(empty);
+    }
+    // Numbers are right aligned, so nested items line up: " 9) ..." above "10) ...".
+    let width = items.len().to_string().len();
+    for (i, item) in items.iter().enumerate() {
+        let prefix = format!("{:>width$}{} ", i + 1, marker, width = width);
+        if i > 0 {
+            out.push('\n');
+            out.push_str(&" ".repeat(indent));
+        }
+        out.push_str(&prefix);
+        format_into(out, item, indent + prefix.len());
+    }
+}
+
+/// Quotes a bulk string, escaping what isn't printable ASCII.
+fn quote(data: &[u8]) -> String {
+    let mut out = String::from("\"");
+    for &b in data {
+        match b {
+            b'"' => out.push_str("\\\""),
+            b'\\' => out.push_str("\\\\"),
+            b'\n' => out.push_str("\\n"),
+            b'\r' => out.push_str("\\r"),
+            b'\t' => out.push_str("\\t"),
+            0x20..=0x7e => out.push(b as char),
+            _ => out.push_str(&format!("\\x{:02x}", b)),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    fn args(line: &str) -> Vec<String> {
+        split_args(line).unwrap().into_iter().map(|a| String::from_utf8(a).unwrap()).collect()
+    }
+
+    #[test]
+    fn test_split_args() {
+        assert_eq!(args("  set key  value "), ["set", "key", "value"]);
+        assert_eq!(args(r#"set "a key" 'it\'s' "line\n\x41""#), ["set", "a key", "it's", "line\nA"]);
+        assert_eq!(args(r#"set k """#), ["set", "k", ""]);
+        assert!(split_args(r#"get "key"#).is_err());
+        assert!(split_args(r#"get "key"x"#).is_err());
+    }
+
+    #[test]
+    fn test_complete() {
+        assert_eq!(complete("xr"), ["XRANGE", "XREAD", "XREADGROUP", "XREVRANGE"]);
+        assert!(complete("nope").is_empty());
+    }
+
+    #[test]
+    fn test_format_nested_reply() {
+        let frame = Frame::Array(vec![
+            Frame::Bulk(Bytes::from("s")),
+            Frame::Array(vec![Frame::Integer(1), Frame::Bulk(Bytes::from("a\"b\x01"))]),
+            Frame::Array(vec![]),
+        ]);
+        assert_eq!(format_reply(&frame), "1) \"s\"\n2) 1) (integer) 1\n   2) \"a\\\"b\\x01\"\n3) (empty array)");
+    }
+
+    #[test]
+    fn test_format_resp3_reply() {
+        let frame = Frame::Map(vec![
+            (Frame::Bulk(Bytes::from("proto")), Frame::Integer(3)),
+            (Frame::Bulk(Bytes::from("flags")), Frame::Set(vec![Frame::Boolean(true), Frame::Double(0.5)])),
+        ]);
+        assert_eq!(
+            format_reply(&frame),
+            "1# \"proto\" => (integer) 3\n2# \"flags\" => 1~ (true)\n              2~ (double) 0.5"
+        );
+    }
+}
@@ -0,0 +1,586 @@
+use crate::connection::Connection;
+use crate::frame::Frame;
+use crate::Ttl;
+use bytes::Bytes;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, oneshot};
+
+/// An async client for my_redis (or any RESP2 server).
+///
+/// This is the manager task pattern from the tokio tutorial (`src/bin/client.rs` used to
+/// hand-roll it) turned into a library type:
+/// - A background task owns the `Connection`. `Client` is only the sender half of a channel
+///   to that task, so cloning it is cheap and every clone shares the same connection.
+/// - Concurrent callers are pipelined: the task writes every queued request before waiting
+///   for a reply, and matches the replies to the callers in order (RESP replies always come
+///   back in the order the requests were sent).
+/// - If the connection breaks, the requests in flight fail and the task reconnects with an
+///   exponential backoff. Later requests use the new connection.
+/// - Each request has a timeout, so a caller never hangs on a dead server.
+///
+/// ```no_run
+/// # async fn example() -> my_redis::Result<()> {
+/// let client = my_redis::Client::connect("127.0.0.1:6379").await?;
+/// client.set("hello", "world").await?;
+/// assert_eq!(client.get("hello").await?, Some("world".into()));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct Client {
+    requests: mpsc::Sender<Request>,
+    config: Arc<ClientConfig>,
+}
+
+/// Settings of a `Client`, see `Client::connect_with`.
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    /// How long a request may wait for its reply, including the time spent queued.
+    pub request_timeout: Duration,
+    /// The first delay before reconnecting, doubled after each failed attempt.
+    pub initial_backoff: Duration,
+    /// The longest delay between two reconnection attempts.
+    pub max_backoff: Duration,
+    /// How many requests may be queued for the connection task before callers wait.
+    pub queue_size: usize,
+}
+
+impl Default for ClientConfig {
+    fn default() -> ClientConfig {
+        ClientConfig {
+            request_timeout: Duration::from_secs(5),
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+            queue_size: 1024,
+        }
+    }
+}
+
+/// Receives what a RESP3 server sends without being asked, see `CachingClient`.
+pub(crate) trait PushHandler: Send + Sync {
+    /// Commands sent on every new connection before any request, e.g. `HELLO 3`.
+    fn handshake(&self) -> Vec<Frame>;
+    /// Called when the handshake of a new connection succeeded. Pushes the server sent on
+    /// an earlier connection may have been lost.
+    fn connected(&self);
+    fn push(&self, frame: Frame);
+}
+
+// type alias for the oneshot responder, as in the tutorial
+type Responder = oneshot::Sender<crate::Result<Frame>>;
+
+/// A command on its way to the connection task, with the channel for its reply.
+struct Request {
+    frame: Frame,
+    resp: Responder,
+}
+
+// Upper bound of requests written with a single flush.
+const MAX_BATCH: usize = 128;
+
+impl Client {
+    /// Connects to `addr` with the default `ClientConfig`.
+    pub async fn connect(addr: &str) -> crate::Result<Client> {
+        Client::connect_with(addr, ClientConfig::default()).await
+    }
+
+    /// Connects to `addr`.
+    ///
+    /// The first connection is made before returning, so a wrong address is reported here
+    /// instead of by the first request. Later connections are made in the background.
+    pub async fn connect_with(addr: &str, config: ClientConfig) -> crate::Result<Client> {
+        Client::start(addr, config, None).await
+    }
+
+    /// Like `connect_with`, with `handler` receiving the push messages of every connection.
+    pub(crate) async fn connect_with_handler(
+        addr: &str,
+        config: ClientConfig,
+        handler: Arc<dyn PushHandler>,
+    ) -> crate::Result<Client> {
+        Client::start(addr, config, Some(handler)).await
+    }
+
+    async fn start(addr: &str, config: ClientConfig, handler: Option<Arc<dyn PushHandler>>) -> crate::Result<Client> {
+        let connection = open(addr, handler.as_deref()).await?;
+        let (tx, rx) = mpsc::channel(config.queue_size);
+        let config = Arc::new(config);
+        tokio::spawn(run(addr.to_string(), connection, rx, config.clone(), handler));
+        Ok(Client { requests: tx, config })
+    }
+
+    /// Sends any command and returns the raw reply. An error reply becomes an `Err`.
+    pub async fn command<I, T>(&self, parts: I) -> crate::Result<Frame>
+    where
+        I: IntoIterator<Item = T>,
+        T: AsRef<[u8]>,
+    {
+        self.send(request_frame(parts), Some(self.config.request_timeout)).await
+    }
+
+    /// Sends `frame` and waits for the reply, for at most `timeout` if there is one.
+    async fn send(&self, frame: Frame, timeout: Option<Duration>) -> crate::Result<Frame> {
+        let (resp, reply) = oneshot::channel();
+        let request = async {
+            self.requests
+                .send(Request { frame, resp })
+                .await
+                .map_err(|_| "client connection task has stopped")?;
+            reply.await.map_err(|_| "client connection task has stopped")?
+        };
+        let reply = match timeout {
+            Some(timeout) => tokio::time::timeout(timeout, request).await,
+            None => Ok(request.await),
+        };
+        match reply {
+            Ok(Ok(Frame::Error(msg))) => Err(msg.into()),
+            Ok(result) => result,
+            Err(_) => Err("request timed out".into()),
+        }
+    }
+
+    pub async fn ping(&self) -> crate::Result<()> {
+        self.command(["PING"]).await.map(|_| ())
+    }
+
+    pub async fn get(&self, key: &str) -> crate::Result<Option<Bytes>> {
+        optional_bulk(self.command(["GET", key]).await?)
+    }
+
+    pub async fn set(&self, key: &str, value: impl AsRef<[u8]>) -> crate::Result<()> {
+        let parts: [&[u8]; 3] = [b"SET", key.as_bytes(), value.as_ref()];
+        self.command(parts).await.map(|_| ())
+    }
+
+    /// SET with a time to live (SET key value PX milliseconds).
+    pub async fn set_ex(&self, key: &str, value: impl AsRef<[u8]>, ttl: Duration) -> crate::Result<()> {
+        let millis = ttl.as_millis().to_string();
+        let parts: [&[u8]; 5] = [b"SET", key.as_bytes(), value.as_ref(), b"PX", millis.as_bytes()];
+        self.command(parts).await.map(|_| ())
+    }
+
+    /// Deletes keys, returning how many existed.
+    pub async fn del(&self, keys: &[&str]) -> crate::Result<i64> {
+        let parts = std::iter::once("DEL").chain(keys.iter().copied());
+        integer(self.command(parts).await?)
+    }
+
+    pub async fn exists(&self, key: &str) -> crate::Result<bool> {
+        Ok(integer(self.command(["EXISTS", key]).await?)? == 1)
+    }
+
+    pub async fn incr(&self, key: &str) -> crate::Result<i64> {
+        integer(self.command(["INCR", key]).await?)
+    }
+
+    pub async fn incr_by(&self, key: &str, delta: i64) -> crate::Result<i64> {
+        let delta = delta.to_string();
+        integer(self.command(["INCRBY", key, &delta]).await?)
+    }
+
+    pub async fn decr(&self, key: &str) -> crate::Result<i64> {
+        integer(self.command(["DECR", key]).await?)
+    }
+
+    /// Sets a time to live on `key`, returning whether the key exists.
+    pub async fn expire(&self, key: &str, ttl: Duration) -> crate::Result<bool> {
+        let millis = ttl.as_millis().to_string();
+        Ok(integer(self.command(["PEXPIRE", key, &millis]).await?)? == 1)
+    }
+
+    pub async fn ttl(&self, key: &str) -> crate::Result<Ttl> {
+        match integer(self.command(["PTTL", key]).await?)? {
+            -2 => Ok(Ttl::NoKey),
+            -1 => Ok(Ttl::NoExpiry),
+            millis => Ok(Ttl::Remaining(Duration::from_millis(millis.max(0) as u64))),
+        }
+    }
+
+    pub async fn lpush(&self, key: &str, values: &[&str]) -> crate::Result<i64> {
+        let parts = ["LPUSH", key].into_iter().chain(values.iter().copied());
+        integer(self.command(parts).await?)
+    }
+
+    pub async fn rpush(&self, key: &str, values: &[&str]) -> crate::Result<i64> {
+        let parts = ["RPUSH", key].into_iter().chain(values.iter().copied());
+        integer(self.command(parts).await?)
+    }
+
+    pub async fn lpop(&self, key: &str) -> crate::Result<Option<Bytes>> {
+        optional_bulk(self.command(["LPOP", key]).await?)
+    }
+
+    pub async fn rpop(&self, key: &str) -> crate::Result<Option<Bytes>> {
+        optional_bulk(self.command(["RPOP", key]).await?)
+    }
+
+    pub async fn llen(&self, key: &str) -> crate::Result<i64> {
+        integer(self.command(["LLEN", key]).await?)
+    }
+
+    pub async fn lrange(&self, key: &str, start: i64, stop: i64) -> crate::Result<Vec<Bytes>> {
+        let (start, stop) = (start.to_string(), stop.to_string());
+        match self.command(["LRANGE", key, &start, &stop]).await? {
+            Frame::Array(items) => items.into_iter().map(bulk).collect(),
+            frame => Err(unexpected(&frame)),
+        }
+    }
+
+    /// Pops from the first non-empty list of `keys`, waiting up to `timeout` for one, or
+    /// forever if `timeout` is `None`. Returns the key and the value, or `None` on timeout.
+    ///
+    /// Why a separate timeout?
+    /// Because the request timeout has to be longer than the time the server may block,
+    /// otherwise the client would give up before the server replies.
+    ///
+    /// Note: a zero `timeout` is an error. BLPOP reads `0` as "forever", so the server would
+    /// keep waiting after we gave up, and pop a value nobody reads.
+    ///
+    /// Note: the server doesn't read the next command of a connection while BLPOP blocks,
+    /// so every clone of this client waits too. Use a dedicated `Client` for blocking pops.
+    pub async fn blpop(&self, keys: &[&str], timeout: Option<Duration>) -> crate::Result<Option<(String, Bytes)>> {
+        let (seconds, limit) = match timeout {
+            Some(Duration::ZERO) => return Err("BLPOP timeout must be positive, use None to wait forever".into()),
+            // A timeout too long to add to is as good as forever; the server rejects it anyway.
+            Some(timeout) => (format!("{}", timeout.as_secs_f64()), timeout.checked_add(self.config.request_timeout)),
+            None => ("0".to_string(), None),
+        };
+        let parts = std::iter::once("BLPOP")
+            .chain(keys.iter().copied())
+            .chain(std::iter::once(seconds.as_str()));
+        let frame = self.send(request_frame(parts), limit).await?;
+        match frame {
+            Frame::Null => Ok(None),
+            Frame::Array(mut pair) if pair.len() == 2 => {
+                let value = bulk(pair.pop().unwrap())?;
+                let key = bulk(pair.pop().unwrap())?;
+                Ok(Some((String::from_utf8_lossy(&key).into_owned(), value)))
+            }
+            frame => Err(unexpected(&frame)),
+        }
+    }
+}
+
+/// Like `Frame::command`, but copies borrowed arguments instead of requiring owned `Bytes`.
+pub(crate) fn request_frame<I, T>(parts: I) -> Frame
+where
+    I: IntoIterator<Item = T>,
+    T: AsRef<[u8]>,
+{
+    Frame::Array(parts.into_iter().map(|p| Frame::Bulk(Bytes::copy_from_slice(p.as_ref()))).collect())
+}
+
+fn unexpected(frame: &Frame) -> crate::Error {
+    format!("unexpected reply: {}", frame).into()
+}
+
+fn integer(frame: Frame) -> crate::Result<i64> {
+    match frame {
+        Frame::Integer(n) => Ok(n),
+        frame => Err(unexpected(&frame)),
+    }
+}
+
+fn bulk(frame: Frame) -> crate::Result<Bytes> {
+    match frame {
+        Frame::Bulk(data) => Ok(data),
+        Frame::Simple(s) => Ok(Bytes::from(s)),
+        frame => Err(unexpected(&frame)),
+    }
+}
+
+fn optional_bulk(frame: Frame) -> crate::Result<Option<Bytes>> {
+    match frame {
+        Frame::Null => Ok(None),
+        frame => bulk(frame).map(Some),
+    }
+}
+
+/// Connects to `addr`, and runs the handshake of `handler` if there is one.
+async fn open(addr: &str, handler: Option<&dyn PushHandler>) -> crate::Result<Connection> {
+    let mut conn = Connection::new(TcpStream::connect(addr).await?);
+    let Some(handler) = handler else { return Ok(conn) };
+    let frames = handler.handshake();
+    conn.write_frames(&frames).await?;
+    for _ in &frames {
+        match conn.read_frame().await? {
+            Some(Frame::Error(msg)) => return Err(msg.into()),
+            Some(_) => {}
+            None => return Err("connection closed by the server".into()),
+        }
+    }
+    handler.connected();
+    Ok(conn)
+}
+
+/// The connection task: serves requests until every `Client` is dropped, reconnecting
+/// whenever the connection breaks.
+async fn run(
+    addr: String,
+    connection: Connection,
+    mut rx: mpsc::Receiver<Request>,
+    config: Arc<ClientConfig>,
+    handler: Option<Arc<dyn PushHandler>>,
+) {
+    let mut connection = Some(connection);
+    let mut backoff = config.initial_backoff;
+    loop {
+        let mut conn = match connection.take() {
+            Some(conn) => conn,
+            None => match open(&addr, handler.as_deref()).await {
+                Ok(conn) => {
+                    backoff = config.initial_backoff;
+                    conn
+                }
+                Err(e) => {
+                    // Fail the requests arriving while we wait, rather than letting them
+                    // sit in the queue until they time out.
+                    let sleep = tokio::time::sleep(backoff);
+                    tokio::pin!(sleep);
+                    loop {
+                        tokio::select! {
+                            _ = &mut sleep => break,
+                            request = rx.recv() => match request {
+                                Some(request) => {
+                                    let _ = request.resp.send(Err(format!("not connected: {}", e).into()));
+                                }
+                                None => return,
+                            },
+                        }
+                    }
+                    backoff = (backoff * 2).min(config.max_backoff);
+                    continue;
+                }
+            },
+        };
+
+        match serve(&mut conn, &mut rx, handler.as_deref()).await {
+            // every `Client` is gone
+            Ok(()) => return,
+            Err(e) => eprintln!("connection to {} lost: {}", addr, e),
+        }
+    }
+}
+
+/// Pipelines requests over one connection until it breaks.
+async fn serve(
+    conn: &mut Connection,
+    rx: &mut mpsc::Receiver<Request>,
+    handler: Option<&dyn PushHandler>,
+) -> crate::Result<()> {
+    // Responders of the requests sent but not answered yet, oldest first.
+    let mut in_flight: VecDeque<Responder> = VecDeque::new();
+    let result = loop {
+        tokio::select! {
+            request = rx.recv() => {
+                let Some(request) = request else { break Ok(()) };
+                // Take whatever else is already queued, and send it all with one flush.
+                let mut frames = vec![request.frame];
+                in_flight.push_back(request.resp);
+                while frames.len() < MAX_BATCH {
+                    match rx.try_recv() {
+                        Ok(request) => {
+                            frames.push(request.frame);
+                            in_flight.push_back(request.resp);
+                        }
+                        Err(_) => break,
+                    }
+                }
+                if let Err(e) = conn.write_frames(&frames).await {
+                    break Err(e.into());
+                }
+            }
+            // Note: `read_frame` is cancel safe, partially read data stays in the buffer, so
+            // it's fine for `select!` to drop it when a request arrives first.
+            frame = conn.read_frame() => match frame {
+                // Pushes are not replies, they go to the handler (RESP2 servers never send them).
+                Ok(Some(frame @ Frame::Push(_))) => {
+                    if let Some(handler) = handler {
+                        handler.push(frame);
+                    }
+                }
+                Ok(Some(frame)) => match in_flight.pop_front() {
+                    // The caller may have timed out already, then nobody wants the reply.
+                    Some(resp) => { let _ = resp.send(Ok(frame)); }
+                    None => break Err(format!("reply without a request: {}", frame).into()),
+                },
+                Ok(None) => break Err("connection closed by the server".into()),
+                Err(e) => break Err(e),
+            },
+        }
+    };
+    if let Err(e) = &result {
+        for resp in in_flight {
+            let _ = resp.send(Err(format!("connection lost: {}", e).into()));
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{self, Shared};
+    use crate::{ShardedDatabase, TestServer};
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_typed_commands() {
+        let server = TestServer::start().await.unwrap();
+        let client = server.client().await.unwrap();
+        client.ping().await.unwrap();
+        assert_eq!(client.get("key").await.unwrap(), None);
+        client.set("key", "value").await.unwrap();
+        assert_eq!(client.get("key").await.unwrap(), Some(Bytes::from("value")));
+        assert_eq!(client.incr("n").await.unwrap(), 1);
+        assert_eq!(client.incr_by("n", 10).await.unwrap(), 11);
+        assert_eq!(client.ttl("n").await.unwrap(), Ttl::NoExpiry);
+        assert!(client.expire("n", Duration::from_secs(60)).await.unwrap());
+        assert!(matches!(client.ttl("n").await.unwrap(), Ttl::Remaining(_)));
+        assert_eq!(client.del(&["key", "n", "missing"]).await.unwrap(), 2);
+
+        // an error reply becomes an Err, and the connection keeps working
+        client.rpush("list", &["a"]).await.unwrap();
+        assert!(client.incr("list").await.is_err());
+        assert_eq!(client.lrange("list", 0, -1).await.unwrap(), vec![Bytes::from("a")]);
+    }
+
+    #[tokio::test]
+    async fn test_overflowing_ttl_is_an_error_not_a_poisoned_shard() {
+        let server = TestServer::start().await.unwrap();
+        let client = server.client().await.unwrap();
+        let error = client.command(["SET", "a", "b", "EX", "9223372036854775807"]).await.unwrap_err();
+        assert_eq!(error.to_string(), "ERR invalid expire time in 'set' command");
+        client.set("a", "b").await.unwrap();
+        let error = client.command(["EXPIRE", "a", "9223372036854775807"]).await.unwrap_err();
+        assert_eq!(error.to_string(), "ERR invalid expire time in 'expire' command");
+        // the shard holding "a" still works
+        assert_eq!(client.get("a").await.unwrap(), Some(Bytes::from("b")));
+        assert_eq!(client.ttl("a").await.unwrap(), Ttl::NoExpiry);
+    }
+
+    #[tokio::test]
+    async fn test_blocking_timeouts_are_checked() {
+        let server = TestServer::start().await.unwrap();
+        let client = server.client().await.unwrap();
+        for (timeout, expected) in [
+            ("-1", "ERR timeout is negative"),
+            ("nan", "ERR timeout is not a float or out of range"),
+            ("1e300", "ERR timeout is out of range"),
+            ("inf", "ERR timeout is out of range"),
+        ] {
+            let error = client.command(["BLPOP", "q", timeout]).await.unwrap_err();
+            assert_eq!(error.to_string(), expected, "{}", timeout);
+        }
+        // the connection is still usable
+        client.set("a", "b").await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_blpop_never_sees_a_script_half_done() {
+        let server = TestServer::start().await.unwrap();
+        let client = server.client().await.unwrap();
+        let values: Vec<String> = (0..200).map(|i| i.to_string()).collect();
+        client.rpush("other", &values.iter().map(String::as_str).collect::<Vec<_>>()).await.unwrap();
+
+        // The script's element is pushed and popped again in one step, so BLPOP (which
+        // never parks here, "other" always has data) must always get to "other".
+        let scripter = server.client().await.unwrap();
+        let script = tokio::spawn(async move {
+            for _ in 0..50 {
+                // the loop just widens the window between the two calls
+                let script = r#"redis_call("RPUSH", KEYS[0], "x"); for i in 0..20000 {} redis_call("LPOP", KEYS[0])"#;
+                scripter.command(["EVAL", script, "1", "s"]).await.unwrap();
+            }
+        });
+        for value in &values {
+            let popped = client.blpop(&["s", "other"], Some(Duration::from_secs(1))).await.unwrap();
+            assert_eq!(popped, Some(("other".to_string(), Bytes::from(value.clone()))));
+        }
+        script.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_clones_share_the_connection() {
+        let server = TestServer::start().await.unwrap();
+        let client = server.client().await.unwrap();
+        let mut tasks = Vec::new();
+        for _ in 0..50 {
+            let client = client.clone();
+            tasks.push(tokio::spawn(async move { client.incr("counter").await.unwrap() }));
+        }
+        let mut results = Vec::new();
+        for task in tasks {
+            results.push(task.await.unwrap());
+        }
+        results.sort();
+        assert_eq!(results, (1..=50).collect::<Vec<i64>>());
+    }
+
+    #[tokio::test]
+    async fn test_blpop_waits_longer_than_the_request_timeout() {
+        let config = ClientConfig { request_timeout: Duration::from_millis(100), ..Default::default() };
+        let server = TestServer::start().await.unwrap();
+        let client = Client::connect_with(&server.addr().to_string(), config).await.unwrap();
+        // a clone would be stuck behind the BLPOP on the shared connection
+        let pusher = server.client().await.unwrap();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            pusher.rpush("queue", &["job"]).await.unwrap();
+        });
+        let popped = client.blpop(&["queue"], Some(Duration::from_secs(2))).await.unwrap();
+        assert_eq!(popped, Some(("queue".to_string(), Bytes::from("job"))));
+    }
+
+    #[tokio::test]
+    async fn test_blpop_timeouts() {
+        let config = ClientConfig { request_timeout: Duration::from_millis(100), ..Default::default() };
+        let server = TestServer::start().await.unwrap();
+        let client = Client::connect_with(&server.addr().to_string(), config.clone()).await.unwrap();
+        // zero would be "forever" on the server, long after we gave up
+        let error = client.blpop(&["queue"], Some(Duration::ZERO)).await.unwrap_err();
+        assert!(error.to_string().contains("must be positive"), "{}", error);
+        // too long to add the request timeout to: no panic, the server refuses it
+        let error = client.blpop(&["queue"], Some(Duration::MAX)).await.unwrap_err();
+        assert_eq!(error.to_string(), "ERR timeout is out of range");
+
+        // `None` waits past the request timeout
+        let pusher = server.client().await.unwrap();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            pusher.rpush("queue", &["job"]).await.unwrap();
+        });
+        let popped = client.blpop(&["queue"], None).await.unwrap();
+        assert_eq!(popped, Some(("queue".to_string(), Bytes::from("job"))));
+    }
+
+    #[tokio::test]
+    async fn test_reconnects_after_the_connection_drops() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let client = Client::connect(&addr).await.unwrap();
+
+        // Drop the first connection, then serve the next one for real.
+        let (socket, _) = listener.accept().await.unwrap();
+        drop(socket);
+
+        tokio::spawn(async move {
+            let (socket, peer) = listener.accept().await.unwrap();
+            let shared = Shared::new(ShardedDatabase::new(4));
+            server::process(socket, peer, &shared).await.unwrap();
+        });
+        // A request racing with the reconnection may fail, later ones must succeed.
+        let mut pinged = false;
+        for _ in 0..20 {
+            if client.ping().await.is_ok() {
+                pinged = true;
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        assert!(pinged);
+    }
+}
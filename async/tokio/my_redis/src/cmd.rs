@@ -0,0 +1,120 @@
+use crate::frame::Frame;
+use bytes::Bytes;
+
+/// A command received from a client: an upper-cased name plus its raw arguments.
+///
+/// `mini_redis::Command::from_frame` only knows GET/SET/PUBLISH/SUBSCRIBE, and throws the
+/// arguments of anything else away, so the server parses frames into this type instead.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Command {
+    name: String,
+    args: Vec<Bytes>,
+}
+
+impl Command {
+    /// Parses a client request, which must be an array of bulk (or simple) strings.
+    pub fn from_frame(frame: Frame) -> Result<Command, String> {
+        let parts = match frame {
+            Frame::Array(parts) => parts,
+            frame => return Err(format!("ERR protocol error; expected array, got {}", frame)),
+        };
+
+        let mut args = Vec::with_capacity(parts.len());
+        for part in parts {
+            match part {
+                Frame::Bulk(data) => args.push(data),
+                Frame::Simple(s) => args.push(Bytes::from(s)),
+                Frame::Integer(n) => args.push(Bytes::from(n.to_string())),
+                frame => return Err(format!("ERR protocol error; unexpected argument {}", frame)),
+            }
+        }
+
+        if args.is_empty() {
+            return Err("ERR empty command".to_string());
+        }
+        let name = String::from_utf8_lossy(&args.remove(0)).to_uppercase();
+        Ok(Command { name, args })
+    }
+
+    /// The upper-cased command name, e.g. `"GET"`.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn args(&self) -> &[Bytes] {
+        &self.args
+    }
+
+    /// The frame a client would send for this command, e.g. to pass it on to a replica.
+    pub fn to_frame(&self) -> Frame {
+        let name = Bytes::from(self.name.clone());
+        Frame::command(std::iter::once(name).chain(self.args.iter().cloned()))
+    }
+
+    /// Returns argument `i` as a UTF-8 string.
+    pub fn arg_str(&self, i: usize) -> Result<&str, String> {
+        let arg = self.args.get(i).ok_or_else(|| self.wrong_arity())?;
+        std::str::from_utf8(arg).map_err(|_| "ERR invalid UTF-8 in argument".to_string())
+    }
+
+    /// Returns argument `i` parsed as an integer.
+    pub fn arg_int(&self, i: usize) -> Result<i64, String> {
+        self.arg_str(i)?
+            .parse()
+            .map_err(|_| "ERR value is not an integer or out of range".to_string())
+    }
+
+    /// Returns argument `i` parsed as a float. `inf` and `-inf` are accepted, NaN is not.
+    pub fn arg_float(&self, i: usize) -> Result<f64, String> {
+        match self.arg_str(i)?.parse::<f64>() {
+            Ok(value) if !value.is_nan() => Ok(value),
+            _ => Err("ERR value is not a valid float".to_string()),
+        }
+    }
+
+    /// Fails with the standard Redis arity error unless there are between `min` and `max` args.
+    pub fn check_arity(&self, min: usize, max: usize) -> Result<(), String> {
+        if self.args.len() < min || self.args.len() > max {
+            return Err(self.wrong_arity());
+        }
+        Ok(())
+    }
+
+    /// Whether the command may change the data, for the audit log and replication.
+    ///
+    /// Scripts count as writes: what they do is only known once they ran.
+    pub fn is_write(&self) -> bool {
+        matches!(
+            self.name.as_str(),
+            "SET" | "DEL" | "RESTORE" | "INCR" | "DECR" | "INCRBY" | "DECRBY" | "EXPIRE" | "PEXPIRE" | "PERSIST"
+                | "LPUSH" | "RPUSH" | "LPOP" | "RPOP" | "LMOVE" | "BLPOP" | "BRPOP" | "BLMOVE"
+                | "XADD" | "XGROUP" | "XREADGROUP" | "XACK" | "XCLAIM"
+                | "SETBIT" | "BITOP" | "PFADD" | "PFMERGE" | "ZADD" | "ZREM" | "GEOADD"
+                | "EVAL" | "EVALSHA"
+        )
+    }
+
+    fn wrong_arity(&self) -> String {
+        format!("ERR wrong number of arguments for '{}' command", self.name.to_lowercase())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_frame_uppercases_name() {
+        let cmd = Command::from_frame(Frame::command(["info", "stats"])).unwrap();
+        assert_eq!(cmd.name(), "INFO");
+        assert_eq!(cmd.arg_str(0), Ok("stats"));
+    }
+
+    #[test]
+    fn test_arity_and_int_errors() {
+        let cmd = Command::from_frame(Frame::command(["SLOWLOG", "GET", "ten"])).unwrap();
+        assert!(cmd.check_arity(0, 1).is_err());
+        assert!(cmd.arg_int(1).is_err());
+        assert!(cmd.arg_str(5).is_err());
+    }
+}
@@ -0,0 +1,101 @@
+use crate::frame::{self, Frame};
+use bytes::{Buf, BytesMut};
+use std::io::{self, Cursor};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufWriter};
+use tokio::net::TcpStream;
+
+/// Sends and receives `Frame` values over a `TcpStream`.
+///
+/// Same idea as `mini_redis::Connection`: bytes are read into a buffer until a whole frame
+/// is available, then the frame is parsed and the bytes are discarded from the buffer.
+/// Writes go through a `BufWriter` so that small frames don't each cost a syscall.
+#[derive(Debug)]
+pub struct Connection {
+    stream: BufWriter<TcpStream>,
+    buffer: BytesMut,
+}
+
+impl Connection {
+    pub fn new(socket: TcpStream) -> Connection {
+        // Why disable Nagle's algorithm?
+        // With pipelining, the replies are small writes following each other. Nagle holds
+        // back every write until the previous one is acknowledged, and the peer delays its
+        // ACKs by up to 40ms, so a pipeline of 16 commands took ~40ms instead of ~0.2ms
+        // (found with my-redis-benchmark -P 16). Redis disables it too.
+        let _ = socket.set_nodelay(true);
+        Connection {
+            stream: BufWriter::new(socket),
+            // Default to a 4KB read buffer, it grows when a frame doesn't fit.
+            buffer: BytesMut::with_capacity(4 * 1024),
+        }
+    }
+
+    /// Reads a single frame from the underlying stream.
+    ///
+    /// Returns `None` if the peer closed the connection cleanly (EOF on a frame boundary).
+    pub async fn read_frame(&mut self) -> crate::Result<Option<Frame>> {
+        loop {
+            if let Some(frame) = self.parse_frame()? {
+                return Ok(Some(frame));
+            }
+
+            // Not enough buffered data for a frame, read more from the socket.
+            // 0 bytes read means EOF (see "tokio IO" in NOTES.md).
+            if 0 == self.stream.read_buf(&mut self.buffer).await? {
+                if self.buffer.is_empty() {
+                    return Ok(None);
+                } else {
+                    return Err("connection reset by peer".into());
+                }
+            }
+        }
+    }
+
+    fn parse_frame(&mut self) -> crate::Result<Option<Frame>> {
+        let mut buf = Cursor::new(&self.buffer[..]);
+
+        // `check` is cheap compared to `parse` (no allocations), so we first make sure the
+        // whole frame has arrived before parsing it.
+        match Frame::check(&mut buf) {
+            Ok(_) => {
+                let len = buf.position() as usize;
+                buf.set_position(0);
+                let frame = Frame::parse(&mut buf)?;
+                self.buffer.advance(len);
+                Ok(Some(frame))
+            }
+            Err(frame::Error::Incomplete) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Resolves once the peer has closed the connection.
+    ///
+    /// Used while a command is blocked (e.g. BLPOP) and we are not reading from the socket.
+    /// If the client sends more data instead, we can't tell anymore and never resolve.
+    pub async fn closed(&self) {
+        let mut buf = [0u8; 1];
+        match self.stream.get_ref().peek(&mut buf).await {
+            Ok(0) | Err(_) => {}
+            Ok(_) => std::future::pending().await,
+        }
+    }
+
+    /// Writes a single frame to the underlying stream and flushes it.
+    pub async fn write_frame(&mut self, frame: &Frame) -> io::Result<()> {
+        let mut buf = Vec::new();
+        frame.encode(&mut buf);
+        self.stream.write_all(&buf).await?;
+        self.stream.flush().await
+    }
+
+    /// Writes several frames with a single flush, i.e. pipelining.
+    pub async fn write_frames(&mut self, frames: &[Frame]) -> io::Result<()> {
+        let mut buf = Vec::new();
+        for frame in frames {
+            frame.encode(&mut buf);
+        }
+        self.stream.write_all(&buf).await?;
+        self.stream.flush().await
+    }
+}
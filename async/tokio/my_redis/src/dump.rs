@@ -0,0 +1,161 @@
+//! DUMP/RESTORE: one value serialized into an opaque payload, and back.
+//!
+//! The payload is the `storage::codec` encoding of the value, followed by a footer like the
+//! one of Redis:
+//!
+//! ```text
+//! | encoded value | version (u16, little endian) | crc32 of everything before (u32, LE) |
+//! ```
+//!
+//! The version changes whenever the codec does, so a server refuses a payload written by a
+//! newer one instead of misreading it. The checksum catches payloads damaged on their way
+//! between servers.
+//!
+//! Note: Redis uses its RDB encoding and a CRC64 here, so payloads don't move between Redis
+//! and my_redis.
+
+use crate::storage::codec;
+use crate::{ShardedDatabase, Value};
+use bytes::Bytes;
+use std::fmt;
+use std::time::Duration;
+
+/// The version written in payloads. Payloads up to this version can be restored.
+pub const DUMP_VERSION: u16 = 1;
+
+const FOOTER_LEN: usize = 2 + 4;
+
+/// Errors of RESTORE.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RestoreError {
+    /// The payload is damaged, or from a newer version.
+    BadPayload,
+    /// The key exists and REPLACE wasn't given.
+    BusyKey,
+    /// The TTL is too far in the future to be represented.
+    InvalidExpire,
+}
+
+impl fmt::Display for RestoreError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RestoreError::BadPayload => write!(f, "ERR DUMP payload version or checksum are wrong"),
+            RestoreError::BusyKey => write!(f, "BUSYKEY Target key name already exists."),
+            RestoreError::InvalidExpire => write!(f, "ERR invalid expire time in 'restore' command"),
+        }
+    }
+}
+
+impl std::error::Error for RestoreError {}
+
+/// Serializes a value (DUMP).
+pub fn serialize(value: &Value) -> Bytes {
+    let mut payload = codec::encode_value(value);
+    payload.extend_from_slice(&DUMP_VERSION.to_le_bytes());
+    let crc = crc32fast::hash(&payload);
+    payload.extend_from_slice(&crc.to_le_bytes());
+    Bytes::from(payload)
+}
+
+/// Reads a payload written by `serialize`.
+pub fn deserialize(payload: &[u8]) -> Result<Value, RestoreError> {
+    let Some(body_len) = payload.len().checked_sub(FOOTER_LEN) else {
+        return Err(RestoreError::BadPayload);
+    };
+    let (body, crc) = payload.split_at(payload.len() - 4);
+    if crc32fast::hash(body) != u32::from_le_bytes(crc.try_into().unwrap()) {
+        return Err(RestoreError::BadPayload);
+    }
+    let version = u16::from_le_bytes([body[body_len], body[body_len + 1]]);
+    if version > DUMP_VERSION {
+        return Err(RestoreError::BadPayload);
+    }
+    codec::decode_value(&body[..body_len]).map_err(|_| RestoreError::BadPayload)
+}
+
+impl ShardedDatabase {
+    /// Returns the serialized value of `key` (DUMP), `None` if it doesn't exist.
+    pub fn dump(&self, key: &str) -> Option<Bytes> {
+        let mut shard = self.lock_shard(key);
+        shard.entries.get(key).map(serialize)
+    }
+
+    /// Creates `key` from a payload of `dump` (RESTORE), expiring after `ttl` if given.
+    pub fn restore(&self, key: &str, payload: &[u8], ttl: Option<Duration>, replace: bool) -> Result<(), RestoreError> {
+        // Decoded before taking the lock: a large value may take a while.
+        let value = deserialize(payload)?;
+        let deadline = ttl.map(crate::deadline).transpose().map_err(|_| RestoreError::InvalidExpire)?;
+        let mut shard = self.lock_shard(key);
+        if !replace && shard.entries.contains_key(key) {
+            return Err(RestoreError::BusyKey);
+        }
+        shard.entries.insert(key.to_string(), value);
+        shard.set_deadline(key, deadline);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::list::End;
+    use crate::zset::Update;
+
+    #[test]
+    fn test_dump_and_restore_every_type() {
+        let db = ShardedDatabase::new(4);
+        db.insert("string", Bytes::from("value"));
+        db.push("list", &[Bytes::from("a"), Bytes::from("b")], End::Right).unwrap();
+        db.zadd("zset", &[(1.5, Bytes::from("m"))], Update::Always).unwrap();
+        db.xadd("stream", crate::stream::NewId::Auto, vec![(Bytes::from("f"), Bytes::from("v"))], None, false)
+            .unwrap();
+
+        let copy = ShardedDatabase::new(8);
+        for key in ["string", "list", "zset", "stream"] {
+            let payload = db.dump(key).unwrap();
+            copy.restore(key, &payload, None, false).unwrap();
+            assert_eq!(copy.dump(key), Some(payload));
+        }
+        assert_eq!(db.dump("missing"), None);
+    }
+
+    #[test]
+    fn test_restore_checks_the_payload_and_the_key() {
+        let db = ShardedDatabase::new(4);
+        db.insert("key", Bytes::from("value"));
+        let payload = db.dump("key").unwrap();
+
+        assert_eq!(db.restore("key", &payload, None, false), Err(RestoreError::BusyKey));
+        db.restore("key", &payload, Some(Duration::from_secs(60)), true).unwrap();
+        assert!(matches!(db.ttl("key"), crate::Ttl::Remaining(_)));
+
+        let mut damaged = payload.to_vec();
+        damaged[1] ^= 1;
+        assert_eq!(db.restore("other", &damaged, None, false), Err(RestoreError::BadPayload));
+        assert_eq!(db.restore("other", b"abc", None, false), Err(RestoreError::BadPayload));
+
+        // a payload from a newer version is refused even with a valid checksum
+        let mut newer = payload[..payload.len() - FOOTER_LEN].to_vec();
+        newer.extend_from_slice(&(DUMP_VERSION + 1).to_le_bytes());
+        let crc = crc32fast::hash(&newer);
+        newer.extend_from_slice(&crc.to_le_bytes());
+        assert_eq!(deserialize(&newer), Err(RestoreError::BadPayload));
+        assert!(!db.contains_key("other"));
+    }
+
+    #[test]
+    fn test_restore_refuses_empty_values_and_overflowing_ttls() {
+        let db = ShardedDatabase::new(4);
+        // well formed, checksum and all, but a list can't be empty
+        let empty = serialize(&Value::List(Default::default()));
+        assert_eq!(db.restore("list", &empty, None, false), Err(RestoreError::BadPayload));
+        assert!(!db.contains_key("list"));
+
+        let payload = serialize(&Value::String(Bytes::from("value")));
+        let forever = Some(Duration::from_secs(u64::MAX));
+        assert_eq!(db.restore("key", &payload, forever, false), Err(RestoreError::InvalidExpire));
+        // nothing was written, and the shard still works
+        db.restore("key", &payload, None, false).unwrap();
+        assert_eq!(db.get("key"), Some(Bytes::from("value")));
+    }
+}
@@ -0,0 +1,524 @@
+//! Moving a keyspace between servers: the library side of `my-redis-dump`.
+//!
+//! `export` walks the keys of a server with SCAN and reads every value with DUMP, `import`
+//! writes them back with RESTORE. Both keep the times to live, as absolute unix times in
+//! milliseconds, so the time a file spends between two servers counts: a key exported with
+//! one minute left and imported an hour later is not imported at all.
+//!
+//! In between, the records are written in one of three formats:
+//! - `json`: one object per line, `{"key":..,"type":..,"expires_at":..,"value":..}`,
+//! - `csv`: the columns `key,type,expires_at,value`, the value written as JSON,
+//! - `resp`: the RESTORE commands themselves, which `my-redis-cli --pipe` can also send.
+//!
+//! In JSON and CSV, strings that are not valid UTF-8 are written `{"hex":"..."}`, and sorted
+//! sets as `[member, score]` pairs.
+//!
+//! Note: JSON and CSV keep the entries of a stream but not its consumer groups, RESP keeps
+//! everything.
+
+use crate::frame::Frame;
+use crate::glob::glob_match;
+use crate::json::Json;
+use crate::stream::{Stream, StreamId};
+use crate::zset::SortedSet;
+use crate::{dump, Client, Value};
+use bytes::Bytes;
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::io::{self, Cursor, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::task::JoinSet;
+
+/// The file formats of `write_records` and `read_records`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Json,
+    Csv,
+    Resp,
+}
+
+impl Format {
+    pub fn parse(s: &str) -> Option<Format> {
+        match s.to_lowercase().as_str() {
+            "json" => Some(Format::Json),
+            "csv" => Some(Format::Csv),
+            "resp" => Some(Format::Resp),
+            _ => None,
+        }
+    }
+}
+
+/// One key with its value.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    pub key: String,
+    pub value: Value,
+    /// When the key expires, in milliseconds since the unix epoch.
+    pub expires_at: Option<u64>,
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+}
+
+/// Reads every key matching `pattern` (all keys if `None`), sorted by key.
+pub async fn export(client: &Client, pattern: Option<&str>) -> crate::Result<Vec<Record>> {
+    let mut records = Vec::new();
+    let mut cursor = "0".to_string();
+    loop {
+        let mut scan = vec!["SCAN".to_string(), cursor];
+        if let Some(pattern) = pattern {
+            scan.extend(["MATCH".to_string(), pattern.to_string()]);
+        }
+        let (next, keys) = match client.command(scan).await? {
+            Frame::Array(mut reply) if reply.len() == 2 => match (reply.remove(0), reply.remove(0)) {
+                (Frame::Bulk(next), Frame::Array(keys)) => (String::from_utf8_lossy(&next).into_owned(), keys),
+                _ => return Err("unexpected SCAN reply".into()),
+            },
+            _ => return Err("unexpected SCAN reply".into()),
+        };
+
+        // Why a task per key?
+        // Concurrent requests of a `Client` are pipelined, so the round trips overlap.
+        let mut tasks = JoinSet::new();
+        for key in keys {
+            let Frame::Bulk(key) = key else { return Err("unexpected SCAN reply".into()) };
+            let key = String::from_utf8_lossy(&key).into_owned();
+            let client = client.clone();
+            tasks.spawn(async move { read_key(&client, key).await });
+        }
+        while let Some(record) = tasks.join_next().await {
+            records.extend(record??);
+        }
+
+        if next == "0" {
+            break;
+        }
+        cursor = next;
+    }
+    records.sort_by(|a, b| a.key.cmp(&b.key));
+    Ok(records)
+}
+
+/// DUMP and PTTL of one key, `None` if it was deleted in the meantime.
+async fn read_key(client: &Client, key: String) -> crate::Result<Option<Record>> {
+    let payload = match client.command(["DUMP", &key]).await? {
+        Frame::Bulk(payload) => payload,
+        _ => return Ok(None),
+    };
+    let expires_at = match client.command(["PTTL", &key]).await? {
+        Frame::Integer(-2) => return Ok(None),
+        Frame::Integer(millis) if millis >= 0 => Some(now_millis() + millis as u64),
+        _ => None,
+    };
+    let value = dump::deserialize(&payload).map_err(|e| e.to_string())?;
+    Ok(Some(Record { key, value, expires_at }))
+}
+
+/// Writes the records with RESTORE, skipping the ones not matching `pattern` and the ones
+/// that expired since the export. Returns how many were written.
+///
+/// Without `replace`, a key that already exists fails the import.
+pub async fn import(client: &Client, records: &[Record], pattern: Option<&str>, replace: bool) -> crate::Result<usize> {
+    let now = now_millis();
+    let mut tasks = JoinSet::new();
+    for record in records {
+        if pattern.is_some_and(|pattern| !glob_match(pattern.as_bytes(), record.key.as_bytes())) {
+            continue;
+        }
+        if record.expires_at.is_some_and(|at| at <= now) {
+            continue;
+        }
+        let client = client.clone();
+        let command = restore_command(record, replace);
+        let key = record.key.clone();
+        tasks.spawn(async move {
+            client.command(command).await.map_err(|e| format!("{}: {}", key, e))
+        });
+    }
+    let mut imported = 0;
+    while let Some(result) = tasks.join_next().await {
+        result??;
+        imported += 1;
+    }
+    Ok(imported)
+}
+
+/// `RESTORE key expires_at payload ABSTTL [REPLACE]`
+fn restore_command(record: &Record, replace: bool) -> Vec<Bytes> {
+    let mut command = vec![
+        Bytes::from_static(b"RESTORE"),
+        Bytes::from(record.key.clone()),
+        Bytes::from(record.expires_at.unwrap_or(0).to_string()),
+        dump::serialize(&record.value),
+        Bytes::from_static(b"ABSTTL"),
+    ];
+    if replace {
+        command.push(Bytes::from_static(b"REPLACE"));
+    }
+    command
+}
+
+/// Writes the records to `out` in `format`.
+pub fn write_records(format: Format, records: &[Record], out: &mut dyn Write) -> io::Result<()> {
+    match format {
+        Format::Json => {
+            for record in records {
+                let mut fields = vec![("key".to_string(), Json::String(record.key.clone()))];
+                fields.push(("type".to_string(), Json::String(type_name(&record.value).to_string())));
+                fields.push(("expires_at".to_string(), record.expires_at.map_or(Json::Null, |at| Json::Number(at as f64))));
+                fields.push(("value".to_string(), value_to_json(&record.value)));
+                writeln!(out, "{}", Json::Object(fields))?;
+            }
+        }
+        Format::Csv => {
+            writeln!(out, "key,type,expires_at,value")?;
+            for record in records {
+                let expires_at = record.expires_at.map(|at| at.to_string()).unwrap_or_default();
+                let row = [
+                    csv_field(&record.key),
+                    type_name(&record.value).to_string(),
+                    expires_at,
+                    csv_field(&value_to_json(&record.value).to_string()),
+                ];
+                writeln!(out, "{}", row.join(","))?;
+            }
+        }
+        Format::Resp => {
+            let mut buf = Vec::new();
+            for record in records {
+                Frame::command(restore_command(record, true)).encode(&mut buf);
+            }
+            out.write_all(&buf)?;
+        }
+    }
+    Ok(())
+}
+
+/// Reads records written by `write_records`.
+pub fn read_records(format: Format, data: &[u8]) -> Result<Vec<Record>, String> {
+    match format {
+        Format::Json => {
+            let text = std::str::from_utf8(data).map_err(|_| "the file is not UTF-8".to_string())?;
+            let mut records = Vec::new();
+            for (n, line) in text.lines().enumerate().filter(|(_, line)| !line.trim().is_empty()) {
+                let record = Json::parse(line).and_then(|json| {
+                    let field = |name: &str| json.get(name).ok_or(format!("missing \"{}\"", name));
+                    let key = field("key")?.as_str().ok_or("the key is not a string")?;
+                    let kind = field("type")?.as_str().ok_or("the type is not a string")?;
+                    let expires_at = match json.get("expires_at") {
+                        None | Some(Json::Null) => None,
+                        Some(at) => Some(at.as_f64().ok_or("expires_at is not a number")? as u64),
+                    };
+                    let value = value_from_json(kind, field("value")?)?;
+                    Ok(Record { key: key.to_string(), value, expires_at })
+                });
+                records.push(record.map_err(|e| format!("line {}: {}", n + 1, e))?);
+            }
+            Ok(records)
+        }
+        Format::Csv => {
+            let text = std::str::from_utf8(data).map_err(|_| "the file is not UTF-8".to_string())?;
+            let rows = parse_csv(text)?;
+            let mut records = Vec::new();
+            for (n, row) in rows.iter().enumerate().skip(1) {
+                let record = match row.as_slice() {
+                    [key, kind, expires_at, value] => {
+                        let expires_at = match expires_at.as_str() {
+                            "" => None,
+                            at => Some(at.parse().map_err(|_| "expires_at is not a number".to_string())),
+                        };
+                        Json::parse(value).and_then(|json| {
+                            Ok(Record { key: key.clone(), value: value_from_json(kind, &json)?, expires_at: expires_at.transpose()? })
+                        })
+                    }
+                    _ => Err(format!("expected 4 columns, got {}", row.len())),
+                };
+                records.push(record.map_err(|e| format!("row {}: {}", n + 1, e))?);
+            }
+            Ok(records)
+        }
+        Format::Resp => {
+            let mut cursor = Cursor::new(data);
+            let mut records = Vec::new();
+            while (cursor.position() as usize) < data.len() {
+                let frame = Frame::parse(&mut cursor).map_err(|e| format!("invalid RESP: {}", e))?;
+                records.push(record_from_restore(frame)?);
+            }
+            Ok(records)
+        }
+    }
+}
+
+fn record_from_restore(frame: Frame) -> Result<Record, String> {
+    let parts = match frame {
+        Frame::Array(parts) => parts,
+        frame => return Err(format!("expected a RESTORE command, got {}", frame)),
+    };
+    let parts: Vec<Bytes> = parts
+        .into_iter()
+        .map(|part| match part {
+            Frame::Bulk(data) => Ok(data),
+            frame => Err(format!("unexpected argument {}", frame)),
+        })
+        .collect::<Result<_, _>>()?;
+    if parts.len() < 4 || !parts[0].eq_ignore_ascii_case(b"RESTORE") {
+        return Err("expected a RESTORE command".to_string());
+    }
+    let ttl: u64 = std::str::from_utf8(&parts[2])
+        .ok()
+        .and_then(|ttl| ttl.parse().ok())
+        .ok_or("invalid RESTORE ttl")?;
+    let absolute = parts[4..].iter().any(|option| option.eq_ignore_ascii_case(b"ABSTTL"));
+    let expires_at = match ttl {
+        0 => None,
+        at if absolute => Some(at),
+        millis => Some(now_millis() + millis),
+    };
+    let value = dump::deserialize(&parts[3]).map_err(|e| e.to_string())?;
+    Ok(Record { key: String::from_utf8_lossy(&parts[1]).into_owned(), value, expires_at })
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::String(_) => "string",
+        Value::List(_) => "list",
+        Value::Stream(_) => "stream",
+        Value::SortedSet(_) => "zset",
+    }
+}
+
+fn bytes_to_json(data: &[u8]) -> Json {
+    match std::str::from_utf8(data) {
+        Ok(s) => Json::String(s.to_string()),
+        Err(_) => {
+            let mut hex = String::with_capacity(data.len() * 2);
+            for byte in data {
+                let _ = write!(hex, "{:02x}", byte);
+            }
+            Json::Object(vec![("hex".to_string(), Json::String(hex))])
+        }
+    }
+}
+
+fn bytes_from_json(json: &Json) -> Result<Bytes, String> {
+    if let Some(s) = json.as_str() {
+        return Ok(Bytes::from(s.to_string()));
+    }
+    let hex = json.get("hex").and_then(Json::as_str).ok_or("expected a string or {\"hex\": ...}")?;
+    if hex.len() % 2 != 0 || !hex.is_ascii() {
+        return Err("invalid hex string".to_string());
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| "invalid hex string".to_string()))
+        .collect::<Result<Vec<u8>, _>>()
+        .map(Bytes::from)
+}
+
+fn value_to_json(value: &Value) -> Json {
+    match value {
+        Value::String(data) => bytes_to_json(data),
+        Value::List(items) => Json::Array(items.iter().map(|item| bytes_to_json(item)).collect()),
+        Value::SortedSet(set) => Json::Array(
+            set.iter()
+                .map(|(member, score)| {
+                    // JSON numbers can't be infinite.
+                    let score = if score.is_finite() { Json::Number(score) } else { Json::String(score.to_string()) };
+                    Json::Array(vec![bytes_to_json(member), score])
+                })
+                .collect(),
+        ),
+        Value::Stream(stream) => {
+            let entries = stream
+                .entries()
+                .map(|(id, fields)| {
+                    let fields = fields.iter().flat_map(|(field, value)| [bytes_to_json(field), bytes_to_json(value)]);
+                    Json::Array(vec![Json::String(id.to_string()), Json::Array(fields.collect())])
+                })
+                .collect();
+            Json::Object(vec![
+                ("last_id".to_string(), Json::String(stream.last_id().to_string())),
+                ("entries".to_string(), Json::Array(entries)),
+            ])
+        }
+    }
+}
+
+fn value_from_json(kind: &str, json: &Json) -> Result<Value, String> {
+    // Empty lists and sorted sets can't exist: the commands delete them with their last element.
+    let items = || match json.as_array() {
+        Some([]) => Err(format!("a {} can't be empty", kind)),
+        Some(items) => Ok(items),
+        None => Err(format!("a {} must be an array", kind)),
+    };
+    match kind {
+        "string" => Ok(Value::String(bytes_from_json(json)?)),
+        "list" => Ok(Value::List(items()?.iter().map(bytes_from_json).collect::<Result<VecDeque<_>, _>>()?)),
+        "zset" => {
+            let mut set = SortedSet::default();
+            for pair in items()? {
+                let [member, score] = pair.as_array().ok_or("expected [member, score]")? else {
+                    return Err("expected [member, score]".to_string());
+                };
+                let score = match score {
+                    Json::Number(n) => *n,
+                    Json::String(s) => s.parse::<f64>().ok().filter(|s| !s.is_nan()).ok_or("invalid score")?,
+                    _ => return Err("invalid score".to_string()),
+                };
+                set.insert(bytes_from_json(member)?, score);
+            }
+            Ok(Value::SortedSet(set))
+        }
+        "stream" => {
+            let id = |json: &Json| json.as_str().and_then(|s| StreamId::parse(s, 0)).ok_or("invalid stream id");
+            let last_id = id(json.get("last_id").ok_or("missing last_id")?)?;
+            let mut entries = Vec::new();
+            for entry in json.get("entries").and_then(Json::as_array).ok_or("missing entries")? {
+                let [entry_id, fields] = entry.as_array().ok_or("expected [id, fields]")? else {
+                    return Err("expected [id, fields]".to_string());
+                };
+                let fields = fields.as_array().ok_or("expected [id, fields]")?;
+                if fields.len() % 2 != 0 {
+                    return Err("odd number of fields".to_string());
+                }
+                let fields = fields
+                    .chunks(2)
+                    .map(|pair| Ok((bytes_from_json(&pair[0])?, bytes_from_json(&pair[1])?)))
+                    .collect::<Result<Vec<_>, String>>()?;
+                entries.push((id(entry_id)?, fields));
+            }
+            Ok(Value::Stream(Stream::from_entries(entries, last_id)))
+        }
+        kind => Err(format!("unknown type '{}'", kind)),
+    }
+}
+
+/// Quotes a CSV field if it needs it (RFC 4180).
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Splits CSV text into rows of fields. Quoted fields may contain commas, newlines and `""`.
+fn parse_csv(text: &str) -> Result<Vec<Vec<String>>, String> {
+    let mut rows = Vec::new();
+    let mut row = Vec::new();
+    let mut field = String::new();
+    let mut chars = text.chars().peekable();
+    let mut quoted = false;
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            '"' if quoted => quoted = false,
+            '"' if field.is_empty() => quoted = true,
+            ',' if !quoted => row.push(std::mem::take(&mut field)),
+            '\r' if !quoted && chars.peek() == Some(&'\n') => {}
+            '\n' if !quoted => {
+                row.push(std::mem::take(&mut field));
+                rows.push(std::mem::take(&mut row));
+            }
+            c => field.push(c),
+        }
+    }
+    if quoted {
+        return Err("unterminated quoted field".to_string());
+    }
+    if !field.is_empty() || !row.is_empty() {
+        row.push(field);
+        rows.push(row);
+    }
+    Ok(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::list::End;
+    use crate::stream::NewId;
+    use crate::zset::Update;
+    use crate::{ShardedDatabase, TestServer};
+    use std::time::Duration;
+
+    fn sample(db: &ShardedDatabase) {
+        db.insert("user:1", Bytes::from("alice, \"the\" admin\n"));
+        db.insert_with_ttl("user:2", Bytes::from_static(b"\xff\x00binary"), Some(Duration::from_secs(600))).unwrap();
+        db.push("queue", &[Bytes::from("a"), Bytes::from("b")], End::Right).unwrap();
+        db.zadd("scores", &[(1.5, Bytes::from("x")), (f64::INFINITY, Bytes::from("y"))], Update::Always).unwrap();
+        let fields = vec![(Bytes::from("temp"), Bytes::from("21"))];
+        db.xadd("events", NewId::Explicit(StreamId { ms: 5, seq: 1 }), fields, None, false).unwrap();
+    }
+
+    #[test]
+    fn test_formats_round_trip() {
+        let db = ShardedDatabase::new(4);
+        sample(&db);
+        let records: Vec<Record> = ["events", "queue", "scores", "user:1", "user:2"]
+            .iter()
+            .map(|key| Record {
+                key: key.to_string(),
+                value: dump::deserialize(&db.dump(key).unwrap()).unwrap(),
+                expires_at: (*key == "user:2").then_some(1_700_000_000_000),
+            })
+            .collect();
+
+        for format in [Format::Json, Format::Csv, Format::Resp] {
+            let mut out = Vec::new();
+            write_records(format, &records, &mut out).unwrap();
+            assert_eq!(read_records(format, &out).unwrap(), records, "{:?}", format);
+        }
+
+        let mut json = Vec::new();
+        write_records(Format::Json, &records[3..5], &mut json).unwrap();
+        assert_eq!(
+            String::from_utf8(json).unwrap(),
+            "{\"key\":\"user:1\",\"type\":\"string\",\"expires_at\":null,\"value\":\"alice, \\\"the\\\" admin\\n\"}\n\
+             {\"key\":\"user:2\",\"type\":\"string\",\"expires_at\":1700000000000,\"value\":{\"hex\":\"ff0062696e617279\"}}\n"
+        );
+    }
+
+    #[test]
+    fn test_bad_input_is_reported() {
+        assert!(read_records(Format::Json, b"{\"key\":\"k\"}\n").unwrap_err().starts_with("line 1"));
+        assert!(read_records(Format::Csv, b"key,type,expires_at,value\nk,list,,\"[1]\"\n").is_err());
+        assert!(read_records(Format::Resp, b"*1\r\n$3\r\nGET\r\n").is_err());
+        // lists and sorted sets only exist with elements
+        assert!(value_from_json("list", &Json::Array(Vec::new())).is_err());
+        assert!(value_from_json("zset", &Json::Array(Vec::new())).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_export_and_import_between_servers() {
+        let source = TestServer::start().await.unwrap();
+        sample(&source.shared().db);
+        let client = source.client().await.unwrap();
+        let records = export(&client, Some("user:*")).await.unwrap();
+        assert_eq!(records.iter().map(|r| r.key.as_str()).collect::<Vec<_>>(), vec!["user:1", "user:2"]);
+        let expires_at = records[1].expires_at.unwrap();
+        assert!(expires_at > now_millis() + 590_000);
+
+        let all = export(&client, None).await.unwrap();
+        let target = TestServer::start().await.unwrap();
+        let target_client = target.client().await.unwrap();
+        assert_eq!(import(&target_client, &all, Some("[qs]*"), false).await.unwrap(), 2);
+        assert_eq!(import(&target_client, &all, None, true).await.unwrap(), 5);
+        // without REPLACE, existing keys fail the import
+        assert!(import(&target_client, &all, None, false).await.is_err());
+
+        let db = &target.shared().db;
+        for key in ["events", "queue", "scores", "user:1", "user:2"] {
+            assert_eq!(db.dump(key), source.shared().db.dump(key), "{}", key);
+        }
+        assert!(matches!(db.ttl("user:2"), crate::Ttl::Remaining(ttl) if ttl > Duration::from_secs(590)));
+
+        // a record that expired in the file is skipped
+        let expired = Record { key: "old".to_string(), value: Value::String(Bytes::new()), expires_at: Some(1) };
+        assert_eq!(import(&target_client, &[expired], None, false).await.unwrap(), 0);
+    }
+}
@@ -0,0 +1,438 @@
+use bytes::{Buf, Bytes};
+use std::fmt;
+use std::io::Cursor;
+
+/// The largest bulk string we accept, Redis' default `proto-max-bulk-len`.
+const MAX_BULK_LEN: i64 = 512 * 1024 * 1024;
+/// The most elements an array, set, push or map may announce; Redis has the same limit.
+const MAX_AGGREGATE_LEN: i64 = i32::MAX as i64;
+
+/// A frame in the Redis protocol (RESP).
+///
+/// This follows the "Framing" chapter of the tokio tutorial (https://tokio.rs/tokio/tutorial/framing).
+/// We keep our own copy instead of `mini_redis::Frame` because mini-redis cannot write nested
+/// arrays (e.g. the reply of `SLOWLOG GET`) and its integers are unsigned.
+///
+/// The variants after `Array` are the types RESP3 added (https://github.com/redis/redis-specifications).
+/// The server itself only sends RESP2 so far, but clients like `my-redis-cli` must understand
+/// what a real Redis sends after `HELLO 3`. A RESP3 null (`_`) is parsed as `Frame::Null`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Frame {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Bytes),
+    Null,
+    Array(Vec<Frame>),
+    Double(f64),
+    Boolean(bool),
+    BigNumber(String),
+    /// A string with a 3 letter format, e.g. `txt` or `mkd`.
+    Verbatim(String, String),
+    Map(Vec<(Frame, Frame)>),
+    Set(Vec<Frame>),
+    /// Out-of-band data the server sends on its own, e.g. an invalidation message.
+    Push(Vec<Frame>),
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// Not enough data is available to parse a message
+    Incomplete,
+    /// Invalid message encoding
+    Other(String),
+}
+
+impl Frame {
+    /// Builds the array frame a client sends for a command, e.g. `["GET", "foo"]`.
+    pub fn command<I, T>(parts: I) -> Frame
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<Bytes>,
+    {
+        Frame::Array(parts.into_iter().map(|p| Frame::Bulk(p.into())).collect())
+    }
+
+    /// Checks if an entire message can be decoded from `src`.
+    /// The cursor is advanced past the frame, which tells the caller how many bytes to consume.
+    pub fn check(src: &mut Cursor<&[u8]>) -> Result<(), Error> {
+        match get_u8(src)? {
+            b'+' | b'-' | b',' | b'#' | b'(' | b'_' => {
+                get_line(src)?;
+                Ok(())
+            }
+            b':' => {
+                get_decimal(src)?;
+                Ok(())
+            }
+            b'$' | b'=' | b'!' => {
+                if b'-' == peek_u8(src)? {
+                    // Skip '-1\r\n'
+                    skip(src, 4)
+                } else {
+                    let len = check_len(get_decimal(src)?, MAX_BULK_LEN)?;
+                    // skip that number of bytes + 2 (\r\n).
+                    skip(src, len + 2)
+                }
+            }
+            b'*' | b'~' | b'>' => {
+                let len = get_decimal(src)?;
+                if len == -1 {
+                    // A null array, only valid for '*' but harmless to accept here.
+                    return Ok(());
+                }
+                for _ in 0..check_len(len, MAX_AGGREGATE_LEN)? {
+                    Frame::check(src)?;
+                }
+                Ok(())
+            }
+            b'%' => {
+                // A map has a key and a value per entry.
+                let len = check_len(get_decimal(src)?, MAX_AGGREGATE_LEN)?;
+                let items = len.checked_mul(2).ok_or_else(|| invalid_len(len as i64))?;
+                for _ in 0..items {
+                    Frame::check(src)?;
+                }
+                Ok(())
+            }
+            actual => Err(Error::Other(format!("protocol error; invalid frame type byte `{}`", actual))),
+        }
+    }
+
+    /// The message has already been validated with `check`.
+    pub fn parse(src: &mut Cursor<&[u8]>) -> Result<Frame, Error> {
+        match get_u8(src)? {
+            b'+' => {
+                let line = get_line(src)?.to_vec();
+                let string = String::from_utf8(line).map_err(|_| invalid_format())?;
+                Ok(Frame::Simple(string))
+            }
+            b'-' => {
+                let line = get_line(src)?.to_vec();
+                let string = String::from_utf8(line).map_err(|_| invalid_format())?;
+                Ok(Frame::Error(string))
+            }
+            b':' => Ok(Frame::Integer(get_decimal(src)?)),
+            b'$' => {
+                if b'-' == peek_u8(src)? {
+                    let line = get_line(src)?;
+                    if line != b"-1" {
+                        return Err(invalid_format());
+                    }
+                    Ok(Frame::Null)
+                } else {
+                    Ok(Frame::Bulk(get_blob(src)?))
+                }
+            }
+            b'*' => {
+                let len = get_decimal(src)?;
+                if len < 0 {
+                    return Ok(Frame::Null);
+                }
+                Ok(Frame::Array(parse_items(src, len)?))
+            }
+            b'~' => {
+                let len = get_decimal(src)?;
+                Ok(Frame::Set(parse_items(src, len)?))
+            }
+            b'>' => {
+                let len = get_decimal(src)?;
+                Ok(Frame::Push(parse_items(src, len)?))
+            }
+            b'%' => {
+                let len = get_decimal(src)?;
+                let mut entries = Vec::with_capacity(len.max(0) as usize);
+                for _ in 0..len {
+                    let key = Frame::parse(src)?;
+                    let value = Frame::parse(src)?;
+                    entries.push((key, value));
+                }
+                Ok(Frame::Map(entries))
+            }
+            b'_' => {
+                get_line(src)?;
+                Ok(Frame::Null)
+            }
+            b'#' => match get_line(src)? {
+                b"t" => Ok(Frame::Boolean(true)),
+                b"f" => Ok(Frame::Boolean(false)),
+                _ => Err(invalid_format()),
+            },
+            b',' => {
+                let line = std::str::from_utf8(get_line(src)?).map_err(|_| invalid_format())?;
+                // Rust parses "inf", "-inf" and "nan", which is how RESP3 spells them too.
+                Ok(Frame::Double(line.parse().map_err(|_| invalid_format())?))
+            }
+            b'(' => {
+                let line = get_line(src)?.to_vec();
+                Ok(Frame::BigNumber(String::from_utf8(line).map_err(|_| invalid_format())?))
+            }
+            b'!' => {
+                let data = get_blob(src)?;
+                Ok(Frame::Error(String::from_utf8(data.to_vec()).map_err(|_| invalid_format())?))
+            }
+            b'=' => {
+                // The payload is "txt:<text>", the format is always 3 bytes.
+                let data = get_blob(src)?;
+                if data.len() < 4 || data[3] != b':' {
+                    return Err(invalid_format());
+                }
+                let format = String::from_utf8(data[..3].to_vec()).map_err(|_| invalid_format())?;
+                let text = String::from_utf8(data[4..].to_vec()).map_err(|_| invalid_format())?;
+                Ok(Frame::Verbatim(format, text))
+            }
+            actual => Err(Error::Other(format!("protocol error; invalid frame type byte `{}`", actual))),
+        }
+    }
+
+    /// Serializes the frame into RESP bytes.
+    ///
+    /// Note: unlike mini-redis we encode into a buffer first, which makes the recursion for
+    /// nested arrays trivial (async fns can't recurse without boxing).
+    pub fn encode(&self, dst: &mut Vec<u8>) {
+        match self {
+            Frame::Simple(val) => {
+                dst.push(b'+');
+                dst.extend_from_slice(val.as_bytes());
+                dst.extend_from_slice(b"\r\n");
+            }
+            Frame::Error(val) => {
+                dst.push(b'-');
+                dst.extend_from_slice(val.as_bytes());
+                dst.extend_from_slice(b"\r\n");
+            }
+            Frame::Integer(val) => {
+                dst.extend_from_slice(format!(":{}\r\n", val).as_bytes());
+            }
+            Frame::Null => dst.extend_from_slice(b"$-1\r\n"),
+            Frame::Bulk(val) => {
+                dst.extend_from_slice(format!("${}\r\n", val.len()).as_bytes());
+                dst.extend_from_slice(val);
+                dst.extend_from_slice(b"\r\n");
+            }
+            Frame::Array(val) => encode_items(dst, b'*', val),
+            Frame::Set(val) => encode_items(dst, b'~', val),
+            Frame::Push(val) => encode_items(dst, b'>', val),
+            Frame::Map(entries) => {
+                dst.extend_from_slice(format!("%{}\r\n", entries.len()).as_bytes());
+                for (key, value) in entries {
+                    key.encode(dst);
+                    value.encode(dst);
+                }
+            }
+            Frame::Double(val) => {
+                let val = match val {
+                    v if v.is_nan() => "nan".to_string(),
+                    v if v.is_infinite() && *v > 0.0 => "inf".to_string(),
+                    v if v.is_infinite() => "-inf".to_string(),
+                    v => v.to_string(),
+                };
+                dst.extend_from_slice(format!(",{}\r\n", val).as_bytes());
+            }
+            Frame::Boolean(val) => dst.extend_from_slice(if *val { b"#t\r\n" } else { b"#f\r\n" }),
+            Frame::BigNumber(val) => dst.extend_from_slice(format!("({}\r\n", val).as_bytes()),
+            Frame::Verbatim(format, text) => {
+                dst.extend_from_slice(format!("={}\r\n{}:{}\r\n", text.len() + 4, format, text).as_bytes());
+            }
+        }
+    }
+}
+
+impl fmt::Display for Frame {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Frame::Simple(response) => response.fmt(fmt),
+            Frame::Error(msg) => write!(fmt, "error: {}", msg),
+            Frame::Integer(num) => num.fmt(fmt),
+            Frame::Bulk(msg) => match std::str::from_utf8(msg) {
+                Ok(string) => string.fmt(fmt),
+                Err(_) => write!(fmt, "{:?}", msg),
+            },
+            Frame::Null => "(nil)".fmt(fmt),
+            Frame::Double(num) => num.fmt(fmt),
+            Frame::Boolean(b) => b.fmt(fmt),
+            Frame::BigNumber(num) => num.fmt(fmt),
+            Frame::Verbatim(_, text) => text.fmt(fmt),
+            Frame::Map(entries) => {
+                for (i, (key, value)) in entries.iter().enumerate() {
+                    if i > 0 {
+                        write!(fmt, " ")?;
+                    }
+                    write!(fmt, "{} {}", key, value)?;
+                }
+                Ok(())
+            }
+            Frame::Array(parts) | Frame::Set(parts) | Frame::Push(parts) => {
+                for (i, part) in parts.iter().enumerate() {
+                    if i > 0 {
+                        write!(fmt, " ")?;
+                    }
+                    part.fmt(fmt)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Incomplete => "stream ended early".fmt(fmt),
+            Error::Other(msg) => msg.fmt(fmt),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+fn encode_items(dst: &mut Vec<u8>, kind: u8, items: &[Frame]) {
+    dst.extend_from_slice(format!("{}{}\r\n", kind as char, items.len()).as_bytes());
+    for item in items {
+        item.encode(dst);
+    }
+}
+
+fn parse_items(src: &mut Cursor<&[u8]>, len: i64) -> Result<Vec<Frame>, Error> {
+    let mut out = Vec::with_capacity(len.max(0) as usize);
+    for _ in 0..len {
+        out.push(Frame::parse(src)?);
+    }
+    Ok(out)
+}
+
+/// Reads the `<len>\r\n<data>\r\n` part of a bulk string, a blob error or a verbatim string.
+fn get_blob(src: &mut Cursor<&[u8]>) -> Result<Bytes, Error> {
+    let len = check_len(get_decimal(src)?, MAX_BULK_LEN)?;
+    let n = len + 2;
+    if src.remaining() < n {
+        return Err(Error::Incomplete);
+    }
+    let data = Bytes::copy_from_slice(&src.chunk()[..len]);
+    skip(src, n)?;
+    Ok(data)
+}
+
+fn peek_u8(src: &mut Cursor<&[u8]>) -> Result<u8, Error> {
+    if !src.has_remaining() {
+        return Err(Error::Incomplete);
+    }
+    Ok(src.chunk()[0])
+}
+
+fn get_u8(src: &mut Cursor<&[u8]>) -> Result<u8, Error> {
+    if !src.has_remaining() {
+        return Err(Error::Incomplete);
+    }
+    Ok(src.get_u8())
+}
+
+fn skip(src: &mut Cursor<&[u8]>, n: usize) -> Result<(), Error> {
+    if src.remaining() < n {
+        return Err(Error::Incomplete);
+    }
+    src.advance(n);
+    Ok(())
+}
+
+fn get_decimal(src: &mut Cursor<&[u8]>) -> Result<i64, Error> {
+    let line = get_line(src)?;
+    std::str::from_utf8(line)
+        .ok()
+        .and_then(|s| s.parse::<i64>().ok())
+        .ok_or_else(invalid_format)
+}
+
+/// Finds a line terminated by `\r\n` and returns it without the terminator.
+fn get_line<'a>(src: &mut Cursor<&'a [u8]>) -> Result<&'a [u8], Error> {
+    let start = src.position() as usize;
+    let end = src.get_ref().len();
+    if end < 1 {
+        return Err(Error::Incomplete);
+    }
+
+    for i in start..end - 1 {
+        if src.get_ref()[i] == b'\r' && src.get_ref()[i + 1] == b'\n' {
+            // We found a line, update the position to be *after* the \n
+            src.set_position((i + 2) as u64);
+            return Ok(&src.get_ref()[start..i]);
+        }
+    }
+
+    Err(Error::Incomplete)
+}
+
+/// Checks a length read from a header: a client must not make us overflow or allocate
+/// whatever it announces.
+fn check_len(len: i64, max: i64) -> Result<usize, Error> {
+    if !(0..=max).contains(&len) {
+        return Err(invalid_len(len));
+    }
+    Ok(len as usize)
+}
+
+fn invalid_len(len: i64) -> Error {
+    Error::Other(format!("protocol error; invalid length {}", len))
+}
+
+fn invalid_format() -> Error {
+    Error::Other("protocol error; invalid frame format".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(frame: Frame) -> Frame {
+        let mut buf = Vec::new();
+        frame.encode(&mut buf);
+        let mut cursor = Cursor::new(&buf[..]);
+        Frame::check(&mut cursor).unwrap();
+        cursor.set_position(0);
+        Frame::parse(&mut cursor).unwrap()
+    }
+
+    #[test]
+    fn test_round_trip_nested_array() {
+        let frame = Frame::Array(vec![
+            Frame::Integer(-1),
+            Frame::Array(vec![Frame::Bulk(Bytes::from("a")), Frame::Null]),
+            Frame::Simple("OK".to_string()),
+        ]);
+        assert_eq!(round_trip(frame.clone()), frame);
+    }
+
+    #[test]
+    fn test_round_trip_resp3() {
+        let frame = Frame::Push(vec![
+            Frame::Map(vec![(Frame::Simple("proto".to_string()), Frame::Integer(3))]),
+            Frame::Set(vec![Frame::Boolean(true), Frame::Double(1.5), Frame::Double(f64::INFINITY)]),
+            Frame::BigNumber("3492890328409238509324850943850943825024385".to_string()),
+            Frame::Verbatim("txt".to_string(), "Some string".to_string()),
+        ]);
+        assert_eq!(round_trip(frame.clone()), frame);
+
+        let mut cursor = Cursor::new(&b"_\r\n"[..]);
+        assert_eq!(Frame::parse(&mut cursor).unwrap(), Frame::Null);
+        let mut cursor = Cursor::new(&b"!10\r\nERR failed\r\n"[..]);
+        assert_eq!(Frame::parse(&mut cursor).unwrap(), Frame::Error("ERR failed".to_string()));
+    }
+
+    #[test]
+    fn test_incomplete_frame() {
+        let mut cursor = Cursor::new(&b"*2\r\n$3\r\nGET\r\n"[..]);
+        assert!(matches!(Frame::check(&mut cursor), Err(Error::Incomplete)));
+    }
+
+    #[test]
+    fn test_oversized_lengths_are_protocol_errors() {
+        // twice this overflows an i64
+        for header in [&b"%4611686018427387904\r\n"[..], b"*-2\r\n", b"~3000000000\r\n", b"$536870913\r\n"] {
+            let mut cursor = Cursor::new(header);
+            assert!(matches!(Frame::check(&mut cursor), Err(Error::Other(_))), "{:?}", header);
+        }
+        // a null array is still fine
+        let mut cursor = Cursor::new(&b"*-1\r\n"[..]);
+        Frame::check(&mut cursor).unwrap();
+    }
+}
@@ -0,0 +1,320 @@
+//! Geo commands (GEOADD/GEODIST/GEOSEARCH), stored in sorted sets like in Redis.
+//!
+//! A position becomes a 52 bit geohash: longitude and latitude are each mapped to a 26 bit
+//! cell number, and the bits of the two are interleaved. Close positions share a prefix, and
+//! 52 bits fit exactly in the mantissa of an `f64`, so the hash is the score of the member.
+//! A geo key is an ordinary sorted set: ZRANGE, ZREM, ZSCORE... work on it.
+//!
+//! Decoding a hash gives back the center of its cell, about 0.6m from the original position
+//! at worst. Distances are computed from the decoded positions, like Redis does.
+
+use crate::zset::{Added, Update};
+use crate::{ShardedDatabase, WrongType};
+use bytes::Bytes;
+use std::fmt;
+
+pub const LON_MIN: f64 = -180.0;
+pub const LON_MAX: f64 = 180.0;
+// The Web Mercator limits: the poles themselves can't be indexed.
+pub const LAT_MIN: f64 = -85.05112878;
+pub const LAT_MAX: f64 = 85.05112878;
+
+// bits per coordinate
+const STEP: u32 = 26;
+// The radius Redis uses, so distances match to the last digit.
+const EARTH_RADIUS_M: f64 = 6372797.560856;
+
+/// Whether a position can be indexed.
+pub fn is_valid(lon: f64, lat: f64) -> bool {
+    (LON_MIN..=LON_MAX).contains(&lon) && (LAT_MIN..=LAT_MAX).contains(&lat)
+}
+
+/// Encodes a valid position into its 52 bit geohash: latitude bits at even positions,
+/// longitude bits at odd positions.
+pub fn encode(lon: f64, lat: f64) -> u64 {
+    let cell = |value: f64, min: f64, max: f64| {
+        let cells = (1u64 << STEP) as f64;
+        // `min(...)` keeps the maximum itself in the last cell.
+        (((value - min) / (max - min) * cells) as u64).min((1 << STEP) - 1)
+    };
+    spread(cell(lat, LAT_MIN, LAT_MAX)) | (spread(cell(lon, LON_MIN, LON_MAX)) << 1)
+}
+
+/// Decodes a geohash into the center of its cell, as `(longitude, latitude)`.
+pub fn decode(hash: u64) -> (f64, f64) {
+    let center = |cell: u64, min: f64, max: f64| {
+        let size = (max - min) / (1u64 << STEP) as f64;
+        (min + (cell as f64 + 0.5) * size).clamp(min, max)
+    };
+    (center(squash(hash >> 1), LON_MIN, LON_MAX), center(squash(hash), LAT_MIN, LAT_MAX))
+}
+
+/// Puts the 32 low bits of `x` at the even positions of the result.
+fn spread(x: u64) -> u64 {
+    let mut x = x & 0xffff_ffff;
+    x = (x | (x << 16)) & 0x0000_ffff_0000_ffff;
+    x = (x | (x << 8)) & 0x00ff_00ff_00ff_00ff;
+    x = (x | (x << 4)) & 0x0f0f_0f0f_0f0f_0f0f;
+    x = (x | (x << 2)) & 0x3333_3333_3333_3333;
+    (x | (x << 1)) & 0x5555_5555_5555_5555
+}
+
+/// The inverse of `spread`: gathers the even bits of `x`.
+fn squash(x: u64) -> u64 {
+    let mut x = x & 0x5555_5555_5555_5555;
+    x = (x | (x >> 1)) & 0x3333_3333_3333_3333;
+    x = (x | (x >> 2)) & 0x0f0f_0f0f_0f0f_0f0f;
+    x = (x | (x >> 4)) & 0x00ff_00ff_00ff_00ff;
+    x = (x | (x >> 8)) & 0x0000_ffff_0000_ffff;
+    (x | (x >> 16)) & 0x0000_0000_ffff_ffff
+}
+
+/// The great-circle distance in meters between two positions (haversine formula).
+pub fn distance(lon1: f64, lat1: f64, lon2: f64, lat2: f64) -> f64 {
+    let (lat1, lat2) = (lat1.to_radians(), lat2.to_radians());
+    let u = ((lat2 - lat1) / 2.0).sin();
+    let v = ((lon2.to_radians() - lon1.to_radians()) / 2.0).sin();
+    let a = u * u + lat1.cos() * lat2.cos() * v * v;
+    2.0 * EARTH_RADIUS_M * a.sqrt().asin()
+}
+
+/// A distance unit of the geo commands.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Unit {
+    Meters,
+    Kilometers,
+    Feet,
+    Miles,
+}
+
+impl Unit {
+    pub fn parse(s: &str) -> Option<Unit> {
+        match s.to_lowercase().as_str() {
+            "m" => Some(Unit::Meters),
+            "km" => Some(Unit::Kilometers),
+            "ft" => Some(Unit::Feet),
+            "mi" => Some(Unit::Miles),
+            _ => None,
+        }
+    }
+
+    /// How many meters one unit is.
+    pub fn meters(self) -> f64 {
+        match self {
+            Unit::Meters => 1.0,
+            Unit::Kilometers => 1000.0,
+            Unit::Feet => 0.3048,
+            Unit::Miles => 1609.34,
+        }
+    }
+}
+
+/// Where GEOSEARCH searches from.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Center {
+    /// `FROMMEMBER`: the position of a member of the set.
+    Member(Bytes),
+    /// `FROMLONLAT`
+    Position(f64, f64),
+}
+
+/// The area of GEOSEARCH, in meters.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Shape {
+    /// `BYRADIUS`
+    Radius(f64),
+    /// `BYBOX`, axis-aligned and centered on the search center.
+    Box { width: f64, height: f64 },
+}
+
+/// The options of GEOSEARCH that change which members are returned.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Search {
+    pub center: Center,
+    pub shape: Shape,
+    /// `Some(true)` for ASC, `Some(false)` for DESC, `None` for no particular order.
+    pub ascending: Option<bool>,
+    /// `COUNT n`, with `ANY` when the second field is set: stop at the first `n` matches
+    /// instead of returning the `n` closest.
+    pub count: Option<(usize, bool)>,
+}
+
+/// One member found by GEOSEARCH.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Match {
+    pub member: Bytes,
+    /// From the search center, in meters.
+    pub distance: f64,
+    pub hash: u64,
+    pub lon: f64,
+    pub lat: f64,
+}
+
+/// Errors of GEOSEARCH.
+#[derive(Debug, Clone, PartialEq)]
+pub enum GeoError {
+    WrongType,
+    /// The `FROMMEMBER` member is not in the set.
+    NoMember,
+}
+
+impl fmt::Display for GeoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GeoError::WrongType => WrongType.fmt(f),
+            GeoError::NoMember => write!(f, "ERR could not decode requested zset member"),
+        }
+    }
+}
+
+impl std::error::Error for GeoError {}
+
+impl From<WrongType> for GeoError {
+    fn from(_: WrongType) -> GeoError {
+        GeoError::WrongType
+    }
+}
+
+impl ShardedDatabase {
+    /// Adds members at `(longitude, latitude, member)` (GEOADD). Positions must be valid,
+    /// see `is_valid`.
+    pub fn geoadd(&self, key: &str, members: &[(f64, f64, Bytes)], update: Update) -> Result<Added, WrongType> {
+        let scored: Vec<(f64, Bytes)> = members
+            .iter()
+            .map(|(lon, lat, member)| (encode(*lon, *lat) as f64, member.clone()))
+            .collect();
+        self.zadd(key, &scored, update)
+    }
+
+    /// Returns the distance in meters between two members (GEODIST), `None` if one is missing.
+    pub fn geodist(&self, key: &str, member1: &[u8], member2: &[u8]) -> Result<Option<f64>, WrongType> {
+        let positions = self.with_zset(key, |set| Some((decode(set.score(member1)? as u64), decode(set.score(member2)? as u64))))?;
+        Ok(positions.flatten().map(|((lon1, lat1), (lon2, lat2))| distance(lon1, lat1, lon2, lat2)))
+    }
+
+    /// Returns the members inside an area (GEOSEARCH).
+    ///
+    /// Note: Redis only looks at the members in the 9 geohash cells around the area, found
+    /// with range queries on the scores. We check every member of the set, which gives the
+    /// same results, just in O(N).
+    pub fn geosearch(&self, key: &str, search: &Search) -> Result<Vec<Match>, GeoError> {
+        let matches = self.with_zset(key, |set| -> Result<Vec<Match>, GeoError> {
+            let (lon, lat) = match &search.center {
+                Center::Position(lon, lat) => (*lon, *lat),
+                Center::Member(member) => decode(set.score(member).ok_or(GeoError::NoMember)? as u64),
+            };
+            // With ANY, the first `count` matches are enough.
+            let limit = match search.count {
+                Some((count, true)) => count,
+                _ => usize::MAX,
+            };
+
+            let mut matches = Vec::new();
+            for (member, score) in set.iter() {
+                if matches.len() == limit {
+                    break;
+                }
+                let hash = score as u64;
+                let (member_lon, member_lat) = decode(hash);
+                let inside = match search.shape {
+                    Shape::Radius(radius) => distance(lon, lat, member_lon, member_lat) <= radius,
+                    Shape::Box { width, height } => {
+                        // Both measured from the center, along its latitude for the width.
+                        let north_south = EARTH_RADIUS_M * (member_lat.to_radians() - lat.to_radians()).abs();
+                        north_south <= height / 2.0 && distance(lon, member_lat, member_lon, member_lat) <= width / 2.0
+                    }
+                };
+                if inside {
+                    let distance = distance(lon, lat, member_lon, member_lat);
+                    matches.push(Match { member: member.clone(), distance, hash, lon: member_lon, lat: member_lat });
+                }
+            }
+            Ok(matches)
+        })?;
+        let mut matches = matches.transpose()?.unwrap_or_default();
+
+        // Like Redis, COUNT without ANY returns the closest members.
+        let ascending = search.ascending.or(search.count.filter(|(_, any)| !any).map(|_| true));
+        if let Some(ascending) = ascending {
+            matches.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+            if !ascending {
+                matches.reverse();
+            }
+        }
+        if let Some((count, _)) = search.count {
+            matches.truncate(count);
+        }
+        Ok(matches)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sicily() -> ShardedDatabase {
+        // The example of the Redis documentation.
+        let db = ShardedDatabase::new(4);
+        let members = [
+            (13.361389, 38.115556, Bytes::from("Palermo")),
+            (15.087269, 37.502669, Bytes::from("Catania")),
+        ];
+        db.geoadd("Sicily", &members, Update::Always).unwrap();
+        db
+    }
+
+    fn names(matches: &[Match]) -> Vec<&[u8]> {
+        matches.iter().map(|m| &m.member[..]).collect()
+    }
+
+    #[test]
+    fn test_geohash_matches_redis() {
+        let db = sicily();
+        // ZSCORE Sicily Palermo in Redis
+        assert_eq!(db.zscore("Sicily", b"Palermo"), Ok(Some(3479099956230698.0)));
+        let (lon, lat) = decode(3479099956230698);
+        assert!((lon - 13.361389).abs() < 1e-5 && (lat - 38.115556).abs() < 1e-5);
+        assert_eq!(encode(LON_MAX, LAT_MAX), (1 << 52) - 1);
+    }
+
+    #[test]
+    fn test_geodist() {
+        let db = sicily();
+        let meters = db.geodist("Sicily", b"Palermo", b"Catania").unwrap().unwrap();
+        assert_eq!(format!("{:.4}", meters), "166274.1516");
+        assert_eq!(format!("{:.4}", meters / Unit::Kilometers.meters()), "166.2742");
+        assert_eq!(db.geodist("Sicily", b"Palermo", b"Rome"), Ok(None));
+    }
+
+    #[test]
+    fn test_geosearch() {
+        let db = sicily();
+        let mut search = Search {
+            center: Center::Position(15.0, 37.0),
+            shape: Shape::Radius(200_000.0),
+            ascending: Some(true),
+            count: None,
+        };
+        let found = db.geosearch("Sicily", &search).unwrap();
+        assert_eq!(names(&found), vec![&b"Catania"[..], b"Palermo"]);
+        assert_eq!(format!("{:.4}", found[0].distance / 1000.0), "56.4413");
+        assert_eq!(format!("{:.4}", found[1].distance / 1000.0), "190.4424");
+
+        search.shape = Shape::Radius(100_000.0);
+        assert_eq!(names(&db.geosearch("Sicily", &search).unwrap()), vec![&b"Catania"[..]]);
+
+        search.shape = Shape::Box { width: 400_000.0, height: 400_000.0 };
+        search.ascending = Some(false);
+        assert_eq!(names(&db.geosearch("Sicily", &search).unwrap()), vec![&b"Palermo"[..], b"Catania"]);
+
+        // COUNT alone returns the closest
+        search.center = Center::Member(Bytes::from("Palermo"));
+        search.ascending = None;
+        search.count = Some((1, false));
+        assert_eq!(names(&db.geosearch("Sicily", &search).unwrap()), vec![&b"Palermo"[..]]);
+
+        search.center = Center::Member(Bytes::from("Rome"));
+        assert_eq!(db.geosearch("Sicily", &search), Err(GeoError::NoMember));
+        assert_eq!(db.geosearch("missing", &search), Ok(vec![]));
+    }
+}
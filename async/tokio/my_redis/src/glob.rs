@@ -0,0 +1,139 @@
+//! Glob-style patterns, as used by KEYS and SCAN MATCH.
+//!
+//! - `*` matches any number of bytes, `?` exactly one,
+//! - `[abc]` one of the listed bytes, `[^abc]` any other, `[a-z]` a range,
+//! - `\` makes the next byte literal, e.g. `\*`.
+
+/// Returns whether `string` matches `pattern` as a whole.
+pub fn glob_match(pattern: &[u8], string: &[u8]) -> bool {
+    let (mut p, mut s) = (0, 0);
+    // Where to resume after the last `*`: the pattern just after it, and the next byte of
+    // the string it could swallow.
+    let mut star: Option<(usize, usize)> = None;
+    while s < string.len() {
+        if p < pattern.len() {
+            match pattern[p] {
+                b'*' => {
+                    star = Some((p + 1, s));
+                    p += 1;
+                    continue;
+                }
+                b'?' => {
+                    p += 1;
+                    s += 1;
+                    continue;
+                }
+                b'[' => match match_class(pattern, p + 1, string[s]) {
+                    Some((true, next)) => {
+                        p = next;
+                        s += 1;
+                        continue;
+                    }
+                    None if string[s] == b'[' => {
+                        p += 1;
+                        s += 1;
+                        continue;
+                    }
+                    _ => {}
+                },
+                b'\\' if p + 1 < pattern.len() => {
+                    if pattern[p + 1] == string[s] {
+                        p += 2;
+                        s += 1;
+                        continue;
+                    }
+                }
+                byte => {
+                    if byte == string[s] {
+                        p += 1;
+                        s += 1;
+                        continue;
+                    }
+                }
+            }
+        }
+        // Mismatch: let the last `*` swallow one more byte, or fail.
+        match star {
+            Some((after, swallowed)) => {
+                p = after;
+                s = swallowed + 1;
+                star = Some((after, s));
+            }
+            None => return false,
+        }
+    }
+    pattern[p..].iter().all(|&b| b == b'*')
+}
+
+/// Matches `byte` against the class starting at `pattern[start]` (just after `[`).
+/// Returns whether it matched and where the pattern continues, or `None` for an unclosed
+/// class, which then only matches a literal `[`.
+fn match_class(pattern: &[u8], start: usize, byte: u8) -> Option<(bool, usize)> {
+    let mut i = start;
+    let negate = pattern.get(i) == Some(&b'^');
+    if negate {
+        i += 1;
+    }
+    let mut matched = false;
+    let mut first = true;
+    loop {
+        let &c = pattern.get(i)?;
+        match c {
+            b']' if !first => return Some((matched != negate, i + 1)),
+            b'\\' => {
+                let &escaped = pattern.get(i + 1)?;
+                matched |= escaped == byte;
+                i += 2;
+            }
+            _ if pattern.get(i + 1) == Some(&b'-') && pattern.get(i + 2).is_some_and(|&end| end != b']') => {
+                let (low, high) = (c.min(pattern[i + 2]), c.max(pattern[i + 2]));
+                matched |= (low..=high).contains(&byte);
+                i += 3;
+            }
+            _ => {
+                matched |= c == byte;
+                i += 1;
+            }
+        }
+        first = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, string: &str) -> bool {
+        glob_match(pattern.as_bytes(), string.as_bytes())
+    }
+
+    #[test]
+    fn test_wildcards() {
+        assert!(matches("*", ""));
+        assert!(matches("*", "anything"));
+        assert!(matches("user:*", "user:42"));
+        assert!(!matches("user:*", "session:42"));
+        assert!(matches("*:42", "user:42"));
+        assert!(matches("h?llo", "hello"));
+        assert!(!matches("h?llo", "hllo"));
+        assert!(matches("a*b*c", "aXbYbZc"));
+        assert!(!matches("a*b*c", "aXbYbZ"));
+        assert!(matches("exact", "exact"));
+        assert!(!matches("exact", "exactly"));
+    }
+
+    #[test]
+    fn test_classes_and_escapes() {
+        assert!(matches("h[ae]llo", "hallo"));
+        assert!(!matches("h[ae]llo", "hillo"));
+        assert!(matches("h[^e]llo", "hallo"));
+        assert!(!matches("h[^e]llo", "hello"));
+        assert!(matches("h[a-c]llo", "hbllo"));
+        assert!(!matches("h[a-c]llo", "hdllo"));
+        assert!(matches("key\\*", "key*"));
+        assert!(!matches("key\\*", "keys"));
+        assert!(matches("[]]", "]"));
+        // an unclosed class is a literal `[`
+        assert!(matches("a[b", "a[b"));
+    }
+}
@@ -1,6 +1,259 @@
 use bytes::Bytes;
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::io;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+pub mod audit;
+pub mod benchmark;
+pub mod bitmap;
+pub mod cache;
+pub mod cli;
+pub mod client;
+pub mod cmd;
+pub mod connection;
+pub mod dump;
+pub mod export;
+pub mod frame;
+pub mod geo;
+pub mod glob;
+pub mod hyperloglog;
+pub mod json;
+pub mod list;
+pub mod metrics;
+pub mod monitor;
+pub mod pool;
+pub mod replication;
+pub mod script;
+pub mod sentinel;
+pub mod server;
+pub mod storage;
+pub mod stream;
+pub mod test_server;
+pub mod tracking;
+pub mod zset;
+
+pub use cache::{CacheConfig, CacheStats, CachingClient};
+pub use client::{Client, ClientConfig};
+pub use connection::Connection;
+pub use frame::Frame;
+pub use metrics::Metrics;
+pub use pool::{Pool, PoolConfig, PooledConnection};
+pub use sentinel::{Sentinel, SentinelConfig};
+pub use storage::StorageBackend;
+pub use test_server::TestServer;
+pub use tracking::Tracking;
+
+/// Boxed error type, same approach as mini-redis: most errors are just reported and the
+/// connection is closed, so a trait object is enough.
+pub type Error = Box<dyn std::error::Error + Send + Sync>;
+
+/// A specialized `Result` type for my_redis operations.
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// The value stored under a key.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    String(Bytes),
+    List(VecDeque<Bytes>),
+    Stream(stream::Stream),
+    SortedSet(zset::SortedSet),
+}
+
+impl Value {
+    /// Approximate number of bytes held by the value.
+    fn size(&self) -> usize {
+        match self {
+            Value::String(data) => data.len(),
+            Value::List(items) => items.iter().map(|item| item.len()).sum(),
+            Value::Stream(stream) => stream.size(),
+            Value::SortedSet(set) => set.size(),
+        }
+    }
+}
+
+/// Returned when a command is used against a key holding another kind of value,
+/// e.g. `LPUSH` on a string.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WrongType;
+
+impl fmt::Display for WrongType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "WRONGTYPE Operation against a key holding the wrong kind of value")
+    }
+}
+
+impl std::error::Error for WrongType {}
+
+/// Returned for a time to live so long that its deadline can't be represented, e.g.
+/// `SET key value EX 9223372036854775807`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InvalidExpire;
+
+impl fmt::Display for InvalidExpire {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ERR invalid expire time")
+    }
+}
+
+impl std::error::Error for InvalidExpire {}
+
+/// A deadline as milliseconds since the unix epoch, the way storage keeps it.
+fn to_unix_millis(deadline: Instant) -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let at = now.saturating_add(deadline.saturating_duration_since(Instant::now()));
+    u64::try_from(at.as_millis()).unwrap_or(u64::MAX)
+}
+
+/// The other way around. A deadline that passed while the server was down is now.
+fn from_unix_millis(at: u64) -> Instant {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let left = Duration::from_millis(at).saturating_sub(now);
+    // Note: what storage holds came from an `Instant` once, so this only fails on a damaged file.
+    Instant::now().checked_add(left).unwrap_or_else(Instant::now)
+}
+
+/// The deadline of a time to live starting now.
+///
+/// Note: computed before taking a shard lock. `Instant + Duration` panics on overflow, and a
+/// panic while the lock is held would poison the shard for every later command.
+pub(crate) fn deadline(ttl: Duration) -> std::result::Result<Instant, InvalidExpire> {
+    Instant::now().checked_add(ttl).ok_or(InvalidExpire)
+}
+
+/// Errors of INCR/INCRBY/DECR/DECRBY.
+#[derive(Debug, Clone, PartialEq)]
+pub enum IncrError {
+    WrongType,
+    /// The current value is not a 64 bit integer.
+    NotAnInteger,
+    Overflow,
+}
+
+impl fmt::Display for IncrError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IncrError::WrongType => WrongType.fmt(f),
+            IncrError::NotAnInteger => write!(f, "ERR value is not an integer or out of range"),
+            IncrError::Overflow => write!(f, "ERR increment or decrement would overflow"),
+        }
+    }
+}
+
+impl std::error::Error for IncrError {}
+
+/// The time to live of a key, as reported by TTL/PTTL.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Ttl {
+    /// The key doesn't exist (-2 in Redis).
+    NoKey,
+    /// The key exists but never expires (-1 in Redis).
+    NoExpiry,
+    /// The key expires after this much time.
+    Remaining(Duration),
+}
+
+/// The data behind one shard lock.
+struct Shard {
+    entries: tracking::Recorded,
+    // Clients parked in BLPOP/BRPOP/BLMOVE on a key, oldest first.
+    // They live next to the data so that a push can hand values over under the same lock.
+    waiters: HashMap<String, VecDeque<Arc<list::Waiter>>>,
+    // Clients blocked in XREAD/XREADGROUP, woken up on every XADD to the key.
+    stream_watchers: HashMap<String, Vec<Arc<tokio::sync::Notify>>>,
+    // Deadlines of the keys with a time to live (EXPIRE, SET EX). Expired keys are dropped
+    // lazily by `lock_shard`, and in the background by `purge_expired`.
+    expires: HashMap<String, Instant>,
+}
+
+impl Shard {
+    fn new(entries: tracking::Recorded) -> Shard {
+        Shard { entries, waiters: HashMap::new(), stream_watchers: HashMap::new(), expires: HashMap::new() }
+    }
+
+    /// Removes a key together with its time to live, returning whether it existed.
+    fn remove(&mut self, key: &str) -> bool {
+        self.expires.remove(key);
+        self.entries.remove(key)
+    }
+
+    /// Sets or clears the deadline of `key`, returning whether it had one. The backend is told
+    /// too, so that it can keep the deadline across a restart.
+    fn set_deadline(&mut self, key: &str, deadline: Option<Instant>) -> bool {
+        self.entries.set_expiry(key, deadline.map(to_unix_millis));
+        match deadline {
+            Some(deadline) => self.expires.insert(key.to_string(), deadline).is_some(),
+            None => self.expires.remove(key).is_some(),
+        }
+    }
+
+    fn is_expired(&self, key: &str, now: Instant) -> bool {
+        matches!(self.expires.get(key), Some(deadline) if *deadline <= now)
+    }
+}
+
+thread_local! {
+    // Set while `committing` runs: where `ShardGuard::drop` leaves the first storage error.
+    static STORAGE_ERROR: RefCell<Option<Option<io::Error>>> = const { RefCell::new(None) };
+}
+
+/// Runs `f`, and returns the first error the storage backend hit while writing its changes.
+///
+/// Why not return it from every method of `ShardedDatabase`?
+/// The backend writes when the shard lock is released, after the method built its result, and
+/// every method would need an error variant for it. The caller of a command only has to know
+/// whether to reply with an error instead, which this tells it.
+pub(crate) fn committing<T>(f: impl FnOnce() -> T) -> (T, io::Result<()>) {
+    let outer = STORAGE_ERROR.replace(Some(None));
+    let result = f();
+    let error = STORAGE_ERROR.replace(outer).flatten();
+    (result, error.map_or(Ok(()), Err))
+}
+
+/// A locked shard, returned by `ShardedDatabase::lock_shard`.
+///
+/// Why not the `MutexGuard` itself?
+/// The storage backend must persist what a command changed before anyone else sees the shard.
+/// Dropping the guard syncs the backend, sends the CLIENT TRACKING invalidations, and only
+/// then releases the lock, so a client can't read the new value before being told.
+struct ShardGuard<'a>(MutexGuard<'a, Shard>);
+
+impl Deref for ShardGuard<'_> {
+    type Target = Shard;
+
+    fn deref(&self) -> &Shard {
+        &self.0
+    }
+}
+
+impl DerefMut for ShardGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Shard {
+        &mut self.0
+    }
+}
+
+impl Drop for ShardGuard<'_> {
+    fn drop(&mut self) {
+        if let Err(e) = self.0.entries.sync() {
+            // Outside of `committing` (e.g. keys expiring in the background) no client waits
+            // for the outcome, all we can do is report it.
+            let unreported = STORAGE_ERROR.with_borrow_mut(|slot| match slot {
+                Some(first) => {
+                    first.get_or_insert(e);
+                    None
+                }
+                None => Some(e),
+            });
+            if let Some(e) = unreported {
+                eprintln!("storage error: {}", e);
+            }
+        }
+        self.0.entries.send_invalidations();
+    }
+}
 
 /// A sharded database that distributes keys across multiple shards
 /// to reduce lock contention in concurrent access scenarios.
@@ -8,48 +261,259 @@ use std::sync::{Arc, Mutex};
 /// Uses the **newtype pattern** to wrap the internal Arc, allowing us to
 /// implement methods directly on the type.
 pub struct ShardedDatabase {
-    shards: Arc<Vec<Mutex<HashMap<String, Bytes>>>>,
+    shards: Arc<Vec<Mutex<Shard>>>,
+    tracking: Tracking,
 }
 
 impl ShardedDatabase {
-    /// Creates a new sharded database with the specified number of shards.
+    /// Creates a new sharded database with the specified number of shards, kept in memory.
     pub fn new(num_shards: usize) -> Self {
-        let mut shards = Vec::with_capacity(num_shards);
+        let mut storage: Vec<Box<dyn StorageBackend>> = Vec::with_capacity(num_shards);
         for _ in 0..num_shards {
-            shards.push(Mutex::new(HashMap::new()));
+            storage.push(Box::new(HashMap::<String, Value>::new()));
         }
-        Self { shards: Arc::new(shards) }
+        Self::with_storage(storage)
+    }
+
+    /// Creates a database with one shard per storage backend, e.g. the ones of
+    /// `storage::bitcask::open_shards`.
+    pub fn with_storage(storage: Vec<Box<dyn StorageBackend>>) -> Self {
+        let tracking = Tracking::new();
+        let shards = storage
+            .into_iter()
+            .map(|entries| {
+                let mut shard = Shard::new(tracking::Recorded::new(entries, tracking.clone()));
+                for (key, expires_at) in shard.entries.expiries() {
+                    shard.expires.insert(key, from_unix_millis(expires_at));
+                }
+                Mutex::new(shard)
+            })
+            .collect();
+        Self { shards: Arc::new(shards), tracking }
+    }
+
+    /// The clients with CLIENT TRACKING on, told about every change of this database.
+    pub fn tracking(&self) -> Tracking {
+        self.tracking.clone()
     }
 
     /// Inserts a key-value pair into the appropriate shard.
+    /// Like SET in Redis, this replaces any value and time to live the key had.
     pub fn insert(&self, key: &str, value: Bytes) {
-        let shard_index = Self::get_shard_index(key, self.shards.len());
-        let mut shard = self.shards[shard_index].lock().unwrap();
-        shard.insert(key.to_string(), value);
+        self.store(key, value, None);
     }
 
-    /// Retrieves a value by key from the appropriate shard.
+    /// Inserts a key-value pair that expires after `ttl` (SET key value EX/PX).
+    pub fn insert_with_ttl(&self, key: &str, value: Bytes, ttl: Option<Duration>) -> std::result::Result<(), InvalidExpire> {
+        let deadline = ttl.map(deadline).transpose()?;
+        self.store(key, value, deadline);
+        Ok(())
+    }
+
+    fn store(&self, key: &str, value: Bytes, deadline: Option<Instant>) {
+        let mut shard = self.lock_shard(key);
+        shard.entries.insert(key.to_string(), Value::String(value));
+        shard.set_deadline(key, deadline);
+    }
+
+    /// Retrieves a string value by key from the appropriate shard.
+    /// Keys holding another type (e.g. a list) are reported as missing.
     pub fn get(&self, key: &str) -> Option<Bytes> {
+        let mut shard = self.lock_shard(key);
+        match shard.entries.get(key) {
+            Some(Value::String(value)) => Some(value.clone()),
+            _ => None,
+        }
+    }
+
+    /// Like `get`, but a key holding another type is an error instead of missing.
+    pub(crate) fn get_string(&self, key: &str) -> std::result::Result<Option<Bytes>, WrongType> {
+        let mut shard = self.lock_shard(key);
+        match shard.entries.get(key) {
+            Some(Value::String(value)) => Ok(Some(value.clone())),
+            Some(_) => Err(WrongType),
+            None => Ok(None),
+        }
+    }
+
+    /// Removes a key of any type, returning whether it existed (DEL).
+    pub fn remove(&self, key: &str) -> bool {
+        self.lock_shard(key).remove(key)
+    }
+
+    /// Returns whether the key exists (EXISTS).
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.lock_shard(key).entries.contains_key(key)
+    }
+
+    /// Returns the keys matching the glob-style `pattern` (KEYS).
+    ///
+    /// Like in Redis, this walks the whole database; `scan` does it a shard at a time.
+    pub fn keys(&self, pattern: &str) -> Vec<String> {
+        let mut keys = Vec::new();
+        let mut cursor = 0;
+        loop {
+            let (next, batch) = self.scan(cursor, Some(pattern));
+            keys.extend(batch);
+            if next == 0 {
+                return keys;
+            }
+            cursor = next;
+        }
+    }
+
+    /// One step of SCAN: the keys of the shard `cursor` that match `pattern`, with the cursor
+    /// of the next step, 0 once every shard was visited.
+    ///
+    /// Why one shard per step?
+    /// A position inside a `HashMap` means nothing once keys are added, but a shard index
+    /// stays valid. So a key that exists for the whole scan is returned exactly once, and the
+    /// server only holds one shard lock at a time.
+    pub fn scan(&self, cursor: u64, pattern: Option<&str>) -> (u64, Vec<String>) {
+        let Some(index) = usize::try_from(cursor).ok().filter(|i| *i < self.shards.len()) else {
+            return (0, Vec::new());
+        };
+        let shard = self.lock_index(index);
+        let now = Instant::now();
+        let keys = shard
+            .entries
+            .keys()
+            .into_iter()
+            .filter(|key| !shard.is_expired(key, now))
+            .filter(|key| pattern.is_none_or(|pattern| glob::glob_match(pattern.as_bytes(), key.as_bytes())))
+            .collect();
+        let next = if index + 1 == self.shards.len() { 0 } else { index as u64 + 1 };
+        (next, keys)
+    }
+
+    /// Adds `delta` to the integer stored as a string at `key`, starting from 0 if the key
+    /// doesn't exist (INCRBY). Returns the new value.
+    pub fn incr_by(&self, key: &str, delta: i64) -> std::result::Result<i64, IncrError> {
+        let mut shard = self.lock_shard(key);
+        let current = match shard.entries.get(key) {
+            None => 0,
+            Some(Value::String(data)) => std::str::from_utf8(data)
+                .ok()
+                .and_then(|s| s.parse::<i64>().ok())
+                .ok_or(IncrError::NotAnInteger)?,
+            Some(_) => return Err(IncrError::WrongType),
+        };
+        let new = current.checked_add(delta).ok_or(IncrError::Overflow)?;
+        // Unlike SET, INCR keeps the time to live.
+        shard.entries.insert(key.to_string(), Value::String(Bytes::from(new.to_string())));
+        Ok(new)
+    }
+
+    /// Sets a time to live on an existing key, returning whether the key exists (EXPIRE).
+    pub fn expire(&self, key: &str, ttl: Duration) -> std::result::Result<bool, InvalidExpire> {
+        let deadline = deadline(ttl)?;
+        let mut shard = self.lock_shard(key);
+        if !shard.entries.contains_key(key) {
+            return Ok(false);
+        }
+        shard.set_deadline(key, Some(deadline));
+        Ok(true)
+    }
+
+    /// Removes the time to live of a key, returning whether it had one (PERSIST).
+    pub fn persist(&self, key: &str) -> bool {
+        self.lock_shard(key).set_deadline(key, None)
+    }
+
+    /// Returns the time to live of a key (TTL/PTTL).
+    pub fn ttl(&self, key: &str) -> Ttl {
+        let shard = self.lock_shard(key);
+        if !shard.entries.contains_key(key) {
+            return Ttl::NoKey;
+        }
+        match shard.expires.get(key) {
+            Some(deadline) => Ttl::Remaining(deadline.saturating_duration_since(Instant::now())),
+            None => Ttl::NoExpiry,
+        }
+    }
+
+    /// Removes every expired key. Without this, keys nobody reads again would stay forever.
+    pub fn purge_expired(&self) {
+        let now = Instant::now();
+        for i in 0..self.shards.len() {
+            let mut shard = self.lock_index(i);
+            let expired: Vec<String> = shard
+                .expires
+                .iter()
+                .filter(|(_, deadline)| **deadline <= now)
+                .map(|(key, _)| key.clone())
+                .collect();
+            for key in expired {
+                shard.remove(&key);
+            }
+        }
+    }
+
+    /// Returns the number of shards the keys are spread across.
+    pub fn num_shards(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Returns the total number of keys across all shards.
+    pub fn len(&self) -> usize {
+        (0..self.shards.len()).map(|i| self.lock_index(i).entries.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Approximates the memory used by the data: the sum of all key and value lengths.
+    /// Allocator and `HashMap` overhead is not included.
+    pub fn used_memory(&self) -> usize {
+        (0..self.shards.len()).map(|i| self.lock_index(i).entries.used_memory()).sum()
+    }
+
+    /// Asks the storage of every shard to reclaim the space of deleted and overwritten values.
+    pub fn compact(&self) -> io::Result<()> {
+        for i in 0..self.shards.len() {
+            self.lock_index(i).entries.compact()?;
+        }
+        Ok(())
+    }
+
+    /// Locks and returns the shard that owns `key`.
+    ///
+    /// Every access to a key goes through here, so this is where an expired key is removed,
+    /// before anyone can see it.
+    fn lock_shard(&self, key: &str) -> ShardGuard<'_> {
         let shard_index = Self::get_shard_index(key, self.shards.len());
-        let shard = self.shards[shard_index].lock().unwrap();
-        shard.get(key).cloned()
+        let mut shard = self.lock_index(shard_index);
+        if shard.is_expired(key, Instant::now()) {
+            shard.remove(key);
+        }
+        shard
+    }
+
+    fn lock_index(&self, index: usize) -> ShardGuard<'_> {
+        ShardGuard(self.shards[index].lock().unwrap())
     }
 
     /// Computes which shard a key belongs to using a hash function.
     /// This is a pure function that doesn't require instance data.
+    ///
+    /// Why FNV-1a instead of `DefaultHasher`?
+    /// With a disk backend, a key must land in the same shard after a restart, possibly with
+    /// a newer Rust. The algorithm of `DefaultHasher` is allowed to change between releases,
+    /// FNV-1a is a few lines we control.
     fn get_shard_index(key: &str, num_shards: usize) -> usize {
-        use std::hash::{Hash, Hasher};
-        use std::collections::hash_map::DefaultHasher;
-
-        let mut hasher = DefaultHasher::new();
-        key.hash(&mut hasher);
-        (hasher.finish() as usize) % num_shards
+        let mut hash: u64 = 0xcbf29ce484222325;
+        for byte in key.bytes() {
+            hash ^= u64::from(byte);
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+        (hash % num_shards as u64) as usize
     }
 }
 
 impl Clone for ShardedDatabase {
     fn clone(&self) -> Self {
-        Self { shards: Arc::clone(&self.shards) }
+        Self { shards: Arc::clone(&self.shards), tracking: self.tracking.clone() }
     }
 }
 
@@ -142,6 +606,87 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_expire_and_ttl() {
+        let db = ShardedDatabase::new(4);
+        assert_eq!(db.ttl("key"), Ttl::NoKey);
+        assert_eq!(db.expire("key", Duration::from_secs(10)), Ok(false));
+
+        db.insert("key", Bytes::from("value"));
+        assert_eq!(db.ttl("key"), Ttl::NoExpiry);
+        assert_eq!(db.expire("key", Duration::from_secs(10)), Ok(true));
+        assert!(matches!(db.ttl("key"), Ttl::Remaining(ttl) if ttl > Duration::from_secs(9)));
+        assert!(db.persist("key"));
+        assert_eq!(db.ttl("key"), Ttl::NoExpiry);
+
+        // an expired key is gone, even before the purge runs
+        db.insert_with_ttl("key", Bytes::from("value"), Some(Duration::ZERO)).unwrap();
+        assert_eq!(db.get("key"), None);
+        assert!(!db.contains_key("key"));
+    }
+
+    #[test]
+    fn test_overflowing_ttl_is_rejected() {
+        let db = ShardedDatabase::new(1);
+        assert_eq!(db.insert_with_ttl("key", Bytes::from("value"), Some(Duration::MAX)), Err(InvalidExpire));
+        assert_eq!(db.get("key"), None);
+        db.insert("key", Bytes::from("value"));
+        assert_eq!(db.expire("key", Duration::MAX), Err(InvalidExpire));
+        // the shard isn't poisoned, and the key keeps no time to live
+        assert_eq!(db.ttl("key"), Ttl::NoExpiry);
+        assert_eq!(db.get("key"), Some(Bytes::from("value")));
+    }
+
+    #[test]
+    fn test_purge_expired() {
+        let db = ShardedDatabase::new(4);
+        db.insert_with_ttl("short", Bytes::from("1"), Some(Duration::ZERO)).unwrap();
+        db.insert_with_ttl("long", Bytes::from("2"), Some(Duration::from_secs(60))).unwrap();
+        assert_eq!(db.len(), 2);
+        db.purge_expired();
+        assert_eq!(db.len(), 1);
+    }
+
+    #[test]
+    fn test_keys_and_scan() {
+        let db = ShardedDatabase::new(4);
+        for key in ["user:1", "user:2", "session:1"] {
+            db.insert(key, Bytes::from("x"));
+        }
+        db.insert_with_ttl("user:expired", Bytes::from("x"), Some(Duration::ZERO)).unwrap();
+        let mut keys = db.keys("user:*");
+        keys.sort();
+        assert_eq!(keys, vec!["user:1", "user:2"]);
+
+        let (mut cursor, mut scanned) = (0, Vec::new());
+        loop {
+            let (next, batch) = db.scan(cursor, None);
+            scanned.extend(batch);
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+        scanned.sort();
+        assert_eq!(scanned, vec!["session:1", "user:1", "user:2"]);
+        assert_eq!(db.scan(99, None), (0, vec![]));
+    }
+
+    #[test]
+    fn test_incr_by_and_remove() {
+        let db = ShardedDatabase::new(4);
+        assert_eq!(db.incr_by("n", 5), Ok(5));
+        assert_eq!(db.incr_by("n", -7), Ok(-2));
+        assert_eq!(db.get("n"), Some(Bytes::from("-2")));
+        db.insert("text", Bytes::from("abc"));
+        assert_eq!(db.incr_by("text", 1), Err(IncrError::NotAnInteger));
+        db.insert("max", Bytes::from(i64::MAX.to_string()));
+        assert_eq!(db.incr_by("max", 1), Err(IncrError::Overflow));
+
+        assert!(db.remove("n"));
+        assert!(!db.remove("n"));
+    }
+
     #[test]
     fn test_keys_distribute_across_shards() {
         // With multiple shards, different keys should hash to different shards
@@ -160,4 +705,61 @@ mod tests {
             assert!(db.get(&key).is_some(), "Key {} should exist", key);
         }
     }
+
+    /// Memory storage whose writes fail, like a full disk.
+    struct FailingStorage(HashMap<String, Value>);
+
+    impl StorageBackend for FailingStorage {
+        fn get(&mut self, key: &str) -> Option<&Value> {
+            self.0.get(key)
+        }
+
+        fn get_mut(&mut self, key: &str) -> Option<&mut Value> {
+            self.0.get_mut(key)
+        }
+
+        fn insert(&mut self, key: String, value: Value) {
+            self.0.insert(key, value);
+        }
+
+        fn remove(&mut self, key: &str) -> bool {
+            self.0.remove(key).is_some()
+        }
+
+        fn contains_key(&self, key: &str) -> bool {
+            self.0.contains_key(key)
+        }
+
+        fn len(&self) -> usize {
+            self.0.len()
+        }
+
+        fn keys(&self) -> Vec<String> {
+            self.0.keys().cloned().collect()
+        }
+
+        fn used_memory(&self) -> usize {
+            0
+        }
+
+        fn sync(&mut self) -> io::Result<()> {
+            Err(io::Error::other("disk full"))
+        }
+    }
+
+    #[test]
+    fn test_storage_errors_reach_the_command() {
+        let db = ShardedDatabase::with_storage(vec![Box::new(FailingStorage(HashMap::new()))]);
+        let ((), stored) = committing(|| db.insert("key", Bytes::from("value")));
+        assert_eq!(stored.unwrap_err().to_string(), "disk full");
+        // nothing is left over for the next command
+        assert!(committing(|| ()).1.is_ok());
+
+        let shared = crate::server::Shared::new(db);
+        let cmd = crate::cmd::Command::from_frame(crate::frame::Frame::command(["SET", "key", "value"])).unwrap();
+        assert_eq!(
+            crate::server::execute(&cmd, &shared),
+            crate::frame::Frame::Error("ERR storage error: disk full".to_string())
+        );
+    }
 }
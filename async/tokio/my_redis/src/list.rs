@@ -0,0 +1,383 @@
+//! List values and the blocking pops (BLPOP/BRPOP/BLMOVE) built on top of them.
+//!
+//! A blocked client registers a `Waiter` on every key it waits for. When a value is pushed
+//! to one of those keys, the pushing task pops it for the oldest waiter and hands it over
+//! through a oneshot channel, while still holding the shard lock. This gives FIFO wakeups:
+//! the client that blocked first is served first, and nobody else can steal the value
+//! between the wakeup and the pop.
+
+use crate::{Shard, ShardedDatabase, Value, WrongType};
+use bytes::Bytes;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::oneshot;
+
+/// Which end of a list to push to or pop from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum End {
+    Left,
+    Right,
+}
+
+impl End {
+    /// Parses the `LEFT`/`RIGHT` arguments of LMOVE and BLMOVE.
+    pub fn parse(s: &str) -> Option<End> {
+        match s.to_uppercase().as_str() {
+            "LEFT" => Some(End::Left),
+            "RIGHT" => Some(End::Right),
+            _ => None,
+        }
+    }
+}
+
+/// A client parked on one or more keys.
+pub(crate) struct Waiter {
+    // Only used to find the waiter again when it gives up.
+    id: u64,
+    end: End,
+    // Taken by whoever serves the waiter first, so a client blocked on several keys
+    // receives exactly one value.
+    sender: Mutex<Option<oneshot::Sender<(String, Bytes)>>>,
+}
+
+static NEXT_WAITER_ID: AtomicU64 = AtomicU64::new(0);
+
+/// The result of `ShardedDatabase::blocking_pop`.
+pub enum BlockingPop {
+    /// One of the keys had data, no need to wait.
+    Ready(String, Bytes),
+    /// All keys were empty; await `Parked::wait` to get the next value pushed to any of them.
+    Parked(Parked),
+}
+
+/// A registered blocking pop.
+///
+/// Dropping it (e.g. because the timeout elapsed or the client went away) unregisters the
+/// waiter. If a value was handed over in the meantime but never received, it is pushed back
+/// where it came from, so no data is lost.
+pub struct Parked {
+    db: ShardedDatabase,
+    keys: Vec<String>,
+    waiter: Arc<Waiter>,
+    receiver: oneshot::Receiver<(String, Bytes)>,
+}
+
+impl Parked {
+    /// Waits until a value is pushed to one of the keys, returning the key and the value.
+    pub async fn wait(&mut self) -> Option<(String, Bytes)> {
+        (&mut self.receiver).await.ok()
+    }
+}
+
+impl Drop for Parked {
+    fn drop(&mut self) {
+        for key in &self.keys {
+            let mut shard = self.db.lock_shard(key);
+            if let Some(queue) = shard.waiters.get_mut(key) {
+                queue.retain(|w| w.id != self.waiter.id);
+                if queue.is_empty() {
+                    shard.waiters.remove(key);
+                }
+            }
+        }
+
+        // Closing first guarantees no value can arrive after `try_recv`.
+        self.receiver.close();
+        if let Ok((key, value)) = self.receiver.try_recv() {
+            let mut shard = self.db.lock_shard(&key);
+            if let Some(Value::List(list)) = shard.entries.get_mut(&key) {
+                push(list, self.waiter.end, value);
+            } else {
+                shard.entries.insert(key.clone(), Value::List(VecDeque::from([value])));
+            }
+            serve_waiters(&mut shard, &key);
+        }
+    }
+}
+
+impl ShardedDatabase {
+    /// Pushes `values` one by one to the given end of the list at `key`, creating the list
+    /// if needed (LPUSH/RPUSH). Returns the length of the list after the push.
+    ///
+    /// Clients blocked on `key` are served right away, oldest first.
+    pub fn push(&self, key: &str, values: &[Bytes], end: End) -> Result<usize, WrongType> {
+        let mut shard = self.lock_shard(key);
+        if !shard.entries.contains_key(key) {
+            shard.entries.insert(key.to_string(), Value::List(VecDeque::new()));
+        }
+        let Some(Value::List(list)) = shard.entries.get_mut(key) else {
+            return Err(WrongType);
+        };
+        for value in values {
+            push(list, end, value.clone());
+        }
+        let len = list.len();
+        serve_waiters(&mut shard, key);
+        Ok(len)
+    }
+
+    /// Pops up to `count` values from the given end of the list (LPOP/RPOP).
+    /// The key is removed once the list is empty, like in Redis.
+    pub fn pop(&self, key: &str, end: End, count: usize) -> Result<Vec<Bytes>, WrongType> {
+        let mut shard = self.lock_shard(key);
+        let list = match shard.entries.get_mut(key) {
+            Some(Value::List(list)) => list,
+            Some(_) => return Err(WrongType),
+            None => return Ok(vec![]),
+        };
+        let mut popped = Vec::with_capacity(count.min(list.len()));
+        while popped.len() < count {
+            match pop(list, end) {
+                Some(value) => popped.push(value),
+                None => break,
+            }
+        }
+        if list.is_empty() {
+            shard.remove(key);
+        }
+        Ok(popped)
+    }
+
+    /// Returns the length of the list at `key`, 0 if it doesn't exist (LLEN).
+    pub fn list_len(&self, key: &str) -> Result<usize, WrongType> {
+        let mut shard = self.lock_shard(key);
+        match shard.entries.get(key) {
+            Some(Value::List(list)) => Ok(list.len()),
+            Some(_) => Err(WrongType),
+            None => Ok(0),
+        }
+    }
+
+    /// Returns the elements between `start` and `stop`, both inclusive (LRANGE).
+    /// Negative indexes count from the end, -1 being the last element.
+    pub fn list_range(&self, key: &str, start: i64, stop: i64) -> Result<Vec<Bytes>, WrongType> {
+        let mut shard = self.lock_shard(key);
+        let list = match shard.entries.get(key) {
+            Some(Value::List(list)) => list,
+            Some(_) => return Err(WrongType),
+            None => return Ok(vec![]),
+        };
+        let len = list.len() as i64;
+        let start = if start < 0 { (len + start).max(0) } else { start };
+        let stop = if stop < 0 { len + stop } else { stop.min(len - 1) };
+        if start > stop {
+            return Ok(vec![]);
+        }
+        Ok(list
+            .range(start as usize..=stop as usize)
+            .cloned()
+            .collect())
+    }
+
+    /// Moves one element from `source` to `destination` (LMOVE).
+    ///
+    /// The two keys may live in different shards, so unlike Redis this is a pop followed by
+    /// a push rather than one atomic step.
+    pub fn list_move(&self, source: &str, destination: &str, from: End, to: End) -> Result<Option<Bytes>, WrongType> {
+        // Check the destination type first so that we never pop a value we can't push.
+        if let Some(value) = self.lock_shard(destination).entries.get(destination) {
+            if !matches!(value, Value::List(_)) {
+                return Err(WrongType);
+            }
+        }
+        let value = match self.pop(source, from, 1)?.pop() {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        self.push(destination, std::slice::from_ref(&value), to)?;
+        Ok(Some(value))
+    }
+
+    /// Pops from the first non-empty list among `keys` (checked in order), or registers the
+    /// caller as a waiter on all of them (BLPOP/BRPOP).
+    pub fn blocking_pop(&self, keys: &[String], end: End) -> Result<BlockingPop, WrongType> {
+        let (sender, receiver) = oneshot::channel();
+        let waiter = Arc::new(Waiter {
+            id: NEXT_WAITER_ID.fetch_add(1, Ordering::Relaxed),
+            end,
+            sender: Mutex::new(Some(sender)),
+        });
+        // Build the guard up front: if we return early, its Drop undoes the registrations.
+        let mut parked = Parked {
+            db: self.clone(),
+            keys: Vec::with_capacity(keys.len()),
+            waiter: Arc::clone(&waiter),
+            receiver,
+        };
+
+        for key in keys {
+            let mut shard = self.lock_shard(key);
+            match shard.entries.get_mut(key) {
+                Some(Value::List(list)) => {
+                    // Between two keys a push may already have served us through an earlier
+                    // registration; then the value is waiting in the receiver.
+                    if waiter.sender.lock().unwrap().take().is_none() {
+                        break;
+                    }
+                    let value = pop(list, end).expect("empty lists are removed");
+                    if list.is_empty() {
+                        shard.remove(key);
+                    }
+                    return Ok(BlockingPop::Ready(key.clone(), value));
+                }
+                Some(_) => return Err(WrongType),
+                None => {
+                    shard
+                        .waiters
+                        .entry(key.clone())
+                        .or_default()
+                        .push_back(Arc::clone(&waiter));
+                    parked.keys.push(key.clone());
+                }
+            }
+        }
+        Ok(BlockingPop::Parked(parked))
+    }
+}
+
+/// Hands values of the list at `key` to the parked clients, oldest first, until either
+/// runs out. Called with the shard lock held.
+fn serve_waiters(shard: &mut Shard, key: &str) {
+    // Borrow the two maps separately so we can hold a list and a queue at the same time.
+    let Shard { entries, waiters, expires, .. } = shard;
+    let Some(queue) = waiters.get_mut(key) else {
+        return;
+    };
+
+    while let Some(waiter) = queue.pop_front() {
+        let list = match entries.get_mut(key) {
+            Some(Value::List(list)) if !list.is_empty() => list,
+            _ => {
+                queue.push_front(waiter);
+                break;
+            }
+        };
+        // Already served through another key, drop the stale registration.
+        let Some(sender) = waiter.sender.lock().unwrap().take() else {
+            continue;
+        };
+        let value = pop(list, waiter.end).expect("checked non-empty above");
+        if let Err((_, value)) = sender.send((key.to_string(), value)) {
+            // The client stopped waiting, give the value to the next one.
+            push(list, waiter.end, value);
+        }
+    }
+
+    if queue.is_empty() {
+        waiters.remove(key);
+    }
+    if matches!(entries.get(key), Some(Value::List(list)) if list.is_empty()) {
+        entries.remove(key);
+        expires.remove(key);
+    }
+}
+
+fn push(list: &mut VecDeque<Bytes>, end: End, value: Bytes) {
+    match end {
+        End::Left => list.push_front(value),
+        End::Right => list.push_back(value),
+    }
+}
+
+fn pop(list: &mut VecDeque<Bytes>, end: End) -> Option<Bytes> {
+    match end {
+        End::Left => list.pop_front(),
+        End::Right => list.pop_back(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn b(s: &str) -> Bytes {
+        Bytes::from(s.to_string())
+    }
+
+    #[test]
+    fn test_push_pop_and_range() {
+        let db = ShardedDatabase::new(4);
+        assert_eq!(db.push("l", &[b("a"), b("b")], End::Right), Ok(2));
+        assert_eq!(db.push("l", &[b("z")], End::Left), Ok(3));
+        assert_eq!(db.list_range("l", 0, -1), Ok(vec![b("z"), b("a"), b("b")]));
+        assert_eq!(db.list_range("l", -2, 100), Ok(vec![b("a"), b("b")]));
+        assert_eq!(db.pop("l", End::Right, 2), Ok(vec![b("b"), b("a")]));
+        assert_eq!(db.pop("l", End::Left, 5), Ok(vec![b("z")]));
+        // empty lists are removed
+        assert_eq!(db.len(), 0);
+    }
+
+    #[test]
+    fn test_wrong_type() {
+        let db = ShardedDatabase::new(4);
+        db.insert("s", b("string"));
+        assert_eq!(db.push("s", &[b("a")], End::Left), Err(WrongType));
+        assert_eq!(db.list_len("s"), Err(WrongType));
+        db.push("l", &[b("a")], End::Left).unwrap();
+        assert_eq!(db.get("l"), None);
+    }
+
+    #[test]
+    fn test_list_move() {
+        let db = ShardedDatabase::new(4);
+        db.push("src", &[b("1"), b("2")], End::Right).unwrap();
+        assert_eq!(db.list_move("src", "dst", End::Left, End::Right), Ok(Some(b("1"))));
+        assert_eq!(db.list_range("dst", 0, -1), Ok(vec![b("1")]));
+        assert_eq!(db.list_move("empty", "dst", End::Left, End::Right), Ok(None));
+    }
+
+    #[test]
+    fn test_blocking_pop_ready() {
+        let db = ShardedDatabase::new(4);
+        db.push("b", &[b("x")], End::Right).unwrap();
+        let keys = vec!["a".to_string(), "b".to_string()];
+        match db.blocking_pop(&keys, End::Left).unwrap() {
+            BlockingPop::Ready(key, value) => assert_eq!((key.as_str(), value), ("b", b("x"))),
+            BlockingPop::Parked(_) => panic!("should not block"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_blocked_clients_are_served_in_fifo_order() {
+        let db = ShardedDatabase::new(4);
+        let keys = vec!["jobs".to_string()];
+        let mut first = match db.blocking_pop(&keys, End::Left).unwrap() {
+            BlockingPop::Parked(parked) => parked,
+            BlockingPop::Ready(..) => panic!("list is empty"),
+        };
+        let mut second = match db.blocking_pop(&keys, End::Left).unwrap() {
+            BlockingPop::Parked(parked) => parked,
+            BlockingPop::Ready(..) => panic!("list is empty"),
+        };
+
+        db.push("jobs", &[b("1"), b("2")], End::Right).unwrap();
+        assert_eq!(first.wait().await, Some(("jobs".to_string(), b("1"))));
+        assert_eq!(second.wait().await, Some(("jobs".to_string(), b("2"))));
+        assert_eq!(db.list_len("jobs"), Ok(0));
+    }
+
+    #[tokio::test]
+    async fn test_dropped_waiter_does_not_lose_values() {
+        let db = ShardedDatabase::new(4);
+        let keys = vec!["a".to_string(), "b".to_string()];
+        let mut parked = match db.blocking_pop(&keys, End::Left).unwrap() {
+            BlockingPop::Parked(parked) => parked,
+            BlockingPop::Ready(..) => panic!("lists are empty"),
+        };
+        // times out: nothing was pushed yet
+        let waited = tokio::time::timeout(Duration::from_millis(10), parked.wait()).await;
+        assert!(waited.is_err());
+
+        // Handed over, but the client gives up before receiving it.
+        db.push("a", &[b("v")], End::Right).unwrap();
+        assert_eq!(db.list_len("a"), Ok(0));
+        drop(parked);
+        assert_eq!(db.list_range("a", 0, -1), Ok(vec![b("v")]));
+
+        // A push to the other key no longer finds a waiter.
+        db.push("b", &[b("w")], End::Right).unwrap();
+        assert_eq!(db.list_len("b"), Ok(1));
+    }
+}
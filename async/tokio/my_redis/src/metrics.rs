@@ -0,0 +1,497 @@
+use crate::cmd::Command;
+use crate::frame::Frame;
+use crate::ShardedDatabase;
+use bytes::Bytes;
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+/// Upper bounds (in microseconds) of the latency histogram buckets.
+/// Anything slower than the last bound lands in an extra "+Inf" bucket.
+const LATENCY_BUCKETS_US: [u64; 10] = [10, 50, 100, 250, 500, 1_000, 5_000, 10_000, 100_000, 1_000_000];
+
+/// Like Redis, only the first arguments of a slow command are kept, and long ones are cut.
+const SLOWLOG_MAX_ARGS: usize = 32;
+const SLOWLOG_MAX_ARG_LEN: usize = 128;
+
+/// Server statistics shared by every connection task.
+///
+/// Same trick as `ShardedDatabase`: the state lives behind an `Arc`, so cloning a `Metrics`
+/// is cheap and all clones record into the same counters.
+#[derive(Clone)]
+pub struct Metrics {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    started_at: Instant,
+    connected_clients: AtomicU64,
+    total_connections: AtomicU64,
+    total_commands: AtomicU64,
+    // A BTreeMap keeps INFO output sorted by command name.
+    commands: Mutex<BTreeMap<String, CommandStats>>,
+    slowlog: Mutex<SlowLog>,
+}
+
+/// Call count and latency histogram of a single command.
+#[derive(Debug, Clone, Default)]
+pub struct CommandStats {
+    pub calls: u64,
+    pub total_us: u64,
+    /// `buckets[i]` counts the calls that took at most `LATENCY_BUCKETS_US[i]`,
+    /// the last slot counts the ones that were slower than every bound.
+    pub buckets: [u64; LATENCY_BUCKETS_US.len() + 1],
+}
+
+impl CommandStats {
+    fn record(&mut self, elapsed_us: u64) {
+        self.calls += 1;
+        self.total_us += elapsed_us;
+        let bucket = LATENCY_BUCKETS_US
+            .iter()
+            .position(|&bound| elapsed_us <= bound)
+            .unwrap_or(LATENCY_BUCKETS_US.len());
+        self.buckets[bucket] += 1;
+    }
+
+    /// Estimates the latency percentile `p` (0.0..=1.0) as the upper bound of the bucket
+    /// the percentile falls into.
+    pub fn percentile_us(&self, p: f64) -> u64 {
+        let target = ((self.calls as f64) * p).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (i, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= target {
+                return LATENCY_BUCKETS_US.get(i).copied().unwrap_or(u64::MAX);
+            }
+        }
+        u64::MAX
+    }
+}
+
+/// One entry of the slow log, see `SLOWLOG GET`.
+#[derive(Debug, Clone)]
+pub struct SlowLogEntry {
+    pub id: u64,
+    pub timestamp: u64,
+    pub duration_us: u64,
+    pub args: Vec<Bytes>,
+    pub client: String,
+}
+
+struct SlowLog {
+    entries: VecDeque<SlowLogEntry>,
+    next_id: u64,
+    // `None` disables the slow log, `Some(0)` logs every command.
+    threshold_us: Option<u64>,
+    max_len: usize,
+}
+
+impl Metrics {
+    /// Creates metrics with the Redis default slow log settings: commands slower than 10ms
+    /// are logged and the newest 128 entries are kept.
+    pub fn new() -> Metrics {
+        Metrics {
+            inner: Arc::new(Inner {
+                started_at: Instant::now(),
+                connected_clients: AtomicU64::new(0),
+                total_connections: AtomicU64::new(0),
+                total_commands: AtomicU64::new(0),
+                commands: Mutex::new(BTreeMap::new()),
+                slowlog: Mutex::new(SlowLog {
+                    entries: VecDeque::new(),
+                    next_id: 0,
+                    threshold_us: Some(10_000),
+                    max_len: 128,
+                }),
+            }),
+        }
+    }
+
+    /// Sets the slow log threshold; `None` disables the slow log.
+    pub fn set_slowlog_threshold(&self, threshold: Option<Duration>) {
+        self.inner.slowlog.lock().unwrap().threshold_us = threshold.map(|t| t.as_micros() as u64);
+    }
+
+    /// Sets how many entries the slow log keeps, dropping the oldest ones if needed.
+    pub fn set_slowlog_max_len(&self, max_len: usize) {
+        let mut slowlog = self.inner.slowlog.lock().unwrap();
+        slowlog.max_len = max_len;
+        while slowlog.entries.len() > max_len {
+            slowlog.entries.pop_back();
+        }
+    }
+
+    pub fn client_connected(&self) {
+        self.inner.connected_clients.fetch_add(1, Ordering::Relaxed);
+        self.inner.total_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn client_disconnected(&self) {
+        self.inner.connected_clients.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Records one executed command: bumps its counters and, if it was slow enough,
+    /// adds it to the slow log.
+    pub fn record(&self, cmd: &Command, elapsed: Duration, client: SocketAddr) {
+        let elapsed_us = elapsed.as_micros() as u64;
+        self.inner.total_commands.fetch_add(1, Ordering::Relaxed);
+        self.inner
+            .commands
+            .lock()
+            .unwrap()
+            .entry(stats_name(cmd))
+            .or_default()
+            .record(elapsed_us);
+
+        let mut slowlog = self.inner.slowlog.lock().unwrap();
+        match slowlog.threshold_us {
+            Some(threshold) if elapsed_us >= threshold && slowlog.max_len > 0 => {}
+            _ => return,
+        }
+
+        let mut args = vec![Bytes::from(cmd.name().to_string())];
+        for arg in cmd.args().iter().take(SLOWLOG_MAX_ARGS - 1) {
+            if arg.len() > SLOWLOG_MAX_ARG_LEN {
+                let more = arg.len() - SLOWLOG_MAX_ARG_LEN;
+                let mut cut = arg.slice(..SLOWLOG_MAX_ARG_LEN).to_vec();
+                cut.extend_from_slice(format!("... ({} more bytes)", more).as_bytes());
+                args.push(Bytes::from(cut));
+            } else {
+                args.push(arg.clone());
+            }
+        }
+        if cmd.args().len() > SLOWLOG_MAX_ARGS - 1 {
+            let more = cmd.args().len() - (SLOWLOG_MAX_ARGS - 1);
+            args.push(Bytes::from(format!("... ({} more arguments)", more)));
+        }
+
+        let entry = SlowLogEntry {
+            id: slowlog.next_id,
+            timestamp: unix_time(),
+            duration_us: elapsed_us,
+            args,
+            client: client.to_string(),
+        };
+        slowlog.next_id += 1;
+        // Newest entries first, like `SLOWLOG GET`.
+        slowlog.entries.push_front(entry);
+        if slowlog.entries.len() > slowlog.max_len {
+            slowlog.entries.pop_back();
+        }
+    }
+
+    /// Returns up to `count` of the newest slow log entries.
+    pub fn slowlog_get(&self, count: usize) -> Vec<SlowLogEntry> {
+        let slowlog = self.inner.slowlog.lock().unwrap();
+        slowlog.entries.iter().take(count).cloned().collect()
+    }
+
+    pub fn slowlog_len(&self) -> usize {
+        self.inner.slowlog.lock().unwrap().entries.len()
+    }
+
+    pub fn slowlog_reset(&self) {
+        self.inner.slowlog.lock().unwrap().entries.clear();
+    }
+
+    /// Returns a snapshot of the per-command statistics.
+    pub fn command_stats(&self) -> BTreeMap<String, CommandStats> {
+        self.inner.commands.lock().unwrap().clone()
+    }
+
+    /// Renders the `INFO` reply.
+    ///
+    /// `section` selects one section (case-insensitive); `None` or `"default"` gives the
+    /// server/clients/memory/stats/keyspace sections, `"all"` adds the per-command ones.
+    pub fn info(&self, section: Option<&str>, db: &ShardedDatabase) -> String {
+        let section = section.map(|s| s.to_lowercase());
+        let wanted = |name: &str| match section.as_deref() {
+            None | Some("default") => !matches!(name, "commandstats" | "latencystats"),
+            Some("all") | Some("everything") => true,
+            Some(s) => s == name,
+        };
+
+        let mut out = String::new();
+        if wanted("server") {
+            out.push_str("# Server\r\n");
+            let _ = write!(out, "redis_version:{}\r\n", env!("CARGO_PKG_VERSION"));
+            let _ = write!(out, "process_id:{}\r\n", std::process::id());
+            let _ = write!(out, "uptime_in_seconds:{}\r\n", self.inner.started_at.elapsed().as_secs());
+            let _ = write!(out, "shards:{}\r\n", db.num_shards());
+            out.push_str("\r\n");
+        }
+        if wanted("clients") {
+            out.push_str("# Clients\r\n");
+            let _ = write!(out, "connected_clients:{}\r\n", self.inner.connected_clients.load(Ordering::Relaxed));
+            out.push_str("\r\n");
+        }
+        if wanted("memory") {
+            out.push_str("# Memory\r\n");
+            let _ = write!(out, "used_memory:{}\r\n", db.used_memory());
+            out.push_str("\r\n");
+        }
+        if wanted("stats") {
+            out.push_str("# Stats\r\n");
+            let _ = write!(out, "total_connections_received:{}\r\n", self.inner.total_connections.load(Ordering::Relaxed));
+            let _ = write!(out, "total_commands_processed:{}\r\n", self.inner.total_commands.load(Ordering::Relaxed));
+            let _ = write!(out, "slowlog_len:{}\r\n", self.slowlog_len());
+            out.push_str("\r\n");
+        }
+        if wanted("keyspace") {
+            out.push_str("# Keyspace\r\n");
+            let keys = db.len();
+            if keys > 0 {
+                let _ = write!(out, "db0:keys={}\r\n", keys);
+            }
+            out.push_str("\r\n");
+        }
+        if wanted("commandstats") {
+            out.push_str("# Commandstats\r\n");
+            for (name, stats) in self.command_stats() {
+                let per_call = stats.total_us as f64 / stats.calls as f64;
+                let _ = write!(
+                    out,
+                    "cmdstat_{}:calls={},usec={},usec_per_call={:.2}\r\n",
+                    name, stats.calls, stats.total_us, per_call
+                );
+            }
+            out.push_str("\r\n");
+        }
+        if wanted("latencystats") {
+            out.push_str("# Latencystats\r\n");
+            for (name, stats) in self.command_stats() {
+                let _ = write!(
+                    out,
+                    "latency_percentiles_usec_{}:p50={},p99={},p99.9={}\r\n",
+                    name,
+                    stats.percentile_us(0.5),
+                    stats.percentile_us(0.99),
+                    stats.percentile_us(0.999)
+                );
+            }
+            out.push_str("\r\n");
+        }
+        out
+    }
+
+    /// Renders all metrics in the Prometheus text exposition format.
+    pub fn prometheus(&self, db: &ShardedDatabase) -> String {
+        let mut out = String::new();
+        let gauge = |out: &mut String, name: &str, help: &str, value: u64| {
+            let _ = write!(out, "# HELP {} {}\n# TYPE {} gauge\n{} {}\n", name, help, name, name, value);
+        };
+        gauge(&mut out, "my_redis_uptime_seconds", "Seconds since the server started.", self.inner.started_at.elapsed().as_secs());
+        gauge(&mut out, "my_redis_connected_clients", "Number of open client connections.", self.inner.connected_clients.load(Ordering::Relaxed));
+        gauge(&mut out, "my_redis_used_memory_bytes", "Approximate size of all keys and values.", db.used_memory() as u64);
+        gauge(&mut out, "my_redis_keys", "Number of keys in the database.", db.len() as u64);
+
+        out.push_str("# HELP my_redis_connections_received_total Connections accepted by the server.\n");
+        out.push_str("# TYPE my_redis_connections_received_total counter\n");
+        let _ = writeln!(out, "my_redis_connections_received_total {}", self.inner.total_connections.load(Ordering::Relaxed));
+
+        let stats = self.command_stats();
+        out.push_str("# HELP my_redis_commands_total Commands processed, by command.\n");
+        out.push_str("# TYPE my_redis_commands_total counter\n");
+        for (name, s) in &stats {
+            let _ = writeln!(out, "my_redis_commands_total{{cmd=\"{}\"}} {}", escape_label(name), s.calls);
+        }
+
+        // Prometheus histograms are cumulative: bucket `le` counts everything at most `le`.
+        out.push_str("# HELP my_redis_command_duration_seconds Command execution time.\n");
+        out.push_str("# TYPE my_redis_command_duration_seconds histogram\n");
+        for (name, s) in &stats {
+            let name = escape_label(name);
+            let mut cumulative = 0;
+            for (i, count) in s.buckets.iter().enumerate() {
+                cumulative += count;
+                let le = match LATENCY_BUCKETS_US.get(i) {
+                    Some(us) => format!("{}", *us as f64 / 1_000_000.0),
+                    None => "+Inf".to_string(),
+                };
+                let _ = writeln!(out, "my_redis_command_duration_seconds_bucket{{cmd=\"{}\",le=\"{}\"}} {}", name, le, cumulative);
+            }
+            let _ = writeln!(out, "my_redis_command_duration_seconds_sum{{cmd=\"{}\"}} {}", name, s.total_us as f64 / 1_000_000.0);
+            let _ = writeln!(out, "my_redis_command_duration_seconds_count{{cmd=\"{}\"}} {}", name, s.calls);
+        }
+        out
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SlowLogEntry {
+    /// The `SLOWLOG GET` representation: id, timestamp, duration, arguments, client address, client name.
+    pub fn to_frame(&self) -> Frame {
+        Frame::Array(vec![
+            Frame::Integer(self.id as i64),
+            Frame::Integer(self.timestamp as i64),
+            Frame::Integer(self.duration_us as i64),
+            Frame::Array(self.args.iter().cloned().map(Frame::Bulk).collect()),
+            Frame::Bulk(Bytes::from(self.client.clone())),
+            Frame::Bulk(Bytes::new()),
+        ])
+    }
+}
+
+/// The name the stats of `cmd` are kept under.
+///
+/// Every name a client sends would otherwise get its own entry, and a client sending random
+/// ones would grow the map (and the INFO and Prometheus output) without bound.
+fn stats_name(cmd: &Command) -> String {
+    if crate::cli::COMMANDS.contains(&cmd.name()) || cmd.name() == "REPLCONF" {
+        cmd.name().to_lowercase()
+    } else {
+        "unknown".to_string()
+    }
+}
+
+/// Escapes a label value for the Prometheus text format.
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Serves `Metrics::prometheus` over plain HTTP on `listener`, for a Prometheus scraper.
+///
+/// This is deliberately tiny: every request, whatever its path, gets the metrics page and the
+/// connection is closed afterwards.
+pub async fn serve_prometheus(listener: TcpListener, metrics: Metrics, db: ShardedDatabase) {
+    loop {
+        let (mut socket, _) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                eprintln!("metrics endpoint failed to accept: {}", e);
+                continue;
+            }
+        };
+        let metrics = metrics.clone();
+        let db = db.clone();
+        tokio::spawn(async move {
+            // Read until the end of the request headers; the request itself is ignored.
+            let mut request = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                match socket.read(&mut buf).await {
+                    Ok(0) | Err(_) => return,
+                    Ok(n) => request.extend_from_slice(&buf[..n]),
+                }
+                if request.len() > 16 * 1024 {
+                    return;
+                }
+            }
+            let body = metrics.prometheus(&db);
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            let _ = socket.write_all(response.as_bytes()).await;
+        });
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr() -> SocketAddr {
+        "127.0.0.1:50000".parse().unwrap()
+    }
+
+    fn command(parts: &[&str]) -> Command {
+        Command::from_frame(Frame::command(parts.iter().map(|p| p.to_string()))).unwrap()
+    }
+
+    #[test]
+    fn test_record_counts_calls_per_command() {
+        let metrics = Metrics::new();
+        metrics.record(&command(&["GET", "a"]), Duration::from_micros(5), addr());
+        metrics.record(&command(&["get", "b"]), Duration::from_micros(70), addr());
+        metrics.record(&command(&["SET", "a", "1"]), Duration::from_micros(5), addr());
+
+        let stats = metrics.command_stats();
+        assert_eq!(stats["get"].calls, 2);
+        assert_eq!(stats["get"].total_us, 75);
+        assert_eq!(stats["set"].calls, 1);
+        assert_eq!(stats["get"].percentile_us(0.5), 10);
+        assert_eq!(stats["get"].percentile_us(0.99), 100);
+    }
+
+    #[test]
+    fn test_unknown_commands_share_one_entry() {
+        let metrics = Metrics::new();
+        for name in ["NOPE", "nope2", "x\"}\n"] {
+            metrics.record(&command(&[name]), Duration::from_micros(5), addr());
+        }
+        let stats = metrics.command_stats();
+        assert_eq!(stats.len(), 1);
+        assert_eq!(stats["unknown"].calls, 3);
+        assert_eq!(escape_label("a\\b\"c\nd"), "a\\\\b\\\"c\\nd");
+    }
+
+    #[test]
+    fn test_slowlog_threshold_and_max_len() {
+        let metrics = Metrics::new();
+        metrics.set_slowlog_threshold(Some(Duration::from_millis(1)));
+        metrics.set_slowlog_max_len(2);
+
+        metrics.record(&command(&["GET", "fast"]), Duration::from_micros(10), addr());
+        assert_eq!(metrics.slowlog_len(), 0);
+
+        for key in ["a", "b", "c"] {
+            metrics.record(&command(&["GET", key]), Duration::from_millis(2), addr());
+        }
+        let entries = metrics.slowlog_get(10);
+        assert_eq!(entries.len(), 2);
+        // newest first
+        assert_eq!(entries[0].args[1], Bytes::from("c"));
+        assert_eq!(entries[0].id, 2);
+
+        metrics.slowlog_reset();
+        assert_eq!(metrics.slowlog_len(), 0);
+    }
+
+    #[test]
+    fn test_info_sections() {
+        let metrics = Metrics::new();
+        let db = ShardedDatabase::new(4);
+        db.insert("k", Bytes::from("v"));
+        metrics.record(&command(&["GET", "k"]), Duration::from_micros(5), addr());
+
+        let info = metrics.info(None, &db);
+        assert!(info.contains("# Keyspace\r\ndb0:keys=1"));
+        assert!(!info.contains("cmdstat_get"));
+        let info = metrics.info(Some("commandstats"), &db);
+        assert!(info.contains("cmdstat_get:calls=1"));
+        assert!(!info.contains("# Server"));
+    }
+
+    #[test]
+    fn test_prometheus_histogram_is_cumulative() {
+        let metrics = Metrics::new();
+        let db = ShardedDatabase::new(4);
+        metrics.record(&command(&["GET", "k"]), Duration::from_micros(5), addr());
+        metrics.record(&command(&["GET", "k"]), Duration::from_secs(2), addr());
+
+        let text = metrics.prometheus(&db);
+        assert!(text.contains("my_redis_command_duration_seconds_bucket{cmd=\"get\",le=\"0.00001\"} 1"));
+        assert!(text.contains("my_redis_command_duration_seconds_bucket{cmd=\"get\",le=\"+Inf\"} 2"));
+        assert!(text.contains("my_redis_commands_total{cmd=\"get\"} 2"));
+    }
+}
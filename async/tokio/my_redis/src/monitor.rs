@@ -0,0 +1,139 @@
+//! MONITOR: every command the server executes, streamed to the connections that ask.
+//!
+//! Each command becomes one status reply on the monitoring connections, in the format of
+//! Redis:
+//!
+//! ```text
+//! +1700000000.123456 [0 127.0.0.1:52144] "SET" "key" "value"
+//! ```
+//!
+//! The `0` is the database number (we only have one), followed by the client address, or
+//! `lua` for the commands a script runs.
+
+use crate::cmd::Command;
+use std::fmt::{self, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+
+// How many lines a slow monitoring connection may fall behind before it misses some.
+const BUFFER: usize = 4096;
+
+/// The feed of executed commands, shared by every connection task.
+///
+/// Cloning is cheap, clones feed the same subscribers.
+#[derive(Clone)]
+pub struct Monitor {
+    lines: broadcast::Sender<String>,
+}
+
+impl Default for Monitor {
+    fn default() -> Monitor {
+        Monitor { lines: broadcast::channel(BUFFER).0 }
+    }
+}
+
+impl Monitor {
+    pub fn new() -> Monitor {
+        Monitor::default()
+    }
+
+    /// Returns the receiving end of the feed, for a connection that sent MONITOR.
+    ///
+    /// Why a broadcast channel?
+    /// Every subscriber gets every line, and a subscriber that doesn't keep up only loses
+    /// lines (`RecvError::Lagged`) instead of slowing down the clients being watched.
+    pub fn subscribe(&self) -> broadcast::Receiver<String> {
+        self.lines.subscribe()
+    }
+
+    /// Sends `cmd`, run by `client`, to the subscribers. Costs nothing without subscribers.
+    pub fn feed(&self, cmd: &Command, client: impl fmt::Display) {
+        if self.lines.receiver_count() == 0 {
+            return;
+        }
+        // An error means the last subscriber just left.
+        let _ = self.lines.send(format_line(SystemTime::now(), client, cmd));
+    }
+}
+
+/// Formats one MONITOR line, without the leading `+`.
+pub fn format_line(time: SystemTime, client: impl fmt::Display, cmd: &Command) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let mut line = format!("{}.{:06} [0 {}]", since_epoch.as_secs(), since_epoch.subsec_micros(), client);
+    for part in std::iter::once(cmd.name().as_bytes()).chain(cmd.args().iter().map(|arg| &arg[..])) {
+        line.push(' ');
+        quote(&mut line, part);
+    }
+    line
+}
+
+/// Quotes an argument like Redis does (`sdscatrepr`), so that a line never contains a raw
+/// newline or a binary byte.
+fn quote(out: &mut String, arg: &[u8]) {
+    out.push('"');
+    for &byte in arg {
+        match byte {
+            b'"' => out.push_str("\\\""),
+            b'\\' => out.push_str("\\\\"),
+            b'\n' => out.push_str("\\n"),
+            b'\r' => out.push_str("\\r"),
+            b'\t' => out.push_str("\\t"),
+            0x07 => out.push_str("\\a"),
+            0x08 => out.push_str("\\b"),
+            0x20..=0x7e => out.push(byte as char),
+            _ => {
+                let _ = write!(out, "\\x{:02x}", byte);
+            }
+        }
+    }
+    out.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::Connection;
+    use crate::frame::Frame;
+    use crate::TestServer;
+    use std::time::Duration;
+    use tokio::net::TcpStream;
+
+    fn command(parts: &[&[u8]]) -> Command {
+        let parts = parts.iter().map(|part| bytes::Bytes::copy_from_slice(part));
+        Command::from_frame(Frame::command(parts)).unwrap()
+    }
+
+    #[test]
+    fn test_format_line() {
+        let time = UNIX_EPOCH + Duration::from_micros(1_700_000_000_000_042);
+        let cmd = command(&[b"set", b"key", b"say \"hi\"\n\x00\xff"]);
+        assert_eq!(
+            format_line(time, "127.0.0.1:5000", &cmd),
+            r#"1700000000.000042 [0 127.0.0.1:5000] "SET" "key" "say \"hi\"\n\x00\xff""#
+        );
+    }
+
+    #[tokio::test]
+    async fn test_monitor_streams_commands() {
+        let server = TestServer::start().await.unwrap();
+        let mut monitor = Connection::new(TcpStream::connect(server.addr()).await.unwrap());
+        monitor.write_frame(&Frame::command(["MONITOR"])).await.unwrap();
+        assert_eq!(monitor.read_frame().await.unwrap(), Some(Frame::Simple("OK".to_string())));
+
+        let client = server.client().await.unwrap();
+        client.set("key", "value").await.unwrap();
+        client.command(["EVAL", "redis_call(\"GET\", \"key\")", "0"]).await.unwrap();
+
+        let mut lines = Vec::new();
+        for _ in 0..3 {
+            let frame = tokio::time::timeout(Duration::from_secs(1), monitor.read_frame()).await.unwrap();
+            let Some(Frame::Simple(line)) = frame.unwrap() else { panic!("not a status reply") };
+            // drop the time
+            lines.push(line.split_once(' ').unwrap().1.to_string());
+        }
+        let client_addr = lines[0].split(['[', ' ', ']']).nth(2).unwrap().to_string();
+        assert_eq!(lines[0], format!(r#"[0 {}] "SET" "key" "value""#, client_addr));
+        assert!(lines[1].starts_with(&format!(r#"[0 {}] "EVAL""#, client_addr)));
+        assert_eq!(lines[2], r#"[0 lua] "GET" "key""#);
+    }
+}
@@ -0,0 +1,367 @@
+use crate::client::request_frame;
+use crate::connection::Connection;
+use crate::frame::Frame;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// A bounded pool of connections, for services with many concurrent requests.
+///
+/// Why not just `Client`?
+/// `Client` pipelines everything over one socket, so one slow reply (a big LRANGE, a BLPOP)
+/// delays every caller behind it. A pool hands out whole connections instead: each caller
+/// gets a socket of its own for as long as it holds the `PooledConnection`.
+///
+/// - At most `max_size` connections exist. Callers wait for a free one in FIFO order (tokio's
+///   `Semaphore` is fair), and give up after `checkout_timeout`.
+/// - Returned connections are kept idle for reuse, up to `max_idle` of them.
+/// - Connections older than `max_lifetime`, or idle for longer than `idle_timeout`, are closed
+///   instead of being reused. With `health_check`, an idle connection must answer a PING
+///   before it's handed out.
+/// - `stats()` reports how long callers waited for a connection.
+///
+/// Cloning a `Pool` is cheap, all clones share the same connections.
+#[derive(Clone)]
+pub struct Pool {
+    inner: Arc<Inner>,
+}
+
+/// Settings of a `Pool`, see `Pool::new`.
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+    pub max_size: usize,
+    /// How many unused connections are kept open.
+    pub max_idle: usize,
+    /// Connections are closed once they are this old, `None` keeps them forever.
+    pub max_lifetime: Option<Duration>,
+    /// Idle connections are closed after this long, `None` keeps them forever.
+    pub idle_timeout: Option<Duration>,
+    /// How long `get` waits for a connection, including the time to open one.
+    pub checkout_timeout: Duration,
+    /// PING idle connections before handing them out.
+    pub health_check: bool,
+}
+
+impl Default for PoolConfig {
+    fn default() -> PoolConfig {
+        PoolConfig {
+            max_size: 16,
+            max_idle: 8,
+            max_lifetime: Some(Duration::from_secs(30 * 60)),
+            idle_timeout: Some(Duration::from_secs(5 * 60)),
+            checkout_timeout: Duration::from_secs(5),
+            health_check: true,
+        }
+    }
+}
+
+struct Inner {
+    addr: String,
+    config: PoolConfig,
+    // One permit per connection that may exist, held by every `PooledConnection`.
+    permits: Arc<Semaphore>,
+    idle: Mutex<VecDeque<IdleConnection>>,
+    stats: Stats,
+}
+
+struct IdleConnection {
+    connection: Connection,
+    created_at: Instant,
+    idle_since: Instant,
+}
+
+#[derive(Default)]
+struct Stats {
+    open: AtomicU64,
+    checkouts: AtomicU64,
+    timeouts: AtomicU64,
+    failed_health_checks: AtomicU64,
+    wait_us_total: AtomicU64,
+    wait_us_max: AtomicU64,
+}
+
+/// A snapshot of the pool statistics.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PoolStats {
+    /// Connections currently open, idle or in use.
+    pub open: u64,
+    pub idle: u64,
+    pub in_use: u64,
+    /// Successful `get` calls.
+    pub checkouts: u64,
+    /// `get` calls that gave up after `checkout_timeout`.
+    pub timeouts: u64,
+    pub failed_health_checks: u64,
+    /// Total and longest time `get` waited, over all successful checkouts.
+    pub wait_total: Duration,
+    pub wait_max: Duration,
+}
+
+impl PoolStats {
+    /// The average time `get` waited for a connection.
+    pub fn wait_avg(&self) -> Duration {
+        match self.checkouts {
+            0 => Duration::ZERO,
+            n => self.wait_total / n as u32,
+        }
+    }
+}
+
+impl Pool {
+    /// Creates a pool of connections to `addr`. Connections are opened on demand.
+    pub fn new(addr: &str, config: PoolConfig) -> Pool {
+        Pool {
+            inner: Arc::new(Inner {
+                addr: addr.to_string(),
+                permits: Arc::new(Semaphore::new(config.max_size)),
+                config,
+                idle: Mutex::new(VecDeque::new()),
+                stats: Stats::default(),
+            }),
+        }
+    }
+
+    /// Checks a connection out of the pool, waiting for one if all of them are in use.
+    pub async fn get(&self) -> crate::Result<PooledConnection> {
+        let start = Instant::now();
+        let timeout = self.inner.config.checkout_timeout;
+        match tokio::time::timeout(timeout, self.checkout()).await {
+            Ok(Ok(conn)) => {
+                let waited = start.elapsed().as_micros() as u64;
+                let stats = &self.inner.stats;
+                stats.checkouts.fetch_add(1, Ordering::Relaxed);
+                stats.wait_us_total.fetch_add(waited, Ordering::Relaxed);
+                stats.wait_us_max.fetch_max(waited, Ordering::Relaxed);
+                Ok(conn)
+            }
+            Ok(Err(e)) => Err(e),
+            Err(_) => {
+                self.inner.stats.timeouts.fetch_add(1, Ordering::Relaxed);
+                Err("timed out waiting for a pooled connection".into())
+            }
+        }
+    }
+
+    async fn checkout(&self) -> crate::Result<PooledConnection> {
+        // Waiting on the semaphore is what makes checkout fair: permits are handed out in
+        // the order `acquire_owned` was called.
+        let mut permit = self.inner.permits.clone().acquire_owned().await?;
+
+        // Reuse the most recently returned connection, it is the least likely to be stale.
+        while let Some(idle) = self.pop_idle() {
+            if self.is_expired(&idle) {
+                self.close(idle.connection);
+                continue;
+            }
+            let mut conn = self.wrap(idle.connection, idle.created_at, permit);
+            if !self.inner.config.health_check || conn.command(["PING"]).await.is_ok() {
+                return Ok(conn);
+            }
+            self.inner.stats.failed_health_checks.fetch_add(1, Ordering::Relaxed);
+            // Keep the permit for the next try, dropping `conn` closes the dead connection.
+            permit = conn.permit.take().expect("permit taken");
+            conn.broken = true;
+        }
+        self.connect(permit).await
+    }
+
+    async fn connect(&self, permit: OwnedSemaphorePermit) -> crate::Result<PooledConnection> {
+        let socket = TcpStream::connect(&self.inner.addr).await?;
+        self.inner.stats.open.fetch_add(1, Ordering::Relaxed);
+        Ok(self.wrap(Connection::new(socket), Instant::now(), permit))
+    }
+
+    fn wrap(&self, connection: Connection, created_at: Instant, permit: OwnedSemaphorePermit) -> PooledConnection {
+        PooledConnection {
+            connection: Some(connection),
+            created_at,
+            broken: false,
+            pool: self.clone(),
+            permit: Some(permit),
+        }
+    }
+
+    fn pop_idle(&self) -> Option<IdleConnection> {
+        self.inner.idle.lock().unwrap().pop_back()
+    }
+
+    fn is_expired(&self, idle: &IdleConnection) -> bool {
+        let config = &self.inner.config;
+        let too_old = config.max_lifetime.is_some_and(|max| idle.created_at.elapsed() >= max);
+        let idle_too_long = config.idle_timeout.is_some_and(|max| idle.idle_since.elapsed() >= max);
+        too_old || idle_too_long
+    }
+
+    fn close(&self, connection: Connection) {
+        drop(connection);
+        self.inner.stats.open.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Called when a `PooledConnection` is dropped.
+    fn put_back(&self, connection: Connection, created_at: Instant, broken: bool) {
+        let idle = IdleConnection { connection, created_at, idle_since: Instant::now() };
+        if broken || self.is_expired(&idle) {
+            self.close(idle.connection);
+            return;
+        }
+        let mut pool = self.inner.idle.lock().unwrap();
+        if pool.len() >= self.inner.config.max_idle {
+            drop(pool);
+            self.close(idle.connection);
+        } else {
+            pool.push_back(idle);
+        }
+    }
+
+    /// Closes the idle connections that expired. Checkout does this too, but only for the
+    /// connections it looks at.
+    pub fn reap(&self) {
+        let expired: VecDeque<IdleConnection> = {
+            let mut idle = self.inner.idle.lock().unwrap();
+            let (expired, kept) = idle.drain(..).partition(|conn| self.is_expired(conn));
+            *idle = kept;
+            expired
+        };
+        for conn in expired {
+            self.close(conn.connection);
+        }
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        let stats = &self.inner.stats;
+        let open = stats.open.load(Ordering::Relaxed);
+        let idle = self.inner.idle.lock().unwrap().len() as u64;
+        PoolStats {
+            open,
+            idle,
+            in_use: open.saturating_sub(idle),
+            checkouts: stats.checkouts.load(Ordering::Relaxed),
+            timeouts: stats.timeouts.load(Ordering::Relaxed),
+            failed_health_checks: stats.failed_health_checks.load(Ordering::Relaxed),
+            wait_total: Duration::from_micros(stats.wait_us_total.load(Ordering::Relaxed)),
+            wait_max: Duration::from_micros(stats.wait_us_max.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// A connection checked out of a `Pool`. It goes back to the pool when dropped.
+pub struct PooledConnection {
+    // Always `Some`, except while `drop` moves the connection back into the pool.
+    connection: Option<Connection>,
+    created_at: Instant,
+    // Set while a reply is outstanding: if the caller gives up in the middle of a command,
+    // the reply would be read by the next user of the connection, so it must not be reused.
+    broken: bool,
+    pool: Pool,
+    // Only `None` when a failed health check moved the permit to the next connection.
+    permit: Option<OwnedSemaphorePermit>,
+}
+
+impl PooledConnection {
+    /// Sends a command and waits for its reply. An error reply becomes an `Err`.
+    ///
+    /// Since the connection isn't shared, blocking commands like BLPOP are fine here.
+    pub async fn command<I, T>(&mut self, parts: I) -> crate::Result<Frame>
+    where
+        I: IntoIterator<Item = T>,
+        T: AsRef<[u8]>,
+    {
+        let connection = self.connection.as_mut().expect("connection taken");
+        self.broken = true;
+        connection.write_frame(&request_frame(parts)).await?;
+        let reply = connection
+            .read_frame()
+            .await?
+            .ok_or("connection closed by the server")?;
+        self.broken = false;
+        match reply {
+            Frame::Error(msg) => Err(msg.into()),
+            frame => Ok(frame),
+        }
+    }
+}
+
+impl Drop for PooledConnection {
+    fn drop(&mut self) {
+        if let Some(connection) = self.connection.take() {
+            self.pool.put_back(connection, self.created_at, self.broken);
+        }
+        // `permit` is dropped after this, so a waiter only wakes up once the connection
+        // is back in the idle list.
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TestServer;
+
+    #[tokio::test]
+    async fn test_connections_are_reused() {
+        let server = TestServer::start().await.unwrap();
+        let pool = Pool::new(&server.addr().to_string(), PoolConfig::default());
+        {
+            let mut conn = pool.get().await.unwrap();
+            conn.command(["SET", "key", "value"]).await.unwrap();
+        }
+        let mut conn = pool.get().await.unwrap();
+        assert_eq!(conn.command(["GET", "key"]).await.unwrap(), Frame::Bulk("value".into()));
+        assert!(conn.command(["LPUSH"]).await.is_err());
+        drop(conn);
+
+        let stats = pool.stats();
+        assert_eq!((stats.open, stats.idle, stats.in_use, stats.checkouts), (1, 1, 0, 2));
+    }
+
+    #[tokio::test]
+    async fn test_checkout_waits_and_times_out() {
+        let config = PoolConfig { max_size: 1, checkout_timeout: Duration::from_millis(100), ..Default::default() };
+        let server = TestServer::start().await.unwrap();
+        let pool = Pool::new(&server.addr().to_string(), config);
+        let conn = pool.get().await.unwrap();
+        assert!(pool.get().await.is_err());
+        assert_eq!(pool.stats().timeouts, 1);
+
+        // once the connection is returned, the next caller gets it
+        let waiter = {
+            let pool = pool.clone();
+            tokio::spawn(async move { pool.get().await.map(|_| ()) })
+        };
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        drop(conn);
+        waiter.await.unwrap().unwrap();
+        assert!(pool.stats().wait_max >= Duration::from_millis(20));
+    }
+
+    #[tokio::test]
+    async fn test_max_idle_and_idle_timeout() {
+        let config = PoolConfig { max_idle: 1, idle_timeout: Some(Duration::from_millis(50)), ..Default::default() };
+        let server = TestServer::start().await.unwrap();
+        let pool = Pool::new(&server.addr().to_string(), config);
+        let a = pool.get().await.unwrap();
+        let b = pool.get().await.unwrap();
+        drop(a);
+        drop(b);
+        // only one of the two connections is kept
+        assert_eq!((pool.stats().open, pool.stats().idle), (1, 1));
+
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        pool.reap();
+        assert_eq!((pool.stats().open, pool.stats().idle), (0, 0));
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_command_discards_the_connection() {
+        let server = TestServer::start().await.unwrap();
+        let pool = Pool::new(&server.addr().to_string(), PoolConfig::default());
+        let mut conn = pool.get().await.unwrap();
+        // BLPOP blocks for a second, give up long before the reply arrives
+        let blpop = conn.command(["BLPOP", "missing", "1"]);
+        assert!(tokio::time::timeout(Duration::from_millis(50), blpop).await.is_err());
+        drop(conn);
+        assert_eq!(pool.stats().open, 0);
+    }
+}
@@ -0,0 +1,443 @@
+//! Primary/replica replication: a replica copies the whole keyspace of its primary, then
+//! applies every write the primary runs after that.
+//!
+//! ```text
+//! replica                                primary
+//!   REPLCONF listening-port 6380   ->
+//!                                  <-    +OK
+//!   SYNC                           ->
+//!                                  <-    +FULLRESYNC <offset> <number of keys>
+//!                                  <-    RESTORE key ttl payload REPLACE   (one per key)
+//!                                  <-    SET key value, LPUSH ...          (every write, forever)
+//! ```
+//!
+//! Both sides count the bytes of the write stream: the primary's offset is how much it sent,
+//! a replica's how much it applied. The replica with the highest offset is the most up to
+//! date, the one a sentinel promotes (see `src/sentinel.rs`).
+//!
+//! Writes are passed on as the commands that actually ran: a script sends the commands it
+//! called rather than itself, a BLPOP that got a value sends an LPOP.
+//!
+//! Note: Redis sends an RDB snapshot and resumes a broken link from a backlog (PSYNC). Here
+//! every new link starts with a full copy.
+//! Note: replicas expire keys on their own instead of waiting for the primary's DEL, and the
+//! consumer group changes of XREADGROUP are not replicated.
+
+use crate::cmd::Command;
+use crate::connection::Connection;
+use crate::frame::Frame;
+use crate::server::{self, Shared};
+use crate::{ShardedDatabase, Ttl};
+use bytes::Bytes;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU16, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::sync::{broadcast, watch};
+use tokio::task::JoinHandle;
+
+// How many writes a replica may fall behind before it is disconnected (it then copies
+// everything again).
+const BUFFER: usize = 16 * 1024;
+
+// The delay between two attempts to reach the primary.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// What a server is in a replication setup.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Role {
+    Primary,
+    Replica { host: String, port: u16, link_up: bool },
+}
+
+/// The replication state of a server, shared by every connection task.
+///
+/// Cloning is cheap, clones share the same state.
+#[derive(Clone)]
+pub struct Replication {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    state: Mutex<State>,
+    // Every write that ran, for the connections of the replicas.
+    stream: broadcast::Sender<Frame>,
+    // Bytes of the stream so far.
+    offset: AtomicU64,
+    // Bumped when this server starts copying from a primary: its own replicas hold the old
+    // data then, and must copy again.
+    resync: watch::Sender<u64>,
+    // How many replicas are connected.
+    attached: AtomicUsize,
+    order: Mutex<()>,
+    // The port this server listens on, announced to its primary.
+    port: AtomicU16,
+}
+
+struct State {
+    role: Role,
+    // The connected replicas and the port they listen on.
+    replicas: BTreeMap<SocketAddr, Option<u16>>,
+    // On a replica, the task copying from the primary.
+    link: Option<JoinHandle<()>>,
+}
+
+impl Default for Replication {
+    fn default() -> Replication {
+        Replication {
+            inner: Arc::new(Inner {
+                state: Mutex::new(State { role: Role::Primary, replicas: BTreeMap::new(), link: None }),
+                stream: broadcast::channel(BUFFER).0,
+                offset: AtomicU64::new(0),
+                resync: watch::channel(0).0,
+                attached: AtomicUsize::new(0),
+                order: Mutex::new(()),
+                port: AtomicU16::new(0),
+            }),
+        }
+    }
+}
+
+impl Replication {
+    pub fn new() -> Replication {
+        Replication::default()
+    }
+
+    pub fn role(&self) -> Role {
+        self.inner.state.lock().unwrap().role.clone()
+    }
+
+    pub fn is_replica(&self) -> bool {
+        matches!(self.inner.state.lock().unwrap().role, Role::Replica { .. })
+    }
+
+    /// The replication offset: bytes of writes sent on a primary, applied on a replica.
+    pub fn offset(&self) -> u64 {
+        self.inner.offset.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn set_port(&self, port: u16) {
+        self.inner.port.store(port, Ordering::SeqCst);
+    }
+
+    /// Makes the writes run one at a time while replicas are connected, to be held while a
+    /// write runs and is passed on.
+    ///
+    /// Why?
+    /// Commands on different shards run in parallel. Without this, two clients writing the
+    /// same key could run their commands in one order and pass them on in the other.
+    pub(crate) fn order(&self, cmd: &Command) -> Option<MutexGuard<'_, ()>> {
+        if cmd.is_write() && self.inner.attached.load(Ordering::SeqCst) > 0 {
+            Some(self.inner.order.lock().unwrap())
+        } else {
+            None
+        }
+    }
+
+    /// Passes a command that ran on to the replicas, unless it failed or doesn't write.
+    pub(crate) fn propagate(&self, cmd: &Command, reply: &Frame) {
+        // Scripts pass on the commands they call instead.
+        if !cmd.is_write() || matches!(cmd.name(), "EVAL" | "EVALSHA") || matches!(reply, Frame::Error(_)) {
+            return;
+        }
+        let frame = cmd.to_frame();
+        let mut buf = Vec::new();
+        frame.encode(&mut buf);
+        self.inner.offset.fetch_add(buf.len() as u64, Ordering::SeqCst);
+        // An error only means that no replica is connected.
+        let _ = self.inner.stream.send(frame);
+    }
+
+    /// Starts copying from `host:port` (REPLICAOF host port). The current data is dropped
+    /// once the primary answers.
+    pub(crate) fn follow(&self, host: &str, port: u16, shared: Shared) {
+        let mut state = self.inner.state.lock().unwrap();
+        if let Some(link) = state.link.take() {
+            link.abort();
+        }
+        state.role = Role::Replica { host: host.to_string(), port, link_up: false };
+        let addr = format!("{}:{}", host, port);
+        state.link = Some(tokio::spawn(async move {
+            loop {
+                if let Err(e) = sync_from(&addr, &shared).await {
+                    eprintln!("replication from {} stopped: {}", addr, e);
+                }
+                shared.replication.set_link_up(false);
+                tokio::time::sleep(RETRY_DELAY).await;
+            }
+        }));
+    }
+
+    /// Stops copying and accepts writes again (REPLICAOF NO ONE). The data and the offset
+    /// are kept.
+    pub(crate) fn promote(&self) {
+        let mut state = self.inner.state.lock().unwrap();
+        if let Some(link) = state.link.take() {
+            link.abort();
+        }
+        state.role = Role::Primary;
+    }
+
+    /// Stops the link to the primary, when the server shuts down.
+    pub(crate) fn stop(&self) {
+        if let Some(link) = self.inner.state.lock().unwrap().link.take() {
+            link.abort();
+        }
+    }
+
+    fn set_link_up(&self, up: bool) {
+        if let Role::Replica { link_up, .. } = &mut self.inner.state.lock().unwrap().role {
+            *link_up = up;
+        }
+    }
+
+    /// The `# Replication` section of INFO, with the field names of Redis.
+    pub fn info(&self) -> String {
+        let state = self.inner.state.lock().unwrap();
+        let mut out = "# Replication\r\n".to_string();
+        match &state.role {
+            Role::Primary => out.push_str("role:master\r\n"),
+            Role::Replica { host, port, link_up } => {
+                let _ = write!(out, "role:slave\r\nmaster_host:{}\r\nmaster_port:{}\r\n", host, port);
+                let _ = write!(out, "master_link_status:{}\r\n", if *link_up { "up" } else { "down" });
+                let _ = write!(out, "slave_repl_offset:{}\r\n", self.offset());
+            }
+        }
+        let _ = write!(out, "connected_slaves:{}\r\n", state.replicas.len());
+        for (i, (addr, port)) in state.replicas.iter().enumerate() {
+            let port = port.unwrap_or(addr.port());
+            let _ = write!(out, "slave{}:ip={},port={},state=online\r\n", i, addr.ip(), port);
+        }
+        let _ = write!(out, "master_repl_offset:{}\r\n\r\n", self.offset());
+        out
+    }
+}
+
+/// REPLICAOF host port / REPLICAOF NO ONE
+pub(crate) fn replicaof(cmd: &Command, shared: &Shared) -> Result<Frame, String> {
+    cmd.check_arity(2, 2)?;
+    let (host, port) = (cmd.arg_str(0)?, cmd.arg_str(1)?);
+    if host.eq_ignore_ascii_case("no") && port.eq_ignore_ascii_case("one") {
+        shared.replication.promote();
+    } else {
+        let port = port.parse().map_err(|_| "ERR Invalid master port".to_string())?;
+        shared.replication.follow(host, port, shared.clone());
+    }
+    Ok(Frame::Simple("OK".to_string()))
+}
+
+/// Serves a replica that sent SYNC on `connection`: a copy of the data, then the writes as
+/// they run. Returns once the replica disconnects or falls too far behind.
+pub(crate) async fn serve_replica(connection: &mut Connection, addr: SocketAddr, port: Option<u16>, shared: &Shared) -> crate::Result<()> {
+    let inner = &shared.replication.inner;
+    let mut resync = inner.resync.subscribe();
+    let (mut stream, offset, snapshot) = {
+        // No command runs while the copy is made, so every write is either in the copy or
+        // in the stream, never in both or neither.
+        // Note: that holds up every client for as long as the copy takes; Redis forks.
+        let _guard = shared.exec_lock.write().unwrap();
+        inner.attached.fetch_add(1, Ordering::SeqCst);
+        (inner.stream.subscribe(), inner.offset.load(Ordering::SeqCst), snapshot(&shared.db))
+    };
+    inner.state.lock().unwrap().replicas.insert(addr, port);
+    let _attached = Attached { replication: &shared.replication, addr };
+
+    connection.write_frame(&Frame::Simple(format!("FULLRESYNC {} {}", offset, snapshot.len()))).await?;
+    connection.write_frames(&snapshot).await?;
+    loop {
+        tokio::select! {
+            frame = stream.recv() => match frame {
+                Ok(frame) => {
+                    let mut frames = vec![frame];
+                    while let Ok(frame) = stream.try_recv() {
+                        frames.push(frame);
+                    }
+                    connection.write_frames(&frames).await?;
+                }
+                Err(broadcast::error::RecvError::Lagged(_)) => return Err("the replica fell too far behind".into()),
+                Err(broadcast::error::RecvError::Closed) => return Ok(()),
+            },
+            _ = resync.changed() => return Ok(()),
+            _ = connection.closed() => return Ok(()),
+        }
+    }
+}
+
+/// Unregisters a replica when its connection ends, however it ends.
+struct Attached<'a> {
+    replication: &'a Replication,
+    addr: SocketAddr,
+}
+
+impl Drop for Attached<'_> {
+    fn drop(&mut self) {
+        let inner = &self.replication.inner;
+        inner.state.lock().unwrap().replicas.remove(&self.addr);
+        inner.attached.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Every key as a RESTORE command.
+fn snapshot(db: &ShardedDatabase) -> Vec<Frame> {
+    let mut frames = Vec::new();
+    for key in db.keys("*") {
+        // The key may expire between the two calls.
+        let Some(payload) = db.dump(&key) else { continue };
+        let ttl = match db.ttl(&key) {
+            Ttl::Remaining(ttl) => ttl.as_millis().max(1) as u64,
+            _ => 0,
+        };
+        frames.push(Frame::command([
+            Bytes::from_static(b"RESTORE"),
+            Bytes::from(key),
+            Bytes::from(ttl.to_string()),
+            payload,
+            Bytes::from_static(b"REPLACE"),
+        ]));
+    }
+    frames
+}
+
+/// One link to the primary: copies everything, then applies the writes until the link
+/// breaks.
+async fn sync_from(addr: &str, shared: &Shared) -> crate::Result<()> {
+    let mut connection = Connection::new(TcpStream::connect(addr).await?);
+    let port = shared.replication.inner.port.load(Ordering::SeqCst).to_string();
+    let handshake = [
+        Frame::command(["REPLCONF".to_string(), "listening-port".to_string(), port]),
+        Frame::command(["SYNC"]),
+    ];
+    connection.write_frames(&handshake).await?;
+    match read(&mut connection).await? {
+        Frame::Simple(ok) if ok == "OK" => {}
+        frame => return Err(format!("unexpected reply to REPLCONF: {}", frame).into()),
+    }
+    let (offset, keys) = match read(&mut connection).await? {
+        Frame::Simple(line) => parse_fullresync(&line).ok_or_else(|| format!("unexpected reply to SYNC: {}", line))?,
+        frame => return Err(format!("unexpected reply to SYNC: {}", frame).into()),
+    };
+
+    {
+        let _guard = shared.exec_lock.write().unwrap();
+        for key in shared.db.keys("*") {
+            shared.db.remove(&key);
+        }
+        shared.replication.inner.resync.send_modify(|n| *n += 1);
+    }
+    // `dispatch` rather than `execute`: the copy is not part of the stream, so it must not
+    // count in the offset.
+    for _ in 0..keys {
+        let cmd = Command::from_frame(read(&mut connection).await?)?;
+        let _guard = shared.exec_lock.read().unwrap();
+        if let Frame::Error(e) = server::dispatch(&cmd, shared) {
+            return Err(e.into());
+        }
+    }
+    shared.replication.inner.offset.store(offset, Ordering::SeqCst);
+    shared.replication.set_link_up(true);
+
+    loop {
+        let cmd = Command::from_frame(read(&mut connection).await?)?;
+        // Passed on to this server's own replicas, if any, and counted in the offset.
+        server::execute(&cmd, shared);
+    }
+}
+
+async fn read(connection: &mut Connection) -> crate::Result<Frame> {
+    connection.read_frame().await?.ok_or_else(|| "the primary closed the connection".into())
+}
+
+/// Parses `FULLRESYNC <offset> <keys>`.
+fn parse_fullresync(line: &str) -> Option<(u64, usize)> {
+    let mut parts = line.split(' ');
+    if parts.next() != Some("FULLRESYNC") {
+        return None;
+    }
+    let offset = parts.next()?.parse().ok()?;
+    let keys = parts.next()?.parse().ok()?;
+    Some((offset, keys))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TestServer;
+    use std::time::Instant;
+
+    async fn wait_for(what: &str, condition: impl Fn() -> bool) {
+        let start = Instant::now();
+        while !condition() {
+            assert!(start.elapsed() < Duration::from_secs(5), "timed out waiting for {}", what);
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_replica_copies_then_follows() {
+        let primary = TestServer::start().await.unwrap();
+        let client = primary.client().await.unwrap();
+        client.set("before", "1").await.unwrap();
+        client.set_ex("expiring", "1", Duration::from_secs(60)).await.unwrap();
+
+        let replica = TestServer::start().await.unwrap();
+        replica.shared().db.insert("stale", Bytes::from("x"));
+        let replica_client = replica.client().await.unwrap();
+        let port = primary.addr().port().to_string();
+        replica_client.command(["REPLICAOF", "127.0.0.1", &port]).await.unwrap();
+        let replication = replica.shared().replication.clone();
+        wait_for("the link", || matches!(replication.role(), Role::Replica { link_up: true, .. })).await;
+
+        let db = &replica.shared().db;
+        assert_eq!(db.get("before"), Some(Bytes::from("1")));
+        assert!(matches!(db.ttl("expiring"), Ttl::Remaining(_)));
+        assert!(!db.contains_key("stale"));
+
+        client.rpush("list", &["a", "b"]).await.unwrap();
+        client.incr("counter").await.unwrap();
+        // a failed write is not passed on
+        assert!(client.incr("list").await.is_err());
+        let primary_replication = primary.shared().replication.clone();
+        wait_for("the writes", || replication.offset() == primary_replication.offset()).await;
+        assert_eq!(db.get("counter"), Some(Bytes::from("1")));
+        assert_eq!(replica_client.lrange("list", 0, -1).await.unwrap(), vec![Bytes::from("a"), Bytes::from("b")]);
+
+        // replicas don't take writes from clients
+        let err = replica_client.set("key", "value").await.unwrap_err();
+        assert!(err.to_string().starts_with("READONLY"));
+
+        let info = primary.shared().replication.info();
+        assert!(info.contains("connected_slaves:1\r\n"));
+        assert!(info.contains(&format!("slave0:ip=127.0.0.1,port={},state=online", replica.addr().port())));
+
+        // once promoted, it takes writes and stops following
+        replica_client.command(["REPLICAOF", "NO", "ONE"]).await.unwrap();
+        replica_client.set("key", "value").await.unwrap();
+        client.set("after", "1").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!db.contains_key("after"));
+        assert_eq!(replica.shared().replication.role(), Role::Primary);
+    }
+
+    #[tokio::test]
+    async fn test_scripts_and_blocking_pops_are_passed_on_as_their_effects() {
+        let primary = TestServer::start().await.unwrap();
+        let replica = TestServer::start().await.unwrap();
+        replica.shared().replication.follow("127.0.0.1", primary.addr().port(), replica.shared().clone());
+        let replication = replica.shared().replication.clone();
+        wait_for("the link", || matches!(replication.role(), Role::Replica { link_up: true, .. })).await;
+
+        let client = primary.client().await.unwrap();
+        client.command(["EVAL", "redis_call(\"SET\", KEYS[0], \"from-script\")", "1", "key"]).await.unwrap();
+        client.rpush("queue", &["a", "b"]).await.unwrap();
+        client.blpop(&["queue"], Some(Duration::from_secs(1))).await.unwrap();
+        let primary_replication = primary.shared().replication.clone();
+        wait_for("the writes", || replication.offset() == primary_replication.offset()).await;
+
+        let db = &replica.shared().db;
+        assert_eq!(db.get("key"), Some(Bytes::from("from-script")));
+        assert_eq!(db.list_len("queue"), Ok(1));
+    }
+}
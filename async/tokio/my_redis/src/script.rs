@@ -0,0 +1,380 @@
+//! Server-side scripting (EVAL/EVALSHA/SCRIPT) with the embedded Rhai engine.
+//!
+//! Redis embeds Lua; we use Rhai (https://rhai.rs) instead because it is pure Rust.
+//! A script gets the `KEYS` and `ARGV` arrays and calls commands with
+//! `redis_call("SET", KEYS[0], ARGV[0])`, the counterpart of Lua's `redis.call`:
+//!
+//! ```text
+//! EVAL "let n = redis_call(\"RPUSH\", KEYS[0], ARGV[0]); if n > 100 { redis_call(\"LPOP\", KEYS[0]) } n" 1 recent item
+//! ```
+//!
+//! `redis_pcall` returns errors as a `#{ err: "..." }` map instead of aborting the script.
+//! The whole script runs while holding the server's execution lock for writing, so no other
+//! command can observe it half done.
+
+use crate::cmd::Command;
+use crate::frame::Frame;
+use crate::server::{self, Shared};
+use bytes::Bytes;
+use rhai::{Array, Dynamic, Engine, EvalAltResult, ImmutableString, Map, Scope, AST};
+use std::any::TypeId;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Scripts can call commands with up to this many arguments (the command name included).
+const MAX_CALL_ARGS: usize = 16;
+
+/// Commands a script is not allowed to run.
+const NOT_ALLOWED: [&str; 3] = ["EVAL", "EVALSHA", "SCRIPT"];
+
+/// The script cache, shared by all connections.
+///
+/// Scripts are cached by the SHA1 of their source, both by SCRIPT LOAD and by EVAL,
+/// so clients can switch to the cheaper EVALSHA afterwards.
+#[derive(Clone)]
+pub struct Scripts {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    // Only used to compile; each run gets an engine bound to the connection's state.
+    compiler: Engine,
+    cache: Mutex<HashMap<String, Arc<AST>>>,
+    time_limit_ms: AtomicU64,
+}
+
+impl Scripts {
+    /// Creates an empty cache; scripts may run for 5 seconds by default (Redis' `busy-reply-threshold`).
+    pub fn new() -> Scripts {
+        Scripts {
+            inner: Arc::new(Inner {
+                compiler: Engine::new(),
+                cache: Mutex::new(HashMap::new()),
+                time_limit_ms: AtomicU64::new(5_000),
+            }),
+        }
+    }
+
+    /// Sets how long a script may run before it is aborted.
+    pub fn set_time_limit(&self, limit: Duration) {
+        self.inner.time_limit_ms.store(limit.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    fn time_limit(&self) -> Duration {
+        Duration::from_millis(self.inner.time_limit_ms.load(Ordering::Relaxed))
+    }
+
+    /// Compiles and caches `source`, returning its SHA1 (SCRIPT LOAD).
+    pub fn load(&self, source: &str) -> Result<String, String> {
+        let sha = sha1_hex(source);
+        if self.get(&sha).is_none() {
+            let ast = self
+                .inner
+                .compiler
+                .compile(source)
+                .map_err(|e| format!("ERR Error compiling script: {}", e))?;
+            self.inner.cache.lock().unwrap().insert(sha.clone(), Arc::new(ast));
+        }
+        Ok(sha)
+    }
+
+    /// Looks up a cached script by SHA1 (case-insensitive, like Redis).
+    pub fn get(&self, sha: &str) -> Option<Arc<AST>> {
+        self.inner.cache.lock().unwrap().get(&sha.to_lowercase()).cloned()
+    }
+
+    /// Empties the cache (SCRIPT FLUSH).
+    pub fn flush(&self) {
+        self.inner.cache.lock().unwrap().clear();
+    }
+}
+
+impl Default for Scripts {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Runs EVAL, EVALSHA and SCRIPT.
+pub fn execute(cmd: &Command, shared: &Shared) -> Result<Frame, String> {
+    match cmd.name() {
+        "EVAL" | "EVALSHA" => eval(cmd, shared),
+        _ => script(cmd, shared),
+    }
+}
+
+fn eval(cmd: &Command, shared: &Shared) -> Result<Frame, String> {
+    // EVAL script numkeys [key ...] [arg ...] / EVALSHA sha1 numkeys [key ...] [arg ...]
+    cmd.check_arity(2, usize::MAX)?;
+    let ast = match cmd.name() {
+        "EVAL" => {
+            let sha = shared.scripts.load(cmd.arg_str(0)?)?;
+            shared.scripts.get(&sha).expect("just loaded")
+        }
+        _ => shared
+            .scripts
+            .get(cmd.arg_str(0)?)
+            .ok_or_else(|| "NOSCRIPT No matching script. Please use EVAL.".to_string())?,
+    };
+
+    let numkeys = cmd.arg_int(1)?;
+    if numkeys < 0 {
+        return Err("ERR Number of keys can't be negative".to_string());
+    }
+    let numkeys = numkeys as usize;
+    if numkeys > cmd.args().len() - 2 {
+        return Err("ERR Number of keys can't be greater than number of args".to_string());
+    }
+    let keys = &cmd.args()[2..2 + numkeys];
+    let argv = &cmd.args()[2 + numkeys..];
+
+    // Exclusive access: no other command runs until the script is done.
+    let _guard = shared.exec_lock.write().unwrap();
+    run(&ast, keys, argv, shared)
+}
+
+fn script(cmd: &Command, shared: &Shared) -> Result<Frame, String> {
+    cmd.check_arity(1, usize::MAX)?;
+    match cmd.arg_str(0)?.to_uppercase().as_str() {
+        "LOAD" => {
+            cmd.check_arity(2, 2)?;
+            let sha = shared.scripts.load(cmd.arg_str(1)?)?;
+            Ok(Frame::Bulk(Bytes::from(sha)))
+        }
+        "EXISTS" => {
+            let mut exists = Vec::with_capacity(cmd.args().len() - 1);
+            for i in 1..cmd.args().len() {
+                exists.push(Frame::Integer(shared.scripts.get(cmd.arg_str(i)?).is_some() as i64));
+            }
+            Ok(Frame::Array(exists))
+        }
+        "FLUSH" => {
+            shared.scripts.flush();
+            Ok(Frame::Simple("OK".to_string()))
+        }
+        sub => Err(format!("ERR unknown subcommand '{}' for 'script'", sub.to_lowercase())),
+    }
+}
+
+/// Runs a compiled script; the caller holds the execution lock.
+fn run(ast: &AST, keys: &[Bytes], argv: &[Bytes], shared: &Shared) -> Result<Frame, String> {
+    let mut engine = Engine::new();
+
+    // Register `redis_call`/`redis_pcall` for every arity up to MAX_CALL_ARGS.
+    // Rhai has no variadic functions, but a "raw" function can be registered several
+    // times with a different number of `Dynamic` (= any type) parameters.
+    for arity in 1..=MAX_CALL_ARGS {
+        let arg_types = vec![TypeId::of::<Dynamic>(); arity];
+        let state = shared.clone();
+        engine.register_raw_fn("redis_call", &arg_types, move |_, args| {
+            match call(args, &state) {
+                Frame::Error(e) => Err(e.into()),
+                frame => Ok(to_dynamic(frame)),
+            }
+        });
+        let state = shared.clone();
+        engine.register_raw_fn("redis_pcall", &arg_types, move |_, args| {
+            Ok::<_, Box<EvalAltResult>>(to_dynamic(call(args, &state)))
+        });
+    }
+
+    // Called by the engine between operations; returning a value aborts the script.
+    let limit = shared.scripts.time_limit();
+    let start = Instant::now();
+    engine.on_progress(move |_| (start.elapsed() > limit).then(|| Dynamic::from("timeout")));
+
+    let mut scope = Scope::new();
+    scope.push("KEYS", to_array(keys));
+    scope.push("ARGV", to_array(argv));
+
+    match engine.eval_ast_with_scope::<Dynamic>(&mut scope, ast) {
+        Ok(result) => Ok(to_frame(result)),
+        Err(e) => match *e {
+            EvalAltResult::ErrorTerminated(..) => {
+                Err(format!("ERR script timed out after {} ms, changes made so far were kept", limit.as_millis()))
+            }
+            EvalAltResult::ErrorRuntime(value, _) => Err(value.to_string()),
+            e => Err(format!("ERR Error running script: {}", e)),
+        },
+    }
+}
+
+/// Executes the command described by the arguments of `redis_call`.
+fn call(args: &mut [&mut Dynamic], shared: &Shared) -> Frame {
+    let mut parts = Vec::with_capacity(args.len());
+    for arg in args.iter() {
+        match to_bytes(arg) {
+            Some(bytes) => parts.push(bytes),
+            None => return Frame::Error(format!("ERR unsupported argument type '{}' in redis_call", arg.type_name())),
+        }
+    }
+    let cmd = match Command::from_frame(Frame::command(parts)) {
+        Ok(cmd) => cmd,
+        Err(e) => return Frame::Error(e),
+    };
+    if NOT_ALLOWED.contains(&cmd.name()) {
+        return Frame::Error("ERR This Redis command is not allowed from script".to_string());
+    }
+    shared.monitor.feed(&cmd, "lua");
+    // `dispatch` rather than `execute`: we already hold the execution lock.
+    let reply = server::dispatch(&cmd, shared);
+    shared.replication.propagate(&cmd, &reply);
+    reply
+}
+
+fn to_bytes(value: &Dynamic) -> Option<Bytes> {
+    if value.is_string() {
+        let s = value.clone().into_immutable_string().ok()?;
+        return Some(Bytes::from(s.to_string()));
+    }
+    if value.is_blob() {
+        return value.clone().into_blob().ok().map(Bytes::from);
+    }
+    if let Ok(n) = value.as_int() {
+        return Some(Bytes::from(n.to_string()));
+    }
+    if let Ok(f) = value.as_float() {
+        return Some(Bytes::from(f.to_string()));
+    }
+    None
+}
+
+fn to_array(values: &[Bytes]) -> Array {
+    values.iter().map(|v| bytes_to_dynamic(v.clone())).collect()
+}
+
+fn bytes_to_dynamic(data: Bytes) -> Dynamic {
+    match String::from_utf8(data.to_vec()) {
+        Ok(s) => Dynamic::from(ImmutableString::from(s)),
+        Err(_) => Dynamic::from_blob(data.to_vec()),
+    }
+}
+
+/// Converts a reply into a script value, following the Lua conversion rules of Redis:
+/// nil becomes `()`, status and error replies become `#{ ok: .. }` / `#{ err: .. }` maps.
+fn to_dynamic(frame: Frame) -> Dynamic {
+    match frame {
+        Frame::Simple(s) => {
+            let mut map = Map::new();
+            map.insert("ok".into(), Dynamic::from(ImmutableString::from(s)));
+            Dynamic::from_map(map)
+        }
+        Frame::Error(e) => {
+            let mut map = Map::new();
+            map.insert("err".into(), Dynamic::from(ImmutableString::from(e)));
+            Dynamic::from_map(map)
+        }
+        Frame::Integer(n) => Dynamic::from_int(n),
+        Frame::Bulk(data) => bytes_to_dynamic(data),
+        Frame::Null => Dynamic::UNIT,
+        Frame::Array(items) | Frame::Set(items) | Frame::Push(items) => {
+            Dynamic::from_array(items.into_iter().map(to_dynamic).collect())
+        }
+        // The server only replies with RESP2 frames, these are here for completeness.
+        Frame::Double(f) => Dynamic::from_float(f),
+        Frame::Boolean(b) => Dynamic::from_bool(b),
+        Frame::BigNumber(s) | Frame::Verbatim(_, s) => Dynamic::from(ImmutableString::from(s)),
+        Frame::Map(entries) => {
+            let mut map = Map::new();
+            for (key, value) in entries {
+                map.insert(key.to_string().into(), to_dynamic(value));
+            }
+            Dynamic::from_map(map)
+        }
+    }
+}
+
+/// Converts the value a script returns into a reply, the reverse of `to_dynamic`.
+/// Like in Lua scripts, `true` becomes 1, `false` becomes nil and floats are truncated.
+fn to_frame(value: Dynamic) -> Frame {
+    if value.is_unit() {
+        return Frame::Null;
+    }
+    if let Ok(b) = value.as_bool() {
+        return if b { Frame::Integer(1) } else { Frame::Null };
+    }
+    if let Ok(n) = value.as_int() {
+        return Frame::Integer(n);
+    }
+    if let Ok(f) = value.as_float() {
+        return Frame::Integer(f as i64);
+    }
+    if value.is_array() {
+        let items = value.into_array().unwrap_or_default();
+        return Frame::Array(items.into_iter().map(to_frame).collect());
+    }
+    if value.is_map() {
+        let map = value.cast::<Map>();
+        if let Some(err) = map.get("err") {
+            return Frame::Error(err.to_string());
+        }
+        if let Some(ok) = map.get("ok") {
+            return Frame::Simple(ok.to_string());
+        }
+        return Frame::Array(vec![]);
+    }
+    match to_bytes(&value) {
+        Some(bytes) => Frame::Bulk(bytes),
+        None => Frame::Bulk(Bytes::from(value.to_string())),
+    }
+}
+
+fn sha1_hex(source: &str) -> String {
+    sha1_smol::Sha1::from(source).digest().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ShardedDatabase;
+
+    fn eval(shared: &Shared, parts: &[&str]) -> Frame {
+        let cmd = Command::from_frame(Frame::command(parts.iter().map(|p| p.to_string()))).unwrap();
+        server::execute(&cmd, shared)
+    }
+
+    #[test]
+    fn test_eval_calls_back_into_the_database() {
+        let shared = Shared::new(ShardedDatabase::new(4));
+        let script = r#"redis_call("SET", KEYS[0], ARGV[0]); redis_call("GET", KEYS[0])"#;
+        assert_eq!(eval(&shared, &["EVAL", script, "1", "k", "v"]), Frame::Bulk(Bytes::from("v")));
+        assert_eq!(shared.db.get("k"), Some(Bytes::from("v")));
+    }
+
+    #[test]
+    fn test_evalsha_uses_the_cache() {
+        let shared = Shared::new(ShardedDatabase::new(4));
+        let sha = shared.scripts.load("ARGV.len()").unwrap();
+        assert_eq!(sha, sha1_hex("ARGV.len()"));
+        assert_eq!(eval(&shared, &["EVALSHA", &sha, "0", "a", "b"]), Frame::Integer(2));
+        assert_eq!(eval(&shared, &["SCRIPT", "EXISTS", &sha, "nope"]), Frame::Array(vec![Frame::Integer(1), Frame::Integer(0)]));
+
+        eval(&shared, &["SCRIPT", "FLUSH"]);
+        assert!(matches!(eval(&shared, &["EVALSHA", &sha, "0"]), Frame::Error(e) if e.starts_with("NOSCRIPT")));
+    }
+
+    #[test]
+    fn test_errors_and_pcall() {
+        let shared = Shared::new(ShardedDatabase::new(4));
+        shared.db.insert("s", Bytes::from("string"));
+        let failing = r#"redis_call("LPUSH", "s", "x")"#;
+        assert!(matches!(eval(&shared, &["EVAL", failing, "0"]), Frame::Error(e) if e.starts_with("WRONGTYPE")));
+        let caught = r#"let r = redis_pcall("LPUSH", "s", "x"); r.err"#;
+        assert!(matches!(eval(&shared, &["EVAL", caught, "0"]), Frame::Bulk(e) if e.starts_with(b"WRONGTYPE")));
+        let nested = r#"redis_call("EVAL", "1", 0)"#;
+        assert!(matches!(eval(&shared, &["EVAL", nested, "0"]), Frame::Error(e) if e.contains("not allowed")));
+        assert!(matches!(eval(&shared, &["EVAL", "1", "2", "a"]), Frame::Error(_)));
+    }
+
+    #[test]
+    fn test_time_limit() {
+        let shared = Shared::new(ShardedDatabase::new(4));
+        shared.scripts.set_time_limit(Duration::from_millis(50));
+        match eval(&shared, &["EVAL", "loop {}", "0"]) {
+            Frame::Error(e) => assert!(e.contains("timed out")),
+            frame => panic!("unexpected reply {:?}", frame),
+        }
+    }
+}
@@ -0,0 +1,724 @@
+//! A sentinel: watches a primary and its replicas, and when the primary goes down, promotes
+//! the most up-to-date replica in its place.
+//!
+//! It works like Redis Sentinel:
+//! - every `check_interval`, the sentinel asks each node for `INFO replication`. A primary
+//!   that doesn't answer for `down_after` is *subjectively* down. Its replicas are found in
+//!   its INFO.
+//! - it then asks the other sentinels (`SENTINEL is-master-down-by-addr`). Once `quorum`
+//!   of them agree, the primary is *objectively* down.
+//! - only one sentinel may fail over, so it asks the others for their vote in a new epoch (a
+//!   term, as in Raft). Each sentinel votes once per epoch, for the first one asking. With
+//!   the votes of a majority, and at least `quorum`, it is the leader.
+//! - the leader sends `REPLICAOF NO ONE` to the replica with the highest replication offset
+//!   and `REPLICAOF <new primary>` to the others, then tells the other sentinels about the
+//!   new primary (`SENTINEL hello`) along with the epoch. The newest configuration wins.
+//! - nodes that come back or missed the change (like the old primary) are turned into
+//!   replicas of the new primary.
+//!
+//! Clients ask any sentinel for the address of the primary (see `primary_addr`), or
+//! `SUBSCRIBE +switch-master` to be told when it changes.
+//!
+//! Note: Redis sentinels find each other through the pub/sub channel of the primary; my_redis
+//! has no pub/sub, so the other sentinels are given as `peers` and the hello messages are
+//! sent to them directly.
+
+use crate::cmd::Command;
+use crate::connection::Connection;
+use crate::frame::Frame;
+use bytes::Bytes;
+use std::collections::hash_map::RandomState;
+use std::collections::BTreeMap;
+use std::hash::BuildHasher;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast;
+use tokio::task::JoinSet;
+
+// The longest a sentinel waits for the answer of a node or of another sentinel.
+const ASK_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Settings of a `Sentinel`.
+#[derive(Debug, Clone)]
+pub struct SentinelConfig {
+    /// The name clients ask for, e.g. `mymaster`.
+    pub name: String,
+    /// The address of the primary when the sentinel starts, e.g. `127.0.0.1:6379`.
+    pub primary: String,
+    /// How many sentinels must find the primary down before it is failed over.
+    pub quorum: usize,
+    /// The addresses of the other sentinels watching the same primary.
+    pub peers: Vec<String>,
+    /// How long a node may not answer before it is considered down.
+    pub down_after: Duration,
+    /// How long a sentinel waits before trying again after a failed attempt, or after voting
+    /// for another sentinel.
+    pub failover_timeout: Duration,
+    /// How often every node is checked.
+    pub check_interval: Duration,
+}
+
+impl SentinelConfig {
+    pub fn new(name: &str, primary: &str) -> SentinelConfig {
+        SentinelConfig {
+            name: name.to_string(),
+            primary: primary.to_string(),
+            quorum: 2,
+            peers: Vec::new(),
+            down_after: Duration::from_secs(5),
+            failover_timeout: Duration::from_secs(30),
+            check_interval: Duration::from_secs(1),
+        }
+    }
+}
+
+/// A running sentinel. Dropping it stops it.
+pub struct Sentinel {
+    addr: SocketAddr,
+    inner: Arc<Inner>,
+    // Dropping a JoinSet aborts its tasks.
+    _tasks: JoinSet<()>,
+}
+
+struct Inner {
+    config: SentinelConfig,
+    // Identifies this sentinel in the votes.
+    run_id: String,
+    state: Mutex<State>,
+    // The `+switch-master` messages, for the subscribed clients.
+    switches: broadcast::Sender<String>,
+}
+
+struct State {
+    primary: String,
+    // When the primary last answered.
+    primary_seen: Instant,
+    replicas: BTreeMap<String, Node>,
+    // The epoch of the failover that chose `primary`, 0 for the initial one.
+    config_epoch: u64,
+    // The newest epoch this sentinel heard of.
+    current_epoch: u64,
+    // Who this sentinel voted for, in which epoch.
+    vote: Option<(u64, String)>,
+    // When this sentinel last tried to fail over or voted for another sentinel.
+    last_failover: Option<Instant>,
+}
+
+/// What a sentinel knows about a replica.
+#[derive(Debug, Clone, Default)]
+struct Node {
+    seen: Option<Instant>,
+    info: Info,
+}
+
+/// The fields of `INFO replication` a sentinel uses.
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Info {
+    is_primary: bool,
+    // The primary of a replica, as `host:port`.
+    primary: Option<String>,
+    offset: u64,
+    replicas: Vec<String>,
+}
+
+impl Sentinel {
+    /// Starts watching `config.primary`, and serving clients and the other sentinels on
+    /// `listener`.
+    pub fn start(config: SentinelConfig, listener: TcpListener) -> io::Result<Sentinel> {
+        let addr = listener.local_addr()?;
+        let state = State {
+            primary: config.primary.clone(),
+            primary_seen: Instant::now(),
+            replicas: BTreeMap::new(),
+            config_epoch: 0,
+            current_epoch: 0,
+            vote: None,
+            last_failover: None,
+        };
+        let inner = Arc::new(Inner {
+            config,
+            run_id: random_id(),
+            state: Mutex::new(state),
+            switches: broadcast::channel(16).0,
+        });
+        let mut tasks = JoinSet::new();
+        tasks.spawn(watch(inner.clone()));
+        tasks.spawn(serve(listener, inner.clone()));
+        Ok(Sentinel { addr, inner, _tasks: tasks })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The address of the primary, as far as this sentinel knows.
+    pub fn primary(&self) -> String {
+        self.inner.state.lock().unwrap().primary.clone()
+    }
+
+    /// The addresses of the replicas found so far.
+    pub fn replicas(&self) -> Vec<String> {
+        self.inner.state.lock().unwrap().replicas.keys().cloned().collect()
+    }
+}
+
+/// Asks the sentinels in turn for the address of the primary called `name`.
+pub async fn primary_addr(sentinels: &[String], name: &str) -> crate::Result<String> {
+    for sentinel in sentinels {
+        if let Ok(Frame::Array(parts)) = ask(sentinel, &["SENTINEL", "get-master-addr-by-name", name], ASK_TIMEOUT).await {
+            if let [Frame::Bulk(host), Frame::Bulk(port)] = parts.as_slice() {
+                return Ok(format!("{}:{}", String::from_utf8_lossy(host), String::from_utf8_lossy(port)));
+            }
+        }
+    }
+    Err(format!("no sentinel knows the primary '{}'", name).into())
+}
+
+/// Sends one command on a new connection and returns the reply. An error reply becomes an
+/// `Err`.
+///
+/// Why a new connection every time?
+/// A node that went down and came back needs a new connection anyway, and the sentinel only
+/// sends a few commands per second.
+async fn ask(addr: &str, parts: &[&str], timeout: Duration) -> crate::Result<Frame> {
+    let request = async {
+        let mut connection = Connection::new(TcpStream::connect(addr).await?);
+        connection.write_frame(&Frame::command(parts.iter().map(|p| Bytes::copy_from_slice(p.as_bytes())))).await?;
+        connection.read_frame().await?.ok_or_else(|| crate::Error::from("connection closed"))
+    };
+    match tokio::time::timeout(timeout, request).await {
+        Ok(Ok(Frame::Error(e))) => Err(e.into()),
+        Ok(result) => result,
+        Err(_) => Err("timed out".into()),
+    }
+}
+
+/// Checks the nodes every `check_interval`, forever.
+async fn watch(inner: Arc<Inner>) {
+    let mut interval = tokio::time::interval(inner.config.check_interval);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        inner.check().await;
+    }
+}
+
+impl Inner {
+    async fn check(&self) {
+        let nodes: Vec<String> = {
+            let state = self.state.lock().unwrap();
+            std::iter::once(state.primary.clone()).chain(state.replicas.keys().cloned()).collect()
+        };
+        let timeout = self.config.down_after.min(ASK_TIMEOUT);
+        let mut asks = JoinSet::new();
+        for node in nodes {
+            asks.spawn(async move {
+                let reply = ask(&node, &["INFO", "replication"], timeout).await;
+                (node, reply)
+            });
+        }
+        while let Some(Ok((node, reply))) = asks.join_next().await {
+            if let Ok(Frame::Bulk(text)) = reply {
+                self.seen(&node, parse_info(&String::from_utf8_lossy(&text)));
+            }
+        }
+
+        self.send_hello().await;
+        if self.primary_down() {
+            self.try_failover().await;
+        } else {
+            self.reconfigure().await;
+        }
+    }
+
+    fn seen(&self, node: &str, info: Info) {
+        let mut state = self.state.lock().unwrap();
+        if node == state.primary {
+            state.primary_seen = Instant::now();
+            for replica in &info.replicas {
+                state.replicas.entry(replica.clone()).or_default();
+            }
+        } else if let Some(replica) = state.replicas.get_mut(node) {
+            *replica = Node { seen: Some(Instant::now()), info };
+        }
+    }
+
+    fn primary_down(&self) -> bool {
+        self.state.lock().unwrap().primary_seen.elapsed() > self.config.down_after
+    }
+
+    fn is_up(&self, node: &Node) -> bool {
+        node.seen.is_some_and(|seen| seen.elapsed() <= self.config.down_after)
+    }
+
+    /// Makes the replicas that follow another node, or none, follow the primary.
+    async fn reconfigure(&self) {
+        let (primary, stale): (String, Vec<String>) = {
+            let state = self.state.lock().unwrap();
+            let stale = state
+                .replicas
+                .iter()
+                .filter(|(_, node)| self.is_up(node))
+                .filter(|(_, node)| node.info.is_primary || node.info.primary.as_ref() != Some(&state.primary))
+                .map(|(addr, _)| addr.clone())
+                .collect();
+            (state.primary.clone(), stale)
+        };
+        let Some((host, port)) = primary.rsplit_once(':') else { return };
+        for node in stale {
+            if let Err(e) = ask(&node, &["REPLICAOF", host, port], ASK_TIMEOUT).await {
+                eprintln!("could not make {} a replica of {}: {}", node, primary, e);
+            }
+        }
+    }
+
+    /// Tells the other sentinels which primary this one follows, once there was a failover.
+    async fn send_hello(&self) {
+        let (primary, epoch) = {
+            let state = self.state.lock().unwrap();
+            (state.primary.clone(), state.config_epoch)
+        };
+        let Some((host, port)) = primary.rsplit_once(':') else { return };
+        if epoch > 0 {
+            self.ask_peers(&["SENTINEL", "hello", &self.config.name, host, port, &epoch.to_string()]).await;
+        }
+    }
+
+    /// Sends a command to every other sentinel at once, returning the replies that came.
+    async fn ask_peers(&self, parts: &[&str]) -> Vec<Frame> {
+        let mut asks = JoinSet::new();
+        for peer in self.config.peers.clone() {
+            let parts: Vec<String> = parts.iter().map(|p| p.to_string()).collect();
+            asks.spawn(async move {
+                let parts: Vec<&str> = parts.iter().map(String::as_str).collect();
+                ask(&peer, &parts, ASK_TIMEOUT).await
+            });
+        }
+        let mut replies = Vec::new();
+        while let Some(reply) = asks.join_next().await {
+            if let Ok(Ok(reply)) = reply {
+                replies.push(reply);
+            }
+        }
+        replies
+    }
+
+    fn waiting(&self, state: &State) -> bool {
+        state.last_failover.is_some_and(|at| at.elapsed() < self.config.failover_timeout)
+    }
+
+    /// Fails the primary over if enough sentinels agree it is down and this one wins the vote.
+    async fn try_failover(&self) {
+        let (primary, epoch) = {
+            let state = self.state.lock().unwrap();
+            if self.waiting(&state) {
+                return;
+            }
+            (state.primary.clone(), state.current_epoch)
+        };
+        let Some((host, port)) = primary.rsplit_once(':') else { return };
+
+        let replies = self.ask_peers(&["SENTINEL", "is-master-down-by-addr", host, port, &epoch.to_string(), "*"]).await;
+        let agree = 1 + replies.iter().filter(|reply| parse_vote(reply).is_some_and(|(down, _, _)| down)).count();
+        if agree < self.config.quorum {
+            return;
+        }
+
+        // Sentinels find the primary down at about the same time. Waiting a random delay
+        // before asking for votes makes it less likely that each votes for itself.
+        let max_delay = self.config.check_interval.as_millis() as u64 * 2 + 1;
+        tokio::time::sleep(Duration::from_millis(random_u64() % max_delay)).await;
+
+        let epoch = {
+            let mut state = self.state.lock().unwrap();
+            // We may have voted for another sentinel in the meantime.
+            if self.waiting(&state) {
+                return;
+            }
+            state.current_epoch += 1;
+            state.vote = Some((state.current_epoch, self.run_id.clone()));
+            state.last_failover = Some(Instant::now());
+            state.current_epoch
+        };
+        let epoch_arg = epoch.to_string();
+        let replies = self.ask_peers(&["SENTINEL", "is-master-down-by-addr", host, port, &epoch_arg, &self.run_id]).await;
+        let votes = 1 + replies
+            .iter()
+            .filter_map(parse_vote)
+            .filter(|(_, leader, leader_epoch)| *leader == self.run_id && *leader_epoch == epoch)
+            .count();
+        let sentinels = self.config.peers.len() + 1;
+        let majority = sentinels / 2 + 1;
+        if votes < self.config.quorum.max(majority) {
+            // Another sentinel may have won; if not, we try again after `failover_timeout`.
+            return;
+        }
+        self.failover(epoch).await;
+    }
+
+    /// Promotes the most up-to-date replica, as the leader of `epoch`.
+    async fn failover(&self, epoch: u64) {
+        let (chosen, others) = {
+            let state = self.state.lock().unwrap();
+            let mut candidates: Vec<(&String, &Node)> =
+                state.replicas.iter().filter(|(_, node)| self.is_up(node) && !node.info.is_primary).collect();
+            // The highest offset first, then the lowest address, so every sentinel would choose
+            // the same one.
+            candidates.sort_by(|(a, x), (b, y)| y.info.offset.cmp(&x.info.offset).then(a.cmp(b)));
+            let Some((chosen, _)) = candidates.first() else {
+                eprintln!("no replica of {} can be promoted", state.primary);
+                return;
+            };
+            let others: Vec<String> = state.replicas.keys().filter(|addr| addr != chosen).cloned().collect();
+            (chosen.to_string(), others)
+        };
+
+        if let Err(e) = ask(&chosen, &["REPLICAOF", "NO", "ONE"], ASK_TIMEOUT).await {
+            eprintln!("could not promote {}: {}", chosen, e);
+            return;
+        }
+        self.switch(&chosen, epoch);
+        self.send_hello().await;
+        let Some((host, port)) = chosen.rsplit_once(':') else { return };
+        // Replicas that don't answer now are reconfigured when they do.
+        for node in others {
+            let _ = ask(&node, &["REPLICAOF", host, port], ASK_TIMEOUT).await;
+        }
+    }
+
+    /// Follows `primary` from now on, as chosen in `epoch`.
+    fn switch(&self, primary: &str, epoch: u64) {
+        let mut state = self.state.lock().unwrap();
+        let old = std::mem::replace(&mut state.primary, primary.to_string());
+        state.replicas.remove(primary);
+        state.replicas.insert(old.clone(), Node::default());
+        state.primary_seen = Instant::now();
+        state.config_epoch = epoch;
+        state.current_epoch = state.current_epoch.max(epoch);
+
+        let (old_host, old_port) = old.rsplit_once(':').unwrap_or((&old, ""));
+        let (new_host, new_port) = primary.rsplit_once(':').unwrap_or((primary, ""));
+        let message = format!("{} {} {} {} {}", self.config.name, old_host, old_port, new_host, new_port);
+        // An error only means that no client is subscribed.
+        let _ = self.switches.send(message);
+    }
+
+    /// SENTINEL is-master-down-by-addr ip port epoch runid: whether this sentinel finds the
+    /// primary down, and its vote in `epoch` if `runid` isn't `*`.
+    fn is_master_down(&self, cmd: &Command) -> Result<Frame, String> {
+        cmd.check_arity(5, 5)?;
+        let addr = format!("{}:{}", cmd.arg_str(1)?, cmd.arg_str(2)?);
+        let epoch = u64::try_from(cmd.arg_int(3)?).map_err(|_| "ERR invalid epoch".to_string())?;
+        let run_id = cmd.arg_str(4)?;
+
+        let down = self.primary_down();
+        let mut state = self.state.lock().unwrap();
+        let down = down && addr == state.primary;
+        let mut leader = ("*".to_string(), 0);
+        if run_id != "*" {
+            state.current_epoch = state.current_epoch.max(epoch);
+            if state.vote.as_ref().is_none_or(|(voted_epoch, _)| *voted_epoch < epoch) {
+                state.vote = Some((epoch, run_id.to_string()));
+                if run_id != self.run_id {
+                    // Give the sentinel we voted for time to fail over.
+                    state.last_failover = Some(Instant::now());
+                }
+            }
+            if let Some((voted_epoch, voted)) = &state.vote {
+                leader = (voted.clone(), *voted_epoch);
+            }
+        }
+        Ok(Frame::Array(vec![
+            Frame::Integer(down as i64),
+            Frame::Bulk(Bytes::from(leader.0)),
+            Frame::Integer(leader.1 as i64),
+        ]))
+    }
+
+    /// Replies to a command of a client or of another sentinel.
+    fn command(&self, cmd: &Command) -> Result<Frame, String> {
+        match cmd.name() {
+            "PING" => Ok(Frame::Simple("PONG".to_string())),
+            "SENTINEL" => {
+                cmd.check_arity(1, usize::MAX)?;
+                match cmd.arg_str(0)?.to_lowercase().as_str() {
+                    "get-master-addr-by-name" => {
+                        cmd.check_arity(2, 2)?;
+                        if cmd.arg_str(1)? != self.config.name {
+                            return Ok(Frame::Null);
+                        }
+                        let primary = self.state.lock().unwrap().primary.clone();
+                        let (host, port) = primary.rsplit_once(':').unwrap_or((&primary, ""));
+                        Ok(Frame::command([host.to_string(), port.to_string()]))
+                    }
+                    "master" => {
+                        cmd.check_arity(2, 2)?;
+                        if cmd.arg_str(1)? != self.config.name {
+                            return Err("ERR No such master with that name".to_string());
+                        }
+                        let flags = if self.primary_down() { "master,s_down" } else { "master" };
+                        let state = self.state.lock().unwrap();
+                        Ok(Frame::command([
+                            "name".to_string(),
+                            self.config.name.clone(),
+                            "addr".to_string(),
+                            state.primary.clone(),
+                            "flags".to_string(),
+                            flags.to_string(),
+                            "num-slaves".to_string(),
+                            state.replicas.len().to_string(),
+                            "quorum".to_string(),
+                            self.config.quorum.to_string(),
+                            "config-epoch".to_string(),
+                            state.config_epoch.to_string(),
+                        ]))
+                    }
+                    "replicas" | "slaves" => {
+                        cmd.check_arity(2, 2)?;
+                        let state = self.state.lock().unwrap();
+                        let replicas = state.replicas.iter().map(|(addr, node)| {
+                            let flags = if self.is_up(node) { "slave" } else { "slave,s_down" };
+                            Frame::command([
+                                "addr".to_string(),
+                                addr.clone(),
+                                "flags".to_string(),
+                                flags.to_string(),
+                                "slave-repl-offset".to_string(),
+                                node.info.offset.to_string(),
+                            ])
+                        });
+                        Ok(Frame::Array(replicas.collect()))
+                    }
+                    "is-master-down-by-addr" => self.is_master_down(cmd),
+                    "hello" => {
+                        // SENTINEL hello name ip port config-epoch, from another sentinel.
+                        cmd.check_arity(5, 5)?;
+                        let epoch = u64::try_from(cmd.arg_int(4)?).map_err(|_| "ERR invalid epoch".to_string())?;
+                        let primary = format!("{}:{}", cmd.arg_str(2)?, cmd.arg_str(3)?);
+                        let newer = epoch > self.state.lock().unwrap().config_epoch;
+                        if cmd.arg_str(1)? == self.config.name && newer {
+                            self.switch(&primary, epoch);
+                        }
+                        Ok(Frame::Simple("OK".to_string()))
+                    }
+                    sub => Err(format!("ERR unknown subcommand '{}' for 'sentinel'", sub)),
+                }
+            }
+            name => Err(format!("ERR unknown command '{}'", name.to_lowercase())),
+        }
+    }
+}
+
+/// Parses the reply of `is-master-down-by-addr` into (down, leader, leader epoch).
+fn parse_vote(reply: &Frame) -> Option<(bool, String, u64)> {
+    match reply {
+        Frame::Array(parts) => match parts.as_slice() {
+            [Frame::Integer(down), Frame::Bulk(leader), Frame::Integer(epoch)] => {
+                Some((*down == 1, String::from_utf8_lossy(leader).into_owned(), *epoch as u64))
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Parses the `INFO replication` of a node.
+fn parse_info(text: &str) -> Info {
+    let mut info = Info::default();
+    let (mut host, mut port) = (None, None);
+    for line in text.lines() {
+        let Some((name, value)) = line.split_once(':') else { continue };
+        match name {
+            "role" => info.is_primary = value == "master",
+            "master_host" => host = Some(value),
+            "master_port" => port = Some(value),
+            "master_repl_offset" | "slave_repl_offset" => info.offset = value.parse().unwrap_or(0),
+            // slave0:ip=127.0.0.1,port=6380,state=online
+            name if name.starts_with("slave") => {
+                let field = |wanted: &str| {
+                    value.split(',').find_map(|field| field.strip_prefix(wanted)?.strip_prefix('='))
+                };
+                if let (Some(ip), Some(port)) = (field("ip"), field("port")) {
+                    info.replicas.push(format!("{}:{}", ip, port));
+                }
+            }
+            _ => {}
+        }
+    }
+    if let (Some(host), Some(port)) = (host, port) {
+        info.primary = Some(format!("{}:{}", host, port));
+    }
+    info
+}
+
+/// Accepts the connections of clients and other sentinels.
+async fn serve(listener: TcpListener, inner: Arc<Inner>) {
+    // In a JoinSet, so that stopping the sentinel closes them too.
+    let mut connections = JoinSet::new();
+    loop {
+        tokio::select! {
+            Some(_) = connections.join_next() => {}
+            accepted = listener.accept() => match accepted {
+                Ok((socket, _)) => {
+                    let inner = inner.clone();
+                    connections.spawn(async move {
+                        let _ = process(Connection::new(socket), &inner).await;
+                    });
+                }
+                Err(e) => eprintln!("failed to accept connection: {}", e),
+            },
+        }
+    }
+}
+
+async fn process(mut connection: Connection, inner: &Inner) -> crate::Result<()> {
+    while let Some(frame) = connection.read_frame().await? {
+        let reply = match Command::from_frame(frame) {
+            Ok(cmd) if cmd.name() == "SUBSCRIBE" => return subscribe(connection, &cmd, inner).await,
+            Ok(cmd) => inner.command(&cmd).unwrap_or_else(Frame::Error),
+            Err(e) => Frame::Error(e),
+        };
+        connection.write_frame(&reply).await?;
+    }
+    Ok(())
+}
+
+/// SUBSCRIBE channel [channel ...]: only `+switch-master` has messages, in the format of
+/// Redis: `<name> <old ip> <old port> <new ip> <new port>`.
+async fn subscribe(mut connection: Connection, cmd: &Command, inner: &Inner) -> crate::Result<()> {
+    if let Err(e) = cmd.check_arity(1, usize::MAX) {
+        connection.write_frame(&Frame::Error(e)).await?;
+        return Ok(());
+    }
+    let mut switches = inner.switches.subscribe();
+    for (i, channel) in cmd.args().iter().enumerate() {
+        let reply = vec![Frame::Bulk(Bytes::from_static(b"subscribe")), Frame::Bulk(channel.clone()), Frame::Integer(i as i64 + 1)];
+        connection.write_frame(&Frame::Array(reply)).await?;
+    }
+    let wanted = cmd.args().iter().any(|channel| channel.as_ref() == b"+switch-master");
+    loop {
+        tokio::select! {
+            message = switches.recv(), if wanted => match message {
+                Ok(message) => {
+                    let message = Frame::command(["message".to_string(), "+switch-master".to_string(), message]);
+                    connection.write_frame(&message).await?;
+                }
+                Err(broadcast::error::RecvError::Lagged(_)) => {}
+                Err(broadcast::error::RecvError::Closed) => return Ok(()),
+            },
+            _ = connection.closed() => return Ok(()),
+        }
+    }
+}
+
+fn random_u64() -> u64 {
+    // Every `RandomState` has new random keys, so hashing nothing gives a random number.
+    RandomState::new().hash_one(())
+}
+
+/// A run id like the ones of Redis: 40 random hex digits.
+fn random_id() -> String {
+    let mut id = String::new();
+    while id.len() < 40 {
+        id.push_str(&format!("{:016x}", random_u64()));
+    }
+    id.truncate(40);
+    id
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TestServer;
+
+    async fn wait_for(what: &str, condition: impl Fn() -> bool) {
+        let start = Instant::now();
+        while !condition() {
+            assert!(start.elapsed() < Duration::from_secs(10), "timed out waiting for {}", what);
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    }
+
+    #[test]
+    fn test_parse_info() {
+        let primary = "# Replication\r\nrole:master\r\nconnected_slaves:2\r\n\
+                       slave0:ip=127.0.0.1,port=6380,state=online\r\nslave1:ip=10.0.0.2,port=6381,state=online\r\n\
+                       master_repl_offset:120\r\n";
+        let info = parse_info(primary);
+        assert!(info.is_primary);
+        assert_eq!(info.offset, 120);
+        assert_eq!(info.replicas, vec!["127.0.0.1:6380", "10.0.0.2:6381"]);
+
+        let replica = "role:slave\r\nmaster_host:127.0.0.1\r\nmaster_port:6379\r\nslave_repl_offset:99\r\n";
+        let info = parse_info(replica);
+        assert!(!info.is_primary);
+        assert_eq!(info.primary.as_deref(), Some("127.0.0.1:6379"));
+        assert_eq!(info.offset, 99);
+    }
+
+    #[tokio::test]
+    async fn test_failover_promotes_the_most_up_to_date_replica() {
+        let primary = TestServer::start().await.unwrap();
+        let (a, b) = (TestServer::start().await.unwrap(), TestServer::start().await.unwrap());
+        let port = primary.addr().port().to_string();
+        for replica in [&a, &b] {
+            replica.client().await.unwrap().command(["REPLICAOF", "127.0.0.1", &port]).await.unwrap();
+        }
+
+        // Three sentinels, any two of which can fail over.
+        let mut listeners = Vec::new();
+        for _ in 0..3 {
+            listeners.push(TcpListener::bind("127.0.0.1:0").await.unwrap());
+        }
+        let addrs: Vec<String> = listeners.iter().map(|l| l.local_addr().unwrap().to_string()).collect();
+        let sentinels: Vec<Sentinel> = listeners
+            .into_iter()
+            .enumerate()
+            .map(|(i, listener)| {
+                let mut config = SentinelConfig::new("mymaster", &primary.addr().to_string());
+                config.peers = addrs.iter().filter(|addr| **addr != addrs[i]).cloned().collect();
+                config.down_after = Duration::from_millis(300);
+                config.failover_timeout = Duration::from_secs(1);
+                config.check_interval = Duration::from_millis(50);
+                Sentinel::start(config, listener).unwrap()
+            })
+            .collect();
+        wait_for("the replicas", || sentinels.iter().all(|s| s.replicas().len() == 2)).await;
+
+        let client = primary.client().await.unwrap();
+        client.set("key", "1").await.unwrap();
+        let (primary_offset, a_offset) = (primary.shared().replication.clone(), a.shared().replication.clone());
+        wait_for("the replication", || a_offset.offset() == primary_offset.offset()).await;
+        // b stops copying, so a is the most up to date
+        b.shared().replication.stop();
+        client.set("key", "2").await.unwrap();
+        wait_for("the replication", || a_offset.offset() == primary_offset.offset()).await;
+
+        let mut subscriber = Connection::new(TcpStream::connect(&addrs[0]).await.unwrap());
+        subscriber.write_frame(&Frame::command(["SUBSCRIBE", "+switch-master"])).await.unwrap();
+        subscriber.read_frame().await.unwrap();
+
+        drop(primary);
+        let new_primary = a.addr().to_string();
+        wait_for("the failover", || sentinels.iter().all(|s| s.primary() == new_primary)).await;
+        assert_eq!(primary_addr(&addrs, "mymaster").await.unwrap(), new_primary);
+        let master = ask(&addrs[1], &["SENTINEL", "master", "mymaster"], ASK_TIMEOUT).await.unwrap();
+        assert!(matches!(master, Frame::Array(fields) if fields[3] == Frame::Bulk(Bytes::from(new_primary.clone()))));
+        let message = Frame::command([
+            "message".to_string(),
+            "+switch-master".to_string(),
+            format!("mymaster 127.0.0.1 {} 127.0.0.1 {}", port, a.addr().port()),
+        ]);
+        assert_eq!(subscriber.read_frame().await.unwrap(), Some(message));
+
+        // a takes writes, and b copies from it
+        let a_client = a.client().await.unwrap();
+        assert_eq!(a_client.get("key").await.unwrap(), Some(Bytes::from("2")));
+        a_client.set("key", "3").await.unwrap();
+        let b_db = b.shared().db.clone();
+        wait_for("b to follow a", || b_db.get("key") == Some(Bytes::from("3"))).await;
+    }
+}
@@ -0,0 +1,1484 @@
+use crate::audit::AuditLog;
+use crate::bitmap::{self, BitOp, BitUnit};
+use crate::cmd::Command;
+use crate::connection::Connection;
+use crate::frame::Frame;
+use crate::geo::{self, Center, Search, Shape, Unit};
+use crate::list::{BlockingPop, End};
+use crate::metrics::Metrics;
+use crate::monitor::Monitor;
+use crate::replication::{self, Replication};
+use crate::script::{self, Scripts};
+use crate::stream::{GroupReadId, NewId, PendingFilter, StreamEntry, StreamError, StreamId};
+use crate::tracking::{self, ClientId, Mode};
+use crate::zset::{self, Added, Update};
+use crate::{ShardedDatabase, Ttl, WrongType};
+use bytes::Bytes;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, mpsc};
+use tokio::task::JoinSet;
+
+/// Everything a connection task needs to execute commands.
+///
+/// Cloning is cheap: all fields are `Arc`s internally.
+#[derive(Clone)]
+pub struct Shared {
+    pub db: ShardedDatabase,
+    pub metrics: Metrics,
+    pub scripts: Scripts,
+    pub monitor: Monitor,
+    /// Where mutating commands are logged, if anywhere (`--audit-log`).
+    pub audit: Option<AuditLog>,
+    pub replication: Replication,
+    // Commands run holding this lock for reading, scripts hold it for writing.
+    // That makes a script atomic: nothing else runs in the middle of it.
+    pub(crate) exec_lock: Arc<RwLock<()>>,
+}
+
+impl Shared {
+    /// Creates the shared state around `db`, with default metrics and an empty script cache.
+    pub fn new(db: ShardedDatabase) -> Shared {
+        Shared {
+            db,
+            metrics: Metrics::new(),
+            scripts: Scripts::new(),
+            monitor: Monitor::new(),
+            audit: None,
+            replication: Replication::new(),
+            exec_lock: Arc::new(RwLock::new(())),
+        }
+    }
+}
+
+/// Accepts connections forever, spawning one task per connection.
+pub async fn run(listener: TcpListener, shared: Shared) {
+    run_until(listener, shared, std::future::pending()).await
+}
+
+/// Same as `run`, until `shutdown` completes. Then the listener is closed and every
+/// connection task is cancelled.
+pub async fn run_until(listener: TcpListener, shared: Shared, shutdown: impl Future<Output = ()>) {
+    // Why a JoinSet instead of plain `tokio::spawn`?
+    // Dropping a JoinSet aborts its tasks, so returning from here stops the whole server,
+    // not just the accept loop. That's what `TestServer` relies on.
+    let mut tasks = JoinSet::new();
+    // Announced to the primary if this server becomes a replica.
+    if let Ok(addr) = listener.local_addr() {
+        shared.replication.set_port(addr.port());
+    }
+
+    // Expired keys are removed when touched, this task catches the ones nobody touches.
+    let db = shared.db.clone();
+    tasks.spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_millis(100));
+        loop {
+            interval.tick().await;
+            db.purge_expired();
+        }
+    });
+
+    tokio::pin!(shutdown);
+    loop {
+        let (socket, addr) = tokio::select! {
+            _ = &mut shutdown => {
+                shared.replication.stop();
+                return;
+            }
+            // Reap finished connection tasks, otherwise the set keeps growing.
+            Some(_) = tasks.join_next() => continue,
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    eprintln!("failed to accept connection: {}", e);
+                    continue;
+                }
+            },
+        };
+        // Why do we need to clone here?
+        // Because the db is wrapped in an Arc internally, cloning it only increments
+        // the reference count, allowing multiple tasks to share the same database.
+        let shared = shared.clone();
+        // Spawn a new task to handle the connection
+        tasks.spawn(async move {
+            shared.metrics.client_connected();
+            if let Err(e) = process(socket, addr, &shared).await {
+                eprintln!("connection {} closed with error: {}", addr, e);
+            }
+            shared.metrics.client_disconnected();
+        });
+    }
+}
+
+/// Reads commands from one client until it disconnects.
+pub async fn process(socket: TcpStream, addr: SocketAddr, shared: &Shared) -> crate::Result<()> {
+    // Connection handles parsing frames from the socket
+    let mut connection = Connection::new(socket);
+    let mut session = Session::new(shared.db.tracking().next_id());
+    let result = serve(&mut connection, addr, shared, &mut session).await;
+    shared.db.tracking().disable(session.id);
+    result
+}
+
+async fn serve(connection: &mut Connection, addr: SocketAddr, shared: &Shared, session: &mut Session) -> crate::Result<()> {
+    loop {
+        // Note: `read_frame` is cancel safe, so a push arriving first doesn't lose the
+        // part of a command already read.
+        let frame = tokio::select! {
+            frame = connection.read_frame() => match frame? {
+                Some(frame) => frame,
+                None => return Ok(()),
+            },
+            Some(push) = next_push(&mut session.pushes) => {
+                connection.write_frame(&push).await?;
+                continue;
+            }
+            line = next_line(&mut session.monitor) => {
+                match line {
+                    Ok(line) => connection.write_frame(&Frame::Simple(line)).await?,
+                    // Redis would disconnect a monitor this slow; we let it skip lines.
+                    Err(broadcast::error::RecvError::Lagged(_)) => {}
+                    Err(broadcast::error::RecvError::Closed) => session.monitor = None,
+                }
+                continue;
+            }
+        };
+
+        let response = match Command::from_frame(frame) {
+            Ok(cmd) => {
+                if session.pushes.is_some() {
+                    shared.db.tracking().remember(session.id, tracking::read_keys(cmd.name(), cmd.args()));
+                }
+                shared.monitor.feed(&cmd, addr);
+                if cmd.name() == "SYNC" {
+                    // From here on, the connection carries the writes to a replica.
+                    return replication::serve_replica(connection, addr, session.replica_port, shared).await;
+                }
+                let start = Instant::now();
+                let (response, blocked) = match cmd.name() {
+                    _ if cmd.is_write() && shared.replication.is_replica() => {
+                        (Frame::Error("READONLY You can't write against a read only replica.".to_string()), Duration::ZERO)
+                    }
+                    "BLPOP" | "BRPOP" | "BLMOVE" | "XREAD" | "XREADGROUP" => execute_blocking(&cmd, shared, connection).await,
+                    "HELLO" | "CLIENT" | "MONITOR" | "REPLCONF" => (session.execute(&cmd, shared).unwrap_or_else(Frame::Error), Duration::ZERO),
+                    "REPLICAOF" => (replication::replicaof(&cmd, shared).unwrap_or_else(Frame::Error), Duration::ZERO),
+                    _ => (execute(&cmd, shared), Duration::ZERO),
+                };
+                // Like Redis, time spent waiting for data doesn't count as execution time.
+                shared.metrics.record(&cmd, start.elapsed().saturating_sub(blocked), addr);
+                if let Some(audit) = &shared.audit {
+                    audit.log(&cmd, addr, !matches!(response, Frame::Error(_)));
+                }
+                response
+            }
+            Err(e) => Frame::Error(e),
+        };
+
+        // Write the response to the client. Invalidations caused by this command are
+        // written after it, by the next turns of the loop.
+        connection.write_frame(&response).await?;
+    }
+}
+
+/// Waits for the next invalidation, forever if tracking is off.
+async fn next_push(pushes: &mut Option<mpsc::UnboundedReceiver<Frame>>) -> Option<Frame> {
+    match pushes {
+        Some(pushes) => pushes.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Waits for the next MONITOR line, forever if the connection isn't monitoring.
+async fn next_line(monitor: &mut Option<broadcast::Receiver<String>>) -> Result<String, broadcast::error::RecvError> {
+    match monitor {
+        Some(monitor) => monitor.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// The state of one connection, for the commands about the connection itself.
+struct Session {
+    id: ClientId,
+    // 2 or 3, set by HELLO.
+    protocol: i64,
+    // Invalidations for this connection while CLIENT TRACKING is on.
+    pushes: Option<mpsc::UnboundedReceiver<Frame>>,
+    // Every executed command, once MONITOR was sent.
+    monitor: Option<broadcast::Receiver<String>>,
+    // The port a replica listens on, sent with REPLCONF before SYNC.
+    replica_port: Option<u16>,
+}
+
+impl Session {
+    fn new(id: ClientId) -> Session {
+        Session { id, protocol: 2, pushes: None, monitor: None, replica_port: None }
+    }
+
+    fn execute(&mut self, cmd: &Command, shared: &Shared) -> Result<Frame, String> {
+        match cmd.name() {
+            "HELLO" => self.hello(cmd, shared),
+            "MONITOR" => {
+                cmd.check_arity(0, 0)?;
+                self.monitor = Some(shared.monitor.subscribe());
+                Ok(Frame::Simple("OK".to_string()))
+            }
+            "REPLCONF" => {
+                // Only `REPLCONF listening-port <port>`, which a replica sends before SYNC.
+                cmd.check_arity(2, 2)?;
+                if !cmd.arg_str(0)?.eq_ignore_ascii_case("listening-port") {
+                    return Err("ERR Unrecognized REPLCONF option".to_string());
+                }
+                let port = cmd.arg_int(1)?;
+                self.replica_port = Some(u16::try_from(port).map_err(|_| "ERR Invalid port".to_string())?);
+                Ok(Frame::Simple("OK".to_string()))
+            }
+            _ => self.client(cmd, shared),
+        }
+    }
+
+    /// HELLO [protover]: switches the protocol, and describes the server.
+    ///
+    /// Note: only push messages and this reply use RESP3 types, every other reply is the
+    /// same as in RESP2. RESP3 clients accept both.
+    fn hello(&mut self, cmd: &Command, shared: &Shared) -> Result<Frame, String> {
+        cmd.check_arity(0, 1)?;
+        if !cmd.args().is_empty() {
+            match cmd.arg_int(0) {
+                Ok(version @ (2 | 3)) => self.protocol = version,
+                _ => return Err("NOPROTO unsupported protocol version".to_string()),
+            }
+        }
+        let role: &'static [u8] = if shared.replication.is_replica() { b"replica" } else { b"master" };
+        let fields = [
+            ("server", Frame::Bulk(Bytes::from_static(b"my_redis"))),
+            ("version", Frame::Bulk(Bytes::from_static(env!("CARGO_PKG_VERSION").as_bytes()))),
+            ("proto", Frame::Integer(self.protocol)),
+            ("id", Frame::Integer(self.id as i64)),
+            ("mode", Frame::Bulk(Bytes::from_static(b"standalone"))),
+            ("role", Frame::Bulk(Bytes::from_static(role))),
+            ("modules", Frame::Array(Vec::new())),
+        ];
+        let fields = fields.into_iter().map(|(name, value)| (Frame::Bulk(Bytes::from_static(name.as_bytes())), value));
+        if self.protocol == 3 {
+            Ok(Frame::Map(fields.collect()))
+        } else {
+            Ok(Frame::Array(fields.flat_map(|(name, value)| [name, value]).collect()))
+        }
+    }
+
+    fn client(&mut self, cmd: &Command, shared: &Shared) -> Result<Frame, String> {
+        cmd.check_arity(1, usize::MAX)?;
+        match cmd.arg_str(0)?.to_uppercase().as_str() {
+            "ID" => {
+                cmd.check_arity(1, 1)?;
+                Ok(Frame::Integer(self.id as i64))
+            }
+            "TRACKING" => self.tracking(cmd, shared),
+            sub => Err(format!("ERR unknown subcommand '{}' for 'client'", sub.to_lowercase())),
+        }
+    }
+
+    /// CLIENT TRACKING ON|OFF [BCAST] [PREFIX prefix ...]
+    fn tracking(&mut self, cmd: &Command, shared: &Shared) -> Result<Frame, String> {
+        cmd.check_arity(2, usize::MAX)?;
+        let tracking = shared.db.tracking();
+        let on = match cmd.arg_str(1)?.to_uppercase().as_str() {
+            "ON" => true,
+            "OFF" => false,
+            _ => return Err("ERR syntax error".to_string()),
+        };
+        if !on {
+            tracking.disable(self.id);
+            self.pushes = None;
+            return Ok(Frame::Simple("OK".to_string()));
+        }
+
+        let mut bcast = false;
+        let mut prefixes = Vec::new();
+        let mut i = 2;
+        while i < cmd.args().len() {
+            match cmd.arg_str(i)?.to_uppercase().as_str() {
+                "BCAST" => bcast = true,
+                "PREFIX" => {
+                    prefixes.push(cmd.arg_str(i + 1)?.to_string());
+                    i += 1;
+                }
+                option @ ("REDIRECT" | "OPTIN" | "OPTOUT" | "NOLOOP") => {
+                    return Err(format!("ERR {} is not supported", option));
+                }
+                _ => return Err("ERR syntax error".to_string()),
+            }
+            i += 1;
+        }
+        if !prefixes.is_empty() && !bcast {
+            return Err("ERR PREFIX option requires BCAST mode to be enabled".to_string());
+        }
+        // Without REDIRECT, invalidations are push messages, which RESP2 doesn't have.
+        if self.protocol != 3 {
+            return Err("ERR CLIENT TRACKING needs RESP3, send HELLO 3 first".to_string());
+        }
+
+        let mode = if bcast { Mode::Broadcast(prefixes) } else { Mode::Default };
+        let (tx, rx) = mpsc::unbounded_channel();
+        // Turning it on again changes the mode; what the old channel held is dropped.
+        tracking.disable(self.id);
+        tracking.enable(self.id, mode, tx);
+        self.pushes = Some(rx);
+        Ok(Frame::Simple("OK".to_string()))
+    }
+}
+
+/// Runs a single command against the shared state and returns the reply.
+pub fn execute(cmd: &Command, shared: &Shared) -> Frame {
+    match cmd.name() {
+        // Scripts take the execution lock for writing themselves.
+        "EVAL" | "EVALSHA" | "SCRIPT" => script::execute(cmd, shared).unwrap_or_else(Frame::Error),
+        _ => {
+            let _guard = shared.exec_lock.read().unwrap();
+            let _order = shared.replication.order(cmd);
+            let reply = dispatch(cmd, shared);
+            shared.replication.propagate(cmd, &reply);
+            reply
+        }
+    }
+}
+
+/// Runs a non-blocking command without taking the execution lock.
+/// Only for callers already holding it, i.e. scripts.
+pub(crate) fn dispatch(cmd: &Command, shared: &Shared) -> Frame {
+    let (result, stored) = crate::committing(|| match cmd.name() {
+        "PING" => ping(cmd),
+        "GET" => get(cmd, shared),
+        "SET" => set(cmd, shared),
+        "DEL" => del(cmd, shared),
+        "EXISTS" => exists(cmd, shared),
+        "INCR" => incr(cmd, shared, 1),
+        "DECR" => incr(cmd, shared, -1),
+        "INCRBY" => incr_by(cmd, shared, 1),
+        "DECRBY" => incr_by(cmd, shared, -1),
+        "EXPIRE" => expire(cmd, shared, Duration::from_secs),
+        "PEXPIRE" => expire(cmd, shared, Duration::from_millis),
+        "TTL" => ttl(cmd, shared, |ttl| ttl.as_millis().div_ceil(1000) as i64),
+        "PTTL" => ttl(cmd, shared, |ttl| ttl.as_millis() as i64),
+        "PERSIST" => persist(cmd, shared),
+        "KEYS" => keys(cmd, shared),
+        "SCAN" => scan(cmd, shared),
+        "DUMP" => dump(cmd, shared),
+        "RESTORE" => restore(cmd, shared),
+        "INFO" => info(cmd, shared),
+        "SLOWLOG" => slowlog(cmd, shared),
+        "LPUSH" => push(cmd, shared, End::Left),
+        "RPUSH" => push(cmd, shared, End::Right),
+        "LPOP" => pop(cmd, shared, End::Left),
+        "RPOP" => pop(cmd, shared, End::Right),
+        "LLEN" => llen(cmd, shared),
+        "LRANGE" => lrange(cmd, shared),
+        "LMOVE" => lmove(cmd, shared),
+        "XADD" => xadd(cmd, shared),
+        "XLEN" => xlen(cmd, shared),
+        "XRANGE" => xrange(cmd, shared, false),
+        "XREVRANGE" => xrange(cmd, shared, true),
+        "XGROUP" => xgroup(cmd, shared),
+        "XACK" => xack(cmd, shared),
+        "XPENDING" => xpending(cmd, shared),
+        "XCLAIM" => xclaim(cmd, shared),
+        "SETBIT" => setbit(cmd, shared),
+        "GETBIT" => getbit(cmd, shared),
+        "BITCOUNT" => bitcount(cmd, shared),
+        "BITOP" => bitop(cmd, shared),
+        "PFADD" => pfadd(cmd, shared),
+        "PFCOUNT" => pfcount(cmd, shared),
+        "PFMERGE" => pfmerge(cmd, shared),
+        "ZADD" => zadd(cmd, shared),
+        "ZSCORE" => zscore(cmd, shared),
+        "ZREM" => zrem(cmd, shared),
+        "ZCARD" => zcard(cmd, shared),
+        "ZRANGE" => zrange(cmd, shared),
+        "GEOADD" => geoadd(cmd, shared),
+        "GEODIST" => geodist(cmd, shared),
+        "GEOSEARCH" => geosearch(cmd, shared),
+        name => Err(format!("ERR unknown command '{}'", name.to_lowercase())),
+    });
+    // The reply must not claim a change the storage didn't keep.
+    let result = stored.map_err(|e| format!("ERR storage error: {}", e)).and(result);
+    // Errors are just another kind of reply in RESP.
+    result.unwrap_or_else(Frame::Error)
+}
+
+fn ping(cmd: &Command) -> Result<Frame, String> {
+    cmd.check_arity(0, 1)?;
+    match cmd.args().first() {
+        Some(msg) => Ok(Frame::Bulk(msg.clone())),
+        None => Ok(Frame::Simple("PONG".to_string())),
+    }
+}
+
+fn get(cmd: &Command, shared: &Shared) -> Result<Frame, String> {
+    cmd.check_arity(1, 1)?;
+    match shared.db.get(cmd.arg_str(0)?) {
+        Some(value) => Ok(Frame::Bulk(value)),
+        None => Ok(Frame::Null),
+    }
+}
+
+fn set(cmd: &Command, shared: &Shared) -> Result<Frame, String> {
+    // SET key value [EX seconds | PX milliseconds]
+    cmd.check_arity(2, 4)?;
+    let ttl = match cmd.args().len() {
+        2 => None,
+        4 => {
+            let amount = positive(cmd.arg_int(3)?, "set")?;
+            match cmd.arg_str(2)?.to_uppercase().as_str() {
+                "EX" => Some(Duration::from_secs(amount)),
+                "PX" => Some(Duration::from_millis(amount)),
+                _ => return Err("ERR syntax error".to_string()),
+            }
+        }
+        _ => return Err("ERR syntax error".to_string()),
+    };
+    shared.db.insert_with_ttl(cmd.arg_str(0)?, cmd.args()[1].clone(), ttl).map_err(|_| invalid_expire("set"))?;
+    Ok(Frame::Simple("OK".to_string()))
+}
+
+fn positive(amount: i64, name: &str) -> Result<u64, String> {
+    match u64::try_from(amount) {
+        Ok(amount) if amount > 0 => Ok(amount),
+        _ => Err(invalid_expire(name)),
+    }
+}
+
+fn invalid_expire(name: &str) -> String {
+    format!("ERR invalid expire time in '{}' command", name)
+}
+
+fn del(cmd: &Command, shared: &Shared) -> Result<Frame, String> {
+    cmd.check_arity(1, usize::MAX)?;
+    let mut removed = 0;
+    for i in 0..cmd.args().len() {
+        removed += shared.db.remove(cmd.arg_str(i)?) as i64;
+    }
+    Ok(Frame::Integer(removed))
+}
+
+fn exists(cmd: &Command, shared: &Shared) -> Result<Frame, String> {
+    cmd.check_arity(1, usize::MAX)?;
+    // A key given twice is counted twice, like in Redis.
+    let mut found = 0;
+    for i in 0..cmd.args().len() {
+        found += shared.db.contains_key(cmd.arg_str(i)?) as i64;
+    }
+    Ok(Frame::Integer(found))
+}
+
+fn incr(cmd: &Command, shared: &Shared, delta: i64) -> Result<Frame, String> {
+    cmd.check_arity(1, 1)?;
+    let value = shared.db.incr_by(cmd.arg_str(0)?, delta).map_err(|e| e.to_string())?;
+    Ok(Frame::Integer(value))
+}
+
+/// INCRBY and DECRBY, `sign` is -1 for DECRBY.
+fn incr_by(cmd: &Command, shared: &Shared, sign: i64) -> Result<Frame, String> {
+    cmd.check_arity(2, 2)?;
+    let delta = cmd.arg_int(1)?.checked_mul(sign).ok_or("ERR decrement would overflow")?;
+    let value = shared.db.incr_by(cmd.arg_str(0)?, delta).map_err(|e| e.to_string())?;
+    Ok(Frame::Integer(value))
+}
+
+/// EXPIRE and PEXPIRE, `unit` turns the argument into a `Duration`.
+fn expire(cmd: &Command, shared: &Shared, unit: fn(u64) -> Duration) -> Result<Frame, String> {
+    cmd.check_arity(2, 2)?;
+    let key = cmd.arg_str(0)?;
+    let ttl = cmd.arg_int(1)?;
+    // Like Redis, a ttl that already passed deletes the key.
+    if ttl <= 0 {
+        return Ok(Frame::Integer(shared.db.remove(key) as i64));
+    }
+    let set = shared.db.expire(key, unit(ttl as u64)).map_err(|_| invalid_expire(&cmd.name().to_lowercase()))?;
+    Ok(Frame::Integer(set as i64))
+}
+
+/// TTL and PTTL, `unit` converts the remaining time into seconds or milliseconds.
+fn ttl(cmd: &Command, shared: &Shared, unit: fn(Duration) -> i64) -> Result<Frame, String> {
+    cmd.check_arity(1, 1)?;
+    let ttl = match shared.db.ttl(cmd.arg_str(0)?) {
+        Ttl::NoKey => -2,
+        Ttl::NoExpiry => -1,
+        Ttl::Remaining(ttl) => unit(ttl),
+    };
+    Ok(Frame::Integer(ttl))
+}
+
+fn persist(cmd: &Command, shared: &Shared) -> Result<Frame, String> {
+    cmd.check_arity(1, 1)?;
+    Ok(Frame::Integer(shared.db.persist(cmd.arg_str(0)?) as i64))
+}
+
+fn keys(cmd: &Command, shared: &Shared) -> Result<Frame, String> {
+    cmd.check_arity(1, 1)?;
+    let keys = shared.db.keys(cmd.arg_str(0)?);
+    Ok(Frame::Array(keys.into_iter().map(|key| Frame::Bulk(Bytes::from(key))).collect()))
+}
+
+/// SCAN cursor [MATCH pattern] [COUNT count]
+///
+/// Note: each call returns the keys of one shard, COUNT is accepted but ignored.
+fn scan(cmd: &Command, shared: &Shared) -> Result<Frame, String> {
+    cmd.check_arity(1, 5)?;
+    let cursor: u64 = cmd.arg_str(0)?.parse().map_err(|_| "ERR invalid cursor".to_string())?;
+    let mut pattern = None;
+    let mut i = 1;
+    while i < cmd.args().len() {
+        match cmd.arg_str(i)?.to_uppercase().as_str() {
+            "MATCH" => pattern = Some(cmd.arg_str(i + 1)?),
+            "COUNT" if cmd.arg_int(i + 1)? > 0 => {}
+            _ => return Err("ERR syntax error".to_string()),
+        }
+        i += 2;
+    }
+    let (next, keys) = shared.db.scan(cursor, pattern);
+    Ok(Frame::Array(vec![
+        Frame::Bulk(Bytes::from(next.to_string())),
+        Frame::Array(keys.into_iter().map(|key| Frame::Bulk(Bytes::from(key))).collect()),
+    ]))
+}
+
+fn dump(cmd: &Command, shared: &Shared) -> Result<Frame, String> {
+    cmd.check_arity(1, 1)?;
+    Ok(shared.db.dump(cmd.arg_str(0)?).map_or(Frame::Null, Frame::Bulk))
+}
+
+/// RESTORE key ttl payload [REPLACE] [ABSTTL]
+///
+/// `ttl` is in milliseconds, 0 for none; with ABSTTL it is a unix time in milliseconds.
+/// A time that already passed restores a key that is gone right away, like in Redis.
+fn restore(cmd: &Command, shared: &Shared) -> Result<Frame, String> {
+    cmd.check_arity(3, 5)?;
+    let ttl = u64::try_from(cmd.arg_int(1)?).map_err(|_| "ERR Invalid TTL value, must be >= 0".to_string())?;
+    let (mut replace, mut absolute) = (false, false);
+    for i in 3..cmd.args().len() {
+        match cmd.arg_str(i)?.to_uppercase().as_str() {
+            "REPLACE" => replace = true,
+            "ABSTTL" => absolute = true,
+            _ => return Err("ERR syntax error".to_string()),
+        }
+    }
+    let ttl = match ttl {
+        0 => None,
+        at if absolute => {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+            Some(Duration::from_millis(at).saturating_sub(now))
+        }
+        millis => Some(Duration::from_millis(millis)),
+    };
+    shared
+        .db
+        .restore(cmd.arg_str(0)?, &cmd.args()[2], ttl, replace)
+        .map_err(|e| e.to_string())?;
+    Ok(Frame::Simple("OK".to_string()))
+}
+
+fn info(cmd: &Command, shared: &Shared) -> Result<Frame, String> {
+    cmd.check_arity(0, 1)?;
+    let section = match cmd.args().first() {
+        Some(_) => Some(cmd.arg_str(0)?),
+        None => None,
+    };
+    let mut text = shared.metrics.info(section, &shared.db);
+    if section.is_none_or(|s| matches!(s.to_lowercase().as_str(), "default" | "all" | "everything" | "replication")) {
+        text.push_str(&shared.replication.info());
+    }
+    Ok(Frame::Bulk(Bytes::from(text)))
+}
+
+fn slowlog(cmd: &Command, shared: &Shared) -> Result<Frame, String> {
+    cmd.check_arity(1, 2)?;
+    match cmd.arg_str(0)?.to_uppercase().as_str() {
+        "GET" => {
+            // Redis returns the 10 newest entries by default.
+            let count = match cmd.args().get(1) {
+                Some(_) => cmd.arg_int(1)?.max(0) as usize,
+                None => 10,
+            };
+            let entries = shared.metrics.slowlog_get(count);
+            Ok(Frame::Array(entries.iter().map(|e| e.to_frame()).collect()))
+        }
+        "LEN" => Ok(Frame::Integer(shared.metrics.slowlog_len() as i64)),
+        "RESET" => {
+            shared.metrics.slowlog_reset();
+            Ok(Frame::Simple("OK".to_string()))
+        }
+        sub => Err(format!("ERR unknown subcommand '{}' for 'slowlog'", sub.to_lowercase())),
+    }
+}
+
+fn push(cmd: &Command, shared: &Shared, end: End) -> Result<Frame, String> {
+    cmd.check_arity(2, usize::MAX)?;
+    let len = shared
+        .db
+        .push(cmd.arg_str(0)?, &cmd.args()[1..], end)
+        .map_err(|e| e.to_string())?;
+    Ok(Frame::Integer(len as i64))
+}
+
+fn pop(cmd: &Command, shared: &Shared, end: End) -> Result<Frame, String> {
+    cmd.check_arity(1, 2)?;
+    // Without a count a single bulk string is returned, with a count an array.
+    let count = match cmd.args().get(1) {
+        Some(_) => Some(cmd.arg_int(1)?.max(0) as usize),
+        None => None,
+    };
+    let popped = shared
+        .db
+        .pop(cmd.arg_str(0)?, end, count.unwrap_or(1))
+        .map_err(|e| e.to_string())?;
+    match count {
+        Some(_) if popped.is_empty() => Ok(Frame::Null),
+        Some(_) => Ok(Frame::Array(popped.into_iter().map(Frame::Bulk).collect())),
+        None => Ok(popped.into_iter().next().map(Frame::Bulk).unwrap_or(Frame::Null)),
+    }
+}
+
+fn llen(cmd: &Command, shared: &Shared) -> Result<Frame, String> {
+    cmd.check_arity(1, 1)?;
+    let len = shared.db.list_len(cmd.arg_str(0)?).map_err(|e| e.to_string())?;
+    Ok(Frame::Integer(len as i64))
+}
+
+fn lrange(cmd: &Command, shared: &Shared) -> Result<Frame, String> {
+    cmd.check_arity(3, 3)?;
+    let items = shared
+        .db
+        .list_range(cmd.arg_str(0)?, cmd.arg_int(1)?, cmd.arg_int(2)?)
+        .map_err(|e| e.to_string())?;
+    Ok(Frame::Array(items.into_iter().map(Frame::Bulk).collect()))
+}
+
+fn lmove(cmd: &Command, shared: &Shared) -> Result<Frame, String> {
+    cmd.check_arity(4, 4)?;
+    let (from, to) = (parse_end(cmd, 2)?, parse_end(cmd, 3)?);
+    let moved = shared
+        .db
+        .list_move(cmd.arg_str(0)?, cmd.arg_str(1)?, from, to)
+        .map_err(|e| e.to_string())?;
+    Ok(moved.map(Frame::Bulk).unwrap_or(Frame::Null))
+}
+
+/// Runs a command that may block (BLPOP, BRPOP, BLMOVE, XREAD and XREADGROUP), parking the
+/// connection task until data arrives, the timeout elapses or the client disconnects.
+///
+/// Returns the reply and how long the command was parked.
+async fn execute_blocking(cmd: &Command, shared: &Shared, connection: &Connection) -> (Frame, Duration) {
+    let result = match cmd.name() {
+        "XREAD" => xread(cmd, shared, connection).await,
+        "XREADGROUP" => xreadgroup(cmd, shared, connection).await,
+        _ => blocking_pop(cmd, shared, connection).await,
+    };
+    match result {
+        Ok(done) => done,
+        Err(e) => (Frame::Error(e), Duration::ZERO),
+    }
+}
+
+async fn blocking_pop(cmd: &Command, shared: &Shared, connection: &Connection) -> Result<(Frame, Duration), String> {
+    // BLPOP key [key ...] timeout / BLMOVE source destination LEFT|RIGHT LEFT|RIGHT timeout
+    let (keys, from, destination) = match cmd.name() {
+        "BLMOVE" => {
+            cmd.check_arity(5, 5)?;
+            let destination = (cmd.arg_str(1)?.to_string(), parse_end(cmd, 3)?);
+            (vec![cmd.arg_str(0)?.to_string()], parse_end(cmd, 2)?, Some(destination))
+        }
+        name => {
+            cmd.check_arity(2, usize::MAX)?;
+            let keys = (0..cmd.args().len() - 1)
+                .map(|i| cmd.arg_str(i).map(str::to_string))
+                .collect::<Result<Vec<_>, _>>()?;
+            let end = if name == "BLPOP" { End::Left } else { End::Right };
+            (keys, end, None)
+        }
+    };
+    let timeout = parse_timeout(cmd, cmd.args().len() - 1)?;
+
+    if let Some((destination, _)) = &destination {
+        // Fail before blocking rather than after popping a value we can't push.
+        shared.db.list_len(destination).map_err(|e| e.to_string())?;
+    }
+
+    let start = Instant::now();
+    let parked = {
+        // Note: the first attempt is an ordinary LPOP/RPOP (or LMOVE), so it takes the
+        // execution lock like any other command; otherwise it could see a script half done.
+        // The order guard is held until the effect is passed on, so the two are one step.
+        // Both are released before waiting.
+        let _guard = shared.exec_lock.read().unwrap();
+        let _order = shared.replication.order(cmd);
+        let (popped, stored) = crate::committing(|| shared.db.blocking_pop(&keys, from));
+        stored.map_err(|e| format!("ERR storage error: {}", e))?;
+        match popped.map_err(|e| e.to_string())? {
+            BlockingPop::Ready(key, value) => {
+                let reply = finish_pop(cmd, shared, key, value, from, destination)?;
+                return Ok((reply, start.elapsed()));
+            }
+            BlockingPop::Parked(parked) => parked,
+        }
+    };
+
+    // Dropping `parked` (when this block ends) unregisters us from the keys.
+    let popped = {
+        let mut parked = parked;
+        let wait = async {
+            match timeout {
+                Some(timeout) => tokio::time::timeout(timeout, parked.wait()).await.ok().flatten(),
+                None => parked.wait().await,
+            }
+        };
+        tokio::select! {
+            popped = wait => popped,
+            _ = connection.closed() => None,
+        }
+    };
+    let blocked = start.elapsed();
+    let Some((key, value)) = popped else {
+        return Ok((Frame::Null, blocked));
+    };
+
+    // Note: the value was taken off the list inside the command that pushed it, but the pop
+    // is only passed on now, so a SYNC starting in between may miss it. The BLMOVE push and
+    // passing the effect on are still one step.
+    let _guard = shared.exec_lock.read().unwrap();
+    let _order = shared.replication.order(cmd);
+    let reply = finish_pop(cmd, shared, key, value, from, destination)?;
+    Ok((reply, blocked))
+}
+
+/// Completes a blocking pop that got `value` from `key`: BLMOVE pushes it to the destination,
+/// then the effect is passed on to the replicas.
+///
+/// Called with the execution lock and the order guard held.
+fn finish_pop(cmd: &Command, shared: &Shared, key: String, value: Bytes, from: End, destination: Option<(String, End)>) -> Result<Frame, String> {
+    let reply = match destination {
+        Some((destination, to)) => {
+            let (pushed, stored) = crate::committing(|| {
+                let pushed = shared.db.push(&destination, std::slice::from_ref(&value), to);
+                if let Err(WrongType) = pushed {
+                    // The destination changed type while we were blocked; put the value back.
+                    let _ = shared.db.push(&key, std::slice::from_ref(&value), from);
+                }
+                pushed
+            });
+            stored.map_err(|e| format!("ERR storage error: {}", e))?;
+            pushed.map_err(|e| e.to_string())?;
+            Frame::Bulk(value)
+        }
+        None => Frame::Array(vec![Frame::Bulk(Bytes::from(key)), Frame::Bulk(value)]),
+    };
+
+    // Replicas don't wait: they get what happened, as the command that doesn't block.
+    let args = cmd.args()[..cmd.args().len() - 1].iter().cloned();
+    let effect = match (&reply, cmd.name()) {
+        (Frame::Bulk(_), "BLMOVE") => Some(Frame::command(std::iter::once(Bytes::from_static(b"LMOVE")).chain(args))),
+        (Frame::Array(popped), name) => match popped.as_slice() {
+            [Frame::Bulk(key), _] => {
+                let pop: &'static [u8] = if name == "BLPOP" { b"LPOP" } else { b"RPOP" };
+                Some(Frame::command([Bytes::from_static(pop), key.clone()]))
+            }
+            _ => None,
+        },
+        _ => None,
+    };
+    if let Some(Ok(effect)) = effect.map(Command::from_frame) {
+        shared.replication.propagate(&effect, &reply);
+    }
+    Ok(reply)
+}
+
+fn parse_end(cmd: &Command, i: usize) -> Result<End, String> {
+    End::parse(cmd.arg_str(i)?).ok_or_else(|| "ERR syntax error".to_string())
+}
+
+/// Parses a timeout in seconds (fractions allowed); 0 means wait forever.
+fn parse_timeout(cmd: &Command, i: usize) -> Result<Option<Duration>, String> {
+    let seconds: f64 = cmd
+        .arg_str(i)?
+        .parse()
+        .ok()
+        .filter(|seconds: &f64| !seconds.is_nan())
+        .ok_or_else(|| "ERR timeout is not a float or out of range".to_string())?;
+    if seconds < 0.0 {
+        return Err("ERR timeout is negative".to_string());
+    }
+    if seconds == 0.0 {
+        return Ok(None);
+    }
+    // Note: `from_secs_f64` panics on values a Duration can't hold, e.g. `inf` or `1e300`.
+    Duration::try_from_secs_f64(seconds).map(Some).map_err(|_| "ERR timeout is out of range".to_string())
+}
+
+fn xadd(cmd: &Command, shared: &Shared) -> Result<Frame, String> {
+    // XADD key [NOMKSTREAM] [MAXLEN [=|~] threshold] id field value [field value ...]
+    cmd.check_arity(4, usize::MAX)?;
+    let key = cmd.arg_str(0)?;
+    let mut i = 1;
+    let mut nomkstream = false;
+    let mut maxlen = None;
+    loop {
+        match cmd.arg_str(i)?.to_uppercase().as_str() {
+            "NOMKSTREAM" => {
+                nomkstream = true;
+                i += 1;
+            }
+            "MAXLEN" => {
+                // `~` asks for approximate trimming, which Redis does for efficiency.
+                // We always trim exactly, which is a valid implementation of "approximately".
+                if matches!(cmd.arg_str(i + 1)?, "=" | "~") {
+                    i += 1;
+                }
+                let len = cmd.arg_int(i + 1)?;
+                maxlen = Some(usize::try_from(len).map_err(|_| "ERR The MAXLEN argument must be >= 0.".to_string())?);
+                i += 2;
+            }
+            _ => break,
+        }
+    }
+    let id = NewId::parse(cmd.arg_str(i)?).ok_or_else(invalid_stream_id)?;
+    let pairs = &cmd.args()[i + 1..];
+    if pairs.is_empty() || !pairs.len().is_multiple_of(2) {
+        return Err("ERR wrong number of arguments for 'xadd' command".to_string());
+    }
+    let fields = pairs.chunks(2).map(|pair| (pair[0].clone(), pair[1].clone())).collect();
+
+    match shared.db.xadd(key, id, fields, maxlen, nomkstream).map_err(|e| e.to_string())? {
+        Some(id) => Ok(Frame::Bulk(Bytes::from(id.to_string()))),
+        None => Ok(Frame::Null),
+    }
+}
+
+fn xlen(cmd: &Command, shared: &Shared) -> Result<Frame, String> {
+    cmd.check_arity(1, 1)?;
+    let len = shared.db.xlen(cmd.arg_str(0)?).map_err(|e| e.to_string())?;
+    Ok(Frame::Integer(len as i64))
+}
+
+fn xrange(cmd: &Command, shared: &Shared, rev: bool) -> Result<Frame, String> {
+    // XRANGE key start end [COUNT n] / XREVRANGE key end start [COUNT n]
+    cmd.check_arity(3, 5)?;
+    let (start, end) = if rev { (2, 1) } else { (1, 2) };
+    let start = StreamId::parse_bound(cmd.arg_str(start)?, true).ok_or_else(invalid_stream_id)?;
+    let end = StreamId::parse_bound(cmd.arg_str(end)?, false).ok_or_else(invalid_stream_id)?;
+    let count = match cmd.args().len() {
+        3 => None,
+        5 if cmd.arg_str(3)?.eq_ignore_ascii_case("COUNT") => Some(cmd.arg_int(4)?.max(0) as usize),
+        _ => return Err("ERR syntax error".to_string()),
+    };
+    let entries = shared
+        .db
+        .xrange(cmd.arg_str(0)?, start, end, count, rev)
+        .map_err(|e| e.to_string())?;
+    Ok(entries_frame(entries))
+}
+
+/// The options shared by XREAD and XREADGROUP, which end with `STREAMS key... id...`.
+struct ReadOptions {
+    count: Option<usize>,
+    // `None` doesn't block, `Some(None)` blocks forever.
+    block: Option<Option<Duration>>,
+    noack: bool,
+    keys: Vec<String>,
+    ids: Vec<String>,
+}
+
+impl ReadOptions {
+    fn parse(cmd: &Command, mut i: usize) -> Result<ReadOptions, String> {
+        let mut options = ReadOptions { count: None, block: None, noack: false, keys: vec![], ids: vec![] };
+        loop {
+            match cmd.arg_str(i)?.to_uppercase().as_str() {
+                "COUNT" => {
+                    options.count = Some(cmd.arg_int(i + 1)?.max(0) as usize);
+                    i += 2;
+                }
+                "BLOCK" => {
+                    let ms = cmd.arg_int(i + 1)?;
+                    if ms < 0 {
+                        return Err("ERR timeout is negative".to_string());
+                    }
+                    options.block = Some((ms > 0).then(|| Duration::from_millis(ms as u64)));
+                    i += 2;
+                }
+                "NOACK" if cmd.name() == "XREADGROUP" => {
+                    options.noack = true;
+                    i += 1;
+                }
+                "STREAMS" => {
+                    i += 1;
+                    break;
+                }
+                _ => return Err("ERR syntax error".to_string()),
+            }
+        }
+
+        let rest = cmd.args().len() - i;
+        if rest == 0 || !rest.is_multiple_of(2) {
+            return Err("ERR Unbalanced 'xread' list of streams: for each stream key an ID or '$' must be specified.".to_string());
+        }
+        for j in 0..rest / 2 {
+            options.keys.push(cmd.arg_str(i + j)?.to_string());
+            options.ids.push(cmd.arg_str(i + rest / 2 + j)?.to_string());
+        }
+        Ok(options)
+    }
+}
+
+async fn xread(cmd: &Command, shared: &Shared, connection: &Connection) -> Result<(Frame, Duration), String> {
+    // XREAD [COUNT n] [BLOCK ms] STREAMS key [key ...] id [id ...]
+    let options = ReadOptions::parse(cmd, 0)?;
+    let mut streams = Vec::with_capacity(options.keys.len());
+    for (key, id) in options.keys.iter().zip(&options.ids) {
+        // `$` means "only what is added from now on", so resolve it once, before blocking.
+        let id = match id.as_str() {
+            "$" => shared.db.xlast_id(key).map_err(|e| e.to_string())?,
+            id => StreamId::parse(id, 0).ok_or_else(invalid_stream_id)?,
+        };
+        streams.push((key.clone(), id));
+    }
+
+    let read = || shared.db.xread(&streams, options.count);
+    let (result, blocked) = wait_for_streams(&options, shared, connection, read).await?;
+    Ok((streams_frame(result), blocked))
+}
+
+async fn xreadgroup(cmd: &Command, shared: &Shared, connection: &Connection) -> Result<(Frame, Duration), String> {
+    // XREADGROUP GROUP group consumer [COUNT n] [BLOCK ms] [NOACK] STREAMS key [key ...] id [id ...]
+    cmd.check_arity(6, usize::MAX)?;
+    if !cmd.arg_str(0)?.eq_ignore_ascii_case("GROUP") {
+        return Err("ERR syntax error".to_string());
+    }
+    let (group, consumer) = (cmd.arg_str(1)?, cmd.arg_str(2)?);
+    let options = ReadOptions::parse(cmd, 3)?;
+    let mut streams = Vec::with_capacity(options.keys.len());
+    for (key, id) in options.keys.iter().zip(&options.ids) {
+        let id = match id.as_str() {
+            ">" => GroupReadId::New,
+            id => GroupReadId::After(StreamId::parse(id, 0).ok_or_else(invalid_stream_id)?),
+        };
+        streams.push((key.clone(), id));
+    }
+
+    let read = || shared.db.xreadgroup(group, consumer, &streams, options.count, options.noack);
+    let (result, blocked) = wait_for_streams(&options, shared, connection, read).await?;
+    Ok((streams_frame(result), blocked))
+}
+
+/// Runs `read` until it returns something, waiting for an XADD to one of the keys between
+/// attempts, for as long as the BLOCK option allows.
+async fn wait_for_streams<F>(options: &ReadOptions, shared: &Shared, connection: &Connection, read: F) -> Result<(Vec<(String, Vec<StreamEntry>)>, Duration), String>
+where
+    F: Fn() -> Result<Vec<(String, Vec<StreamEntry>)>, StreamError>,
+{
+    // Each attempt takes the execution lock so it never sees a script half done, but it must
+    // not hold it while waiting. XREADGROUP writes (the pending entries), so it is committed
+    // like any other command.
+    let read = || {
+        let _guard = shared.exec_lock.read().unwrap();
+        let (result, stored) = crate::committing(&read);
+        stored.map_err(|e| format!("ERR storage error: {}", e))?;
+        result.map_err(|e| e.to_string())
+    };
+    let Some(timeout) = options.block else {
+        return Ok((read()?, Duration::ZERO));
+    };
+
+    let start = Instant::now();
+    // Note: how far an Instant reaches depends on the platform, so don't assume BLOCK fits.
+    let deadline = timeout
+        .map(|timeout| tokio::time::Instant::now().checked_add(timeout))
+        .map(|deadline| deadline.ok_or_else(|| "ERR timeout is out of range".to_string()))
+        .transpose()?;
+    // Watch before reading, so an entry added right after the read still wakes us up.
+    let watch = shared.db.watch_streams(&options.keys);
+    loop {
+        let result = read()?;
+        if !result.is_empty() {
+            return Ok((result, start.elapsed()));
+        }
+        let changed = async {
+            match deadline {
+                Some(deadline) => tokio::time::timeout_at(deadline, watch.changed()).await.is_ok(),
+                None => {
+                    watch.changed().await;
+                    true
+                }
+            }
+        };
+        let woken = tokio::select! {
+            woken = changed => woken,
+            _ = connection.closed() => false,
+        };
+        if !woken {
+            return Ok((vec![], start.elapsed()));
+        }
+    }
+}
+
+fn xgroup(cmd: &Command, shared: &Shared) -> Result<Frame, String> {
+    cmd.check_arity(3, 5)?;
+    let (key, group) = (cmd.arg_str(1)?, cmd.arg_str(2)?);
+    match cmd.arg_str(0)?.to_uppercase().as_str() {
+        // XGROUP CREATE key group id|$ [MKSTREAM]
+        "CREATE" => {
+            let id = match cmd.arg_str(3)? {
+                "$" => None,
+                id => Some(StreamId::parse(id, 0).ok_or_else(invalid_stream_id)?),
+            };
+            let mkstream = match cmd.args().get(4) {
+                None => false,
+                Some(_) if cmd.arg_str(4)?.eq_ignore_ascii_case("MKSTREAM") => true,
+                Some(_) => return Err("ERR syntax error".to_string()),
+            };
+            shared.db.xgroup_create(key, group, id, mkstream).map_err(|e| e.to_string())?;
+            Ok(Frame::Simple("OK".to_string()))
+        }
+        // XGROUP DESTROY key group
+        "DESTROY" => {
+            let destroyed = shared.db.xgroup_destroy(key, group).map_err(|e| e.to_string())?;
+            Ok(Frame::Integer(destroyed as i64))
+        }
+        sub => Err(format!("ERR unknown subcommand '{}' for 'xgroup'", sub.to_lowercase())),
+    }
+}
+
+fn xack(cmd: &Command, shared: &Shared) -> Result<Frame, String> {
+    // XACK key group id [id ...]
+    cmd.check_arity(3, usize::MAX)?;
+    let ids = parse_ids(cmd, 2..cmd.args().len())?;
+    let acked = shared
+        .db
+        .xack(cmd.arg_str(0)?, cmd.arg_str(1)?, &ids)
+        .map_err(|e| e.to_string())?;
+    Ok(Frame::Integer(acked as i64))
+}
+
+fn xpending(cmd: &Command, shared: &Shared) -> Result<Frame, String> {
+    // XPENDING key group [[IDLE min-idle-time] start end count [consumer]]
+    cmd.check_arity(2, 8)?;
+    let (key, group) = (cmd.arg_str(0)?, cmd.arg_str(1)?);
+
+    if cmd.args().len() == 2 {
+        let summary = shared.db.xpending_summary(key, group).map_err(|e| e.to_string())?;
+        let (first, last) = match summary.range {
+            Some((first, last)) => (Frame::Bulk(Bytes::from(first.to_string())), Frame::Bulk(Bytes::from(last.to_string()))),
+            None => (Frame::Null, Frame::Null),
+        };
+        let consumers = summary
+            .consumers
+            .into_iter()
+            .map(|(name, count)| Frame::Array(vec![Frame::Bulk(Bytes::from(name)), Frame::Bulk(Bytes::from(count.to_string()))]))
+            .collect();
+        return Ok(Frame::Array(vec![Frame::Integer(summary.count as i64), first, last, Frame::Array(consumers)]));
+    }
+
+    let mut i = 2;
+    let mut min_idle = Duration::ZERO;
+    if cmd.arg_str(i)?.eq_ignore_ascii_case("IDLE") {
+        min_idle = Duration::from_millis(cmd.arg_int(i + 1)?.max(0) as u64);
+        i += 2;
+    }
+    let start = StreamId::parse_bound(cmd.arg_str(i)?, true).ok_or_else(invalid_stream_id)?;
+    let end = StreamId::parse_bound(cmd.arg_str(i + 1)?, false).ok_or_else(invalid_stream_id)?;
+    let count = cmd.arg_int(i + 2)?.max(0) as usize;
+    let consumer = match cmd.args().len() - i {
+        3 => None,
+        4 => Some(cmd.arg_str(i + 3)?.to_string()),
+        _ => return Err("ERR syntax error".to_string()),
+    };
+
+    let filter = PendingFilter { start, end, count, consumer, min_idle };
+    let pending = shared
+        .db
+        .xpending(key, group, &filter)
+        .map_err(|e| e.to_string())?;
+    Ok(Frame::Array(
+        pending
+            .into_iter()
+            .map(|p| {
+                Frame::Array(vec![
+                    Frame::Bulk(Bytes::from(p.id.to_string())),
+                    Frame::Bulk(Bytes::from(p.consumer)),
+                    Frame::Integer(p.idle.as_millis() as i64),
+                    Frame::Integer(p.delivery_count as i64),
+                ])
+            })
+            .collect(),
+    ))
+}
+
+fn xclaim(cmd: &Command, shared: &Shared) -> Result<Frame, String> {
+    // XCLAIM key group consumer min-idle-time id [id ...] [JUSTID]
+    cmd.check_arity(5, usize::MAX)?;
+    let justid = cmd.arg_str(cmd.args().len() - 1)?.eq_ignore_ascii_case("JUSTID");
+    let last_id = if justid { cmd.args().len() - 1 } else { cmd.args().len() };
+    let ids = parse_ids(cmd, 4..last_id)?;
+    let min_idle = Duration::from_millis(cmd.arg_int(3)?.max(0) as u64);
+
+    let claimed = shared
+        .db
+        .xclaim(cmd.arg_str(0)?, cmd.arg_str(1)?, cmd.arg_str(2)?, min_idle, &ids, justid)
+        .map_err(|e| e.to_string())?;
+    if justid {
+        let ids = claimed.into_iter().map(|(id, _)| Frame::Bulk(Bytes::from(id.to_string())));
+        return Ok(Frame::Array(ids.collect()));
+    }
+    Ok(entries_frame(claimed))
+}
+
+fn parse_ids(cmd: &Command, range: std::ops::Range<usize>) -> Result<Vec<StreamId>, String> {
+    range
+        .map(|i| StreamId::parse(cmd.arg_str(i)?, 0).ok_or_else(invalid_stream_id))
+        .collect()
+}
+
+fn invalid_stream_id() -> String {
+    "ERR Invalid stream ID specified as stream command argument".to_string()
+}
+
+/// Encodes entries as `[[id, [field, value, ...]], ...]`.
+fn entries_frame(entries: Vec<StreamEntry>) -> Frame {
+    Frame::Array(
+        entries
+            .into_iter()
+            .map(|(id, fields)| {
+                let fields = fields
+                    .into_iter()
+                    .flat_map(|(field, value)| [Frame::Bulk(field), Frame::Bulk(value)])
+                    .collect();
+                Frame::Array(vec![Frame::Bulk(Bytes::from(id.to_string())), Frame::Array(fields)])
+            })
+            .collect(),
+    )
+}
+
+/// Encodes the reply of XREAD/XREADGROUP as `[[key, entries], ...]`, or nil if there is nothing.
+fn streams_frame(streams: Vec<(String, Vec<StreamEntry>)>) -> Frame {
+    if streams.is_empty() {
+        return Frame::Null;
+    }
+    Frame::Array(
+        streams
+            .into_iter()
+            .map(|(key, entries)| Frame::Array(vec![Frame::Bulk(Bytes::from(key)), entries_frame(entries)]))
+            .collect(),
+    )
+}
+
+fn arg_offset(cmd: &Command, i: usize) -> Result<u64, String> {
+    match u64::try_from(cmd.arg_int(i)?) {
+        Ok(offset) if offset <= bitmap::MAX_BIT_OFFSET => Ok(offset),
+        _ => Err("ERR bit offset is not an integer or out of range".to_string()),
+    }
+}
+
+fn setbit(cmd: &Command, shared: &Shared) -> Result<Frame, String> {
+    // SETBIT key offset 0|1
+    cmd.check_arity(3, 3)?;
+    let bit = match cmd.arg_str(2)? {
+        "0" => false,
+        "1" => true,
+        _ => return Err("ERR bit is not an integer or out of range".to_string()),
+    };
+    let old = shared.db.setbit(cmd.arg_str(0)?, arg_offset(cmd, 1)?, bit).map_err(|e| e.to_string())?;
+    Ok(Frame::Integer(old as i64))
+}
+
+fn getbit(cmd: &Command, shared: &Shared) -> Result<Frame, String> {
+    cmd.check_arity(2, 2)?;
+    let bit = shared.db.getbit(cmd.arg_str(0)?, arg_offset(cmd, 1)?).map_err(|e| e.to_string())?;
+    Ok(Frame::Integer(bit as i64))
+}
+
+fn bitcount(cmd: &Command, shared: &Shared) -> Result<Frame, String> {
+    // BITCOUNT key [start end [BYTE|BIT]]
+    cmd.check_arity(1, 4)?;
+    let range = match cmd.args().len() {
+        1 => None,
+        2 => return Err("ERR syntax error".to_string()),
+        len => {
+            let unit = match len {
+                3 => BitUnit::Byte,
+                _ => match cmd.arg_str(3)?.to_uppercase().as_str() {
+                    "BYTE" => BitUnit::Byte,
+                    "BIT" => BitUnit::Bit,
+                    _ => return Err("ERR syntax error".to_string()),
+                },
+            };
+            Some((cmd.arg_int(1)?, cmd.arg_int(2)?, unit))
+        }
+    };
+    let count = shared.db.bitcount(cmd.arg_str(0)?, range).map_err(|e| e.to_string())?;
+    Ok(Frame::Integer(count as i64))
+}
+
+fn bitop(cmd: &Command, shared: &Shared) -> Result<Frame, String> {
+    // BITOP AND|OR|XOR|NOT destkey key [key ...]
+    cmd.check_arity(3, usize::MAX)?;
+    let op = BitOp::parse(cmd.arg_str(0)?).ok_or("ERR syntax error")?;
+    if op == BitOp::Not && cmd.args().len() != 3 {
+        return Err("ERR BITOP NOT must be called with a single source key.".to_string());
+    }
+    let keys = string_args(cmd, 2..cmd.args().len())?;
+    let len = shared.db.bitop(op, cmd.arg_str(1)?, &keys).map_err(|e| e.to_string())?;
+    Ok(Frame::Integer(len as i64))
+}
+
+fn pfadd(cmd: &Command, shared: &Shared) -> Result<Frame, String> {
+    // PFADD key [element ...]
+    cmd.check_arity(1, usize::MAX)?;
+    let changed = shared.db.pfadd(cmd.arg_str(0)?, &cmd.args()[1..]).map_err(|e| e.to_string())?;
+    Ok(Frame::Integer(changed as i64))
+}
+
+fn pfcount(cmd: &Command, shared: &Shared) -> Result<Frame, String> {
+    // PFCOUNT key [key ...]
+    cmd.check_arity(1, usize::MAX)?;
+    let keys = string_args(cmd, 0..cmd.args().len())?;
+    let count = shared.db.pfcount(&keys).map_err(|e| e.to_string())?;
+    Ok(Frame::Integer(count as i64))
+}
+
+fn pfmerge(cmd: &Command, shared: &Shared) -> Result<Frame, String> {
+    // PFMERGE destkey [sourcekey ...]
+    cmd.check_arity(1, usize::MAX)?;
+    let sources = string_args(cmd, 1..cmd.args().len())?;
+    shared.db.pfmerge(cmd.arg_str(0)?, &sources).map_err(|e| e.to_string())?;
+    Ok(Frame::Simple("OK".to_string()))
+}
+
+/// Parses the `[NX|XX] [CH]` options of ZADD and GEOADD, starting at argument `i`.
+/// Returns them with the index of the first argument after them.
+fn parse_update(cmd: &Command, mut i: usize) -> Result<(Update, bool, usize), String> {
+    let (mut update, mut ch) = (Update::Always, false);
+    while let Some(arg) = cmd.args().get(i) {
+        let option = match arg.to_ascii_uppercase().as_slice() {
+            b"NX" => Update::OnlyNew,
+            b"XX" => Update::OnlyExisting,
+            b"CH" => {
+                ch = true;
+                i += 1;
+                continue;
+            }
+            _ => break,
+        };
+        if update != Update::Always && update != option {
+            return Err("ERR XX and NX options at the same time are not compatible".to_string());
+        }
+        update = option;
+        i += 1;
+    }
+    Ok((update, ch, i))
+}
+
+/// The reply of ZADD/GEOADD: the number of added members, plus the updated ones with CH.
+fn added_frame(added: Added, ch: bool) -> Frame {
+    Frame::Integer((added.added + if ch { added.updated } else { 0 }) as i64)
+}
+
+fn zadd(cmd: &Command, shared: &Shared) -> Result<Frame, String> {
+    // ZADD key [NX|XX] [CH] score member [score member ...]
+    cmd.check_arity(3, usize::MAX)?;
+    let (update, ch, first) = parse_update(cmd, 1)?;
+    let pairs = cmd.args().len() - first;
+    if pairs == 0 || !pairs.is_multiple_of(2) {
+        return Err("ERR syntax error".to_string());
+    }
+    let mut members = Vec::with_capacity(pairs / 2);
+    for i in (first..cmd.args().len()).step_by(2) {
+        members.push((cmd.arg_float(i)?, cmd.args()[i + 1].clone()));
+    }
+    let added = shared.db.zadd(cmd.arg_str(0)?, &members, update).map_err(|e| e.to_string())?;
+    Ok(added_frame(added, ch))
+}
+
+fn zscore(cmd: &Command, shared: &Shared) -> Result<Frame, String> {
+    cmd.check_arity(2, 2)?;
+    match shared.db.zscore(cmd.arg_str(0)?, &cmd.args()[1]).map_err(|e| e.to_string())? {
+        Some(score) => Ok(Frame::Bulk(Bytes::from(zset::format_score(score)))),
+        None => Ok(Frame::Null),
+    }
+}
+
+fn zrem(cmd: &Command, shared: &Shared) -> Result<Frame, String> {
+    // ZREM key member [member ...]
+    cmd.check_arity(2, usize::MAX)?;
+    let removed = shared.db.zrem(cmd.arg_str(0)?, &cmd.args()[1..]).map_err(|e| e.to_string())?;
+    Ok(Frame::Integer(removed as i64))
+}
+
+fn zcard(cmd: &Command, shared: &Shared) -> Result<Frame, String> {
+    cmd.check_arity(1, 1)?;
+    let len = shared.db.zcard(cmd.arg_str(0)?).map_err(|e| e.to_string())?;
+    Ok(Frame::Integer(len as i64))
+}
+
+fn zrange(cmd: &Command, shared: &Shared) -> Result<Frame, String> {
+    // ZRANGE key start stop [WITHSCORES]
+    cmd.check_arity(3, 4)?;
+    let withscores = match cmd.args().get(3) {
+        None => false,
+        Some(_) if cmd.arg_str(3)?.eq_ignore_ascii_case("WITHSCORES") => true,
+        Some(_) => return Err("ERR syntax error".to_string()),
+    };
+    let range = shared
+        .db
+        .zrange(cmd.arg_str(0)?, cmd.arg_int(1)?, cmd.arg_int(2)?)
+        .map_err(|e| e.to_string())?;
+    let mut frames = Vec::new();
+    for (member, score) in range {
+        frames.push(Frame::Bulk(member));
+        if withscores {
+            frames.push(Frame::Bulk(Bytes::from(zset::format_score(score))));
+        }
+    }
+    Ok(Frame::Array(frames))
+}
+
+fn geoadd(cmd: &Command, shared: &Shared) -> Result<Frame, String> {
+    // GEOADD key [NX|XX] [CH] longitude latitude member [longitude latitude member ...]
+    cmd.check_arity(4, usize::MAX)?;
+    let (update, ch, first) = parse_update(cmd, 1)?;
+    let triples = cmd.args().len() - first;
+    if triples == 0 || !triples.is_multiple_of(3) {
+        return Err("ERR syntax error".to_string());
+    }
+    let mut members = Vec::with_capacity(triples / 3);
+    for i in (first..cmd.args().len()).step_by(3) {
+        let (lon, lat) = (cmd.arg_float(i)?, cmd.arg_float(i + 1)?);
+        if !geo::is_valid(lon, lat) {
+            return Err(format!("ERR invalid longitude,latitude pair {:.6},{:.6}", lon, lat));
+        }
+        members.push((lon, lat, cmd.args()[i + 2].clone()));
+    }
+    let added = shared.db.geoadd(cmd.arg_str(0)?, &members, update).map_err(|e| e.to_string())?;
+    Ok(added_frame(added, ch))
+}
+
+fn parse_unit(cmd: &Command, i: usize) -> Result<Unit, String> {
+    Unit::parse(cmd.arg_str(i)?).ok_or_else(|| "ERR unsupported unit provided. please use M, KM, FT, MI".to_string())
+}
+
+/// Formats a distance like Redis, with 4 decimals.
+fn distance_frame(meters: f64, unit: Unit) -> Frame {
+    Frame::Bulk(Bytes::from(format!("{:.4}", meters / unit.meters())))
+}
+
+fn geodist(cmd: &Command, shared: &Shared) -> Result<Frame, String> {
+    // GEODIST key member1 member2 [M|KM|FT|MI]
+    cmd.check_arity(3, 4)?;
+    let unit = match cmd.args().len() {
+        4 => parse_unit(cmd, 3)?,
+        _ => Unit::Meters,
+    };
+    let meters = shared
+        .db
+        .geodist(cmd.arg_str(0)?, &cmd.args()[1], &cmd.args()[2])
+        .map_err(|e| e.to_string())?;
+    Ok(meters.map_or(Frame::Null, |meters| distance_frame(meters, unit)))
+}
+
+fn geosearch(cmd: &Command, shared: &Shared) -> Result<Frame, String> {
+    // GEOSEARCH key FROMMEMBER member | FROMLONLAT longitude latitude
+    //   BYRADIUS radius unit | BYBOX width height unit
+    //   [ASC|DESC] [COUNT count [ANY]] [WITHCOORD] [WITHDIST] [WITHHASH]
+    cmd.check_arity(5, usize::MAX)?;
+    let (mut center, mut shape, mut unit) = (None, None, Unit::Meters);
+    let (mut ascending, mut count) = (None, None);
+    let (mut withcoord, mut withdist, mut withhash) = (false, false, false);
+    let mut i = 1;
+    while i < cmd.args().len() {
+        match cmd.arg_str(i)?.to_uppercase().as_str() {
+            "FROMMEMBER" if center.is_none() => {
+                center = Some(Center::Member(cmd.args().get(i + 1).ok_or("ERR syntax error")?.clone()));
+                i += 2;
+            }
+            "FROMLONLAT" if center.is_none() => {
+                let (lon, lat) = (cmd.arg_float(i + 1)?, cmd.arg_float(i + 2)?);
+                if !geo::is_valid(lon, lat) {
+                    return Err(format!("ERR invalid longitude,latitude pair {:.6},{:.6}", lon, lat));
+                }
+                center = Some(Center::Position(lon, lat));
+                i += 3;
+            }
+            "FROMMEMBER" | "FROMLONLAT" => {
+                return Err("ERR exactly one of FROMMEMBER or FROMLONLAT can be specified for GEOSEARCH".to_string())
+            }
+            "BYRADIUS" if shape.is_none() => {
+                let radius = cmd.arg_float(i + 1)?;
+                unit = parse_unit(cmd, i + 2)?;
+                if radius < 0.0 {
+                    return Err("ERR radius cannot be negative".to_string());
+                }
+                shape = Some(Shape::Radius(radius * unit.meters()));
+                i += 3;
+            }
+            "BYBOX" if shape.is_none() => {
+                let (width, height) = (cmd.arg_float(i + 1)?, cmd.arg_float(i + 2)?);
+                unit = parse_unit(cmd, i + 3)?;
+                if width < 0.0 || height < 0.0 {
+                    return Err("ERR height or width cannot be negative".to_string());
+                }
+                shape = Some(Shape::Box { width: width * unit.meters(), height: height * unit.meters() });
+                i += 4;
+            }
+            "BYRADIUS" | "BYBOX" => {
+                return Err("ERR exactly one of BYRADIUS and BYBOX can be specified for GEOSEARCH".to_string())
+            }
+            "ASC" => {
+                ascending = Some(true);
+                i += 1;
+            }
+            "DESC" => {
+                ascending = Some(false);
+                i += 1;
+            }
+            "COUNT" => {
+                let n = usize::try_from(cmd.arg_int(i + 1)?).ok().filter(|n| *n > 0).ok_or("ERR COUNT must be > 0")?;
+                let any = cmd.args().get(i + 2).is_some_and(|arg| arg.eq_ignore_ascii_case(b"ANY"));
+                count = Some((n, any));
+                i += if any { 3 } else { 2 };
+            }
+            "ANY" => return Err("ERR the ANY argument requires COUNT argument".to_string()),
+            "WITHCOORD" => (withcoord, i) = (true, i + 1),
+            "WITHDIST" => (withdist, i) = (true, i + 1),
+            "WITHHASH" => (withhash, i) = (true, i + 1),
+            _ => return Err("ERR syntax error".to_string()),
+        }
+    }
+    let center = center.ok_or("ERR exactly one of FROMMEMBER or FROMLONLAT can be specified for GEOSEARCH")?;
+    let shape = shape.ok_or("ERR exactly one of BYRADIUS and BYBOX can be specified for GEOSEARCH")?;
+
+    let search = Search { center, shape, ascending, count };
+    let matches = shared.db.geosearch(cmd.arg_str(0)?, &search).map_err(|e| e.to_string())?;
+    let frames = matches.into_iter().map(|m| {
+        if !(withcoord || withdist || withhash) {
+            return Frame::Bulk(m.member);
+        }
+        // [member, distance?, hash?, [longitude, latitude]?], in this order like Redis
+        let mut item = vec![Frame::Bulk(m.member)];
+        if withdist {
+            item.push(distance_frame(m.distance, unit));
+        }
+        if withhash {
+            item.push(Frame::Integer(m.hash as i64));
+        }
+        if withcoord {
+            let coordinate = |value: f64| Frame::Bulk(Bytes::from(value.to_string()));
+            item.push(Frame::Array(vec![coordinate(m.lon), coordinate(m.lat)]));
+        }
+        Frame::Array(item)
+    });
+    Ok(Frame::Array(frames.collect()))
+}
+
+/// Arguments in `range` as owned strings, e.g. a list of keys.
+fn string_args(cmd: &Command, range: std::ops::Range<usize>) -> Result<Vec<String>, String> {
+    range.map(|i| cmd.arg_str(i).map(str::to_string)).collect()
+}
@@ -0,0 +1,774 @@
+//! A disk-backed `StorageBackend` in the style of Bitcask (the storage engine of Riak).
+//!
+//! - Every write appends a record to the active data file, nothing is ever changed in place.
+//!   A delete appends a tombstone.
+//! - The key directory (`keydir`), in memory, maps every key to the position of its latest
+//!   record, so a read is one seek. Only keys live in memory, values stay on disk.
+//! - Data files are numbered (`1.log`, `2.log`, ...). When the active one reaches
+//!   `max_file_size`, a new one is started.
+//! - Overwritten and deleted values are dead bytes. Once there are enough of them, a merge
+//!   copies the live records to a new file and deletes the old ones. The merge also writes a
+//!   hint file (`N.hint`) listing the keys and positions of the new file, so the next start
+//!   reads that instead of scanning the data.
+//!
+//! Record layout, integers in little endian:
+//!
+//! ```text
+//! | crc32 (4) | key length (4) | value length (4) | expires at (8) | key | value |
+//! ```
+//!
+//! The checksum covers everything after it. A tombstone has a value length of `u32::MAX`
+//! and no value. Values are encoded with `storage::codec`. The expiry is in milliseconds
+//! since the unix epoch, 0 if the key doesn't expire.
+//!
+//! Why a unix time and not what's left of the time to live?
+//! The shard counts with `Instant`s, which mean nothing after a restart. A key that expired
+//! while the server was down is dropped when the files are read, like a deleted one.
+
+use super::codec::{self, invalid};
+use super::StorageBackend;
+use crate::Value;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const HEADER_LEN: usize = 20;
+const TOMBSTONE: u32 = u32::MAX;
+
+/// Options of a `Bitcask` store.
+#[derive(Debug, Clone)]
+pub struct BitcaskConfig {
+    /// Size after which the active data file is closed and a new one started.
+    pub max_file_size: u64,
+    /// Whether every sync waits for the data to reach the disk (`fsync`). Without it, the data
+    /// is handed to the OS at the end of every command, which survives a crash of the process
+    /// but not of the machine (like `appendfsync no` in Redis).
+    pub sync_writes: bool,
+    /// Number of dead bytes that triggers a merge.
+    pub compaction_threshold: u64,
+}
+
+impl Default for BitcaskConfig {
+    fn default() -> BitcaskConfig {
+        BitcaskConfig { max_file_size: 64 * 1024 * 1024, sync_writes: false, compaction_threshold: 16 * 1024 * 1024 }
+    }
+}
+
+/// Where the latest record of a key is.
+#[derive(Debug, Clone, Copy)]
+struct Location {
+    file_id: u64,
+    offset: u64,
+    // length of the whole record, header included
+    len: u32,
+}
+
+/// A log-structured store in one directory. See the module documentation.
+pub struct Bitcask {
+    dir: PathBuf,
+    config: BitcaskConfig,
+    keydir: HashMap<String, Location>,
+    // one read handle per data file, the active one included
+    readers: HashMap<u64, File>,
+    writer: BufWriter<File>,
+    active: u64,
+    active_size: u64,
+    dead_bytes: u64,
+    // Values read or written by the current command, dropped by `sync`.
+    cache: HashMap<String, Value>,
+    // Keys to write on `sync`: the value in `cache`, or a tombstone if it's not there.
+    dirty: HashSet<String>,
+    // The first error that happened since the last `sync`.
+    error: Option<io::Error>,
+    // When the keys with a time to live expire, in unix milliseconds. Written with the value.
+    expiries: HashMap<String, u64>,
+}
+
+impl Bitcask {
+    /// Opens the store in `dir`, creating the directory if needed, and rebuilds the key
+    /// directory from the hint and data files.
+    ///
+    /// A record cut short at the end of the newest data file (the process died while writing
+    /// it) is dropped. Any other damage is an error.
+    pub fn open(dir: impl Into<PathBuf>, config: BitcaskConfig) -> io::Result<Bitcask> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+
+        let generations = generations(&dir)?;
+        let mut keys = Keys { keydir: HashMap::new(), expiries: HashMap::new(), dead_bytes: 0, now: unix_millis() };
+        let mut readers = HashMap::new();
+        for (i, &generation) in generations.iter().enumerate() {
+            let hint = hint_path(&dir, generation);
+            if hint.exists() {
+                load_hint(&hint, generation, &mut keys)?;
+            } else {
+                let is_newest = i + 1 == generations.len();
+                load_log(&log_path(&dir, generation), generation, is_newest, &mut keys)?;
+            }
+            readers.insert(generation, File::open(log_path(&dir, generation))?);
+        }
+
+        // Why not append to the newest file?
+        // Starting a new one is simpler, and never mixes records of two runs in one file.
+        // Merges get rid of the small files this leaves behind.
+        let active = generations.last().map_or(1, |generation| generation + 1);
+        let writer = new_log(&dir, active)?;
+        readers.insert(active, File::open(log_path(&dir, active))?);
+
+        Ok(Bitcask {
+            dir,
+            config,
+            keydir: keys.keydir,
+            readers,
+            writer,
+            active,
+            active_size: 0,
+            dead_bytes: keys.dead_bytes,
+            cache: HashMap::new(),
+            dirty: HashSet::new(),
+            error: None,
+            expiries: keys.expiries,
+        })
+    }
+
+    /// Bytes of the data files that belong to overwritten or deleted values.
+    pub fn dead_bytes(&self) -> u64 {
+        self.dead_bytes
+    }
+
+    /// Brings the value of `key` into the cache, if it's stored and not there already.
+    fn load(&mut self, key: &str) {
+        if self.cache.contains_key(key) || self.dirty.contains(key) {
+            return;
+        }
+        let Some(&location) = self.keydir.get(key) else {
+            return;
+        };
+        let value = self.read_record(location).and_then(|record| match parse_record(&record)? {
+            Some(Record { value: Some(value), .. }) => codec::decode_value(value),
+            _ => Err(invalid(format!("no value at {:?}", location))),
+        });
+        match value {
+            Ok(value) => {
+                self.cache.insert(key.to_string(), value);
+            }
+            Err(e) => {
+                self.error.get_or_insert(e);
+            }
+        }
+    }
+
+    fn read_record(&mut self, location: Location) -> io::Result<Vec<u8>> {
+        let file = self.readers.get_mut(&location.file_id).ok_or_else(|| invalid("missing data file"))?;
+        file.seek(SeekFrom::Start(location.offset))?;
+        let mut record = vec![0; location.len as usize];
+        file.read_exact(&mut record)?;
+        Ok(record)
+    }
+
+    /// Appends a record to the active file, starting a new file if it's full.
+    fn append(&mut self, key: &str, value: Option<&[u8]>) -> io::Result<Location> {
+        let expires_at = value.and(self.expiries.get(key).copied());
+        let record = encode_record(key, value, expires_at)?;
+        self.writer.write_all(&record)?;
+        let location = Location { file_id: self.active, offset: self.active_size, len: record.len() as u32 };
+        self.active_size += record.len() as u64;
+        if self.active_size >= self.config.max_file_size {
+            self.writer.flush()?;
+            self.active += 1;
+            self.active_size = 0;
+            self.writer = new_log(&self.dir, self.active)?;
+            self.readers.insert(self.active, File::open(log_path(&self.dir, self.active))?);
+        }
+        Ok(location)
+    }
+
+    /// Writes the dirty keys to the active file.
+    ///
+    /// A key stays dirty until its record is written, so after an error the next call retries
+    /// what is left.
+    fn write_dirty(&mut self) -> io::Result<()> {
+        let mut dirty: Vec<String> = self.dirty.iter().cloned().collect();
+        // Same input, same file: makes the layout reproducible.
+        dirty.sort();
+        for key in dirty {
+            match self.cache.get(&key).map(codec::encode_value) {
+                Some(value) => {
+                    let location = self.append(&key, Some(&value))?;
+                    if let Some(old) = self.keydir.insert(key.clone(), location) {
+                        self.dead_bytes += u64::from(old.len);
+                    }
+                }
+                None => {
+                    if let Some(&old) = self.keydir.get(&key) {
+                        let tombstone = self.append(&key, None)?;
+                        self.keydir.remove(&key);
+                        self.dead_bytes += u64::from(old.len) + u64::from(tombstone.len);
+                    }
+                }
+            }
+            self.dirty.remove(&key);
+        }
+        self.writer.flush()?;
+        if self.config.sync_writes {
+            self.writer.get_ref().sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// Copies the live records to a new data file with its hint file, then deletes the older
+    /// files.
+    ///
+    /// The new file gets the number after the active one, and writes continue in the one after
+    /// that. If the process dies in the middle, the next start reads the old files and then
+    /// the merged one, whose records win since they come last: nothing is lost.
+    fn merge(&mut self) -> io::Result<()> {
+        let merged = self.active + 1;
+        let mut writer = new_log(&self.dir, merged)?;
+        let mut hint = Vec::new();
+        let mut keydir = HashMap::with_capacity(self.keydir.len());
+        let mut offset = 0;
+        let live: Vec<(String, Location)> = self.keydir.iter().map(|(k, l)| (k.clone(), *l)).collect();
+        for (key, location) in live {
+            writer.write_all(&self.read_record(location)?)?;
+            let location = Location { file_id: merged, offset, len: location.len };
+            hint.extend_from_slice(&(key.len() as u32).to_le_bytes());
+            hint.extend_from_slice(key.as_bytes());
+            hint.extend_from_slice(&location.offset.to_le_bytes());
+            hint.extend_from_slice(&location.len.to_le_bytes());
+            hint.extend_from_slice(&self.expiries.get(&key).copied().unwrap_or(0).to_le_bytes());
+            keydir.insert(key, location);
+            offset += u64::from(location.len);
+        }
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+
+        // Written under another name and renamed, so a hint file is always complete.
+        let tmp = self.dir.join(format!("{}.hint.tmp", merged));
+        let mut file = File::create(&tmp)?;
+        file.write_all(&hint)?;
+        file.sync_all()?;
+        fs::rename(&tmp, hint_path(&self.dir, merged))?;
+
+        let mut old: Vec<u64> = self.readers.keys().copied().collect();
+        old.sort();
+        self.readers.insert(merged, File::open(log_path(&self.dir, merged))?);
+        self.keydir = keydir;
+        self.active = merged + 1;
+        self.active_size = 0;
+        self.writer = new_log(&self.dir, self.active)?;
+        self.readers.insert(self.active, File::open(log_path(&self.dir, self.active))?);
+        self.dead_bytes = 0;
+
+        // Oldest first: if we stop half way, what's left is still a valid sequence of files.
+        for generation in old {
+            self.readers.remove(&generation);
+            fs::remove_file(log_path(&self.dir, generation))?;
+            match fs::remove_file(hint_path(&self.dir, generation)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+impl StorageBackend for Bitcask {
+    fn get(&mut self, key: &str) -> Option<&Value> {
+        self.load(key);
+        self.cache.get(key)
+    }
+
+    fn get_mut(&mut self, key: &str) -> Option<&mut Value> {
+        self.load(key);
+        if self.cache.contains_key(key) {
+            self.dirty.insert(key.to_string());
+        }
+        self.cache.get_mut(key)
+    }
+
+    fn insert(&mut self, key: String, value: Value) {
+        self.dirty.insert(key.clone());
+        self.cache.insert(key, value);
+    }
+
+    fn remove(&mut self, key: &str) -> bool {
+        let existed = self.contains_key(key);
+        if existed {
+            self.cache.remove(key);
+            self.dirty.insert(key.to_string());
+        }
+        self.expiries.remove(key);
+        existed
+    }
+
+    fn contains_key(&self, key: &str) -> bool {
+        // A dirty key missing from the cache is a pending delete.
+        self.cache.contains_key(key) || (self.keydir.contains_key(key) && !self.dirty.contains(key))
+    }
+
+    fn len(&self) -> usize {
+        let added = self.cache.keys().filter(|key| !self.keydir.contains_key(*key)).count();
+        let removed = self
+            .dirty
+            .iter()
+            .filter(|key| !self.cache.contains_key(*key) && self.keydir.contains_key(*key))
+            .count();
+        self.keydir.len() + added - removed
+    }
+
+    fn keys(&self) -> Vec<String> {
+        let stored = self.keydir.keys().filter(|key| !self.dirty.contains(*key));
+        let changed = self.cache.keys();
+        let mut keys: Vec<String> = stored.chain(changed).cloned().collect();
+        // A key can be both on disk and changed in the cache.
+        keys.sort_unstable();
+        keys.dedup();
+        keys
+    }
+
+    fn used_memory(&self) -> usize {
+        // The record without its header is the key and the encoded value.
+        self.keydir.values().map(|location| location.len as usize - HEADER_LEN).sum()
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        let written = self.write_dirty();
+        // The values not written yet are only in the cache: keep them for the next try.
+        self.cache.retain(|key, _| self.dirty.contains(key));
+        written?;
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        if self.dead_bytes >= self.config.compaction_threshold {
+            self.merge()?;
+        }
+        Ok(())
+    }
+
+    fn compact(&mut self) -> io::Result<()> {
+        self.write_dirty()?;
+        self.merge()
+    }
+
+    fn set_expiry(&mut self, key: &str, expires_at: Option<u64>) {
+        let changed = match expires_at {
+            Some(at) => self.expiries.insert(key.to_string(), at) != Some(at),
+            None => self.expiries.remove(key).is_some(),
+        };
+        // The expiry is part of the record, so the value is written again.
+        if changed {
+            self.load(key);
+            if self.cache.contains_key(key) {
+                self.dirty.insert(key.to_string());
+            }
+        }
+    }
+
+    fn expiries(&self) -> Vec<(String, u64)> {
+        self.expiries.iter().map(|(key, at)| (key.clone(), *at)).collect()
+    }
+}
+
+/// Opens one `Bitcask` per shard, in `dir/shard-00`, `dir/shard-01`, ...
+///
+/// Which shard a key goes to depends on the number of shards, so it is written to
+/// `dir/META` the first time and must be the same on the next starts.
+pub fn open_shards(dir: &Path, num_shards: usize, config: &BitcaskConfig) -> io::Result<Vec<Box<dyn StorageBackend>>> {
+    fs::create_dir_all(dir)?;
+    let meta = dir.join("META");
+    match fs::read_to_string(&meta) {
+        Ok(content) => {
+            let existing = content.trim().strip_prefix("shards ").and_then(|n| n.parse::<usize>().ok());
+            if existing != Some(num_shards) {
+                return Err(invalid(format!("{} was created for another number of shards: {}", dir.display(), content.trim())));
+            }
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => fs::write(&meta, format!("shards {}\n", num_shards))?,
+        Err(e) => return Err(e),
+    }
+
+    let mut shards: Vec<Box<dyn StorageBackend>> = Vec::with_capacity(num_shards);
+    for i in 0..num_shards {
+        shards.push(Box::new(Bitcask::open(dir.join(format!("shard-{:02}", i)), config.clone())?));
+    }
+    Ok(shards)
+}
+
+struct Record<'a> {
+    key: &'a [u8],
+    // `None` for a tombstone
+    value: Option<&'a [u8]>,
+    expires_at: Option<u64>,
+    len: usize,
+}
+
+fn encode_record(key: &str, value: Option<&[u8]>, expires_at: Option<u64>) -> io::Result<Vec<u8>> {
+    let too_large = || invalid("value too large for a record");
+    let value_len = match value {
+        Some(value) => u32::try_from(value.len()).ok().filter(|len| *len != TOMBSTONE).ok_or_else(too_large)?,
+        None => TOMBSTONE,
+    };
+    let key_len = u32::try_from(key.len()).map_err(|_| too_large())?;
+
+    let mut record = Vec::with_capacity(HEADER_LEN + key.len() + value.map_or(0, <[u8]>::len));
+    record.extend_from_slice(&[0; 4]);
+    record.extend_from_slice(&key_len.to_le_bytes());
+    record.extend_from_slice(&value_len.to_le_bytes());
+    record.extend_from_slice(&expires_at.unwrap_or(0).to_le_bytes());
+    record.extend_from_slice(key.as_bytes());
+    record.extend_from_slice(value.unwrap_or_default());
+    let crc = crc32fast::hash(&record[4..]);
+    record[..4].copy_from_slice(&crc.to_le_bytes());
+    Ok(record)
+}
+
+/// Parses the record at the start of `data`. `None` if `data` ends before the record does.
+fn parse_record(data: &[u8]) -> io::Result<Option<Record<'_>>> {
+    if data.len() < HEADER_LEN {
+        return Ok(None);
+    }
+    let u32_at = |at: usize| u32::from_le_bytes(data[at..at + 4].try_into().unwrap());
+    let key_len = u32_at(4) as usize;
+    let value_len = match u32_at(8) {
+        TOMBSTONE => None,
+        len => Some(len as usize),
+    };
+    let len = HEADER_LEN + key_len + value_len.unwrap_or(0);
+    if data.len() < len {
+        return Ok(None);
+    }
+    if crc32fast::hash(&data[4..len]) != u32_at(0) {
+        return Err(invalid("record checksum mismatch"));
+    }
+    let key = &data[HEADER_LEN..HEADER_LEN + key_len];
+    let value = value_len.map(|_| &data[HEADER_LEN + key_len..len]);
+    let expires_at = Some(u64::from_le_bytes(data[12..20].try_into().unwrap())).filter(|at| *at != 0);
+    Ok(Some(Record { key, value, expires_at, len }))
+}
+
+/// What `open` rebuilds from the files.
+struct Keys {
+    keydir: HashMap<String, Location>,
+    expiries: HashMap<String, u64>,
+    dead_bytes: u64,
+    // in unix milliseconds, to tell which records expired while the server was down
+    now: u64,
+}
+
+impl Keys {
+    /// Makes `location` the latest record of `key`, or deletes the key if the record is a
+    /// tombstone or expired.
+    fn apply(&mut self, key: String, location: Location, live: bool, expires_at: Option<u64>) {
+        let expired = expires_at.is_some_and(|at| at <= self.now);
+        let old = if live && !expired {
+            match expires_at {
+                Some(at) => self.expiries.insert(key.clone(), at),
+                None => self.expiries.remove(&key),
+            };
+            self.keydir.insert(key, location)
+        } else {
+            self.dead_bytes += u64::from(location.len);
+            self.expiries.remove(&key);
+            self.keydir.remove(&key)
+        };
+        if let Some(old) = old {
+            self.dead_bytes += u64::from(old.len);
+        }
+    }
+}
+
+/// Replays a data file into the key directory.
+fn load_log(path: &Path, file_id: u64, is_newest: bool, keys: &mut Keys) -> io::Result<()> {
+    let data = fs::read(path)?;
+    let mut offset = 0;
+    while let Some(record) = parse_record(&data[offset..])? {
+        let key = String::from_utf8(record.key.to_vec()).map_err(|_| invalid("key is not UTF-8"))?;
+        let location = Location { file_id, offset: offset as u64, len: record.len as u32 };
+        keys.apply(key, location, record.value.is_some(), record.expires_at);
+        offset += record.len;
+    }
+
+    if offset < data.len() {
+        if !is_newest {
+            return Err(invalid(format!("{} ends with an incomplete record", path.display())));
+        }
+        // The process died while writing the last record, which then never happened.
+        eprintln!("{}: dropping an incomplete record of {} bytes", path.display(), data.len() - offset);
+        OpenOptions::new().write(true).open(path)?.set_len(offset as u64)?;
+    }
+    Ok(())
+}
+
+/// Loads a hint file: for every key,
+/// `| key length (4) | key | offset (8) | record length (4) | expires at (8) |`.
+fn load_hint(path: &Path, file_id: u64, keys: &mut Keys) -> io::Result<()> {
+    let data = fs::read(path)?;
+    let mut rest = &data[..];
+    let mut take = |n: usize| -> io::Result<&[u8]> {
+        if rest.len() < n {
+            return Err(invalid(format!("{} is truncated", path.display())));
+        }
+        let (taken, remaining) = rest.split_at(n);
+        rest = remaining;
+        Ok(taken)
+    };
+    let mut remaining = data.len();
+    while remaining > 0 {
+        let key_len = u32::from_le_bytes(take(4)?.try_into().unwrap()) as usize;
+        let key = String::from_utf8(take(key_len)?.to_vec()).map_err(|_| invalid("key is not UTF-8"))?;
+        let offset = u64::from_le_bytes(take(8)?.try_into().unwrap());
+        let len = u32::from_le_bytes(take(4)?.try_into().unwrap());
+        let expires_at = Some(u64::from_le_bytes(take(8)?.try_into().unwrap())).filter(|at| *at != 0);
+        keys.apply(key, Location { file_id, offset, len }, true, expires_at);
+        remaining -= 24 + key_len;
+    }
+    Ok(())
+}
+
+/// The numbers of the data files in `dir`, oldest first.
+fn generations(dir: &Path) -> io::Result<Vec<u64>> {
+    let mut generations = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "log") {
+            if let Some(generation) = path.file_stem().and_then(|s| s.to_str()).and_then(|s| s.parse().ok()) {
+                generations.push(generation);
+            }
+        }
+    }
+    generations.sort_unstable();
+    Ok(generations)
+}
+
+fn log_path(dir: &Path, generation: u64) -> PathBuf {
+    dir.join(format!("{}.log", generation))
+}
+
+fn hint_path(dir: &Path, generation: u64) -> PathBuf {
+    dir.join(format!("{}.hint", generation))
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64)
+}
+
+fn new_log(dir: &Path, generation: u64) -> io::Result<BufWriter<File>> {
+    let file = OpenOptions::new().create(true).append(true).open(log_path(dir, generation))?;
+    Ok(BufWriter::new(file))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ShardedDatabase;
+    use bytes::Bytes;
+    use std::collections::VecDeque;
+
+    /// An empty directory for one test, under the system temp dir.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("my_redis-bitcask-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn string(s: &str) -> Value {
+        Value::String(Bytes::copy_from_slice(s.as_bytes()))
+    }
+
+    fn logs(dir: &Path) -> Vec<u64> {
+        generations(dir).unwrap()
+    }
+
+    #[test]
+    fn test_reopen_keeps_writes_and_deletes() {
+        let dir = temp_dir("reopen");
+        let mut store = Bitcask::open(&dir, BitcaskConfig::default()).unwrap();
+        store.insert("a".to_string(), string("1"));
+        store.insert("b".to_string(), string("2"));
+        store.sync().unwrap();
+        assert!(store.remove("b"));
+        assert!(!store.contains_key("b"));
+        assert_eq!(store.len(), 1);
+        store.insert("c".to_string(), string("3"));
+        assert_eq!(store.keys(), vec!["a", "c"]);
+        assert!(store.remove("c"));
+        store.sync().unwrap();
+        // changed in place, the way LPUSH does it
+        store.insert("list".to_string(), Value::List(VecDeque::from([Bytes::from("w")])));
+        store.sync().unwrap();
+        let Some(Value::List(list)) = store.get_mut("list") else { panic!("not a list") };
+        list.push_back(Bytes::from("x"));
+        store.sync().unwrap();
+        drop(store);
+
+        let mut store = Bitcask::open(&dir, BitcaskConfig::default()).unwrap();
+        assert_eq!(store.len(), 2);
+        assert_eq!(store.get("a"), Some(&string("1")));
+        assert_eq!(store.get("b"), None);
+        assert_eq!(store.get("list"), Some(&Value::List(VecDeque::from([Bytes::from("w"), Bytes::from("x")]))));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_incomplete_last_record_is_dropped() {
+        let dir = temp_dir("torn");
+        let mut store = Bitcask::open(&dir, BitcaskConfig::default()).unwrap();
+        store.insert("kept".to_string(), string("value"));
+        store.sync().unwrap();
+        store.insert("torn".to_string(), string("value"));
+        store.sync().unwrap();
+        drop(store);
+
+        // Cut the last record short, as if the process died while writing it.
+        let path = log_path(&dir, 1);
+        let len = fs::metadata(&path).unwrap().len();
+        OpenOptions::new().write(true).open(&path).unwrap().set_len(len - 3).unwrap();
+
+        let mut store = Bitcask::open(&dir, BitcaskConfig::default()).unwrap();
+        assert_eq!(store.get("kept"), Some(&string("value")));
+        assert_eq!(store.get("torn"), None);
+        assert!(fs::metadata(&path).unwrap().len() < len - 3);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_corrupted_record_is_an_error() {
+        let dir = temp_dir("corrupted");
+        let mut store = Bitcask::open(&dir, BitcaskConfig::default()).unwrap();
+        store.insert("key".to_string(), string("value"));
+        store.sync().unwrap();
+        drop(store);
+
+        let path = log_path(&dir, 1);
+        let mut data = fs::read(&path).unwrap();
+        let last = data.len() - 1;
+        data[last] ^= 0xff;
+        fs::write(&path, data).unwrap();
+        assert!(Bitcask::open(&dir, BitcaskConfig::default()).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_files_rotate() {
+        let dir = temp_dir("rotate");
+        let config = BitcaskConfig { max_file_size: 100, ..BitcaskConfig::default() };
+        let mut store = Bitcask::open(&dir, config.clone()).unwrap();
+        for i in 0..20 {
+            store.insert(format!("key{}", i), string("some value"));
+            store.sync().unwrap();
+        }
+        assert!(logs(&dir).len() > 2);
+        drop(store);
+
+        let mut store = Bitcask::open(&dir, config).unwrap();
+        assert_eq!(store.len(), 20);
+        assert_eq!(store.get("key0"), Some(&string("some value")));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_merge_drops_dead_records_and_writes_a_hint() {
+        let dir = temp_dir("merge");
+        let mut store = Bitcask::open(&dir, BitcaskConfig::default()).unwrap();
+        for i in 0..10 {
+            store.insert("counter".to_string(), string(&i.to_string()));
+            store.insert(format!("gone{}", i), string("x"));
+            store.sync().unwrap();
+            store.remove(&format!("gone{}", i));
+            store.sync().unwrap();
+        }
+        assert!(store.dead_bytes() > 0);
+
+        store.compact().unwrap();
+        assert_eq!(store.dead_bytes(), 0);
+        // the merged file (2) with its hint, and the new active file (3)
+        assert_eq!(logs(&dir), vec![2, 3]);
+        assert!(hint_path(&dir, 2).exists());
+        assert_eq!(store.get("counter"), Some(&string("9")));
+        store.insert("after".to_string(), string("merge"));
+        store.sync().unwrap();
+        drop(store);
+
+        let mut store = Bitcask::open(&dir, BitcaskConfig::default()).unwrap();
+        assert_eq!(store.len(), 2);
+        assert_eq!(store.get("counter"), Some(&string("9")));
+        assert_eq!(store.get("after"), Some(&string("merge")));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_merge_starts_past_the_threshold() {
+        let dir = temp_dir("auto-merge");
+        let config = BitcaskConfig { compaction_threshold: 200, ..BitcaskConfig::default() };
+        let mut store = Bitcask::open(&dir, config).unwrap();
+        for i in 0..50 {
+            store.insert("key".to_string(), string(&i.to_string()));
+            store.sync().unwrap();
+        }
+        assert!(store.dead_bytes() < 200);
+        assert!(logs(&dir).iter().any(|generation| hint_path(&dir, *generation).exists()));
+        assert_eq!(store.get("key"), Some(&string("49")));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_sharded_database_on_bitcask() {
+        let dir = temp_dir("sharded");
+        let config = BitcaskConfig::default();
+        let db = ShardedDatabase::with_storage(open_shards(&dir, 4, &config).unwrap());
+        db.insert("hello", Bytes::from("world"));
+        db.push("list", &[Bytes::from("a"), Bytes::from("b")], crate::list::End::Right).unwrap();
+        assert_eq!(db.incr_by("n", 3), Ok(3));
+        assert!(db.remove("n"));
+        db.insert_with_ttl("session", Bytes::from("x"), Some(std::time::Duration::from_secs(600))).unwrap();
+        drop(db);
+
+        // the number of shards decides where keys live, it can't change
+        assert!(open_shards(&dir, 8, &config).is_err());
+
+        let db = ShardedDatabase::with_storage(open_shards(&dir, 4, &config).unwrap());
+        assert_eq!(db.len(), 3);
+        assert_eq!(db.get("hello"), Some(Bytes::from("world")));
+        assert!(matches!(db.ttl("session"), crate::Ttl::Remaining(ttl) if ttl.as_secs() > 500));
+        assert_eq!(db.list_range("list", 0, -1), Ok(vec![Bytes::from("a"), Bytes::from("b")]));
+        assert!(!db.contains_key("n"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_expiries_survive_a_restart() {
+        let dir = temp_dir("expiry");
+        let later = unix_millis() + 600_000;
+        let mut store = Bitcask::open(&dir, BitcaskConfig::default()).unwrap();
+        store.insert("later".to_string(), string("1"));
+        store.set_expiry("later", Some(later));
+        store.insert("gone".to_string(), string("2"));
+        store.set_expiry("gone", Some(1));
+        store.insert("kept".to_string(), string("3"));
+        store.sync().unwrap();
+        // PERSIST writes the record again, without the expiry
+        store.insert("persisted".to_string(), string("4"));
+        store.set_expiry("persisted", Some(later));
+        store.sync().unwrap();
+        store.set_expiry("persisted", None);
+        store.sync().unwrap();
+        drop(store);
+
+        let mut store = Bitcask::open(&dir, BitcaskConfig::default()).unwrap();
+        // expired while we were down: dropped like a deleted key
+        assert_eq!(store.get("gone"), None);
+        assert_eq!(store.len(), 3);
+        assert_eq!(store.expiries(), vec![("later".to_string(), later)]);
+
+        // and the same from a hint file
+        store.compact().unwrap();
+        drop(store);
+        let mut store = Bitcask::open(&dir, BitcaskConfig::default()).unwrap();
+        assert_eq!(store.expiries(), vec![("later".to_string(), later)]);
+        assert_eq!(store.get("later"), Some(&string("1")));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
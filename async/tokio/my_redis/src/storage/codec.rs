@@ -0,0 +1,190 @@
+//! Binary encoding of a `Value`, used to write values to disk.
+//!
+//! Layout: a one byte type tag followed by the value. Lengths and counts are LEB128 varints
+//! (7 bits per byte, high bit set when more bytes follow), so small lists don't pay 8 bytes
+//! per item.
+
+use crate::stream::Stream;
+use crate::zset::SortedSet;
+use crate::Value;
+use bytes::Bytes;
+use std::collections::VecDeque;
+use std::io;
+
+const STRING: u8 = 0;
+const LIST: u8 = 1;
+const STREAM: u8 = 2;
+const SORTED_SET: u8 = 3;
+
+/// Encodes a value into bytes that `decode_value` turns back into the same value.
+pub fn encode_value(value: &Value) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(value.size() + 8);
+    match value {
+        Value::String(data) => {
+            buf.push(STRING);
+            put_bytes(&mut buf, data);
+        }
+        Value::List(items) => {
+            buf.push(LIST);
+            put_u64(&mut buf, items.len() as u64);
+            for item in items {
+                put_bytes(&mut buf, item);
+            }
+        }
+        Value::Stream(stream) => {
+            buf.push(STREAM);
+            stream.encode(&mut buf);
+        }
+        Value::SortedSet(set) => {
+            buf.push(SORTED_SET);
+            set.encode(&mut buf);
+        }
+    }
+    buf
+}
+
+/// Decodes a value written by `encode_value`.
+pub fn decode_value(data: &[u8]) -> io::Result<Value> {
+    let mut decoder = Decoder::new(data);
+    let value = match decoder.u8()? {
+        STRING => Value::String(decoder.bytes()?),
+        LIST => {
+            let len = decoder.u64()?;
+            let mut items = VecDeque::new();
+            for _ in 0..len {
+                items.push_back(decoder.bytes()?);
+            }
+            Value::List(items)
+        }
+        STREAM => Value::Stream(Stream::decode(&mut decoder)?),
+        SORTED_SET => Value::SortedSet(SortedSet::decode(&mut decoder)?),
+        tag => return Err(invalid(format!("unknown value type {}", tag))),
+    };
+    // Note: the commands delete a list or sorted set with its last element, and rely on it
+    // (BLPOP expects any list it finds to have an item). Streams may be empty, as in Redis.
+    match &value {
+        Value::List(items) if items.is_empty() => return Err(invalid("empty list")),
+        Value::SortedSet(set) if set.is_empty() => return Err(invalid("empty sorted set")),
+        _ => {}
+    }
+    decoder.finish()?;
+    Ok(value)
+}
+
+pub(crate) fn put_u64(buf: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        buf.push((n as u8) | 0x80);
+        n >>= 7;
+    }
+    buf.push(n as u8);
+}
+
+pub(crate) fn put_bytes(buf: &mut Vec<u8>, data: &[u8]) {
+    put_u64(buf, data.len() as u64);
+    buf.extend_from_slice(data);
+}
+
+/// Reads back what the `put_*` functions wrote.
+pub(crate) struct Decoder<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    pub(crate) fn new(data: &'a [u8]) -> Decoder<'a> {
+        Decoder { data }
+    }
+
+    pub(crate) fn u8(&mut self) -> io::Result<u8> {
+        let (&byte, rest) = self.data.split_first().ok_or_else(|| invalid("unexpected end of value"))?;
+        self.data = rest;
+        Ok(byte)
+    }
+
+    pub(crate) fn u64(&mut self) -> io::Result<u64> {
+        let mut n = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.u8()?;
+            n |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(n);
+            }
+        }
+        Err(invalid("varint too long"))
+    }
+
+    pub(crate) fn bytes(&mut self) -> io::Result<Bytes> {
+        let len = usize::try_from(self.u64()?).map_err(|_| invalid("length too large"))?;
+        if len > self.data.len() {
+            return Err(invalid("unexpected end of value"));
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(Bytes::copy_from_slice(bytes))
+    }
+
+    pub(crate) fn string(&mut self) -> io::Result<String> {
+        String::from_utf8(self.bytes()?.to_vec()).map_err(|_| invalid("invalid UTF-8"))
+    }
+
+    /// Checks that the whole input was used, trailing bytes mean it wasn't a value.
+    fn finish(&self) -> io::Result<()> {
+        if self.data.is_empty() {
+            Ok(())
+        } else {
+            Err(invalid("trailing bytes after value"))
+        }
+    }
+}
+
+pub(crate) fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stream::{GroupReadId, NewId, StreamId};
+    use crate::ShardedDatabase;
+
+    #[test]
+    fn test_round_trip() {
+        let values = [
+            Value::String(Bytes::from("hello")),
+            Value::String(Bytes::from(vec![0u8; 300])),
+            Value::List(VecDeque::from([Bytes::from("a"), Bytes::new(), Bytes::from("c")])),
+            Value::SortedSet({
+                let mut set = SortedSet::default();
+                set.insert(Bytes::from("a"), 1.5);
+                set.insert(Bytes::from("b"), f64::NEG_INFINITY);
+                set
+            }),
+        ];
+        for value in values {
+            assert_eq!(decode_value(&encode_value(&value)).unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn test_round_trip_stream_with_groups() {
+        let db = ShardedDatabase::new(1);
+        let fields = vec![(Bytes::from("field"), Bytes::from("value"))];
+        db.xadd("s", NewId::Auto, fields.clone(), None, false).unwrap();
+        db.xadd("s", NewId::Auto, fields, None, false).unwrap();
+        db.xgroup_create("s", "group", Some(StreamId::MIN), false).unwrap();
+        db.xreadgroup("group", "alice", &[("s".to_string(), GroupReadId::New)], Some(1), false).unwrap();
+
+        let value = db.lock_shard("s").entries.get("s").cloned().unwrap();
+        assert_eq!(decode_value(&encode_value(&value)).unwrap(), value);
+    }
+
+    #[test]
+    fn test_rejects_garbage() {
+        assert!(decode_value(&[]).is_err());
+        assert!(decode_value(&[9]).is_err());
+        // a string claiming 5 bytes with only 2
+        assert!(decode_value(&[STRING, 5, b'a', b'b']).is_err());
+        let mut extra = encode_value(&Value::String(Bytes::from("x")));
+        extra.push(0);
+        assert!(decode_value(&extra).is_err());
+    }
+}
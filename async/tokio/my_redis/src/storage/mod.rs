@@ -0,0 +1,113 @@
+//! Where the values of a shard are kept.
+//!
+//! `ShardedDatabase` only talks to its data through the `StorageBackend` trait, so the same
+//! commands run on top of:
+//! - a plain `HashMap<String, Value>`, everything in memory (the default),
+//! - `Bitcask`, a log-structured store on disk (see `bitcask.rs`).
+
+pub mod bitcask;
+pub mod codec;
+
+pub use bitcask::{Bitcask, BitcaskConfig};
+
+use crate::Value;
+use std::collections::HashMap;
+use std::io;
+
+/// The key/value map behind one shard.
+///
+/// The methods mirror the `HashMap` ones the commands used before, so a command can read a
+/// value, change it in place with `get_mut`, and return. The shard calls `sync` when it is
+/// unlocked, which is when a backend that isn't in memory writes out what the command changed.
+///
+/// Why does `get` take `&mut self`?
+/// A disk backend has to read the value into memory before it can hand out a reference to it,
+/// so even a lookup changes the backend (it fills a cache that `sync` empties).
+pub trait StorageBackend: Send {
+    fn get(&mut self, key: &str) -> Option<&Value>;
+
+    /// Like `get`, but the value may be changed: the backend must assume it was.
+    fn get_mut(&mut self, key: &str) -> Option<&mut Value>;
+
+    /// Inserts or replaces the value of `key`.
+    fn insert(&mut self, key: String, value: Value);
+
+    /// Removes `key`, returning whether it existed.
+    fn remove(&mut self, key: &str) -> bool;
+
+    fn contains_key(&self, key: &str) -> bool;
+
+    /// Number of keys.
+    fn len(&self) -> usize;
+
+    /// Every key, in no particular order (KEYS/SCAN).
+    fn keys(&self) -> Vec<String>;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Approximate number of bytes of keys and values (see `ShardedDatabase::used_memory`).
+    fn used_memory(&self) -> usize;
+
+    /// Persists the changes made since the last call. Called with the shard lock held, at the
+    /// end of every command.
+    ///
+    /// Errors can't be returned by the map-like methods above (they happen in the middle of a
+    /// command), so a backend keeps them and reports them here. The command then replies with
+    /// an error, and what couldn't be written must be kept for the next call to retry.
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Reclaims the space used by overwritten and deleted values, if the backend wastes any.
+    fn compact(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Sets when `key` expires, in milliseconds since the unix epoch, or that it never does.
+    ///
+    /// The shard keeps the deadlines and removes expired keys itself. A backend on disk stores
+    /// them as well, so that keys still expire after a restart.
+    fn set_expiry(&mut self, _key: &str, _expires_at: Option<u64>) {}
+
+    /// The stored expiries, which the shard starts with.
+    fn expiries(&self) -> Vec<(String, u64)> {
+        Vec::new()
+    }
+}
+
+/// The in-memory backend: nothing to sync or compact.
+impl StorageBackend for HashMap<String, Value> {
+    fn get(&mut self, key: &str) -> Option<&Value> {
+        HashMap::get(self, key)
+    }
+
+    fn get_mut(&mut self, key: &str) -> Option<&mut Value> {
+        HashMap::get_mut(self, key)
+    }
+
+    fn insert(&mut self, key: String, value: Value) {
+        HashMap::insert(self, key, value);
+    }
+
+    fn remove(&mut self, key: &str) -> bool {
+        HashMap::remove(self, key).is_some()
+    }
+
+    fn contains_key(&self, key: &str) -> bool {
+        HashMap::contains_key(self, key)
+    }
+
+    fn len(&self) -> usize {
+        HashMap::len(self)
+    }
+
+    fn keys(&self) -> Vec<String> {
+        HashMap::keys(self).cloned().collect()
+    }
+
+    fn used_memory(&self) -> usize {
+        self.iter().map(|(k, v)| k.len() + v.size()).sum()
+    }
+}
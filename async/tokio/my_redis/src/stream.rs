@@ -0,0 +1,744 @@
+//! Stream values (XADD/XRANGE/XREAD) and consumer groups (XREADGROUP/XACK/XPENDING/XCLAIM).
+//!
+//! A stream is an append-only log of entries, each identified by a `StreamId` made of a
+//! millisecond timestamp and a sequence number. Entries are kept in a `BTreeMap`, which
+//! gives us range queries in id order for free.
+
+use crate::storage::codec::{self, Decoder};
+use crate::{ShardedDatabase, Value, WrongType};
+use bytes::Bytes;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::Notify;
+
+/// The id of a stream entry, written `<ms>-<seq>`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct StreamId {
+    pub ms: u64,
+    pub seq: u64,
+}
+
+impl StreamId {
+    pub const MIN: StreamId = StreamId { ms: 0, seq: 0 };
+    pub const MAX: StreamId = StreamId { ms: u64::MAX, seq: u64::MAX };
+
+    /// Parses `<ms>-<seq>` or just `<ms>`; a missing sequence number becomes `missing_seq`
+    /// (0 for the start of a range, `u64::MAX` for the end).
+    pub fn parse(s: &str, missing_seq: u64) -> Option<StreamId> {
+        match s.split_once('-') {
+            Some((ms, seq)) => Some(StreamId { ms: ms.parse().ok()?, seq: seq.parse().ok()? }),
+            None => Some(StreamId { ms: s.parse().ok()?, seq: missing_seq }),
+        }
+    }
+
+    /// Parses a range bound of XRANGE/XPENDING: `-`, `+`, or an id.
+    pub fn parse_bound(s: &str, is_start: bool) -> Option<StreamId> {
+        match s {
+            "-" => Some(StreamId::MIN),
+            "+" => Some(StreamId::MAX),
+            s if is_start => StreamId::parse(s, 0),
+            s => StreamId::parse(s, u64::MAX),
+        }
+    }
+
+    /// The smallest id strictly greater than this one.
+    fn next(self) -> Option<StreamId> {
+        match self.seq.checked_add(1) {
+            Some(seq) => Some(StreamId { ms: self.ms, seq }),
+            None => self.ms.checked_add(1).map(|ms| StreamId { ms, seq: 0 }),
+        }
+    }
+}
+
+impl fmt::Display for StreamId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}-{}", self.ms, self.seq)
+    }
+}
+
+/// The id argument of XADD.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NewId {
+    /// `*`: timestamp and sequence are generated.
+    Auto,
+    /// `<ms>-*`: only the sequence is generated.
+    AutoSeq(u64),
+    /// `<ms>-<seq>`
+    Explicit(StreamId),
+}
+
+impl NewId {
+    pub fn parse(s: &str) -> Option<NewId> {
+        if s == "*" {
+            return Some(NewId::Auto);
+        }
+        if let Some(ms) = s.strip_suffix("-*") {
+            return ms.parse().ok().map(NewId::AutoSeq);
+        }
+        StreamId::parse(s, 0).map(NewId::Explicit)
+    }
+}
+
+/// The fields of one entry, in the order they were added.
+pub type Fields = Vec<(Bytes, Bytes)>;
+
+/// One entry as returned by the read commands.
+pub type StreamEntry = (StreamId, Fields);
+
+/// A stream value.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Stream {
+    entries: BTreeMap<StreamId, Fields>,
+    last_id: StreamId,
+    groups: HashMap<String, ConsumerGroup>,
+}
+
+impl Stream {
+    /// Approximate number of bytes held by the entries.
+    pub(crate) fn size(&self) -> usize {
+        self.entries
+            .values()
+            .flat_map(|fields| fields.iter())
+            .map(|(field, value)| field.len() + value.len())
+            .sum()
+    }
+
+    /// Writes the stream, consumer groups included, for `storage::codec`.
+    pub(crate) fn encode(&self, buf: &mut Vec<u8>) {
+        let put_id = |buf: &mut Vec<u8>, id: &StreamId| {
+            codec::put_u64(buf, id.ms);
+            codec::put_u64(buf, id.seq);
+        };
+        put_id(buf, &self.last_id);
+        codec::put_u64(buf, self.entries.len() as u64);
+        for (id, fields) in &self.entries {
+            put_id(buf, id);
+            codec::put_u64(buf, fields.len() as u64);
+            for (field, value) in fields {
+                codec::put_bytes(buf, field);
+                codec::put_bytes(buf, value);
+            }
+        }
+        codec::put_u64(buf, self.groups.len() as u64);
+        for (name, group) in &self.groups {
+            codec::put_bytes(buf, name.as_bytes());
+            put_id(buf, &group.last_delivered);
+            codec::put_u64(buf, group.pending.len() as u64);
+            for (id, pending) in &group.pending {
+                put_id(buf, id);
+                codec::put_bytes(buf, pending.consumer.as_bytes());
+                codec::put_u64(buf, pending.delivered_at);
+                codec::put_u64(buf, pending.delivery_count);
+            }
+        }
+    }
+
+    /// Reads a stream written by `encode`.
+    pub(crate) fn decode(decoder: &mut Decoder) -> io::Result<Stream> {
+        fn id(decoder: &mut Decoder) -> io::Result<StreamId> {
+            Ok(StreamId { ms: decoder.u64()?, seq: decoder.u64()? })
+        }
+        let mut stream = Stream { last_id: id(decoder)?, ..Stream::default() };
+        for _ in 0..decoder.u64()? {
+            let entry_id = id(decoder)?;
+            let mut fields = Fields::new();
+            for _ in 0..decoder.u64()? {
+                fields.push((decoder.bytes()?, decoder.bytes()?));
+            }
+            stream.entries.insert(entry_id, fields);
+        }
+        for _ in 0..decoder.u64()? {
+            let name = decoder.string()?;
+            let mut group = ConsumerGroup { last_delivered: id(decoder)?, pending: BTreeMap::new() };
+            for _ in 0..decoder.u64()? {
+                let entry_id = id(decoder)?;
+                let pending = PendingEntry {
+                    consumer: decoder.string()?,
+                    delivered_at: decoder.u64()?,
+                    delivery_count: decoder.u64()?,
+                };
+                group.pending.insert(entry_id, pending);
+            }
+            stream.groups.insert(name, group);
+        }
+        Ok(stream)
+    }
+
+    /// Builds a stream from its entries, without consumer groups (used by imports).
+    /// `last_id` is raised to the last entry if it is lower.
+    pub fn from_entries(entries: Vec<StreamEntry>, last_id: StreamId) -> Stream {
+        let entries: BTreeMap<StreamId, Fields> = entries.into_iter().collect();
+        let last_id = entries.keys().next_back().map_or(last_id, |id| last_id.max(*id));
+        Stream { entries, last_id, groups: HashMap::new() }
+    }
+
+    /// The entries, oldest first.
+    pub fn entries(&self) -> impl DoubleEndedIterator<Item = (&StreamId, &Fields)> {
+        self.entries.iter()
+    }
+
+    /// The highest id ever added, even if that entry was trimmed since.
+    pub fn last_id(&self) -> StreamId {
+        self.last_id
+    }
+
+    fn range(&self, start: StreamId, end: StreamId) -> impl DoubleEndedIterator<Item = (&StreamId, &Fields)> {
+        // `BTreeMap::range` panics when start > end, so handle that case first.
+        let range = if start <= end { Some(self.entries.range(start..=end)) } else { None };
+        range.into_iter().flatten()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+struct ConsumerGroup {
+    // The id of the last entry delivered to any consumer with `>`.
+    last_delivered: StreamId,
+    // The pending entries list (PEL): delivered but not acknowledged yet.
+    pending: BTreeMap<StreamId, PendingEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct PendingEntry {
+    consumer: String,
+    // Unix time in milliseconds of the last delivery.
+    delivered_at: u64,
+    delivery_count: u64,
+}
+
+/// One row of the extended form of XPENDING.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PendingInfo {
+    pub id: StreamId,
+    pub consumer: String,
+    pub idle: Duration,
+    pub delivery_count: u64,
+}
+
+/// Which pending entries the extended form of XPENDING returns.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PendingFilter {
+    /// Range of ids, both inclusive.
+    pub start: StreamId,
+    pub end: StreamId,
+    pub count: usize,
+    /// Only the entries of this consumer.
+    pub consumer: Option<String>,
+    /// Only the entries not delivered for at least this long.
+    pub min_idle: Duration,
+}
+
+/// The summary form of XPENDING.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PendingSummary {
+    pub count: usize,
+    /// Lowest and highest pending ids, if anything is pending.
+    pub range: Option<(StreamId, StreamId)>,
+    /// Number of pending entries per consumer, sorted by consumer name.
+    pub consumers: Vec<(String, usize)>,
+}
+
+/// The per-key id argument of XREADGROUP.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GroupReadId {
+    /// `>`: entries never delivered to any consumer of the group.
+    New,
+    /// An id: the consumer's own pending entries after it (history).
+    After(StreamId),
+}
+
+/// Errors of the stream commands, with the same messages as Redis.
+#[derive(Debug, Clone, PartialEq)]
+pub enum StreamError {
+    WrongType,
+    IdTooSmall,
+    IdZero,
+    NoKey,
+    NoGroup { key: String, group: String },
+    BusyGroup,
+}
+
+impl fmt::Display for StreamError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StreamError::WrongType => WrongType.fmt(f),
+            StreamError::IdTooSmall => {
+                write!(f, "ERR The ID specified in XADD is equal or smaller than the target stream top item")
+            }
+            StreamError::IdZero => write!(f, "ERR The ID specified in XADD must be greater than 0-0"),
+            StreamError::NoKey => write!(
+                f,
+                "ERR The XGROUP subcommand requires the key to exist. \
+                 Note that for CREATE you may want to use the MKSTREAM option to create an empty stream automatically."
+            ),
+            StreamError::NoGroup { key, group } => {
+                write!(f, "NOGROUP No such key '{}' or consumer group '{}'", key, group)
+            }
+            StreamError::BusyGroup => write!(f, "BUSYGROUP Consumer Group name already exists"),
+        }
+    }
+}
+
+impl std::error::Error for StreamError {}
+
+impl From<WrongType> for StreamError {
+    fn from(_: WrongType) -> Self {
+        StreamError::WrongType
+    }
+}
+
+/// Registration of a blocked XREAD/XREADGROUP on some keys.
+///
+/// `changed` resolves after the next XADD to any of the keys; dropping the watch
+/// unregisters it.
+pub struct StreamWatch {
+    db: ShardedDatabase,
+    keys: Vec<String>,
+    notify: Arc<Notify>,
+}
+
+impl StreamWatch {
+    pub async fn changed(&self) {
+        self.notify.notified().await
+    }
+}
+
+impl Drop for StreamWatch {
+    fn drop(&mut self) {
+        for key in &self.keys {
+            let mut shard = self.db.lock_shard(key);
+            if let Some(watchers) = shard.stream_watchers.get_mut(key) {
+                watchers.retain(|n| !Arc::ptr_eq(n, &self.notify));
+                if watchers.is_empty() {
+                    shard.stream_watchers.remove(key);
+                }
+            }
+        }
+    }
+}
+
+impl ShardedDatabase {
+    /// Appends an entry to the stream at `key` (XADD), creating the stream unless `nomkstream`
+    /// is set. With `maxlen`, the oldest entries are trimmed so at most `maxlen` remain.
+    ///
+    /// Returns the id of the new entry, or `None` if the stream doesn't exist and `nomkstream` was given.
+    pub fn xadd(&self, key: &str, id: NewId, fields: Fields, maxlen: Option<usize>, nomkstream: bool) -> Result<Option<StreamId>, StreamError> {
+        let mut shard = self.lock_shard(key);
+        let last = match shard.entries.get(key) {
+            Some(Value::Stream(stream)) => stream.last_id,
+            Some(_) => return Err(StreamError::WrongType),
+            None if nomkstream => return Ok(None),
+            None => StreamId::MIN,
+        };
+        let id = match id {
+            NewId::Explicit(id) => id,
+            NewId::AutoSeq(ms) if ms == last.ms => StreamId { ms, seq: last.seq.checked_add(1).ok_or(StreamError::IdTooSmall)? },
+            NewId::AutoSeq(ms) => StreamId { ms, seq: if ms == 0 { 1 } else { 0 } },
+            NewId::Auto => {
+                let now = unix_millis();
+                if now > last.ms {
+                    StreamId { ms: now, seq: 0 }
+                } else {
+                    // The clock went backwards or we are still in the same millisecond.
+                    last.next().ok_or(StreamError::IdTooSmall)?
+                }
+            }
+        };
+        if id == StreamId::MIN {
+            return Err(StreamError::IdZero);
+        }
+        if id <= last {
+            return Err(StreamError::IdTooSmall);
+        }
+
+        // Created only once the ID is valid: a rejected XADD must not leave an empty stream behind.
+        if !shard.entries.contains_key(key) {
+            shard.entries.insert(key.to_string(), Value::Stream(Stream::default()));
+        }
+        let Some(Value::Stream(stream)) = shard.entries.get_mut(key) else {
+            return Err(StreamError::WrongType);
+        };
+        stream.entries.insert(id, fields);
+        stream.last_id = id;
+        if let Some(maxlen) = maxlen {
+            while stream.entries.len() > maxlen {
+                stream.entries.pop_first();
+            }
+        }
+
+        // Wake up the blocked readers, they re-run their read to find the new entry.
+        if let Some(watchers) = shard.stream_watchers.get(key) {
+            for notify in watchers {
+                notify.notify_one();
+            }
+        }
+        Ok(Some(id))
+    }
+
+    /// Returns the number of entries in the stream (XLEN).
+    pub fn xlen(&self, key: &str) -> Result<usize, StreamError> {
+        self.with_stream(key, |stream| stream.entries.len()).map(|len| len.unwrap_or(0))
+    }
+
+    /// Returns the entries with ids between `start` and `end` inclusive (XRANGE), or in
+    /// reverse order from `end` down to `start` when `rev` is set (XREVRANGE).
+    pub fn xrange(&self, key: &str, start: StreamId, end: StreamId, count: Option<usize>, rev: bool) -> Result<Vec<StreamEntry>, StreamError> {
+        let entries = self.with_stream(key, |stream| {
+            let range = stream.range(start, end);
+            let take = count.unwrap_or(usize::MAX);
+            let clone = |(id, fields): (&StreamId, &Fields)| (*id, fields.clone());
+            if rev {
+                range.rev().take(take).map(clone).collect()
+            } else {
+                range.take(take).map(clone).collect()
+            }
+        })?;
+        Ok(entries.unwrap_or_default())
+    }
+
+    /// Returns the id of the newest entry ever added, which is what `$` means in XREAD.
+    pub fn xlast_id(&self, key: &str) -> Result<StreamId, StreamError> {
+        self.with_stream(key, |stream| stream.last_id).map(|id| id.unwrap_or(StreamId::MIN))
+    }
+
+    /// Returns, for each `(key, id)`, up to `count` entries with an id greater than `id` (XREAD).
+    /// Streams without new entries are left out.
+    pub fn xread(&self, streams: &[(String, StreamId)], count: Option<usize>) -> Result<Vec<(String, Vec<StreamEntry>)>, StreamError> {
+        let mut result = Vec::new();
+        for (key, after) in streams {
+            let Some(start) = after.next() else { continue };
+            let entries = self.xrange(key, start, StreamId::MAX, count, false)?;
+            if !entries.is_empty() {
+                result.push((key.clone(), entries));
+            }
+        }
+        Ok(result)
+    }
+
+    /// Creates a consumer group starting after `id`, or after the current last entry when
+    /// `id` is `None` (`$`) (XGROUP CREATE).
+    pub fn xgroup_create(&self, key: &str, group: &str, id: Option<StreamId>, mkstream: bool) -> Result<(), StreamError> {
+        let mut shard = self.lock_shard(key);
+        if !shard.entries.contains_key(key) {
+            if !mkstream {
+                return Err(StreamError::NoKey);
+            }
+            shard.entries.insert(key.to_string(), Value::Stream(Stream::default()));
+        }
+        let Some(Value::Stream(stream)) = shard.entries.get_mut(key) else {
+            return Err(StreamError::WrongType);
+        };
+        if stream.groups.contains_key(group) {
+            return Err(StreamError::BusyGroup);
+        }
+        let last_delivered = id.unwrap_or(stream.last_id);
+        stream.groups.insert(group.to_string(), ConsumerGroup { last_delivered, pending: BTreeMap::new() });
+        Ok(())
+    }
+
+    /// Removes a consumer group, returning whether it existed (XGROUP DESTROY).
+    pub fn xgroup_destroy(&self, key: &str, group: &str) -> Result<bool, StreamError> {
+        let removed = self.with_stream_mut(key, |stream| stream.groups.remove(group).is_some())?;
+        Ok(removed.unwrap_or(false))
+    }
+
+    /// Reads entries on behalf of `consumer` in `group` (XREADGROUP).
+    ///
+    /// With `GroupReadId::New`, entries never delivered to the group are returned and added to
+    /// the pending entries list (unless `noack`). With an id, the consumer's own pending entries
+    /// after that id are returned again. Streams without entries to return are left out.
+    pub fn xreadgroup(&self, group: &str, consumer: &str, streams: &[(String, GroupReadId)], count: Option<usize>, noack: bool) -> Result<Vec<(String, Vec<StreamEntry>)>, StreamError> {
+        let mut result = Vec::new();
+        let take = count.unwrap_or(usize::MAX);
+        for (key, read_id) in streams {
+            let entries = self.with_group(key, group, |stream, group| match read_id {
+                GroupReadId::New => {
+                    let Some(start) = group.last_delivered.next() else {
+                        return vec![];
+                    };
+                    let entries: Vec<StreamEntry> = stream
+                        .range(start, StreamId::MAX)
+                        .take(take)
+                        .map(|(id, fields)| (*id, fields.clone()))
+                        .collect();
+                    let now = unix_millis();
+                    for (id, _) in &entries {
+                        group.last_delivered = *id;
+                        if !noack {
+                            let pending = PendingEntry { consumer: consumer.to_string(), delivered_at: now, delivery_count: 1 };
+                            group.pending.insert(*id, pending);
+                        }
+                    }
+                    entries
+                }
+                GroupReadId::After(after) => {
+                    let Some(start) = after.next() else {
+                        return vec![];
+                    };
+                    group
+                        .pending
+                        .range(start..)
+                        .filter(|(_, p)| p.consumer == consumer)
+                        .take(take)
+                        // An entry may have been trimmed away since it was delivered.
+                        .map(|(id, _)| (*id, stream.entries.get(id).cloned().unwrap_or_default()))
+                        .collect()
+                }
+            })?;
+            if !entries.is_empty() || matches!(read_id, GroupReadId::After(_)) {
+                result.push((key.clone(), entries));
+            }
+        }
+        Ok(result)
+    }
+
+    /// Acknowledges entries, removing them from the group's pending list (XACK).
+    /// Returns how many were actually pending.
+    pub fn xack(&self, key: &str, group: &str, ids: &[StreamId]) -> Result<usize, StreamError> {
+        match self.with_group(key, group, |_, group| ids.iter().filter(|id| group.pending.remove(*id).is_some()).count()) {
+            // Unlike the other group commands, XACK on a missing key or group just returns 0.
+            Err(StreamError::NoGroup { .. }) => Ok(0),
+            other => other,
+        }
+    }
+
+    /// The summary form of XPENDING.
+    pub fn xpending_summary(&self, key: &str, group: &str) -> Result<PendingSummary, StreamError> {
+        self.with_group(key, group, |_, group| {
+            let mut consumers: BTreeMap<&str, usize> = BTreeMap::new();
+            for pending in group.pending.values() {
+                *consumers.entry(&pending.consumer).or_default() += 1;
+            }
+            let first = group.pending.keys().next();
+            let last = group.pending.keys().next_back();
+            PendingSummary {
+                count: group.pending.len(),
+                range: first.zip(last).map(|(a, b)| (*a, *b)),
+                consumers: consumers.into_iter().map(|(c, n)| (c.to_string(), n)).collect(),
+            }
+        })
+    }
+
+    /// The extended form of XPENDING: the pending entries matching `filter`.
+    pub fn xpending(&self, key: &str, group: &str, filter: &PendingFilter) -> Result<Vec<PendingInfo>, StreamError> {
+        let PendingFilter { start, end, count, consumer, min_idle } = filter;
+        self.with_group(key, group, |_, group| {
+            if start > end {
+                return vec![];
+            }
+            let now = unix_millis();
+            group
+                .pending
+                .range(start..=end)
+                .map(|(id, p)| PendingInfo {
+                    id: *id,
+                    consumer: p.consumer.clone(),
+                    idle: Duration::from_millis(now.saturating_sub(p.delivered_at)),
+                    delivery_count: p.delivery_count,
+                })
+                .filter(|info| consumer.as_ref().is_none_or(|c| *c == info.consumer) && info.idle >= *min_idle)
+                .take(*count)
+                .collect()
+        })
+    }
+
+    /// Transfers pending entries idle for at least `min_idle` to `consumer` (XCLAIM).
+    ///
+    /// Claimed entries get their idle time reset and, unless `justid`, their delivery count
+    /// incremented. Returns the claimed entries.
+    pub fn xclaim(&self, key: &str, group: &str, consumer: &str, min_idle: Duration, ids: &[StreamId], justid: bool) -> Result<Vec<StreamEntry>, StreamError> {
+        self.with_group(key, group, |stream, group| {
+            let now = unix_millis();
+            let mut claimed = Vec::new();
+            for id in ids {
+                let Some(pending) = group.pending.get_mut(id) else { continue };
+                if Duration::from_millis(now.saturating_sub(pending.delivered_at)) < min_idle {
+                    continue;
+                }
+                let Some(fields) = stream.entries.get(id) else {
+                    // Trimmed away: nothing left to claim.
+                    group.pending.remove(id);
+                    continue;
+                };
+                pending.consumer = consumer.to_string();
+                pending.delivered_at = now;
+                if !justid {
+                    pending.delivery_count += 1;
+                }
+                claimed.push((*id, fields.clone()));
+            }
+            claimed
+        })
+    }
+
+    /// Registers for a wakeup on the next XADD to any of `keys`.
+    ///
+    /// Register *before* reading: an entry added between the read and the wait still wakes
+    /// us up, because `Notify` keeps a permit when nobody is waiting yet.
+    pub fn watch_streams(&self, keys: &[String]) -> StreamWatch {
+        let notify = Arc::new(Notify::new());
+        for key in keys {
+            let mut shard = self.lock_shard(key);
+            shard.stream_watchers.entry(key.clone()).or_default().push(Arc::clone(&notify));
+        }
+        StreamWatch { db: self.clone(), keys: keys.to_vec(), notify }
+    }
+
+    /// Runs `f` on the stream at `key`; `None` if the key doesn't exist.
+    fn with_stream<T>(&self, key: &str, f: impl FnOnce(&Stream) -> T) -> Result<Option<T>, StreamError> {
+        let mut shard = self.lock_shard(key);
+        match shard.entries.get(key) {
+            Some(Value::Stream(stream)) => Ok(Some(f(stream))),
+            Some(_) => Err(StreamError::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    fn with_stream_mut<T>(&self, key: &str, f: impl FnOnce(&mut Stream) -> T) -> Result<Option<T>, StreamError> {
+        let mut shard = self.lock_shard(key);
+        match shard.entries.get_mut(key) {
+            Some(Value::Stream(stream)) => Ok(Some(f(stream))),
+            Some(_) => Err(StreamError::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    /// Runs `f` on the stream at `key` and its consumer group `group`.
+    fn with_group<T>(&self, key: &str, group: &str, f: impl FnOnce(&Stream, &mut ConsumerGroup) -> T) -> Result<T, StreamError> {
+        let no_group = || StreamError::NoGroup { key: key.to_string(), group: group.to_string() };
+        self.with_stream_mut(key, |stream| {
+            // Take the group out so we can hand out `&Stream` and `&mut ConsumerGroup` together.
+            let mut consumer_group = stream.groups.remove(group)?;
+            let result = f(stream, &mut consumer_group);
+            stream.groups.insert(group.to_string(), consumer_group);
+            Some(result)
+        })?
+        .flatten()
+        .ok_or_else(no_group)
+    }
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(pairs: &[(&str, &str)]) -> Fields {
+        pairs
+            .iter()
+            .map(|(f, v)| (Bytes::from(f.to_string()), Bytes::from(v.to_string())))
+            .collect()
+    }
+
+    fn id(ms: u64, seq: u64) -> StreamId {
+        StreamId { ms, seq }
+    }
+
+    #[test]
+    fn test_parse_ids() {
+        assert_eq!(NewId::parse("*"), Some(NewId::Auto));
+        assert_eq!(NewId::parse("5-*"), Some(NewId::AutoSeq(5)));
+        assert_eq!(NewId::parse("5-3"), Some(NewId::Explicit(id(5, 3))));
+        assert_eq!(StreamId::parse_bound("7", false), Some(id(7, u64::MAX)));
+        assert_eq!(StreamId::parse_bound("-", true), Some(StreamId::MIN));
+        assert_eq!(StreamId::parse("abc", 0), None);
+    }
+
+    #[test]
+    fn test_xadd_ids_must_increase() {
+        let db = ShardedDatabase::new(4);
+        let first = db.xadd("s", NewId::Explicit(id(1, 1)), fields(&[("a", "1")]), None, false);
+        assert_eq!(first, Ok(Some(id(1, 1))));
+        assert_eq!(db.xadd("s", NewId::AutoSeq(1), fields(&[("a", "2")]), None, false), Ok(Some(id(1, 2))));
+        assert_eq!(db.xadd("s", NewId::Explicit(id(1, 2)), fields(&[]), None, false), Err(StreamError::IdTooSmall));
+        assert_eq!(db.xadd("z", NewId::Explicit(id(0, 0)), fields(&[]), None, false), Err(StreamError::IdZero));
+        // the rejected XADD didn't create the key
+        assert!(!db.contains_key("z"));
+        // auto ids are based on the clock and greater than anything before
+        let auto = db.xadd("s", NewId::Auto, fields(&[("a", "3")]), None, false).unwrap().unwrap();
+        assert!(auto > id(1, 2));
+        assert_eq!(db.xadd("missing", NewId::Auto, fields(&[]), None, true), Ok(None));
+        assert_eq!(db.xlen("missing"), Ok(0));
+    }
+
+    #[test]
+    fn test_maxlen_and_ranges() {
+        let db = ShardedDatabase::new(4);
+        for seq in 1..=5 {
+            db.xadd("s", NewId::Explicit(id(1, seq)), fields(&[("n", "x")]), Some(3), false).unwrap();
+        }
+        assert_eq!(db.xlen("s"), Ok(3));
+        let ids = |entries: Vec<StreamEntry>| entries.into_iter().map(|(id, _)| id).collect::<Vec<_>>();
+        assert_eq!(ids(db.xrange("s", StreamId::MIN, StreamId::MAX, None, false).unwrap()), vec![id(1, 3), id(1, 4), id(1, 5)]);
+        assert_eq!(ids(db.xrange("s", StreamId::MIN, StreamId::MAX, Some(2), true).unwrap()), vec![id(1, 5), id(1, 4)]);
+        assert_eq!(ids(db.xrange("s", id(1, 5), id(1, 3), None, false).unwrap()), vec![]);
+
+        let read = db.xread(&[("s".to_string(), id(1, 4)), ("other".to_string(), StreamId::MIN)], None).unwrap();
+        assert_eq!(read.len(), 1);
+        assert_eq!(ids(read[0].1.clone()), vec![id(1, 5)]);
+    }
+
+    #[test]
+    fn test_consumer_group_lifecycle() {
+        let db = ShardedDatabase::new(4);
+        assert_eq!(db.xgroup_create("s", "g", None, false), Err(StreamError::NoKey));
+        db.xgroup_create("s", "g", Some(StreamId::MIN), true).unwrap();
+        assert_eq!(db.xgroup_create("s", "g", None, false), Err(StreamError::BusyGroup));
+        for seq in 1..=3 {
+            db.xadd("s", NewId::Explicit(id(1, seq)), fields(&[("job", "x")]), None, false).unwrap();
+        }
+
+        let new = [("s".to_string(), GroupReadId::New)];
+        let alice = db.xreadgroup("g", "alice", &new, Some(2), false).unwrap();
+        assert_eq!(alice[0].1.len(), 2);
+        let bob = db.xreadgroup("g", "bob", &new, None, false).unwrap();
+        assert_eq!(bob[0].1[0].0, id(1, 3));
+        // everything was delivered
+        assert!(db.xreadgroup("g", "bob", &new, None, false).unwrap().is_empty());
+
+        let summary = db.xpending_summary("s", "g").unwrap();
+        assert_eq!(summary.count, 3);
+        assert_eq!(summary.range, Some((id(1, 1), id(1, 3))));
+        assert_eq!(summary.consumers, vec![("alice".to_string(), 2), ("bob".to_string(), 1)]);
+
+        // alice's history
+        let history = [("s".to_string(), GroupReadId::After(StreamId::MIN))];
+        assert_eq!(db.xreadgroup("g", "alice", &history, None, false).unwrap()[0].1.len(), 2);
+
+        assert_eq!(db.xack("s", "g", &[id(1, 1), id(9, 9)]), Ok(1));
+        let claimed = db.xclaim("s", "g", "bob", Duration::ZERO, &[id(1, 2)], false).unwrap();
+        assert_eq!(claimed.len(), 1);
+        let filter = PendingFilter {
+            start: StreamId::MIN,
+            end: StreamId::MAX,
+            count: 10,
+            consumer: Some("bob".to_string()),
+            min_idle: Duration::ZERO,
+        };
+        let pending = db.xpending("s", "g", &filter).unwrap();
+        assert_eq!(pending.len(), 2);
+        assert_eq!(pending[0].delivery_count, 2);
+
+        assert_eq!(db.xgroup_destroy("s", "g"), Ok(true));
+        assert!(matches!(db.xpending_summary("s", "g"), Err(StreamError::NoGroup { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_watch_wakes_up_on_xadd() {
+        let db = ShardedDatabase::new(4);
+        let watch = db.watch_streams(&["s".to_string()]);
+        db.xadd("s", NewId::Auto, fields(&[("a", "1")]), None, false).unwrap();
+        // the permit is kept even though nobody was waiting yet
+        tokio::time::timeout(Duration::from_secs(1), watch.changed()).await.unwrap();
+        drop(watch);
+        assert!(db.lock_shard("s").stream_watchers.is_empty());
+    }
+}
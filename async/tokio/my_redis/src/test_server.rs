@@ -0,0 +1,94 @@
+use crate::client::Client;
+use crate::server::{self, Shared};
+use crate::ShardedDatabase;
+use std::net::SocketAddr;
+use tokio::net::TcpListener;
+use tokio::sync::oneshot;
+
+/// A real server running inside the current tokio runtime, for tests.
+///
+/// It listens on an ephemeral port of 127.0.0.1, so tests can run in parallel and don't need
+/// a `server` binary started by hand on 6379. Connections are served by the same
+/// `server::process` loop as the binary. Dropping the `TestServer` shuts it down, closing
+/// the listener and every connection.
+///
+/// ```
+/// # #[tokio::main]
+/// # async fn main() -> my_redis::Result<()> {
+/// let server = my_redis::TestServer::start().await?;
+/// let client = server.client().await?;
+/// client.set("hello", "world").await?;
+/// // the database can be inspected directly
+/// assert_eq!(server.shared().db.get("hello"), Some("world".into()));
+/// # Ok(())
+/// # }
+/// ```
+pub struct TestServer {
+    addr: SocketAddr,
+    shared: Shared,
+    // Dropping the sender resolves the receiver the server waits on, which stops it.
+    _shutdown: oneshot::Sender<()>,
+}
+
+impl TestServer {
+    /// Starts a server with an empty database.
+    pub async fn start() -> crate::Result<TestServer> {
+        TestServer::start_with(Shared::new(ShardedDatabase::new(4))).await
+    }
+
+    /// Starts a server around existing state, e.g. a pre-filled database or tuned metrics.
+    pub async fn start_with(shared: Shared) -> crate::Result<TestServer> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let (tx, rx) = oneshot::channel::<()>();
+        tokio::spawn(server::run_until(listener, shared.clone(), async {
+            let _ = rx.await;
+        }));
+        Ok(TestServer { addr, shared, _shutdown: tx })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The server state, to set up or check data without going through a connection.
+    pub fn shared(&self) -> &Shared {
+        &self.shared
+    }
+
+    /// Connects a new `Client` to this server.
+    pub async fn client(&self) -> crate::Result<Client> {
+        Client::connect(&self.addr.to_string()).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::net::TcpStream;
+
+    #[tokio::test]
+    async fn test_servers_are_independent() {
+        let a = TestServer::start().await.unwrap();
+        let b = TestServer::start().await.unwrap();
+        assert_ne!(a.addr(), b.addr());
+        a.client().await.unwrap().set("key", "a").await.unwrap();
+        assert_eq!(b.client().await.unwrap().get("key").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_drop_shuts_the_server_down() {
+        let server = TestServer::start().await.unwrap();
+        let addr = server.addr();
+        let mut socket = TcpStream::connect(addr).await.unwrap();
+        drop(server);
+
+        // The open connection is closed, and new ones are refused.
+        use tokio::io::AsyncReadExt;
+        let mut buf = [0u8; 1];
+        let read = tokio::time::timeout(Duration::from_secs(1), socket.read(&mut buf)).await.unwrap();
+        assert!(matches!(read, Ok(0) | Err(_)));
+        assert!(TcpStream::connect(addr).await.is_err());
+    }
+}
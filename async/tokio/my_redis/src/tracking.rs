@@ -0,0 +1,344 @@
+//! Server side of client-side caching (CLIENT TRACKING).
+//!
+//! A client that turns tracking on may keep the values it reads in a local cache. The
+//! server remembers which keys each tracking client read, and when one of them changes it
+//! pushes an invalidation message to those clients, who drop the key from their cache:
+//!
+//! ```text
+//! >2
+//! $10
+//! invalidate
+//! *1
+//! $3
+//! key
+//! ```
+//!
+//! Like in Redis, a key is forgotten once its invalidation is sent: the client has to read
+//! it again to be told about the next change. In broadcast mode (BCAST) the server remembers
+//! nothing, and every change of a key matching one of the client's prefixes is sent.
+//!
+//! Push messages only exist in RESP3, so a client must send `HELLO 3` first.
+//!
+//! Note: Redis also has REDIRECT (invalidations on another connection, for RESP2 clients),
+//! OPTIN/OPTOUT (track only some reads) and NOLOOP (skip the client's own writes). They
+//! are not implemented.
+
+use crate::frame::Frame;
+use crate::{StorageBackend, Value};
+use bytes::Bytes;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+
+/// The id of a connection, as returned by CLIENT ID.
+pub type ClientId = u64;
+
+/// How a client wants to be told about changes.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum Mode {
+    /// About the keys it read (the default).
+    #[default]
+    Default,
+    /// About every key starting with one of these prefixes, read or not. No prefix means
+    /// every key.
+    Broadcast(Vec<String>),
+}
+
+/// The table of tracking clients, shared by the database and the connections.
+///
+/// Cloning is cheap, clones share the same table.
+#[derive(Clone, Default)]
+pub struct Tracking {
+    inner: Arc<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    next_id: AtomicU64,
+    // Whether any client has tracking on. Checked on every write without taking the lock,
+    // so a server nobody tracks pays almost nothing.
+    active: AtomicBool,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    clients: HashMap<ClientId, Client>,
+    // For the clients in default mode: who read each key since its last invalidation.
+    readers: HashMap<String, HashSet<ClientId>>,
+}
+
+struct Client {
+    mode: Mode,
+    // The connection task writes what it receives here between replies.
+    pushes: mpsc::UnboundedSender<Frame>,
+}
+
+impl Tracking {
+    pub fn new() -> Tracking {
+        Tracking::default()
+    }
+
+    /// Hands out the id of a new connection.
+    pub fn next_id(&self) -> ClientId {
+        self.inner.next_id.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Turns tracking on for `id`, replacing its previous mode. Invalidations are sent to
+    /// `pushes`.
+    pub fn enable(&self, id: ClientId, mode: Mode, pushes: mpsc::UnboundedSender<Frame>) {
+        let mut state = self.inner.state.lock().unwrap();
+        state.clients.insert(id, Client { mode, pushes });
+        self.inner.active.store(true, Ordering::Relaxed);
+    }
+
+    /// Turns tracking off for `id`; also called when the connection closes.
+    pub fn disable(&self, id: ClientId) {
+        let mut state = self.inner.state.lock().unwrap();
+        if state.clients.remove(&id).is_none() {
+            return;
+        }
+        state.readers.retain(|_, readers| {
+            readers.remove(&id);
+            !readers.is_empty()
+        });
+        self.inner.active.store(!state.clients.is_empty(), Ordering::Relaxed);
+    }
+
+    /// Whether any client has tracking on.
+    pub fn is_active(&self) -> bool {
+        self.inner.active.load(Ordering::Relaxed)
+    }
+
+    /// Records that `id` is reading `keys`. Does nothing if tracking is off for `id` or it
+    /// is in broadcast mode.
+    ///
+    /// Why before the command runs, and not after?
+    /// A write landing between the read and `remember` would find no reader and send
+    /// nothing, and the client would cache a stale value forever. Remembered first, the
+    /// worst case is an invalidation for a value the client never cached.
+    pub fn remember<'a>(&self, id: ClientId, keys: impl IntoIterator<Item = &'a str>) {
+        if !self.is_active() {
+            return;
+        }
+        let mut state = self.inner.state.lock().unwrap();
+        if !matches!(state.clients.get(&id), Some(Client { mode: Mode::Default, .. })) {
+            return;
+        }
+        for key in keys {
+            state.readers.entry(key.to_string()).or_default().insert(id);
+        }
+    }
+
+    /// Tells the clients interested in `key` that it changed.
+    pub fn invalidate(&self, key: &str) {
+        if !self.is_active() {
+            return;
+        }
+        let mut state = self.inner.state.lock().unwrap();
+        let readers = state.readers.remove(key).unwrap_or_default();
+        for (id, client) in &state.clients {
+            let interested = match &client.mode {
+                Mode::Default => readers.contains(id),
+                Mode::Broadcast(prefixes) => {
+                    prefixes.is_empty() || prefixes.iter().any(|prefix| key.starts_with(prefix.as_str()))
+                }
+            };
+            if interested {
+                // A closed channel means the connection is going away and will disable
+                // tracking itself.
+                let _ = client.pushes.send(invalidation(key));
+            }
+        }
+    }
+}
+
+/// The push message telling a client to drop `key` from its cache.
+pub fn invalidation(key: &str) -> Frame {
+    Frame::Push(vec![
+        Frame::Bulk(Bytes::from_static(b"invalidate")),
+        Frame::Array(vec![Frame::Bulk(Bytes::copy_from_slice(key.as_bytes()))]),
+    ])
+}
+
+/// The storage of a shard, recording the keys that commands modify.
+///
+/// Why here and not in the command handlers?
+/// Keys also change behind the commands' back: expired keys are removed, BLPOP takes values
+/// pushed by another client, scripts run many commands. Every one of those goes through
+/// the storage, so recording there can't miss a write. `ShardGuard` sends the invalidations
+/// when it releases the shard.
+///
+/// Note: `get_mut` counts as a write even if the caller only looks, an extra invalidation
+/// is harmless.
+pub(crate) struct Recorded {
+    storage: Box<dyn StorageBackend>,
+    tracking: Tracking,
+    modified: Vec<String>,
+}
+
+impl Recorded {
+    pub(crate) fn new(storage: Box<dyn StorageBackend>, tracking: Tracking) -> Recorded {
+        Recorded { storage, tracking, modified: Vec::new() }
+    }
+
+    fn record(&mut self, key: &str) {
+        if self.tracking.is_active() {
+            self.modified.push(key.to_string());
+        }
+    }
+
+    pub(crate) fn get(&mut self, key: &str) -> Option<&Value> {
+        self.storage.get(key)
+    }
+
+    pub(crate) fn get_mut(&mut self, key: &str) -> Option<&mut Value> {
+        self.record(key);
+        self.storage.get_mut(key)
+    }
+
+    pub(crate) fn insert(&mut self, key: String, value: Value) {
+        self.record(&key);
+        self.storage.insert(key, value)
+    }
+
+    pub(crate) fn remove(&mut self, key: &str) -> bool {
+        let removed = self.storage.remove(key);
+        if removed {
+            self.record(key);
+        }
+        removed
+    }
+
+    pub(crate) fn contains_key(&self, key: &str) -> bool {
+        self.storage.contains_key(key)
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.storage.len()
+    }
+
+    pub(crate) fn keys(&self) -> Vec<String> {
+        self.storage.keys()
+    }
+
+    pub(crate) fn used_memory(&self) -> usize {
+        self.storage.used_memory()
+    }
+
+    pub(crate) fn sync(&mut self) -> io::Result<()> {
+        self.storage.sync()
+    }
+
+    pub(crate) fn compact(&mut self) -> io::Result<()> {
+        self.storage.compact()
+    }
+
+    pub(crate) fn set_expiry(&mut self, key: &str, expires_at: Option<u64>) {
+        self.storage.set_expiry(key, expires_at)
+    }
+
+    pub(crate) fn expiries(&self) -> Vec<(String, u64)> {
+        self.storage.expiries()
+    }
+
+    /// Sends the invalidations of the keys modified since the last call.
+    pub(crate) fn send_invalidations(&mut self) {
+        // A command may touch a key more than once (insert, then get_mut), tell clients once.
+        let mut sent = HashSet::new();
+        for key in self.modified.drain(..) {
+            if !sent.contains(&key) {
+                self.tracking.invalidate(&key);
+                sent.insert(key);
+            }
+        }
+    }
+}
+
+/// The keys a read-only command reads, for `Tracking::remember`. Empty for writes and for
+/// commands that don't read keys.
+pub fn read_keys<'a>(name: &str, args: &'a [Bytes]) -> Vec<&'a str> {
+    let keys = match name {
+        "EXISTS" | "PFCOUNT" => args,
+        "GET" | "DUMP" | "TTL" | "PTTL" | "LLEN" | "LRANGE" | "XLEN" | "XRANGE" | "XREVRANGE" | "GETBIT" | "BITCOUNT"
+        | "ZSCORE" | "ZCARD" | "ZRANGE" | "GEODIST" | "GEOSEARCH" => &args[..args.len().min(1)],
+        _ => &[],
+    };
+    keys.iter().filter_map(|key| std::str::from_utf8(key).ok()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ShardedDatabase;
+
+    fn keys(rx: &mut mpsc::UnboundedReceiver<Frame>) -> Vec<String> {
+        let mut keys = Vec::new();
+        while let Ok(Frame::Push(mut parts)) = rx.try_recv() {
+            let Some(Frame::Array(invalidated)) = parts.pop() else { panic!("not an invalidation") };
+            for key in invalidated {
+                let Frame::Bulk(key) = key else { panic!("not a key") };
+                keys.push(String::from_utf8(key.to_vec()).unwrap());
+            }
+        }
+        keys
+    }
+
+    #[test]
+    fn test_default_mode_invalidates_read_keys_once() {
+        let db = ShardedDatabase::new(4);
+        let tracking = db.tracking();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let id = tracking.next_id();
+        tracking.enable(id, Mode::Default, tx);
+
+        db.insert("unread", Bytes::from("1"));
+        tracking.remember(id, ["key"]);
+        db.insert("key", Bytes::from("1"));
+        assert_eq!(keys(&mut rx), vec!["key"]);
+        // forgotten until read again
+        db.insert("key", Bytes::from("2"));
+        assert!(keys(&mut rx).is_empty());
+
+        tracking.remember(id, ["key"]);
+        db.insert_with_ttl("key", Bytes::from("3"), Some(std::time::Duration::ZERO)).unwrap();
+        assert_eq!(keys(&mut rx), vec!["key"]);
+        // expiring is a change too
+        tracking.remember(id, ["key"]);
+        assert_eq!(db.get("key"), None);
+        assert_eq!(keys(&mut rx), vec!["key"]);
+
+        tracking.remember(id, ["key"]);
+        tracking.disable(id);
+        db.insert("key", Bytes::from("4"));
+        assert!(keys(&mut rx).is_empty());
+        assert!(!tracking.is_active());
+    }
+
+    #[test]
+    fn test_broadcast_mode_matches_prefixes() {
+        let db = ShardedDatabase::new(4);
+        let tracking = db.tracking();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let id = tracking.next_id();
+        tracking.enable(id, Mode::Broadcast(vec!["user:".to_string(), "session:".to_string()]), tx);
+
+        db.insert("user:1", Bytes::from("a"));
+        db.insert("other", Bytes::from("b"));
+        db.push("session:9", &[Bytes::from("c")], crate::list::End::Left).unwrap();
+        db.remove("missing");
+        assert_eq!(keys(&mut rx), vec!["user:1", "session:9"]);
+    }
+
+    #[test]
+    fn test_read_keys() {
+        let args = |parts: &[&str]| parts.iter().map(|p| Bytes::from(p.to_string())).collect::<Vec<_>>();
+        assert_eq!(read_keys("GET", &args(&["k"])), vec!["k"]);
+        assert_eq!(read_keys("EXISTS", &args(&["a", "b"])), vec!["a", "b"]);
+        assert_eq!(read_keys("LRANGE", &args(&["l", "0", "-1"])), vec!["l"]);
+        assert!(read_keys("SET", &args(&["k", "v"])).is_empty());
+        assert!(read_keys("GET", &[]).is_empty());
+    }
+}
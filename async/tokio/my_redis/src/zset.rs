@@ -0,0 +1,277 @@
+//! Sorted set values (ZADD/ZSCORE/ZREM/ZCARD/ZRANGE), also the storage of the geo commands.
+//!
+//! Every member has a score, and members are ordered by score, then by member for equal
+//! scores. A member is kept twice: in a `HashMap` to find its score, and in a `BTreeSet` of
+//! `(score, member)` pairs for the order. Redis does the same with a dict and a skip list.
+
+use crate::storage::codec::{self, Decoder};
+use crate::{ShardedDatabase, Value, WrongType};
+use bytes::Bytes;
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap};
+use std::io;
+
+/// A sorted set value.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct SortedSet {
+    scores: HashMap<Bytes, f64>,
+    ordered: BTreeSet<(Score, Bytes)>,
+}
+
+/// An `f64` that can go in a `BTreeSet`.
+///
+/// Why not `f64` directly?
+/// `f64` is only `PartialOrd` because of NaN. Scores are never NaN (the commands reject it), and
+/// `total_cmp` gives the same order as `<` for everything else.
+#[derive(Debug, Clone, Copy)]
+struct Score(f64);
+
+impl PartialEq for Score {
+    fn eq(&self, other: &Score) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Score {}
+
+impl PartialOrd for Score {
+    fn partial_cmp(&self, other: &Score) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Score {
+    fn cmp(&self, other: &Score) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+impl SortedSet {
+    /// Sets the score of `member`, returning its previous score.
+    pub fn insert(&mut self, member: Bytes, score: f64) -> Option<f64> {
+        // -0.0 and 0.0 are equal scores, but `total_cmp` orders them apart.
+        let score = if score == 0.0 { 0.0 } else { score };
+        let old = self.scores.insert(member.clone(), score);
+        if let Some(old) = old {
+            self.ordered.remove(&(Score(old), member.clone()));
+        }
+        self.ordered.insert((Score(score), member));
+        old
+    }
+
+    pub fn score(&self, member: &[u8]) -> Option<f64> {
+        self.scores.get(member).copied()
+    }
+
+    /// Removes `member`, returning whether it was there.
+    pub fn remove(&mut self, member: &[u8]) -> bool {
+        match self.scores.remove_entry(member) {
+            Some((member, score)) => self.ordered.remove(&(Score(score), member)),
+            None => false,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.scores.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scores.is_empty()
+    }
+
+    /// The members with their scores, lowest score first.
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = (&Bytes, f64)> {
+        self.ordered.iter().map(|(score, member)| (member, score.0))
+    }
+
+    /// Approximate number of bytes held by the members and scores.
+    pub(crate) fn size(&self) -> usize {
+        self.scores.keys().map(|member| member.len() + 8).sum()
+    }
+
+    /// Writes the members in order, for `storage::codec`.
+    pub(crate) fn encode(&self, buf: &mut Vec<u8>) {
+        codec::put_u64(buf, self.len() as u64);
+        for (member, score) in self.iter() {
+            codec::put_bytes(buf, member);
+            codec::put_u64(buf, score.to_bits());
+        }
+    }
+
+    /// Reads a sorted set written by `encode`.
+    pub(crate) fn decode(decoder: &mut Decoder) -> io::Result<SortedSet> {
+        let mut set = SortedSet::default();
+        for _ in 0..decoder.u64()? {
+            let member = decoder.bytes()?;
+            let score = f64::from_bits(decoder.u64()?);
+            if score.is_nan() {
+                return Err(codec::invalid("NaN score"));
+            }
+            set.insert(member, score);
+        }
+        Ok(set)
+    }
+}
+
+/// Which members ZADD/GEOADD may write.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Update {
+    Always,
+    /// `NX`: only add new members.
+    OnlyNew,
+    /// `XX`: only update existing members.
+    OnlyExisting,
+}
+
+/// What a ZADD did: `added` new members, and changed the score of `updated` existing ones.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Added {
+    pub added: usize,
+    pub updated: usize,
+}
+
+impl ShardedDatabase {
+    /// Adds members or updates their scores (ZADD), creating the set if needed.
+    pub fn zadd(&self, key: &str, members: &[(f64, Bytes)], update: Update) -> Result<Added, WrongType> {
+        let mut shard = self.lock_shard(key);
+        let exists = match shard.entries.get(key) {
+            None => false,
+            Some(Value::SortedSet(_)) => true,
+            Some(_) => return Err(WrongType),
+        };
+        // XX never creates the key.
+        if !exists && update == Update::OnlyExisting {
+            return Ok(Added::default());
+        }
+        if !exists {
+            shard.entries.insert(key.to_string(), Value::SortedSet(SortedSet::default()));
+        }
+        let Some(Value::SortedSet(set)) = shard.entries.get_mut(key) else {
+            unreachable!("checked above");
+        };
+
+        let mut result = Added::default();
+        for (score, member) in members {
+            match (set.score(member), update) {
+                (Some(_), Update::OnlyNew) | (None, Update::OnlyExisting) => {}
+                (Some(old), _) if old == *score => {}
+                (Some(_), _) => {
+                    set.insert(member.clone(), *score);
+                    result.updated += 1;
+                }
+                (None, _) => {
+                    set.insert(member.clone(), *score);
+                    result.added += 1;
+                }
+            }
+        }
+        // NX with only existing members leaves a new set empty.
+        if set.is_empty() {
+            shard.entries.remove(key);
+        }
+        Ok(result)
+    }
+
+    /// Returns the score of `member` (ZSCORE).
+    pub fn zscore(&self, key: &str, member: &[u8]) -> Result<Option<f64>, WrongType> {
+        Ok(self.with_zset(key, |set| set.score(member))?.flatten())
+    }
+
+    /// Removes members, returning how many were there (ZREM). An empty set is deleted.
+    pub fn zrem(&self, key: &str, members: &[Bytes]) -> Result<usize, WrongType> {
+        let mut shard = self.lock_shard(key);
+        let set = match shard.entries.get_mut(key) {
+            None => return Ok(0),
+            Some(Value::SortedSet(set)) => set,
+            Some(_) => return Err(WrongType),
+        };
+        let removed = members.iter().filter(|member| set.remove(member)).count();
+        if set.is_empty() {
+            shard.remove(key);
+        }
+        Ok(removed)
+    }
+
+    /// Returns the number of members (ZCARD).
+    pub fn zcard(&self, key: &str) -> Result<usize, WrongType> {
+        Ok(self.with_zset(key, |set| set.len())?.unwrap_or(0))
+    }
+
+    /// Returns the members with ranks from `start` to `stop`, both inclusive (ZRANGE).
+    /// Negative ranks count from the highest score, like LRANGE.
+    pub fn zrange(&self, key: &str, start: i64, stop: i64) -> Result<Vec<(Bytes, f64)>, WrongType> {
+        let range = self.with_zset(key, |set| {
+            let len = set.len() as i64;
+            let start = if start < 0 { (len + start).max(0) } else { start };
+            let stop = if stop < 0 { len + stop } else { stop.min(len - 1) };
+            if start > stop {
+                return Vec::new();
+            }
+            set.iter()
+                .skip(start as usize)
+                .take((stop - start + 1) as usize)
+                .map(|(member, score)| (member.clone(), score))
+                .collect()
+        })?;
+        Ok(range.unwrap_or_default())
+    }
+
+    /// Runs `f` on the sorted set at `key`; `None` if the key doesn't exist.
+    pub(crate) fn with_zset<T>(&self, key: &str, f: impl FnOnce(&SortedSet) -> T) -> Result<Option<T>, WrongType> {
+        let mut shard = self.lock_shard(key);
+        match shard.entries.get(key) {
+            Some(Value::SortedSet(set)) => Ok(Some(f(set))),
+            Some(_) => Err(WrongType),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Formats a score like Redis: `1.5`, `3`, `inf`.
+pub fn format_score(score: f64) -> String {
+    score.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn b(s: &str) -> Bytes {
+        Bytes::from(s.to_string())
+    }
+
+    #[test]
+    fn test_order_by_score_then_member() {
+        let db = ShardedDatabase::new(4);
+        let members = [(2.0, b("b")), (1.0, b("z")), (2.0, b("a")), (-0.5, b("neg"))];
+        assert_eq!(db.zadd("z", &members, Update::Always), Ok(Added { added: 4, updated: 0 }));
+        let names: Vec<Bytes> = db.zrange("z", 0, -1).unwrap().into_iter().map(|(m, _)| m).collect();
+        assert_eq!(names, vec![b("neg"), b("z"), b("a"), b("b")]);
+        assert_eq!(db.zrange("z", -2, -1).unwrap(), vec![(b("a"), 2.0), (b("b"), 2.0)]);
+        assert_eq!(db.zrange("z", 3, 1).unwrap(), vec![]);
+
+        // moving a member re-sorts it
+        assert_eq!(db.zadd("z", &[(0.0, b("b"))], Update::Always), Ok(Added { added: 0, updated: 1 }));
+        assert_eq!(db.zrange("z", 1, 1).unwrap(), vec![(b("b"), 0.0)]);
+        assert_eq!(db.zcard("z"), Ok(4));
+    }
+
+    #[test]
+    fn test_nx_xx_and_removal() {
+        let db = ShardedDatabase::new(4);
+        assert_eq!(db.zadd("z", &[(1.0, b("a"))], Update::OnlyExisting), Ok(Added::default()));
+        assert!(!db.contains_key("z"));
+        db.zadd("z", &[(1.0, b("a"))], Update::Always).unwrap();
+        assert_eq!(db.zadd("z", &[(5.0, b("a")), (2.0, b("b"))], Update::OnlyNew), Ok(Added { added: 1, updated: 0 }));
+        assert_eq!(db.zscore("z", b"a"), Ok(Some(1.0)));
+        assert_eq!(db.zadd("z", &[(5.0, b("a")), (3.0, b("c"))], Update::OnlyExisting), Ok(Added { added: 0, updated: 1 }));
+        assert_eq!(db.zscore("z", b"c"), Ok(None));
+
+        assert_eq!(db.zrem("z", &[b("a"), b("missing")]), Ok(1));
+        assert_eq!(db.zrem("z", &[b("b")]), Ok(1));
+        assert!(!db.contains_key("z"));
+
+        db.insert("s", b("string"));
+        assert_eq!(db.zcard("s"), Err(WrongType));
+    }
+}
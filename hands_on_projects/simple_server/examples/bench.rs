@@ -0,0 +1,123 @@
+//! Compares the thread-pool `Server` and the tokio `AsyncServer` under many slow clients.
+//!
+//! `cargo run --release --example bench`
+//!
+//! Two scenarios, each against both servers:
+//! - slow handlers: `CLIENTS` requests at once to a route that waits 50 ms;
+//! - slow clients: `CLIENTS` connections send half a request and stall for a second, while
+//!   another client makes quick requests and measures how long they take.
+
+use simple_server::{AsyncRouter, Method, Request, Response, Router, Server};
+use std::net::SocketAddr;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::task::JoinSet;
+
+const CLIENTS: usize = 100;
+const HANDLER_DELAY: Duration = Duration::from_millis(50);
+const STALL: Duration = Duration::from_secs(1);
+const THREADS: usize = 4;
+
+#[tokio::main]
+async fn main() {
+    let sync_addr = start_sync();
+    let async_addr = start_async();
+
+    // Note: measured first and printed after, as the pool logs every job it runs.
+    let handlers = (slow_handlers(sync_addr).await, slow_handlers(async_addr).await);
+    let clients = (slow_clients(sync_addr).await, slow_clients(async_addr).await);
+
+    println!("\n{} clients, {} pool threads\n", CLIENTS, THREADS);
+    println!("{:<16} {:>14} {:>14}", "", "thread pool", "tokio");
+    println!("{:<16} {:>14} {:>14}", "slow handlers", format_ms(handlers.0), format_ms(handlers.1));
+    println!("{:<16} {:>14} {:>14}", "slow clients", format_ms(clients.0), format_ms(clients.1));
+    println!("\nslow handlers: time for all the requests to finish");
+    println!("slow clients: worst latency of a quick request while the others stall");
+}
+
+/// The thread-pool server on its own thread.
+fn start_sync() -> SocketAddr {
+    let mut router = Router::new();
+    router.route(Method::Get, "/", |_: Request| Response::text(200, "hi")).route(Method::Get, "/sleep", |_: Request| {
+        thread::sleep(HANDLER_DELAY);
+        Response::text(200, "slept")
+    });
+    let server = Server::builder().addr("127.0.0.1:0").threads(THREADS).bind(router).unwrap();
+    let addr = server.local_addr();
+    thread::spawn(move || server.run().unwrap());
+    addr
+}
+
+/// The async server on its own runtime and threads, so the clients don't share them.
+fn start_async() -> SocketAddr {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let runtime = tokio::runtime::Builder::new_multi_thread().worker_threads(THREADS).enable_all().build().unwrap();
+        runtime.block_on(async {
+            let mut router = AsyncRouter::new();
+            router.route(Method::Get, "/", |_: Request| async { Response::text(200, "hi") }).route(
+                Method::Get,
+                "/sleep",
+                |_: Request| async {
+                    tokio::time::sleep(HANDLER_DELAY).await;
+                    Response::text(200, "slept")
+                },
+            );
+            let server = Server::builder().addr("127.0.0.1:0").bind_async(router).await.unwrap();
+            sender.send(server.local_addr()).unwrap();
+            server.run().await.unwrap();
+        });
+    });
+    receiver.recv().unwrap()
+}
+
+async fn slow_handlers(addr: SocketAddr) -> Duration {
+    let started = Instant::now();
+    let mut clients = JoinSet::new();
+    for _ in 0..CLIENTS {
+        clients.spawn(get(addr, "/sleep"));
+    }
+    while clients.join_next().await.is_some() {}
+    started.elapsed()
+}
+
+async fn slow_clients(addr: SocketAddr) -> Duration {
+    let mut stalled = JoinSet::new();
+    for _ in 0..CLIENTS {
+        stalled.spawn(async move {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream.write_all(b"GET / HTTP/1.1\r\nHost: bench\r\n").await.unwrap();
+            tokio::time::sleep(STALL).await;
+            stream.write_all(b"Connection: close\r\n\r\n").await.unwrap();
+            let mut out = Vec::new();
+            stream.read_to_end(&mut out).await.unwrap();
+        });
+    }
+    // let the stalled clients connect first
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let mut worst = Duration::ZERO;
+    for _ in 0..10 {
+        let started = Instant::now();
+        get(addr, "/").await;
+        worst = worst.max(started.elapsed());
+    }
+    while stalled.join_next().await.is_some() {}
+    worst
+}
+
+async fn get(addr: SocketAddr, path: &str) {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let request = format!("GET {} HTTP/1.1\r\nHost: bench\r\nConnection: close\r\n\r\n", path);
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut out = Vec::new();
+    stream.read_to_end(&mut out).await.unwrap();
+    assert!(out.starts_with(b"HTTP/1.1 200 "), "{}", String::from_utf8_lossy(&out));
+}
+
+fn format_ms(duration: Duration) -> String {
+    format!("{:.1} ms", duration.as_secs_f64() * 1000.0)
+}
@@ -0,0 +1,547 @@
+//! The same server on tokio.
+//!
+//! Why another server?
+//! `Server` gives each connection a thread from the pool for as long as it is open. A client
+//! sending its request slowly, an idle keep-alive connection, or a handler that sleeps keeps
+//! that thread busy doing nothing, and with 4 threads the fifth client waits. Here each
+//! connection is a task: a waiting task costs a few hundred bytes, not a thread.
+//!
+//! ```ignore
+//! let mut router = AsyncRouter::new();
+//! router.route(Method::Get, "/sleep", |_: Request| async {
+//!     tokio::time::sleep(Duration::from_secs(5)).await;
+//!     Response::text(200, "done")
+//! });
+//! // everything else goes to the usual router, run on tokio's blocking threads
+//! router.not_found(Blocking::new(sync_router));
+//! let server = Server::builder().addr("127.0.0.1:7878").bind_async(router).await?;
+//! server.run().await?;
+//! ```
+
+use crate::connection::{ConnectionConfig, IDLE_POLL, Upgraded, buffer_stream, has_token, wants_keep_alive};
+use crate::request::{BodyFraming, Limits, Method, ParseError, Request, Version, parse_chunk_size, read_headers};
+use crate::response::{Body, Response, StatusCode};
+use crate::router::{Handler, Lookup, Routes, method_not_allowed, not_found};
+use crate::server::{ServerBuilder, ShutdownHandle};
+use std::future::Future;
+use std::io::{self, Read};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Instant;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::oneshot;
+use tokio::task::{self, JoinSet};
+use tokio::time;
+
+/// The future an `AsyncHandler` returns.
+///
+/// Note: boxed so that handlers of different types fit in one route table, like
+/// `Box<dyn Handler>` in `Router`.
+pub type ResponseFuture = Pin<Box<dyn Future<Output = Response> + Send + 'static>>;
+
+/// `Handler` for async code: the response is a future, so the handler can wait (for a
+/// timer, a database, another server) without holding a thread.
+pub trait AsyncHandler: Send + Sync + 'static {
+    fn call(&self, request: Request) -> ResponseFuture;
+}
+
+impl<F, Fut> AsyncHandler for F
+where
+    F: Fn(Request) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Response> + Send + 'static,
+{
+    fn call(&self, request: Request) -> ResponseFuture {
+        Box::pin(self(request))
+    }
+}
+
+/// Runs a blocking `Handler` (a `Router`, `StaticFiles`, middleware...) on tokio's blocking
+/// thread pool, so it can be used by the async server without stalling the other tasks.
+pub struct Blocking<H>(Arc<H>);
+
+impl<H: Handler> Blocking<H> {
+    pub fn new(handler: H) -> Blocking<H> {
+        Blocking(Arc::new(handler))
+    }
+}
+
+impl<H: Handler> AsyncHandler for Blocking<H> {
+    fn call(&self, request: Request) -> ResponseFuture {
+        let handler = Arc::clone(&self.0);
+        Box::pin(async move {
+            match task::spawn_blocking(move || handler.call(request)).await {
+                Ok(response) => response,
+                // the handler panicked
+                Err(_) => internal_error(),
+            }
+        })
+    }
+}
+
+/// `Router` for async handlers, matching routes the same way.
+pub struct AsyncRouter {
+    routes: Routes<dyn AsyncHandler>,
+    not_found: Option<Box<dyn AsyncHandler>>,
+}
+
+impl AsyncRouter {
+    pub fn new() -> AsyncRouter {
+        AsyncRouter { routes: Routes::new(), not_found: None }
+    }
+
+    /// Registers `handler` for `method` requests whose path matches `pattern`.
+    ///
+    /// # Panics
+    ///
+    /// If the pattern doesn't start with `/` or has a `*wildcard` before the last segment.
+    pub fn route(&mut self, method: Method, pattern: &str, handler: impl AsyncHandler) -> &mut AsyncRouter {
+        self.routes.add(method, pattern, Box::new(handler));
+        self
+    }
+
+    /// Registers a blocking handler, run with `Blocking`.
+    pub fn route_blocking(&mut self, method: Method, pattern: &str, handler: impl Handler) -> &mut AsyncRouter {
+        self.route(method, pattern, Blocking::new(handler))
+    }
+
+    /// Replaces the default `404 Not Found` handler, e.g. by a `Blocking` router with the
+    /// routes that don't need to be async.
+    pub fn not_found(&mut self, handler: impl AsyncHandler) -> &mut AsyncRouter {
+        self.not_found = Some(Box::new(handler));
+        self
+    }
+
+    pub fn dispatch(&self, mut request: Request) -> ResponseFuture {
+        match self.routes.find(&mut request) {
+            Lookup::Route(handler) => handler.call(request),
+            Lookup::MethodNotAllowed(allow) => Box::pin(async move { method_not_allowed(&allow) }),
+            Lookup::NotFound => match &self.not_found {
+                Some(handler) => handler.call(request),
+                None => Box::pin(async { not_found() }),
+            },
+        }
+    }
+}
+
+impl Default for AsyncRouter {
+    fn default() -> AsyncRouter {
+        AsyncRouter::new()
+    }
+}
+
+impl AsyncHandler for AsyncRouter {
+    fn call(&self, request: Request) -> ResponseFuture {
+        self.dispatch(request)
+    }
+}
+
+impl ServerBuilder {
+    /// Like `bind`, for the async server. The number of threads doesn't apply: tokio's
+    /// runtime has its own.
+    pub async fn bind_async(self, handler: impl AsyncHandler) -> io::Result<AsyncServer> {
+        let listener = TcpListener::bind(&self.addr).await?;
+        let addr = listener.local_addr()?;
+        Ok(AsyncServer {
+            listener,
+            handler: Arc::new(handler),
+            config: Arc::new(self.connection),
+            handle: ShutdownHandle { shutdown: Arc::new(AtomicBool::new(false)), addr },
+        })
+    }
+}
+
+pub struct AsyncServer {
+    listener: TcpListener,
+    handler: Arc<dyn AsyncHandler>,
+    config: Arc<ConnectionConfig>,
+    handle: ShutdownHandle,
+}
+
+impl AsyncServer {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.handle.addr
+    }
+
+    /// A handle to stop the server, the same as `Server`'s.
+    pub fn handle(&self) -> ShutdownHandle {
+        self.handle.clone()
+    }
+
+    /// Accepts connections until `shutdown` is called on a handle, then waits for the
+    /// connections already accepted to finish.
+    pub async fn run(self) -> io::Result<()> {
+        let mut connections = JoinSet::new();
+        loop {
+            let stream = match self.listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    eprintln!("Failed to establish a connection: {}", e);
+                    continue;
+                }
+            };
+            if self.handle.is_shutdown() {
+                break;
+            }
+            let handler = Arc::clone(&self.handler);
+            let config = Arc::clone(&self.config);
+            let shutdown = Arc::clone(&self.handle.shutdown);
+            connections.spawn(async move {
+                if let Err(e) = serve_connection(stream, handler, &config, &shutdown).await {
+                    eprintln!("Connection error: {}", e);
+                }
+            });
+            // forget the connections that are done
+            while connections.try_join_next().is_some() {}
+        }
+        println!("Server is shutting down.");
+        drop(self.listener);
+        while connections.join_next().await.is_some() {}
+        Ok(())
+    }
+}
+
+/// The async twin of `connection::serve_connection`, with the same keep-alive rules.
+async fn serve_connection(
+    stream: TcpStream,
+    handler: Arc<dyn AsyncHandler>,
+    config: &ConnectionConfig,
+    shutdown: &Arc<AtomicBool>,
+) -> io::Result<usize> {
+    stream.set_nodelay(true)?;
+    let remote_addr = stream.peer_addr().ok();
+    let (read, mut write) = stream.into_split();
+    let mut reader = BufReader::new(read);
+    let mut served = 0;
+
+    loop {
+        let idle_since = Instant::now();
+        let ready = loop {
+            match time::timeout(IDLE_POLL.min(config.keep_alive_timeout), reader.fill_buf()).await {
+                Ok(Ok(buf)) => break !buf.is_empty(),
+                Ok(Err(e)) => return Err(e),
+                Err(_) if shutdown.load(Ordering::SeqCst) || idle_since.elapsed() >= config.keep_alive_timeout => break false,
+                Err(_) => {}
+            }
+        };
+        if !ready {
+            break;
+        }
+
+        // Note: the read timeout covers the whole request here, not each read: a client
+        // sending one byte every few seconds can't keep the connection forever.
+        let mut request = match time::timeout(config.read_timeout, read_request(&mut reader, &config.limits)).await {
+            Ok(Ok(request)) => request,
+            Ok(Err(e)) => {
+                if let Some((code, reason)) = e.status() {
+                    let response = Response::text(code, reason).with_header("Connection", "close");
+                    write_response(&mut write, response, false).await?;
+                }
+                break;
+            }
+            Err(_) => break,
+        };
+        served += 1;
+        request.remote_addr = remote_addr;
+
+        let head = request.method == Method::Head;
+        let version = request.version;
+        let mut keep_alive = wants_keep_alive(&request) && served < config.max_requests;
+        // Why a task per request?
+        // A panic in the handler then ends that task only, and shows up as an error here.
+        let mut response = match tokio::spawn(handler.call(request)).await {
+            Ok(response) => response,
+            Err(_) => internal_error(),
+        };
+        if let Some(upgrade) = response.upgrade.take()
+            && response.status == StatusCode::SWITCHING_PROTOCOLS
+        {
+            write_response(&mut write, response, false).await?;
+            let buffered = reader.buffer().to_vec();
+            // Why go back to a std stream?
+            // `Upgraded` is for blocking code (see `Blocking`), so it gets the socket in
+            // blocking mode again.
+            let stream = reader.into_inner().reunite(write).map_err(io::Error::other)?.into_std()?;
+            stream.set_nonblocking(false)?;
+            let upgraded =
+                Upgraded { stream, buffered, shutdown: Arc::clone(shutdown), read_timeout: config.read_timeout };
+            // Note: not `spawn_blocking`, an upgraded connection may stay open for hours and
+            // would hold one of its threads all along. It gets a thread of its own, like in
+            // `Server`, and the oneshot tells us when it's done.
+            let (done, finished) = oneshot::channel();
+            thread::spawn(move || {
+                upgrade.run(upgraded);
+                let _ = done.send(());
+            });
+            let _ = finished.await;
+            return Ok(served);
+        }
+        if has_token(response.headers.get("Connection"), "close") || shutdown.load(Ordering::SeqCst) {
+            keep_alive = false;
+        }
+
+        if version == Version::Http10 {
+            // HTTP/1.0 clients don't know chunked encoding; see `connection::serve_connection`.
+            if let Body::Stream(stream, None) = response.body {
+                let max = config.limits.max_body;
+                match task::spawn_blocking(move || buffer_stream(stream, max)).await.map_err(io::Error::other)?? {
+                    Some(bytes) => response.body = Body::Bytes(bytes),
+                    None => {
+                        keep_alive = false;
+                        response = Response::text(StatusCode::INTERNAL_SERVER_ERROR, "500 Internal Server Error");
+                    }
+                }
+            }
+            if keep_alive {
+                response = response.with_header("Connection", "keep-alive");
+            }
+        }
+        if !keep_alive {
+            response = response.with_header("Connection", "close");
+        }
+        write_response(&mut write, response, head).await?;
+        if !keep_alive {
+            break;
+        }
+    }
+
+    let _ = write.shutdown().await;
+    Ok(served)
+}
+
+/// Reads a request: the head is collected here and parsed by `Request::read_head`, so both
+/// servers accept and reject exactly the same requests.
+async fn read_request(reader: &mut BufReader<OwnedReadHalf>, limits: &Limits) -> Result<Request, ParseError> {
+    let head = read_head_bytes(reader, limits).await?;
+    let mut request = Request::read_head(&mut &head[..], limits)?;
+    request.body = match request.body_framing(limits)? {
+        BodyFraming::Empty => Vec::new(),
+        BodyFraming::Length(length) => {
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).await?;
+            body
+        }
+        BodyFraming::Chunked => read_chunked(reader, limits).await?,
+    };
+    Ok(request)
+}
+
+/// The bytes of the request line and the headers, up to and with the empty line.
+async fn read_head_bytes(reader: &mut BufReader<OwnedReadHalf>, limits: &Limits) -> Result<Vec<u8>, ParseError> {
+    let max = limits.max_request_line + limits.max_header_bytes + 4;
+    let mut head = Vec::new();
+    loop {
+        let start = head.len();
+        let read = (&mut *reader).take((max - start) as u64).read_until(b'\n', &mut head).await?;
+        if read == 0 {
+            return Err(if head.is_empty() { ParseError::Closed } else { io::Error::from(io::ErrorKind::UnexpectedEof).into() });
+        }
+        if !head.ends_with(b"\n") {
+            if head.len() < max {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+            // still in the request line, or already in the headers?
+            return Err(if head.contains(&b'\n') { ParseError::HeadersTooLarge } else { ParseError::UriTooLong });
+        }
+        let line = &head[start..];
+        if line == b"\r\n" || line == b"\n" {
+            if start == 0 {
+                // an empty line before the request line, see `Request::read_head`
+                head.clear();
+                continue;
+            }
+            return Ok(head);
+        }
+    }
+}
+
+/// The async twin of `request::read_chunked`.
+async fn read_chunked(reader: &mut BufReader<OwnedReadHalf>, limits: &Limits) -> Result<Vec<u8>, ParseError> {
+    let mut body = Vec::new();
+    loop {
+        let line = read_line(reader, 1024).await?.ok_or(ParseError::BadRequest("chunk size line too long"))?;
+        let size = parse_chunk_size(&line, body.len(), limits)?;
+        if size == 0 {
+            break;
+        }
+        let start = body.len();
+        body.resize(start + size, 0);
+        reader.read_exact(&mut body[start..]).await?;
+        let mut crlf = [0; 2];
+        reader.read_exact(&mut crlf).await?;
+        if &crlf != b"\r\n" {
+            return Err(ParseError::BadRequest("chunk not followed by CRLF"));
+        }
+    }
+    // Trailer fields are collected like the head and checked by `request::read_headers`, so
+    // they are held to the same rules (names, count and size) as on the other server.
+    let max = limits.max_header_bytes + 2;
+    let mut trailers = Vec::new();
+    loop {
+        let start = trailers.len();
+        let read = (&mut *reader).take((max - start) as u64).read_until(b'\n', &mut trailers).await?;
+        if read == 0 || !trailers.ends_with(b"\n") {
+            return Err(if trailers.len() >= max { ParseError::HeadersTooLarge } else { io::Error::from(io::ErrorKind::UnexpectedEof).into() });
+        }
+        let line = &trailers[start..];
+        if line == b"\r\n" || line == b"\n" {
+            break;
+        }
+    }
+    read_headers(&mut &trailers[..], limits)?;
+    Ok(body)
+}
+
+/// A line without its line ending, or `None` if it is longer than `max`.
+async fn read_line(reader: &mut BufReader<OwnedReadHalf>, max: usize) -> Result<Option<String>, ParseError> {
+    let mut buf = Vec::new();
+    (&mut *reader).take(max as u64 + 2).read_until(b'\n', &mut buf).await?;
+    if !buf.ends_with(b"\n") {
+        if buf.len() > max {
+            return Ok(None);
+        }
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    buf.pop();
+    if buf.last() == Some(&b'\r') {
+        buf.pop();
+    }
+    String::from_utf8(buf).map(Some).map_err(|_| ParseError::BadRequest("invalid UTF-8"))
+}
+
+async fn write_response(out: &mut OwnedWriteHalf, response: Response, head: bool) -> io::Result<()> {
+    let prepared = response.prepare(head);
+    out.write_all(&prepared.head).await?;
+    match prepared.body {
+        None => {}
+        Some(Body::Bytes(bytes)) => out.write_all(&bytes).await?,
+        Some(Body::Stream(mut stream, _)) => {
+            // Note: the stream is a blocking `Read` (usually a file), so each piece is read
+            // on the blocking pool, handing the reader back and forth.
+            loop {
+                let (returned, piece) = task::spawn_blocking(move || {
+                    let mut buf = vec![0; 64 * 1024];
+                    let piece = stream.read(&mut buf).map(|n| {
+                        buf.truncate(n);
+                        buf
+                    });
+                    (stream, piece)
+                })
+                .await
+                .map_err(io::Error::other)?;
+                stream = returned;
+                let piece = piece?;
+                if piece.is_empty() {
+                    break;
+                }
+                if prepared.chunked {
+                    out.write_all(format!("{:X}\r\n", piece.len()).as_bytes()).await?;
+                    out.write_all(&piece).await?;
+                    out.write_all(b"\r\n").await?;
+                } else {
+                    out.write_all(&piece).await?;
+                }
+            }
+            if prepared.chunked {
+                out.write_all(b"0\r\n\r\n").await?;
+            }
+        }
+    }
+    out.flush().await
+}
+
+/// The response to a panicked handler. The connection is closed after it, as by
+/// `connection::serve_connection`: whatever the handler left half done isn't trusted.
+fn internal_error() -> Response {
+    Response::text(StatusCode::INTERNAL_SERVER_ERROR, "500 Internal Server Error").with_header("Connection", "close")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::Server;
+    use std::time::Duration;
+
+    async fn start() -> (SocketAddr, ShutdownHandle, task::JoinHandle<()>) {
+        let mut blocking = crate::router::Router::new();
+        blocking
+            .route(Method::Post, "/echo", |req: Request| Response::text(200, req.body_str().unwrap_or_default().to_string()))
+            .route(Method::Get, "/panic", |_: Request| -> Response { panic!("handler bug") });
+        let mut router = AsyncRouter::new();
+        router
+            .route(Method::Get, "/sleep/:ms", |req: Request| async move {
+                let ms = req.param("ms").and_then(|ms| ms.parse().ok()).unwrap_or(0);
+                time::sleep(Duration::from_millis(ms)).await;
+                Response::text(200, format!("slept {}", ms))
+            })
+            .not_found(Blocking::new(blocking));
+        let server = Server::builder().addr("127.0.0.1:0").bind_async(router).await.unwrap();
+        let (addr, handle) = (server.local_addr(), server.handle());
+        (addr, handle, tokio::spawn(async move { server.run().await.unwrap() }))
+    }
+
+    async fn exchange(addr: SocketAddr, raw: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(raw.as_bytes()).await.unwrap();
+        let mut out = String::new();
+        time::timeout(Duration::from_secs(5), stream.read_to_string(&mut out)).await.unwrap().unwrap();
+        out
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_slow_handlers_do_not_hold_threads() {
+        let (addr, handle, running) = start().await;
+        let started = Instant::now();
+        let mut clients = JoinSet::new();
+        for _ in 0..50 {
+            clients.spawn(exchange(addr, "GET /sleep/300 HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n"));
+        }
+        while let Some(out) = clients.join_next().await {
+            assert!(out.unwrap().ends_with("slept 300"));
+        }
+        // 50 sleeps of 300 ms on two threads, at the same time
+        assert!(started.elapsed() < Duration::from_millis(1500), "took {:?}", started.elapsed());
+        handle.shutdown();
+        running.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_same_protocol_as_the_thread_pool_server() {
+        let (addr, handle, running) = start().await;
+        let out = exchange(
+            addr,
+            "POST /echo HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n2\r\nde\r\n0\r\n\r\n\
+             GET /sleep/1 HTTP/1.1\r\nHost: x\r\n\r\n\
+             GET /panic HTTP/1.1\r\nHost: x\r\n\r\n",
+        )
+        .await;
+        assert_eq!(out.matches("HTTP/1.1 ").count(), 3, "{}", out);
+        assert!(out.contains("\r\n\r\nabcde") && out.contains("slept 1"), "{}", out);
+        assert!(out.contains("HTTP/1.1 500 Internal Server Error"), "{}", out);
+
+        assert!(exchange(addr, "nonsense\r\n\r\n").await.starts_with("HTTP/1.1 400 Bad Request"));
+        assert!(exchange(addr, "DELETE /echo HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n").await.starts_with("HTTP/1.1 405 "));
+        let long = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(40_000));
+        assert!(exchange(addr, &long).await.starts_with("HTTP/1.1 414 "));
+        // trailers follow the header rules too
+        let chunked = "POST /echo HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n";
+        let bad_name = format!("{}Bad Name: 1\r\n\r\n", chunked);
+        assert!(exchange(addr, &bad_name).await.starts_with("HTTP/1.1 400 "));
+        let many = format!("{}{}\r\n", chunked, "T: 1\r\n".repeat(101));
+        assert!(exchange(addr, &many).await.starts_with("HTTP/1.1 431 "));
+
+        // an idle keep-alive connection doesn't hold the shutdown up
+        let mut idle = TcpStream::connect(addr).await.unwrap();
+        idle.write_all(b"GET /sleep/0 HTTP/1.1\r\nHost: x\r\n\r\n").await.unwrap();
+        let mut buf = [0; 1024];
+        assert!(idle.read(&mut buf).await.unwrap() > 0);
+        let started = Instant::now();
+        handle.shutdown();
+        running.await.unwrap();
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,281 @@
+//! Compressing response bodies for clients that accept it.
+//!
+//! ```text
+//! GET /report HTTP/1.1
+//! Accept-Encoding: gzip, deflate;q=0.5      <- what the client can decode, and how much it likes it
+//!
+//! HTTP/1.1 200 OK
+//! Content-Encoding: gzip                    <- what we picked
+//! Vary: Accept-Encoding                     <- caches: the body depends on that request header
+//! ```
+//!
+//! Note: brotli compresses text better still, but needs a crate we don't depend on; gzip
+//! is what every client supports.
+
+use crate::middleware::Middleware;
+use crate::request::Request;
+use crate::response::{Body, Response, add_vary};
+use crate::router::Handler;
+use flate2::Compression as Level;
+use flate2::read::{DeflateEncoder, GzEncoder};
+use std::io::{Read, Write};
+use std::mem;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Gzip,
+    Deflate,
+}
+
+impl Encoding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+}
+
+/// A middleware compressing the bodies of responses with gzip or deflate, when the client
+/// accepts it, the body is large enough, and its type isn't compressed already.
+pub struct Compression {
+    min_size: u64,
+    level: u32,
+}
+
+impl Compression {
+    /// Compresses bodies of 1 KiB or more at the default level.
+    pub fn new() -> Compression {
+        Compression { min_size: 1024, level: 6 }
+    }
+
+    /// Smaller bodies are sent as they are: below a packet or so, compressing saves no time
+    /// and gzip's 18 bytes of framing can even make them bigger.
+    pub fn min_size(mut self, bytes: u64) -> Compression {
+        self.min_size = bytes;
+        self
+    }
+
+    /// From 0 (no compression) to 9 (smallest, slowest).
+    pub fn level(mut self, level: u32) -> Compression {
+        self.level = level.min(9);
+        self
+    }
+
+    fn compress(&self, mut response: Response, encoding: Encoding) -> Response {
+        let level = Level::new(self.level);
+        let body = mem::replace(&mut response.body, Body::Bytes(Vec::new()));
+        response.body = match body {
+            Body::Bytes(bytes) => {
+                let compressed = match encoding {
+                    Encoding::Gzip => {
+                        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), level);
+                        encoder.write_all(&bytes).and_then(|_| encoder.finish())
+                    }
+                    Encoding::Deflate => {
+                        let mut encoder = flate2::write::DeflateEncoder::new(Vec::new(), level);
+                        encoder.write_all(&bytes).and_then(|_| encoder.finish())
+                    }
+                };
+                // writing to a Vec can't fail
+                Body::Bytes(compressed.unwrap())
+            }
+            // Note: a stream is compressed while it is sent; its compressed length isn't known
+            // in advance, so it goes out chunked.
+            Body::Stream(reader, _) => {
+                let reader: Box<dyn Read + Send> = match encoding {
+                    Encoding::Gzip => Box::new(GzEncoder::new(reader, level)),
+                    Encoding::Deflate => Box::new(DeflateEncoder::new(reader, level)),
+                };
+                Body::Stream(reader, None)
+            }
+        };
+        response = response.with_header("Content-Encoding", encoding.as_str());
+        // Byte ranges would refer to the compressed body, which changes with the level.
+        response.headers.remove("Accept-Ranges");
+        // Why weaken the ETag?
+        // The compressed bytes differ from the ones the strong ETag names; a weak ETag says
+        // "same content", and If-None-Match compares weakly, so 304s keep working.
+        if let Some(etag) = response.headers.get("ETag").filter(|etag| etag.starts_with('"')) {
+            let weak = format!("W/{}", etag);
+            response = response.with_header("ETag", &weak);
+        }
+        response
+    }
+}
+
+impl Default for Compression {
+    fn default() -> Compression {
+        Compression::new()
+    }
+}
+
+impl Middleware for Compression {
+    fn handle(&self, request: Request, next: &dyn Handler) -> Response {
+        let encoding = request.header("Accept-Encoding").and_then(negotiate);
+        let mut response = next.call(request);
+
+        let compressible = response.status.allows_body()
+            && !response.headers.contains("Content-Encoding")
+            // a range of the uncompressed file was asked for
+            && !response.headers.contains("Content-Range")
+            && response.headers.get("Content-Type").is_some_and(is_compressible);
+        if !compressible {
+            return response;
+        }
+        // Even when this client gets the body as it is, another would get it compressed:
+        // a shared cache must keep the two apart.
+        add_vary(&mut response, "Accept-Encoding");
+        match encoding {
+            Some(encoding) if response.body.len().is_none_or(|len| len >= self.min_size) => self.compress(response, encoding),
+            _ => response,
+        }
+    }
+}
+
+/// Picks the coding from an `Accept-Encoding` value such as `gzip;q=0.8, deflate, *;q=0`,
+/// or `None` for no compression.
+///
+/// The highest quality wins, gzip on a tie; `*` stands for the codings not listed, and
+/// `q=0` means "not this one".
+pub fn negotiate(accept_encoding: &str) -> Option<Encoding> {
+    let mut listed: Vec<(String, f32)> = Vec::new();
+    for item in accept_encoding.split(',') {
+        let mut parts = item.split(';');
+        let coding = parts.next().unwrap_or_default().trim().to_ascii_lowercase();
+        if coding.is_empty() {
+            continue;
+        }
+        let mut quality = 1.0;
+        for param in parts {
+            if let Some((name, value)) = param.split_once('=')
+                && name.trim().eq_ignore_ascii_case("q")
+            {
+                quality = value.trim().parse().unwrap_or(0.0);
+            }
+        }
+        listed.push((coding, quality));
+    }
+    let quality_of = |name: &str| {
+        let find = |coding: &str| listed.iter().find(|(c, _)| c == coding).map(|(_, q)| *q);
+        // "x-gzip" is an old alias of "gzip"
+        let named = if name == "gzip" { find("gzip").or_else(|| find("x-gzip")) } else { find(name) };
+        named.or_else(|| find("*")).unwrap_or(0.0)
+    };
+
+    let mut best: Option<(Encoding, f32)> = None;
+    for encoding in [Encoding::Gzip, Encoding::Deflate] {
+        let quality = quality_of(encoding.as_str());
+        if quality > 0.0 && best.is_none_or(|(_, q)| quality > q) {
+            best = Some((encoding, quality));
+        }
+    }
+    best.map(|(encoding, _)| encoding)
+}
+
+/// Whether compressing a body of this type is worth it. Images, video, audio, archives and
+/// fonts are compressed by their own format already; another pass only costs time.
+pub fn is_compressible(content_type: &str) -> bool {
+    let mime = content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+    if mime == "image/svg+xml" {
+        return true;
+    }
+    let (kind, subtype) = mime.split_once('/').unwrap_or((&mime, ""));
+    match kind {
+        "image" | "video" | "audio" => false,
+        "font" => !matches!(subtype, "woff" | "woff2"),
+        "application" => !matches!(
+            subtype,
+            "zip" | "gzip" | "x-gzip" | "x-bzip2" | "x-xz" | "zstd" | "x-7z-compressed" | "x-rar-compressed" | "pdf" | "octet-stream" | "wasm"
+        ),
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::Method;
+    use crate::router::Router;
+    use flate2::read::{DeflateDecoder, GzDecoder};
+    use std::io;
+
+    fn app() -> impl Handler {
+        let mut router = Router::new();
+        router
+            .route(Method::Get, "/big", |_: Request| {
+                Response::json(200, format!("[{}0]", "1234567890,".repeat(500))).with_header("ETag", "\"v1\"")
+            })
+            .route(Method::Get, "/small", |_: Request| Response::text(200, "tiny"))
+            .route(Method::Get, "/image", |_: Request| Response::bytes(200, "image/png", vec![0; 5000]))
+            .route(Method::Get, "/stream", |_: Request| {
+                Response::stream(200, "text/plain", io::repeat(b'a').take(100_000), Some(100_000))
+            });
+        router.with(Compression::new())
+    }
+
+    fn get(app: &impl Handler, path: &str, accept: &str) -> Response {
+        let raw = format!("GET {} HTTP/1.1\r\nHost: x\r\nAccept-Encoding: {}\r\n\r\n", path, accept);
+        app.call(Request::read_from(&mut raw.as_bytes()).unwrap())
+    }
+
+    fn body(response: Response) -> Vec<u8> {
+        match response.body {
+            Body::Bytes(bytes) => bytes,
+            Body::Stream(mut reader, _) => {
+                let mut bytes = Vec::new();
+                reader.read_to_end(&mut bytes).unwrap();
+                bytes
+            }
+        }
+    }
+
+    #[test]
+    fn test_negotiation() {
+        assert_eq!(negotiate("gzip, deflate, br"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("deflate, gzip;q=0.5"), Some(Encoding::Deflate));
+        assert_eq!(negotiate("br;q=1.0, *;q=0.1"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("*, gzip;q=0"), Some(Encoding::Deflate));
+        assert_eq!(negotiate("x-gzip"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("identity"), None);
+        assert_eq!(negotiate(""), None);
+    }
+
+    #[test]
+    fn test_compressed_bodies() {
+        let app = app();
+        let plain = body(get(&app, "/big", "identity"));
+
+        let response = get(&app, "/big", "gzip");
+        assert_eq!(response.headers.get("Content-Encoding"), Some("gzip"));
+        assert_eq!(response.headers.get("Vary"), Some("Accept-Encoding"));
+        assert_eq!(response.headers.get("ETag"), Some("W/\"v1\""));
+        let compressed = body(response);
+        assert!(compressed.len() < plain.len() / 10, "{} bytes", compressed.len());
+        let mut decoded = Vec::new();
+        GzDecoder::new(&compressed[..]).read_to_end(&mut decoded).unwrap();
+        assert_eq!(decoded, plain);
+
+        let response = get(&app, "/stream", "deflate");
+        assert_eq!(response.body.len(), None);
+        let mut decoded = Vec::new();
+        DeflateDecoder::new(&body(response)[..]).read_to_end(&mut decoded).unwrap();
+        assert_eq!(decoded, vec![b'a'; 100_000]);
+    }
+
+    #[test]
+    fn test_skip_rules() {
+        let app = app();
+        let identity = get(&app, "/big", "identity");
+        assert_eq!(identity.headers.get("Content-Encoding"), None);
+        assert_eq!(identity.headers.get("Vary"), Some("Accept-Encoding"));
+
+        let small = get(&app, "/small", "gzip");
+        assert_eq!(small.headers.get("Content-Encoding"), None);
+        let image = get(&app, "/image", "gzip");
+        assert_eq!((image.headers.get("Content-Encoding"), image.headers.get("Vary")), (None, None));
+        assert!(is_compressible("image/svg+xml") && is_compressible("text/html; charset=utf-8"));
+        assert!(!is_compressible("application/zip") && !is_compressible("font/woff2"));
+
+    }
+}
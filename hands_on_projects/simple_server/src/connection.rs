@@ -0,0 +1,340 @@
+//! Serving the requests of one connection, one after another.
+//!
+//! HTTP/1.1 connections are persistent by default: after a response the client may send
+//! another request on the same connection, saving a TCP (and TLS) handshake each time. It
+//! may even send several requests without waiting for the responses ("pipelining"); they
+//! must then be answered in order.
+
+use crate::request::{Limits, Method, Request, Version};
+use crate::response::{Body, Response, StatusCode};
+use crate::router::Handler;
+use std::io::{self, BufRead, BufReader, Read};
+use std::net::{Shutdown, TcpStream};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone)]
+pub struct ConnectionConfig {
+    /// How long an idle connection waits for the next request.
+    pub keep_alive_timeout: Duration,
+    /// How long reading a request may stall once it has started.
+    pub read_timeout: Duration,
+    /// After this many requests the connection is closed, so one client can't keep a
+    /// worker thread forever.
+    pub max_requests: usize,
+    pub limits: Limits,
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> ConnectionConfig {
+        ConnectionConfig {
+            keep_alive_timeout: Duration::from_secs(5),
+            read_timeout: Duration::from_secs(10),
+            max_requests: 100,
+            limits: Limits::default(),
+        }
+    }
+}
+
+/// How often an idle connection checks whether the server is shutting down.
+pub(crate) const IDLE_POLL: Duration = Duration::from_millis(100);
+
+/// A connection taken over after a `101 Switching Protocols` response (see
+/// `Response::with_upgrade`): from here on it speaks another protocol, such as WebSocket.
+pub struct Upgraded {
+    pub stream: TcpStream,
+    /// What the client sent after the request and the server already read into its buffer.
+    pub buffered: Vec<u8>,
+    pub(crate) shutdown: Arc<AtomicBool>,
+    pub(crate) read_timeout: Duration,
+}
+
+impl Upgraded {
+    /// Whether the server is shutting down; long-lived protocols should then wrap up.
+    pub fn is_shutdown(&self) -> bool {
+        self.shutdown.load(Ordering::SeqCst)
+    }
+
+    /// The server's `read_timeout`, for reads that have started.
+    pub fn read_timeout(&self) -> Duration {
+        self.read_timeout
+    }
+}
+
+/// The threads running upgraded connections, for the server to wait for when it stops.
+#[derive(Debug, Default)]
+pub struct Upgrades {
+    threads: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl Upgrades {
+    fn spawn(&self, run: impl FnOnce() + Send + 'static) {
+        let mut threads = self.threads.lock().unwrap();
+        threads.retain(|thread| !thread.is_finished());
+        threads.push(thread::spawn(run));
+    }
+
+    /// Waits until every upgraded connection is closed.
+    pub fn join(&self) {
+        let threads = std::mem::take(&mut *self.threads.lock().unwrap());
+        for thread in threads {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Reads requests from `stream` and writes `handler`'s responses, until the client or the
+/// server closes the connection, or `shutdown` is set. Returns how many requests were served.
+///
+/// After a `101 Switching Protocols` response with an upgrade, the connection is handed over
+/// to a thread of its own, kept in `upgrades`, and this returns.
+pub fn serve_connection(
+    stream: TcpStream,
+    handler: &dyn Handler,
+    config: &ConnectionConfig,
+    shutdown: &Arc<AtomicBool>,
+    upgrades: &Upgrades,
+) -> io::Result<usize> {
+    // Why one BufReader for the whole connection?
+    // With pipelining, the bytes of the next request may already be in its buffer after
+    // reading this one. A new BufReader per request would throw them away.
+    // Note: without Nagle's algorithm, a small response isn't held back waiting for the
+    // client to acknowledge the previous packet.
+    stream.set_nodelay(true)?;
+    let mut reader = BufReader::new(&stream);
+    let mut writer = &stream;
+    let mut served = 0;
+
+    loop {
+        // Wait for the first byte of the next request, at most the keep-alive timeout; a
+        // pipelined request is already buffered and doesn't wait at all.
+        // Note: the wait is cut in short reads, so a shutdown doesn't have to wait for every
+        // idle connection to time out.
+        let idle_since = Instant::now();
+        stream.set_read_timeout(Some(IDLE_POLL.min(config.keep_alive_timeout)))?;
+        let ready = loop {
+            match reader.fill_buf() {
+                Ok(buf) => break !buf.is_empty(),
+                Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                    if shutdown.load(Ordering::SeqCst) || idle_since.elapsed() >= config.keep_alive_timeout {
+                        break false;
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        };
+        if !ready {
+            break;
+        }
+        stream.set_read_timeout(Some(config.read_timeout))?;
+
+        let mut request = match Request::read_with_limits(&mut reader, &config.limits) {
+            Ok(request) => request,
+            Err(e) => {
+                // Malformed input gets a 4xx/5xx answer; a timeout or closed connection gets
+                // nothing. Either way we can't tell where a next request would start.
+                if let Some((code, reason)) = e.status() {
+                    Response::text(code, reason).with_header("Connection", "close").write_to(&mut writer, false)?;
+                }
+                break;
+            }
+        };
+        served += 1;
+        request.remote_addr = stream.peer_addr().ok();
+
+        let head = request.method == Method::Head;
+        let version = request.version;
+        let mut keep_alive = wants_keep_alive(&request) && served < config.max_requests;
+        // Why catch panics?
+        // A panicking handler would take its worker thread down with it, leaving the pool
+        // one thread short for good. A 500 for this request is enough.
+        let mut response = match panic::catch_unwind(AssertUnwindSafe(|| handler.call(request))) {
+            Ok(response) => response,
+            Err(_) => {
+                keep_alive = false;
+                Response::text(StatusCode::INTERNAL_SERVER_ERROR, "500 Internal Server Error")
+            }
+        };
+        if let Some(upgrade) = response.upgrade.take()
+            && response.status == StatusCode::SWITCHING_PROTOCOLS
+        {
+            response.write_to(&mut writer, false)?;
+            let buffered = reader.buffer().to_vec();
+            // Note: the new protocol decides how long it waits; a WebSocket may stay quiet
+            // for minutes.
+            stream.set_read_timeout(None)?;
+            let upgraded =
+                Upgraded { stream, buffered, shutdown: Arc::clone(shutdown), read_timeout: config.read_timeout };
+            // Why not stay on this thread?
+            // A WebSocket may stay open for hours, and every one would hold a worker of the
+            // pool: a few idle clients would be enough to stop the server from answering HTTP.
+            // A panic there only ends that thread.
+            upgrades.spawn(move || upgrade.run(upgraded));
+            return Ok(served);
+        }
+        // Note: checked after the handler, which may have run while the server began to stop.
+        if has_token(response.headers.get("Connection"), "close") || shutdown.load(Ordering::SeqCst) {
+            keep_alive = false;
+        }
+
+        if version == Version::Http10 {
+            // HTTP/1.0 clients don't know chunked encoding, so a stream of unknown length is
+            // buffered to learn its length.
+            if let Body::Stream(reader, None) = response.body {
+                match buffer_stream(reader, config.limits.max_body)? {
+                    Some(bytes) => response.body = Body::Bytes(bytes),
+                    None => {
+                        keep_alive = false;
+                        response = Response::text(StatusCode::INTERNAL_SERVER_ERROR, "500 Internal Server Error");
+                    }
+                }
+            }
+            if keep_alive {
+                response = response.with_header("Connection", "keep-alive");
+            }
+        }
+        if !keep_alive {
+            response = response.with_header("Connection", "close");
+        }
+        response.write_to(&mut writer, head)?;
+        if !keep_alive {
+            break;
+        }
+    }
+
+    // Note: shutting down our side first lets the client read the last response before
+    // the socket is dropped. Dropping it with unread pipelined requests in the receive
+    // buffer would send a reset, which can destroy responses the client hasn't read yet.
+    let _ = stream.shutdown(Shutdown::Write);
+    Ok(served)
+}
+
+/// Reads a streamed body of unknown length for an HTTP/1.0 response. `None` if it is longer
+/// than `max`: cut short, it would reach the client as if it were the whole body.
+pub(crate) fn buffer_stream(reader: impl Read, max: usize) -> io::Result<Option<Vec<u8>>> {
+    let mut bytes = Vec::new();
+    reader.take(max as u64 + 1).read_to_end(&mut bytes)?;
+    Ok((bytes.len() <= max).then_some(bytes))
+}
+
+/// HTTP/1.1 keeps the connection unless told `Connection: close`; HTTP/1.0 closes it unless
+/// told `Connection: keep-alive`.
+pub(crate) fn wants_keep_alive(request: &Request) -> bool {
+    let connection = request.header("Connection");
+    match request.version {
+        Version::Http11 => !has_token(connection, "close"),
+        Version::Http10 => has_token(connection, "keep-alive"),
+    }
+}
+
+/// Whether a comma-separated header value such as `keep-alive, Upgrade` contains `token`.
+pub(crate) fn has_token(value: Option<&str>, token: &str) -> bool {
+    value.is_some_and(|v| v.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::router::Router;
+    use std::io::Write;
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::thread;
+
+    /// Serves one connection on a background thread with a router echoing the path.
+    fn start(config: ConnectionConfig) -> (TcpStream, thread::JoinHandle<usize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let mut router = Router::new();
+        router
+            .route(Method::Get, "/stream/:len", |req: Request| {
+                let len = req.param("len").unwrap().parse().unwrap();
+                Response::stream(200, "text/plain", io::repeat(b'x').take(len), None)
+            })
+            .route(Method::Get, "/*path", |req: Request| Response::text(200, req.path));
+        let router = Arc::new(router);
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            serve_connection(stream, &*router, &config, &Arc::new(AtomicBool::new(false)), &Upgrades::default()).unwrap()
+        });
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        (client, server)
+    }
+
+    fn read_all(mut client: TcpStream) -> String {
+        let mut out = String::new();
+        client.read_to_string(&mut out).unwrap();
+        out
+    }
+
+    fn bodies(raw: &str) -> Vec<&str> {
+        raw.split("HTTP/1.1 ").skip(1).map(|r| r.rsplit("\r\n\r\n").next().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_pipelined_requests_are_answered_in_order() {
+        let (mut client, server) = start(ConnectionConfig::default());
+        client
+            .write_all(b"GET /a HTTP/1.1\r\nHost: x\r\n\r\nGET /b HTTP/1.1\r\nHost: x\r\n\r\nGET /c HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let out = read_all(client);
+        assert_eq!(bodies(&out), vec!["/a", "/b", "/c"]);
+        assert_eq!(out.matches("Connection: close").count(), 1);
+        assert_eq!(server.join().unwrap(), 3);
+    }
+
+    #[test]
+    fn test_http10_and_max_requests() {
+        let (mut client, server) = start(ConnectionConfig::default());
+        client.write_all(b"GET /old HTTP/1.0\r\n\r\nGET /never HTTP/1.0\r\n\r\n").unwrap();
+        assert_eq!(bodies(&read_all(client)), vec!["/old"]);
+        assert_eq!(server.join().unwrap(), 1);
+
+        let (mut client, server) = start(ConnectionConfig { max_requests: 2, ..ConnectionConfig::default() });
+        client.write_all(b"GET /1 HTTP/1.0\r\nConnection: keep-alive\r\n\r\n").unwrap();
+        client.write_all(b"GET /2 HTTP/1.1\r\nHost: x\r\n\r\nGET /3 HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+        let out = read_all(client);
+        assert_eq!(bodies(&out), vec!["/1", "/2"]);
+        assert!(out.contains("Connection: keep-alive\r\n"));
+        assert_eq!(server.join().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_http10_stream_over_max_body_is_an_error() {
+        let config = ConnectionConfig { limits: Limits { max_body: 10, ..Limits::default() }, ..ConnectionConfig::default() };
+        let (mut client, _) = start(config.clone());
+        client.write_all(b"GET /stream/10 HTTP/1.0\r\n\r\n").unwrap();
+        let out = read_all(client);
+        assert!(out.starts_with("HTTP/1.1 200 ") && out.contains("Content-Length: 10\r\n"), "{}", out);
+
+        // not sent cut short as if it were the whole body
+        let (mut client, _) = start(config);
+        client.write_all(b"GET /stream/11 HTTP/1.0\r\nConnection: keep-alive\r\n\r\n").unwrap();
+        let out = read_all(client);
+        assert!(out.starts_with("HTTP/1.1 500 ") && out.contains("Connection: close\r\n"), "{}", out);
+    }
+
+    #[test]
+    fn test_idle_connections_time_out() {
+        let config = ConnectionConfig { keep_alive_timeout: Duration::from_millis(200), ..ConnectionConfig::default() };
+        let (mut client, server) = start(config);
+        client.write_all(b"GET /x HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+        let started = Instant::now();
+        assert_eq!(bodies(&read_all(client)), vec!["/x"]);
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert_eq!(server.join().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_malformed_request_closes_the_connection() {
+        let (mut client, server) = start(ConnectionConfig::default());
+        client.write_all(b"GET /ok HTTP/1.1\r\nHost: x\r\n\r\nnonsense\r\n\r\nGET /late HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+        let out = read_all(client);
+        assert_eq!(bodies(&out), vec!["/ok", "Bad Request"]);
+        assert!(out.contains("HTTP/1.1 400 Bad Request\r\n"));
+        assert_eq!(server.join().unwrap(), 1);
+    }
+}
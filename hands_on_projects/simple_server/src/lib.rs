@@ -1,3 +1,25 @@
+pub mod async_server;
+pub mod compression;
+pub mod connection;
+pub mod middleware;
+pub mod request;
+pub mod response;
+pub mod router;
+pub mod server;
+pub mod static_files;
+pub mod websocket;
+
+pub use async_server::{AsyncHandler, AsyncRouter, AsyncServer, Blocking};
+pub use compression::Compression;
+pub use connection::{ConnectionConfig, Upgraded, Upgrades, serve_connection};
+pub use middleware::{AccessLog, BasicAuth, Cors, Middleware, RequestId};
+pub use request::{Method, ParseError, Request};
+pub use response::{Body, Response, StatusCode};
+pub use router::{Handler, Router};
+pub use server::{Server, ServerBuilder, ShutdownHandle};
+pub use static_files::StaticFiles;
+pub use websocket::{Message, WebSocket, WebSocketRoute};
+
 use std::sync::mpsc::Receiver;
 use std::sync::{Arc, Mutex, mpsc};
 use std::thread;
@@ -1,53 +1,140 @@
-use std::net::{TcpListener, TcpStream};
-use std::io::prelude::*;
-use std::io::{BufReader, };
+use std::env;
+use std::process;
 use std::thread;
 use std::time::Duration;
-use simple_server::ThreadPool;
+use simple_server::{
+    AccessLog, AsyncRouter, Blocking, Compression, Handler, Method, Request, RequestId, Response, Router, Server, StaticFiles,
+    WebSocket, WebSocketRoute,
+};
 
-fn main() {
-    let listener = TcpListener::bind("127.0.0.1:7878").unwrap();
-    let pool = ThreadPool::new(4);
-    // accept only two connections for demonstration purposes
-    // also shows the graceful shutdown of the thread pool
-    for tcp_stream in listener.incoming().take(2) {
-        let stream = match tcp_stream {
-            Ok(s) => s,
-            Err(e) => {
-                eprintln!("Failed to establish a connection: {}", e);
+/// `simple_server --addr 0.0.0.0:8080 --threads 8`; stops on Ctrl-C or SIGTERM once the
+/// open connections are done. `--async` runs it on tokio instead of the thread pool, with
+/// `--threads` worker threads (tokio's default, one per core, without it).
+struct Config {
+    addr: String,
+    threads: Option<usize>,
+    run_async: bool,
+}
+
+impl Config {
+    fn build(args: &[String]) -> Result<Config, String> {
+        let mut config = Config { addr: "127.0.0.1:7878".to_string(), threads: None, run_async: false };
+        let mut iter = args.iter().skip(1);
+        while let Some(flag) = iter.next() {
+            if flag == "--async" {
+                config.run_async = true;
                 continue;
             }
-        };
-        pool.execute(|| {
-            handle_connection(stream);
-        });
+            let value = iter.next().ok_or(format!("missing value for {}", flag))?;
+            match flag.as_str() {
+                "--addr" => config.addr = value.clone(),
+                "--threads" => {
+                    let threads = value.parse().ok().filter(|n| *n > 0);
+                    config.threads = Some(threads.ok_or(format!("invalid value for --threads: {}", value))?);
+                }
+                _ => return Err(format!("unknown option {}", flag)),
+            }
+        }
+        Ok(config)
     }
-    println!("Server is shutting down.");
 }
 
-fn handle_connection(mut stream: TcpStream) {
-    stream.set_read_timeout(Some(Duration::new(5, 0))).unwrap();
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let config = Config::build(&args).unwrap_or_else(|err| {
+        eprintln!("Problem parsing arguments: {}", err);
+        process::exit(1);
+    });
 
-    let buf_reader = BufReader::new(&mut stream);
-    let request_line = match buf_reader.lines().next() {
-        Some(Ok(line)) => line,
-        _ => return,  // Timeout or error, just close the connection
-    };
+    // the last middleware runs first: log, then give an ID, then compress the response
+    let app = routes().with(Compression::new()).with(RequestId::new()).with(AccessLog::stdout());
+    if config.run_async {
+        run_async(config, app);
+        return;
+    }
+    let server = Server::builder().addr(config.addr).threads(config.threads.unwrap_or(4)).bind(app).unwrap_or_else(|err| {
+        eprintln!("Failed to start the server: {}", err);
+        process::exit(1);
+    });
+    server.handle().shutdown_on_signals().unwrap();
+    println!("Listening on http://{}", server.local_addr());
+    if let Err(e) = server.run() {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
+
+fn run_async(config: Config, app: impl Handler) {
+    let mut builder = tokio::runtime::Builder::new_multi_thread();
+    if let Some(threads) = config.threads {
+        builder.worker_threads(threads);
+    }
+    let runtime = builder.enable_all().build().unwrap();
+    runtime.block_on(async {
+        let mut router = AsyncRouter::new();
+        // Note: this /sleep waits without holding a thread; it skips the middleware, which
+        // wrap the blocking routes only.
+        router
+            .route(Method::Get, "/sleep", |_: Request| async {
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                Response::text(200, "Hello! This is a simple server.")
+            })
+            .not_found(Blocking::new(app));
+        let server = Server::builder().addr(config.addr).bind_async(router).await.unwrap_or_else(|err| {
+            eprintln!("Failed to start the server: {}", err);
+            process::exit(1);
+        });
+        server.handle().shutdown_on_signals().unwrap();
+        println!("Listening on http://{} (async)", server.local_addr());
+        if let Err(e) = server.run().await {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    });
+}
 
-    let (status_line, content) = match &request_line[..] {
-        "GET / HTTP/1.1" => ("HTTP/1.1 200 OK", "Hello! This is a simple server."),
-        "GET /sleep HTTP/1.1" => {
+fn routes() -> Router {
+    let mut router = Router::new();
+    router
+        .route(Method::Get, "/", |_: Request| Response::text(200, "Hello! This is a simple server."))
+        .route(Method::Get, "/sleep", |_: Request| {
             // Mimic a slow response
-            thread::sleep(std::time::Duration::from_secs(5));
-            ("HTTP/1.1 200 OK", "Hello! This is a simple server.")
-        },
-        _ => ("HTTP/1.1 404 NOT FOUND", "404 Not Found"),
-    };
-    let response = format!(
-        "{}\r\nContent-Length: {}\r\n\r\n{}",
-        status_line,
-        content.len(),
-        content
-    );
-    stream.write_all(response.as_bytes()).unwrap();
+            thread::sleep(Duration::from_secs(5));
+            Response::text(200, "Hello! This is a simple server.")
+        })
+        .route(Method::Get, "/hello/:name", |req: Request| {
+            Response::text(200, format!("Hello, {}!", req.param("name").unwrap_or_default()))
+        })
+        // the docs directory next to where the server is started
+        .route(Method::Get, "/docs/*path", StaticFiles::new("docs").with_listing(true))
+        .route(
+            Method::Get,
+            "/ws/echo",
+            WebSocketRoute::new(|_: Request, mut socket: WebSocket| {
+                while let Ok(Some(message)) = socket.recv() {
+                    if socket.send(message).is_err() {
+                        break;
+                    }
+                }
+            }),
+        )
+        .route(
+            Method::Get,
+            "/ws/ticks",
+            // Mimic a live dashboard: a tick every second, pushed from another thread
+            WebSocketRoute::new(|_: Request, mut socket: WebSocket| {
+                let sender = socket.sender();
+                thread::spawn(move || {
+                    for tick in 1.. {
+                        thread::sleep(Duration::from_secs(1));
+                        if sender.send(format!("tick {}", tick)).is_err() {
+                            break;
+                        }
+                    }
+                });
+                // messages from the client are ignored; this returns once it closes
+                while let Ok(Some(_)) = socket.recv() {}
+            }),
+        );
+    router
 }
@@ -0,0 +1,402 @@
+//! Middleware: code that runs around every handler, such as logging or authentication.
+//!
+//! ```ignore
+//! let app = router
+//!     .with(BasicAuth::new("admin", |user, password| user == "admin" && password == "secret"))
+//!     .with(Cors::any())
+//!     .with(RequestId::new())
+//!     .with(AccessLog::stdout());
+//! ```
+//!
+//! Each `with` wraps everything before it, so the last one runs first: here a request is
+//! logged, gets an ID, is checked for CORS and then for a password before the router sees it.
+
+use crate::request::{Method, Request};
+use crate::response::{Response, StatusCode, add_vary, log_date};
+use crate::router::Handler;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, Write};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime};
+
+/// Something that wraps a handler: it gets the request and the next handler, and may change
+/// the request before calling `next`, change the response after, or answer by itself
+/// without calling `next` at all.
+pub trait Middleware: Send + Sync + 'static {
+    fn handle(&self, request: Request, next: &dyn Handler) -> Response;
+}
+
+impl<F> Middleware for F
+where
+    F: Fn(Request, &dyn Handler) -> Response + Send + Sync + 'static,
+{
+    fn handle(&self, request: Request, next: &dyn Handler) -> Response {
+        self(request, next)
+    }
+}
+
+/// A handler wrapped in a middleware, from `Handler::with`.
+pub struct Wrapped<H, M> {
+    pub(crate) handler: H,
+    pub(crate) middleware: M,
+}
+
+impl<H: Handler, M: Middleware> Handler for Wrapped<H, M> {
+    fn call(&self, request: Request) -> Response {
+        self.middleware.handle(request, &self.handler)
+    }
+}
+
+/// Writes one line per request in the Common Log Format, as Apache and nginx do:
+///
+/// ```text
+/// 127.0.0.1 - alice [10/Oct/2000:13:55:36 +0000] "GET /index.html HTTP/1.1" 200 2326
+/// ```
+///
+/// The fields are the client, its identity (always `-`), the user from basic
+/// authentication, the time, the request line, the status and the size of the body.
+pub struct AccessLog {
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+impl AccessLog {
+    pub fn new(out: impl Write + Send + 'static) -> AccessLog {
+        AccessLog { out: Mutex::new(Box::new(out)) }
+    }
+
+    pub fn stdout() -> AccessLog {
+        AccessLog::new(io::stdout())
+    }
+}
+
+impl Middleware for AccessLog {
+    fn handle(&self, request: Request, next: &dyn Handler) -> Response {
+        let received = SystemTime::now();
+        let client = request.remote_addr.map_or("-".to_string(), |addr| addr.ip().to_string());
+        let user = basic_credentials(&request).map_or("-".to_string(), |(user, _)| user);
+        let request_line = format!("{} {} {}", request.method, request.target(), request.version.as_str());
+        let head = request.method == Method::Head;
+
+        let response = next.call(request);
+
+        let bytes = match response.body.len() {
+            Some(len) if len > 0 && !head && response.status.allows_body() => len.to_string(),
+            _ => "-".to_string(),
+        };
+        let line = format!(
+            "{} - {} [{}] \"{}\" {} {}\n",
+            client,
+            user,
+            log_date(received),
+            request_line.escape_default(),
+            response.status.0,
+            bytes
+        );
+        // Note: one write per line under the lock, so lines from several threads don't mix.
+        let mut out = self.out.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) = out.write_all(line.as_bytes()).and_then(|_| out.flush()) {
+            eprintln!("Failed to write the access log: {}", e);
+        }
+        response
+    }
+}
+
+/// Gives every request an ID in `X-Request-Id`, on the request for the handler and on the
+/// response for the client, so a report of a failed request can be matched with its logs.
+///
+/// An ID the client (or a proxy in front) already sent is kept, if it looks sane.
+pub struct RequestId {
+    prefix: u64,
+    counter: AtomicU64,
+}
+
+impl RequestId {
+    pub const HEADER: &'static str = "X-Request-Id";
+
+    pub fn new() -> RequestId {
+        // Why a random prefix?
+        // The counter starts at 0 after every restart; the prefix keeps IDs from different
+        // runs (or servers) apart. `RandomState` is std's source of random hash keys.
+        let prefix = RandomState::new().build_hasher().finish() & 0xffff_ffff;
+        RequestId { prefix, counter: AtomicU64::new(0) }
+    }
+
+    fn next_id(&self) -> String {
+        format!("{:08x}-{:06x}", self.prefix, self.counter.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+impl Default for RequestId {
+    fn default() -> RequestId {
+        RequestId::new()
+    }
+}
+
+impl Middleware for RequestId {
+    fn handle(&self, mut request: Request, next: &dyn Handler) -> Response {
+        let id = match request.header(RequestId::HEADER) {
+            Some(id) if !id.is_empty() && id.len() <= 64 && id.bytes().all(|b| b.is_ascii_alphanumeric() || b"-_.".contains(&b)) => {
+                id.to_string()
+            }
+            _ => self.next_id(),
+        };
+        request.headers.set(RequestId::HEADER, &id);
+        next.call(request).with_header(RequestId::HEADER, &id)
+    }
+}
+
+/// Cross-Origin Resource Sharing: which other websites may call this server from a browser.
+///
+/// Why is this needed?
+/// A page from `https://app.example` calling `https://api.example` is refused by the
+/// browser unless the API answers with `Access-Control-Allow-Origin`. For requests that
+/// could change something (other methods, custom headers) the browser first asks with an
+/// OPTIONS "preflight" request, which this middleware answers itself.
+pub struct Cors {
+    /// `None` allows every origin.
+    origins: Option<Vec<String>>,
+    methods: Vec<Method>,
+    headers: Vec<String>,
+    max_age: Duration,
+    credentials: bool,
+}
+
+impl Cors {
+    /// Every origin may call, with the usual methods and without cookies.
+    pub fn any() -> Cors {
+        Cors {
+            origins: None,
+            methods: vec![Method::Get, Method::Head, Method::Post, Method::Put, Method::Patch, Method::Delete],
+            headers: vec!["Content-Type".to_string(), "Authorization".to_string()],
+            max_age: Duration::from_secs(600),
+            credentials: false,
+        }
+    }
+
+    /// Only the given origins may call, e.g. `https://app.example`.
+    pub fn origins(origins: &[&str]) -> Cors {
+        Cors { origins: Some(origins.iter().map(|o| o.to_string()).collect()), ..Cors::any() }
+    }
+
+    pub fn methods(mut self, methods: &[Method]) -> Cors {
+        self.methods = methods.to_vec();
+        self
+    }
+
+    /// The request headers pages may send, besides the simple ones browsers always allow.
+    pub fn headers(mut self, headers: &[&str]) -> Cors {
+        self.headers = headers.iter().map(|h| h.to_string()).collect();
+        self
+    }
+
+    /// How long browsers may cache a preflight answer.
+    pub fn max_age(mut self, max_age: Duration) -> Cors {
+        self.max_age = max_age;
+        self
+    }
+
+    /// Let pages send cookies and `Authorization`. Browsers then require the origin to be
+    /// named, so it is echoed instead of `*`.
+    pub fn credentials(mut self, credentials: bool) -> Cors {
+        self.credentials = credentials;
+        self
+    }
+
+    fn allows(&self, origin: &str) -> bool {
+        self.origins.as_ref().is_none_or(|origins| origins.iter().any(|o| o == origin))
+    }
+
+    fn allow_headers(&self, response: Response, origin: &str) -> Response {
+        let allowed = if self.origins.is_none() && !self.credentials { "*" } else { origin };
+        let response = response.with_header("Access-Control-Allow-Origin", allowed);
+        let mut response = if self.credentials { response.with_header("Access-Control-Allow-Credentials", "true") } else { response };
+        // The answer depends on the Origin, so caches must not give it to another origin.
+        if allowed != "*" {
+            add_vary(&mut response, "Origin");
+        }
+        response
+    }
+}
+
+impl Middleware for Cors {
+    fn handle(&self, request: Request, next: &dyn Handler) -> Response {
+        let Some(origin) = request.header("Origin").map(str::to_string) else {
+            // not a cross-origin request from a browser
+            return next.call(request);
+        };
+        let preflight = request.method == Method::Options && request.headers.contains("Access-Control-Request-Method");
+        if preflight {
+            if !self.allows(&origin) {
+                return Response::new(StatusCode::NO_CONTENT);
+            }
+            let methods: Vec<&str> = self.methods.iter().map(Method::as_str).collect();
+            let response = Response::new(StatusCode::NO_CONTENT)
+                .with_header("Access-Control-Allow-Methods", &methods.join(", "))
+                .with_header("Access-Control-Allow-Headers", &self.headers.join(", "))
+                .with_header("Access-Control-Max-Age", &self.max_age.as_secs().to_string());
+            return self.allow_headers(response, &origin);
+        }
+        let response = next.call(request);
+        if self.allows(&origin) { self.allow_headers(response, &origin) } else { response }
+    }
+}
+
+/// HTTP basic authentication: requests without a valid user and password get a 401, which
+/// makes browsers ask for them.
+///
+/// Note: the password is only base64-encoded, not encrypted; use it behind HTTPS.
+pub struct BasicAuth {
+    realm: String,
+    check: Box<Check>,
+}
+
+// Note: a type alias for the credential check, like `Job` for the thread pool.
+type Check = dyn Fn(&str, &str) -> bool + Send + Sync;
+
+impl BasicAuth {
+    /// `check` gets the user and the password and says whether they are valid.
+    pub fn new(realm: &str, check: impl Fn(&str, &str) -> bool + Send + Sync + 'static) -> BasicAuth {
+        BasicAuth { realm: realm.to_string(), check: Box::new(check) }
+    }
+}
+
+impl Middleware for BasicAuth {
+    fn handle(&self, request: Request, next: &dyn Handler) -> Response {
+        match basic_credentials(&request) {
+            Some((user, password)) if (self.check)(&user, &password) => next.call(request),
+            _ => Response::text(StatusCode::UNAUTHORIZED, "401 Unauthorized").with_header(
+                "WWW-Authenticate",
+                &format!("Basic realm=\"{}\", charset=\"UTF-8\"", self.realm.replace(['"', '\\'], "")),
+            ),
+        }
+    }
+}
+
+/// The user and password of `Authorization: Basic base64(user:password)`.
+fn basic_credentials(request: &Request) -> Option<(String, String)> {
+    let (scheme, encoded) = request.header("Authorization")?.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("Basic") {
+        return None;
+    }
+    let decoded = String::from_utf8(BASE64.decode(encoded.trim()).ok()?).ok()?;
+    let (user, password) = decoded.split_once(':')?;
+    Some((user.to_string(), password.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compression::Compression;
+    use crate::router::Router;
+    use std::sync::Arc;
+
+    fn request(raw_head: &str) -> Request {
+        let raw = format!("{}\r\nHost: x\r\n\r\n", raw_head.replace('\n', "\r\n"));
+        Request::read_from(&mut raw.as_bytes()).unwrap()
+    }
+
+    fn router() -> Router {
+        let mut router = Router::new();
+        router.route(Method::Get, "/", |req: Request| {
+            Response::text(200, format!("id {}", req.header(RequestId::HEADER).unwrap_or("-")))
+        });
+        router
+    }
+
+    /// A writer the test can read back.
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_access_log() {
+        let log = Shared::default();
+        let app = router().with(AccessLog::new(log.clone()));
+        let mut req = request("GET /?q=1 HTTP/1.1\nAuthorization: Basic YWxpY2U6c2VjcmV0");
+        req.remote_addr = Some("10.0.0.7:5000".parse().unwrap());
+        app.call(req);
+        app.call(request("GET /missing HTTP/1.0"));
+
+        let log = String::from_utf8(log.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<&str> = log.lines().collect();
+        assert!(lines[0].starts_with("10.0.0.7 - alice ["), "{}", lines[0]);
+        assert!(lines[0].ends_with(" +0000] \"GET /?q=1 HTTP/1.1\" 200 4"), "{}", lines[0]);
+        assert!(lines[1].starts_with("- - - [") && lines[1].ends_with("\"GET /missing HTTP/1.0\" 404 13"), "{}", lines[1]);
+    }
+
+    #[test]
+    fn test_request_ids() {
+        let app = router().with(RequestId::new());
+        let first = app.call(request("GET / HTTP/1.1"));
+        let second = app.call(request("GET / HTTP/1.1"));
+        let id = first.headers.get(RequestId::HEADER).unwrap().to_string();
+        assert_eq!(first.body.as_bytes().unwrap(), format!("id {}", id).as_bytes());
+        assert_ne!(second.headers.get(RequestId::HEADER).unwrap(), id);
+
+        let kept = app.call(request("GET / HTTP/1.1\nX-Request-Id: from-proxy-1"));
+        assert_eq!(kept.headers.get(RequestId::HEADER), Some("from-proxy-1"));
+        let replaced = app.call(request("GET / HTTP/1.1\nX-Request-Id: <script>"));
+        assert_ne!(replaced.headers.get(RequestId::HEADER), Some("<script>"));
+    }
+
+    #[test]
+    fn test_cors() {
+        let app = router().with(Cors::origins(&["https://app.example"]).credentials(true));
+        let preflight = app.call(request(
+            "OPTIONS / HTTP/1.1\nOrigin: https://app.example\nAccess-Control-Request-Method: PUT",
+        ));
+        assert_eq!(preflight.status, 204);
+        assert_eq!(preflight.headers.get("Access-Control-Allow-Origin"), Some("https://app.example"));
+        assert_eq!(preflight.headers.get("Access-Control-Allow-Credentials"), Some("true"));
+        assert!(preflight.headers.get("Access-Control-Allow-Methods").unwrap().contains("PUT"));
+
+        let simple = app.call(request("GET / HTTP/1.1\nOrigin: https://app.example"));
+        assert_eq!(simple.headers.get("Vary"), Some("Origin"));
+        let other = app.call(request("GET / HTTP/1.1\nOrigin: https://evil.example"));
+        assert_eq!((other.status.0, other.headers.get("Access-Control-Allow-Origin")), (200, None));
+
+        let any = router().with(Cors::any()).call(request("GET / HTTP/1.1\nOrigin: https://x.example"));
+        assert_eq!(any.headers.get("Access-Control-Allow-Origin"), Some("*"));
+
+        // with compression on either side, the response varies on both
+        let cors = || Cors::origins(&["https://app.example"]);
+        let raw = "GET / HTTP/1.1\nOrigin: https://app.example\nAccept-Encoding: gzip";
+        let inner = router().with(Compression::new()).with(cors()).call(request(raw));
+        assert_eq!(inner.headers.get("Vary"), Some("Accept-Encoding, Origin"));
+        let outer = router().with(cors()).with(Compression::new()).call(request(raw));
+        assert_eq!(outer.headers.get("Vary"), Some("Origin, Accept-Encoding"));
+    }
+
+    #[test]
+    fn test_basic_auth_and_ordering() {
+        let app = router()
+            .with(BasicAuth::new("admin", |user, password| user == "alice" && password == "secret"))
+            // a closure middleware answering by itself
+            .with(|req: Request, next: &dyn Handler| {
+                if req.path == "/health" { Response::text(200, "ok") } else { next.call(req) }
+            });
+
+        let denied = app.call(request("GET / HTTP/1.1"));
+        assert_eq!(denied.status, 401);
+        assert_eq!(denied.headers.get("WWW-Authenticate"), Some("Basic realm=\"admin\", charset=\"UTF-8\""));
+        // alice:wrong
+        assert_eq!(app.call(request("GET / HTTP/1.1\nAuthorization: Basic YWxpY2U6d3Jvbmc=")).status, 401);
+        // alice:secret
+        assert_eq!(app.call(request("GET / HTTP/1.1\nAuthorization: basic YWxpY2U6c2VjcmV0")).status, 200);
+        assert_eq!(app.call(request("GET /health HTTP/1.1")).status, 200);
+    }
+}
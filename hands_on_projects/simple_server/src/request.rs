@@ -0,0 +1,592 @@
+//! Parsing an HTTP/1.x request from a stream (RFC 9112).
+//!
+//! ```text
+//! POST /users?active=true HTTP/1.1\r\n      <- request line: method, target, version
+//! Host: localhost:7878\r\n                   <- headers, until an empty line
+//! Content-Length: 13\r\n
+//! \r\n
+//! {"name":"ok"}                              <- body: Content-Length bytes, or chunks
+//! ```
+
+use std::fmt;
+use std::io::{self, BufRead, Read};
+use std::net::SocketAddr;
+
+/// The request methods we know about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Method {
+    Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+    Patch,
+    Options,
+}
+
+impl Method {
+    /// Methods are case-sensitive: `get` is not `GET`.
+    pub fn parse(s: &str) -> Option<Method> {
+        match s {
+            "GET" => Some(Method::Get),
+            "HEAD" => Some(Method::Head),
+            "POST" => Some(Method::Post),
+            "PUT" => Some(Method::Put),
+            "DELETE" => Some(Method::Delete),
+            "PATCH" => Some(Method::Patch),
+            "OPTIONS" => Some(Method::Options),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+            Method::Patch => "PATCH",
+            Method::Options => "OPTIONS",
+        }
+    }
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Version {
+    Http10,
+    Http11,
+}
+
+impl Version {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Version::Http10 => "HTTP/1.0",
+            Version::Http11 => "HTTP/1.1",
+        }
+    }
+}
+
+/// Header fields in the order they were received. Names compare case-insensitively.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Headers {
+    fields: Vec<(String, String)>,
+}
+
+impl Headers {
+    pub fn new() -> Headers {
+        Headers::default()
+    }
+
+    /// The first value of the header `name`.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.fields.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+    }
+
+    /// Every value of the header `name`, e.g. several `Accept` lines.
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.fields.iter().filter(move |(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Adds a field, keeping the ones with the same name.
+    pub fn append(&mut self, name: &str, value: &str) {
+        self.fields.push((name.to_string(), value.to_string()));
+    }
+
+    /// Replaces every field called `name` by a single one.
+    pub fn set(&mut self, name: &str, value: &str) {
+        self.remove(name);
+        self.append(name, value);
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.fields.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.fields.iter().map(|(n, v)| (n.as_str(), v.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.fields.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+}
+
+/// Size limits, so a client can't make the server buffer without bound.
+#[derive(Debug, Clone)]
+pub struct Limits {
+    /// The longest request line, in bytes.
+    pub max_request_line: usize,
+    /// The most bytes all header lines together may take.
+    pub max_header_bytes: usize,
+    pub max_headers: usize,
+    /// The largest body, after removing the chunked encoding.
+    pub max_body: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits { max_request_line: 8 * 1024, max_header_bytes: 16 * 1024, max_headers: 100, max_body: 1024 * 1024 }
+    }
+}
+
+/// Why a request couldn't be read.
+#[derive(Debug)]
+pub enum ParseError {
+    /// The connection was closed before the first byte of a request.
+    Closed,
+    /// Reading failed (e.g. the read timed out), or the connection was closed in the middle
+    /// of a request. There is nobody to answer.
+    Io(io::Error),
+    /// The request is malformed: 400.
+    BadRequest(&'static str),
+    /// 414
+    UriTooLong,
+    /// 431
+    HeadersTooLarge,
+    /// 413
+    PayloadTooLarge,
+    /// An unknown method or transfer coding: 501.
+    NotImplemented,
+    /// 505
+    VersionNotSupported,
+}
+
+impl ParseError {
+    /// The status code and reason to answer with, `None` if the client is gone.
+    pub fn status(&self) -> Option<(u16, &'static str)> {
+        match self {
+            ParseError::Closed | ParseError::Io(_) => None,
+            ParseError::BadRequest(_) => Some((400, "Bad Request")),
+            ParseError::UriTooLong => Some((414, "URI Too Long")),
+            ParseError::HeadersTooLarge => Some((431, "Request Header Fields Too Large")),
+            ParseError::PayloadTooLarge => Some((413, "Content Too Large")),
+            ParseError::NotImplemented => Some((501, "Not Implemented")),
+            ParseError::VersionNotSupported => Some((505, "HTTP Version Not Supported")),
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::Closed => write!(f, "connection closed"),
+            ParseError::Io(e) => write!(f, "{}", e),
+            ParseError::BadRequest(why) => write!(f, "bad request: {}", why),
+            _ => write!(f, "{}", self.status().map_or("", |(_, reason)| reason)),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl From<io::Error> for ParseError {
+    fn from(e: io::Error) -> ParseError {
+        ParseError::Io(e)
+    }
+}
+
+/// A parsed request.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Request {
+    pub method: Method,
+    /// The path of the target, still percent-encoded, e.g. `/users/42`.
+    pub path: String,
+    /// What follows `?` in the target, if anything.
+    pub query: Option<String>,
+    pub version: Version,
+    pub headers: Headers,
+    pub body: Vec<u8>,
+    /// The path parameters filled in by the `Router`, e.g. `id` for `/users/:id`.
+    pub params: Vec<(String, String)>,
+    /// The client's address, when the request came from a connection.
+    pub remote_addr: Option<SocketAddr>,
+}
+
+impl Request {
+    /// Reads one request from `reader` with the default `Limits`.
+    pub fn read_from(reader: &mut impl BufRead) -> Result<Request, ParseError> {
+        Request::read_with_limits(reader, &Limits::default())
+    }
+
+    pub fn read_with_limits(reader: &mut impl BufRead, limits: &Limits) -> Result<Request, ParseError> {
+        let mut request = Request::read_head(reader, limits)?;
+        request.body = match request.body_framing(limits)? {
+            BodyFraming::Empty => Vec::new(),
+            BodyFraming::Length(length) => {
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body)?;
+                body
+            }
+            BodyFraming::Chunked => read_chunked(reader, limits)?,
+        };
+        Ok(request)
+    }
+
+    /// Reads the request line and the headers, leaving the body (if any) in `reader` and
+    /// `body` empty. `body_framing` then tells how to read the body.
+    ///
+    /// Note: this is the part an async server can't do with blocking reads of its own, so it
+    /// collects the head first and parses it from memory with this.
+    pub fn read_head(reader: &mut impl BufRead, limits: &Limits) -> Result<Request, ParseError> {
+        // Note: RFC 9112 asks servers to ignore empty lines before a request line.
+        let line = loop {
+            match read_line(reader, limits.max_request_line)? {
+                Line::Eof => return Err(ParseError::Closed),
+                Line::TooLong => return Err(ParseError::UriTooLong),
+                Line::Text(line) if line.is_empty() => continue,
+                Line::Text(line) => break line,
+            }
+        };
+        let (method, target, version) = parse_request_line(&line)?;
+        let (path, query) = match target.split_once('?') {
+            Some((path, query)) => (path.to_string(), Some(query.to_string())),
+            None => (target.to_string(), None),
+        };
+        let headers = read_headers(reader, limits)?;
+        if version == Version::Http11 && !headers.contains("Host") {
+            return Err(ParseError::BadRequest("missing Host header"));
+        }
+        Ok(Request { method, path, query, version, headers, body: Vec::new(), params: Vec::new(), remote_addr: None })
+    }
+
+    /// How the body that follows the head is delimited, checked against the limits.
+    pub fn body_framing(&self, limits: &Limits) -> Result<BodyFraming, ParseError> {
+        let headers = &self.headers;
+        if let Some(coding) = headers.get("Transfer-Encoding") {
+            // Why reject both?
+            // A proxy in front that believes Content-Length while we believe the chunks would
+            // see a different request boundary than us (request smuggling).
+            if headers.contains("Content-Length") {
+                return Err(ParseError::BadRequest("both Transfer-Encoding and Content-Length"));
+            }
+            if !coding.eq_ignore_ascii_case("chunked") || headers.get_all("Transfer-Encoding").count() > 1 {
+                return Err(ParseError::NotImplemented);
+            }
+            return Ok(BodyFraming::Chunked);
+        }
+
+        let mut lengths = headers.get_all("Content-Length");
+        let Some(length) = lengths.next() else { return Ok(BodyFraming::Empty) };
+        if lengths.any(|other| other != length) {
+            return Err(ParseError::BadRequest("conflicting Content-Length headers"));
+        }
+        if length.is_empty() || !length.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(ParseError::BadRequest("invalid Content-Length"));
+        }
+        let length: usize = length.parse().map_err(|_| ParseError::PayloadTooLarge)?;
+        if length > limits.max_body {
+            return Err(ParseError::PayloadTooLarge);
+        }
+        Ok(if length == 0 { BodyFraming::Empty } else { BodyFraming::Length(length) })
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name)
+    }
+
+    /// The value of `name` in the query string, percent-decoded.
+    ///
+    /// `?tag=a&tag=b` gives the first one, `?flag` gives `Some("")`.
+    pub fn query_param(&self, name: &str) -> Option<String> {
+        let query = self.query.as_deref()?;
+        query.split('&').find_map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (form_decode(key) == name).then(|| form_decode(value))
+        })
+    }
+
+    /// The path parameter `name`, percent-decoded, once the `Router` has matched the request.
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str())
+    }
+
+    /// The request target as it was sent, e.g. `/search?q=rust`.
+    pub fn target(&self) -> String {
+        match &self.query {
+            Some(query) => format!("{}?{}", self.path, query),
+            None => self.path.clone(),
+        }
+    }
+
+    /// The body as text, if it is valid UTF-8.
+    pub fn body_str(&self) -> Option<&str> {
+        std::str::from_utf8(&self.body).ok()
+    }
+}
+
+enum Line {
+    Text(String),
+    TooLong,
+    Eof,
+}
+
+/// Reads a line ending with CRLF (or a bare LF), without the line ending, allowing at most
+/// `max` bytes.
+fn read_line(reader: &mut impl BufRead, max: usize) -> Result<Line, ParseError> {
+    let mut buf = Vec::new();
+    // Why `take`?
+    // `read_until` alone would keep reading a line that never ends, as long as the client
+    // keeps sending.
+    let read = reader.take(max as u64 + 2).read_until(b'\n', &mut buf)?;
+    if read == 0 {
+        return Ok(Line::Eof);
+    }
+    if buf.last() != Some(&b'\n') {
+        if buf.len() > max {
+            return Ok(Line::TooLong);
+        }
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed in the middle of a line").into());
+    }
+    buf.pop();
+    if buf.last() == Some(&b'\r') {
+        buf.pop();
+    }
+    if buf.len() > max {
+        return Ok(Line::TooLong);
+    }
+    String::from_utf8(buf).map(Line::Text).map_err(|_| ParseError::BadRequest("invalid UTF-8"))
+}
+
+fn parse_request_line(line: &str) -> Result<(Method, &str, Version), ParseError> {
+    let mut parts = line.split(' ');
+    let (Some(method), Some(target), Some(version), None) = (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(ParseError::BadRequest("malformed request line"));
+    };
+    let version = match version {
+        "HTTP/1.1" => Version::Http11,
+        "HTTP/1.0" => Version::Http10,
+        v if v.starts_with("HTTP/") => return Err(ParseError::VersionNotSupported),
+        _ => return Err(ParseError::BadRequest("malformed request line")),
+    };
+    if method.is_empty() || !method.bytes().all(is_token_byte) {
+        return Err(ParseError::BadRequest("malformed method"));
+    }
+    let method = Method::parse(method).ok_or(ParseError::NotImplemented)?;
+    // Only the origin form (`/path?query`); `*` and absolute URLs are for proxies.
+    if !target.starts_with('/') {
+        return Err(ParseError::BadRequest("the target must start with '/'"));
+    }
+    Ok((method, target, version))
+}
+
+pub(crate) fn read_headers(reader: &mut impl BufRead, limits: &Limits) -> Result<Headers, ParseError> {
+    let mut headers = Headers::new();
+    let mut total = 0;
+    loop {
+        let line = match read_line(reader, limits.max_header_bytes - total)? {
+            Line::Text(line) => line,
+            Line::TooLong => return Err(ParseError::HeadersTooLarge),
+            Line::Eof => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+        };
+        if line.is_empty() {
+            return Ok(headers);
+        }
+        total += line.len() + 2;
+        if total > limits.max_header_bytes || headers.len() == limits.max_headers {
+            return Err(ParseError::HeadersTooLarge);
+        }
+        // A line starting with whitespace continues the previous one ("obs-fold"); RFC
+        // 9112 lets servers reject it.
+        if line.starts_with([' ', '\t']) {
+            return Err(ParseError::BadRequest("folded header line"));
+        }
+        let Some((name, value)) = line.split_once(':') else {
+            return Err(ParseError::BadRequest("header line without ':'"));
+        };
+        // `Name : value` is rejected too: the space was a classic way to smuggle headers past
+        // proxies that parse differently.
+        if name.is_empty() || !name.bytes().all(is_token_byte) {
+            return Err(ParseError::BadRequest("malformed header name"));
+        }
+        headers.append(name, value.trim_matches([' ', '\t']));
+    }
+}
+
+/// How the end of a request body is found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BodyFraming {
+    Empty,
+    /// `Content-Length` bytes.
+    Length(usize),
+    /// `Transfer-Encoding: chunked`, see `read_chunked`.
+    Chunked,
+}
+
+/// Reads a chunked body:
+///
+/// ```text
+/// 5\r\n            <- size in hex, maybe followed by `;extensions`
+/// hello\r\n
+/// 0\r\n            <- the last chunk
+/// \r\n             <- optional trailer fields, then an empty line
+/// ```
+fn read_chunked(reader: &mut impl BufRead, limits: &Limits) -> Result<Vec<u8>, ParseError> {
+    let mut body = Vec::new();
+    loop {
+        let line = match read_line(reader, 1024)? {
+            Line::Text(line) => line,
+            Line::TooLong => return Err(ParseError::BadRequest("chunk size line too long")),
+            Line::Eof => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+        };
+        let size = parse_chunk_size(&line, body.len(), limits)?;
+        if size == 0 {
+            break;
+        }
+        let start = body.len();
+        body.resize(start + size, 0);
+        reader.read_exact(&mut body[start..])?;
+        let mut crlf = [0; 2];
+        reader.read_exact(&mut crlf)?;
+        if &crlf != b"\r\n" {
+            return Err(ParseError::BadRequest("chunk not followed by CRLF"));
+        }
+    }
+    // Trailer fields are allowed to be ignored; they still count against the header limit.
+    read_headers(reader, limits)?;
+    Ok(body)
+}
+
+/// The size on a chunk size line such as `1a;ext=1`, checking that the body so far plus the
+/// chunk stays within the limit.
+pub(crate) fn parse_chunk_size(line: &str, so_far: usize, limits: &Limits) -> Result<usize, ParseError> {
+    let size = line.split(';').next().unwrap_or_default().trim();
+    if size.is_empty() || !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(ParseError::BadRequest("invalid chunk size"));
+    }
+    let size = usize::from_str_radix(size, 16).map_err(|_| ParseError::PayloadTooLarge)?;
+    // Note: a huge hex size can overflow the running total, so check the add.
+    if so_far.checked_add(size).filter(|n| *n <= limits.max_body).is_none() {
+        return Err(ParseError::PayloadTooLarge);
+    }
+    Ok(size)
+}
+
+/// The characters allowed in methods and header names ("tchar" in RFC 9110).
+fn is_token_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+/// Decodes `%XX` escapes. Invalid escapes are kept as they are.
+pub fn percent_decode(s: &str) -> String {
+    decode(s, false)
+}
+
+/// Like `percent_decode`, and `+` is a space, as in query strings sent by HTML forms.
+pub fn form_decode(s: &str) -> String {
+    decode(s, true)
+}
+
+fn decode(s: &str, plus_as_space: bool) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' if i + 2 < bytes.len() => {
+                match (hex_value(bytes[i + 1]), hex_value(bytes[i + 2])) {
+                    (Some(high), Some(low)) => {
+                        out.push(high << 4 | low);
+                        i += 3;
+                        continue;
+                    }
+                    _ => out.push(b'%'),
+                }
+            }
+            b'+' if plus_as_space => out.push(b' '),
+            b => out.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn hex_value(b: u8) -> Option<u8> {
+    (b as char).to_digit(16).map(|d| d as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(raw: &str) -> Result<Request, ParseError> {
+        Request::read_from(&mut raw.as_bytes())
+    }
+
+    fn status(raw: &str) -> u16 {
+        parse(raw).unwrap_err().status().unwrap().0
+    }
+
+    #[test]
+    fn test_request_line_headers_and_query() {
+        let request = parse("GET /search?q=rust%20lang&page=2&flag HTTP/1.1\r\nHost: localhost\r\nAccept: a\r\naccept:  b \r\n\r\n").unwrap();
+        assert_eq!(request.method, Method::Get);
+        assert_eq!(request.path, "/search");
+        assert_eq!(request.version, Version::Http11);
+        assert_eq!(request.query_param("q").as_deref(), Some("rust lang"));
+        assert_eq!(request.query_param("page").as_deref(), Some("2"));
+        assert_eq!(request.query_param("flag").as_deref(), Some(""));
+        assert_eq!(request.query_param("missing"), None);
+        assert_eq!(request.headers.get_all("ACCEPT").collect::<Vec<_>>(), vec!["a", "b"]);
+        assert!(request.body.is_empty());
+    }
+
+    #[test]
+    fn test_bodies() {
+        let request = parse("POST /echo HTTP/1.1\r\nHost: x\r\nContent-Length: 5\r\n\r\nhelloEXTRA").unwrap();
+        assert_eq!(request.body, b"hello");
+
+        let chunked = "POST /echo HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n\
+                       5;ext=1\r\nhello\r\n7\r\n, world\r\n0\r\nTrailer: yes\r\n\r\n";
+        assert_eq!(parse(chunked).unwrap().body_str(), Some("hello, world"));
+
+        // a request is read up to its end, so the next one on the connection can be read
+        let mut two = "GET /a HTTP/1.0\r\n\r\nGET /b HTTP/1.0\r\n\r\n".as_bytes();
+        assert_eq!(Request::read_from(&mut two).unwrap().path, "/a");
+        assert_eq!(Request::read_from(&mut two).unwrap().path, "/b");
+        assert!(matches!(Request::read_from(&mut two), Err(ParseError::Closed)));
+    }
+
+    #[test]
+    fn test_malformed_requests() {
+        assert_eq!(status("GET /\r\n\r\n"), 400);
+        assert_eq!(status("GET / HTTP/1.1\r\n\r\n"), 400); // no Host
+        assert_eq!(status("GET / HTTP/1.1\r\nHost : x\r\n\r\n"), 400);
+        assert_eq!(status("GET / HTTP/1.1\r\nHost: x\r\n folded\r\n\r\n"), 400);
+        assert_eq!(status("GET / HTTP/2.0\r\n\r\n"), 505);
+        assert_eq!(status("BREW / HTTP/1.1\r\nHost: x\r\n\r\n"), 501);
+        assert_eq!(status("POST / HTTP/1.1\r\nHost: x\r\nContent-Length: -1\r\n\r\n"), 400);
+        assert_eq!(status("POST / HTTP/1.1\r\nHost: x\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\n"), 400);
+        assert_eq!(status("POST / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: gzip\r\n\r\n"), 501);
+        let both = "POST / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\nContent-Length: 3\r\n\r\n";
+        assert_eq!(status(both), 400);
+        assert!(matches!(parse("POST / HTTP/1.1\r\nHost: x\r\nContent-Length: 10\r\n\r\nshort"), Err(ParseError::Io(_))));
+    }
+
+    #[test]
+    fn test_limits() {
+        let limits = Limits { max_request_line: 20, max_header_bytes: 40, max_headers: 2, max_body: 4 };
+        let read = |raw: &str| Request::read_with_limits(&mut raw.as_bytes(), &limits).unwrap_err().status().unwrap().0;
+        assert_eq!(read("GET /a-very-long-path-indeed HTTP/1.1\r\n\r\n"), 414);
+        assert_eq!(read("GET / HTTP/1.1\r\nHost: x\r\nA: 1\r\nB: 2\r\n\r\n"), 431);
+        assert_eq!(read("GET / HTTP/1.1\r\nHost: xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx\r\n\r\n"), 431);
+        assert_eq!(read("POST / HTTP/1.1\r\nHost: x\r\nContent-Length: 5\r\n\r\nhello"), 413);
+        assert_eq!(read("POST / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n2\r\nde\r\n0\r\n\r\n"), 413);
+        assert_eq!(read("POST / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\nffffffffffffffff\r\n"), 413);
+    }
+}
@@ -0,0 +1,501 @@
+//! The response sent back for a request.
+//!
+//! ```text
+//! HTTP/1.1 200 OK\r\n                          <- status line
+//! Date: Sun, 06 Nov 1994 08:49:37 GMT\r\n       <- headers
+//! Content-Type: text/plain; charset=utf-8\r\n
+//! Content-Length: 5\r\n
+//! \r\n
+//! hello                                         <- body
+//! ```
+
+use crate::connection::Upgraded;
+use crate::request::Headers;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// A status code such as `StatusCode::NOT_FOUND`. Plain numbers convert too, so
+/// `Response::text(404, ..)` works as well.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct StatusCode(pub u16);
+
+impl StatusCode {
+    pub const SWITCHING_PROTOCOLS: StatusCode = StatusCode(101);
+    pub const OK: StatusCode = StatusCode(200);
+    pub const CREATED: StatusCode = StatusCode(201);
+    pub const NO_CONTENT: StatusCode = StatusCode(204);
+    pub const PARTIAL_CONTENT: StatusCode = StatusCode(206);
+    pub const MOVED_PERMANENTLY: StatusCode = StatusCode(301);
+    pub const FOUND: StatusCode = StatusCode(302);
+    pub const SEE_OTHER: StatusCode = StatusCode(303);
+    pub const NOT_MODIFIED: StatusCode = StatusCode(304);
+    pub const TEMPORARY_REDIRECT: StatusCode = StatusCode(307);
+    pub const PERMANENT_REDIRECT: StatusCode = StatusCode(308);
+    pub const BAD_REQUEST: StatusCode = StatusCode(400);
+    pub const UNAUTHORIZED: StatusCode = StatusCode(401);
+    pub const FORBIDDEN: StatusCode = StatusCode(403);
+    pub const NOT_FOUND: StatusCode = StatusCode(404);
+    pub const METHOD_NOT_ALLOWED: StatusCode = StatusCode(405);
+    pub const RANGE_NOT_SATISFIABLE: StatusCode = StatusCode(416);
+    pub const UPGRADE_REQUIRED: StatusCode = StatusCode(426);
+    pub const INTERNAL_SERVER_ERROR: StatusCode = StatusCode(500);
+    pub const SERVICE_UNAVAILABLE: StatusCode = StatusCode(503);
+
+    /// The reason phrase that goes with the code; clients ignore it, people read it.
+    pub fn reason(&self) -> &'static str {
+        match self.0 {
+            100 => "Continue",
+            101 => "Switching Protocols",
+            200 => "OK",
+            201 => "Created",
+            202 => "Accepted",
+            204 => "No Content",
+            206 => "Partial Content",
+            301 => "Moved Permanently",
+            302 => "Found",
+            303 => "See Other",
+            304 => "Not Modified",
+            307 => "Temporary Redirect",
+            308 => "Permanent Redirect",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            403 => "Forbidden",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            408 => "Request Timeout",
+            413 => "Content Too Large",
+            414 => "URI Too Long",
+            416 => "Range Not Satisfiable",
+            426 => "Upgrade Required",
+            431 => "Request Header Fields Too Large",
+            500 => "Internal Server Error",
+            501 => "Not Implemented",
+            503 => "Service Unavailable",
+            505 => "HTTP Version Not Supported",
+            _ => "",
+        }
+    }
+
+    /// 1xx, 204 and 304 responses never have a body (RFC 9112, section 6.3).
+    pub fn allows_body(&self) -> bool {
+        !(100..200).contains(&self.0) && self.0 != 204 && self.0 != 304
+    }
+}
+
+impl From<u16> for StatusCode {
+    fn from(code: u16) -> StatusCode {
+        StatusCode(code)
+    }
+}
+
+impl PartialEq<u16> for StatusCode {
+    fn eq(&self, other: &u16) -> bool {
+        self.0 == *other
+    }
+}
+
+impl fmt::Display for StatusCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.0, self.reason())
+    }
+}
+
+/// What follows the headers.
+pub enum Body {
+    /// Bytes already in memory.
+    Bytes(Vec<u8>),
+    /// Bytes read as they are sent, e.g. a large file, so it never has to fit in memory.
+    /// With a known length the response gets a `Content-Length`; without one it is sent
+    /// with `Transfer-Encoding: chunked`.
+    Stream(Box<dyn Read + Send>, Option<u64>),
+}
+
+impl Body {
+    /// The bytes, unless the body is a stream.
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Body::Bytes(bytes) => Some(bytes),
+            Body::Stream(..) => None,
+        }
+    }
+
+    /// The length, if known before sending.
+    pub fn len(&self) -> Option<u64> {
+        match self {
+            Body::Bytes(bytes) => Some(bytes.len() as u64),
+            Body::Stream(_, len) => *len,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == Some(0)
+    }
+}
+
+impl fmt::Debug for Body {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Body::Bytes(bytes) => write!(f, "Bytes({} bytes)", bytes.len()),
+            Body::Stream(_, len) => write!(f, "Stream({:?})", len),
+        }
+    }
+}
+
+impl From<Vec<u8>> for Body {
+    fn from(bytes: Vec<u8>) -> Body {
+        Body::Bytes(bytes)
+    }
+}
+
+impl From<String> for Body {
+    fn from(text: String) -> Body {
+        Body::Bytes(text.into_bytes())
+    }
+}
+
+impl From<&str> for Body {
+    fn from(text: &str) -> Body {
+        Body::Bytes(text.as_bytes().to_vec())
+    }
+}
+
+/// What takes the connection over after a `101 Switching Protocols` response.
+pub(crate) struct OnUpgrade(Box<dyn FnOnce(Upgraded) + Send>);
+
+impl OnUpgrade {
+    pub(crate) fn run(self, upgraded: Upgraded) {
+        (self.0)(upgraded)
+    }
+}
+
+impl fmt::Debug for OnUpgrade {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("OnUpgrade")
+    }
+}
+
+#[derive(Debug)]
+pub struct Response {
+    pub status: StatusCode,
+    pub headers: Headers,
+    pub body: Body,
+    pub(crate) upgrade: Option<OnUpgrade>,
+}
+
+impl Response {
+    /// An empty response with the given status.
+    pub fn new(status: impl Into<StatusCode>) -> Response {
+        Response { status: status.into(), headers: Headers::new(), body: Body::Bytes(Vec::new()), upgrade: None }
+    }
+
+    /// A response with any body and content type.
+    pub fn bytes(status: impl Into<StatusCode>, content_type: &str, body: impl Into<Body>) -> Response {
+        Response::new(status).with_header("Content-Type", content_type).with_body(body)
+    }
+
+    /// A streamed response, `len` being the length of the stream if known.
+    pub fn stream(
+        status: impl Into<StatusCode>,
+        content_type: &str,
+        reader: impl Read + Send + 'static,
+        len: Option<u64>,
+    ) -> Response {
+        Response::bytes(status, content_type, Body::Stream(Box::new(reader), len))
+    }
+
+    pub fn text(status: impl Into<StatusCode>, body: impl Into<String>) -> Response {
+        Response::bytes(status, "text/plain; charset=utf-8", body.into())
+    }
+
+    pub fn html(status: impl Into<StatusCode>, body: impl Into<String>) -> Response {
+        Response::bytes(status, "text/html; charset=utf-8", body.into())
+    }
+
+    /// A JSON response. The body is JSON already; `json_string` helps to build it.
+    pub fn json(status: impl Into<StatusCode>, body: impl Into<String>) -> Response {
+        Response::bytes(status, "application/json", body.into())
+    }
+
+    /// A redirect to `location`, with a short body for clients that don't follow it.
+    ///
+    /// Note: 303 turns the next request into a GET, 307/308 keep the method and body,
+    /// and 301/302 are ambiguous about it for historical reasons.
+    pub fn redirect(status: impl Into<StatusCode>, location: &str) -> Response {
+        let status = status.into();
+        assert!((300..400).contains(&status.0), "not a redirect status: {}", status);
+        Response::text(status, format!("Redirecting to {}", location)).with_header("Location", location)
+    }
+
+    /// Sets a header, replacing any with the same name.
+    ///
+    /// Why remove CR and LF?
+    /// A value taken from the request (say, a redirect target) containing `\r\n` would
+    /// otherwise let a client add its own headers to the response ("response splitting").
+    pub fn with_header(mut self, name: &str, value: &str) -> Response {
+        let value: String = value.chars().filter(|c| *c != '\r' && *c != '\n').collect();
+        self.headers.set(name, &value);
+        self
+    }
+
+    pub fn with_body(mut self, body: impl Into<Body>) -> Response {
+        self.body = body.into();
+        self
+    }
+
+    /// Hands the connection to `on_upgrade` once this response is sent, instead of reading
+    /// the next request. Only a `101 Switching Protocols` response upgrades the connection;
+    /// any other status ignores it.
+    pub fn with_upgrade(mut self, on_upgrade: impl FnOnce(Upgraded) + Send + 'static) -> Response {
+        self.upgrade = Some(OnUpgrade(Box::new(on_upgrade)));
+        self
+    }
+
+    /// Writes the response. With `head` (an answer to a HEAD request), everything is
+    /// written as for a GET except the body.
+    ///
+    /// `Content-Length`, `Transfer-Encoding` and `Date` are filled in here, from the body
+    /// and the clock, so handlers can't get them wrong.
+    pub fn write_to(self, out: &mut impl Write, head: bool) -> io::Result<()> {
+        let prepared = self.prepare(head);
+        out.write_all(&prepared.head)?;
+        match prepared.body {
+            None => {}
+            Some(Body::Bytes(bytes)) => out.write_all(&bytes)?,
+            Some(Body::Stream(mut reader, _)) if !prepared.chunked => {
+                io::copy(&mut reader, out)?;
+            }
+            Some(Body::Stream(mut reader, _)) => {
+                let mut buf = [0; 8 * 1024];
+                loop {
+                    let n = reader.read(&mut buf)?;
+                    if n == 0 {
+                        break;
+                    }
+                    write!(out, "{:X}\r\n", n)?;
+                    out.write_all(&buf[..n])?;
+                    out.write_all(b"\r\n")?;
+                }
+                out.write_all(b"0\r\n\r\n")?;
+            }
+        }
+        out.flush()
+    }
+
+    /// Fills in the framing headers and serializes the head, for `write_to` and the async
+    /// server, which writes the body its own way.
+    pub(crate) fn prepare(self, head: bool) -> Prepared {
+        let Response { status, mut headers, body, .. } = self;
+        headers.remove("Content-Length");
+        headers.remove("Transfer-Encoding");
+        if !headers.contains("Date") {
+            headers.set("Date", &http_date(SystemTime::now()));
+        }
+        let chunked = status.allows_body() && body.len().is_none();
+        if status.allows_body() {
+            match body.len() {
+                Some(len) => headers.set("Content-Length", &len.to_string()),
+                None => headers.set("Transfer-Encoding", "chunked"),
+            }
+            if !headers.contains("Content-Type") && !body.is_empty() {
+                headers.set("Content-Type", "application/octet-stream");
+            }
+        }
+
+        // Note: one buffer for the head, so it isn't sent as one tiny packet per header.
+        let mut head_bytes = format!("HTTP/1.1 {}\r\n", status);
+        for (name, value) in headers.iter() {
+            head_bytes.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head_bytes.push_str("\r\n");
+
+        let body = (!head && status.allows_body()).then_some(body);
+        Prepared { head: head_bytes.into_bytes(), body, chunked }
+    }
+}
+
+/// A response ready to be written: the head, and the body unless there is none to send.
+pub(crate) struct Prepared {
+    pub(crate) head: Vec<u8>,
+    pub(crate) body: Option<Body>,
+    /// Whether the body must be sent with chunked encoding.
+    pub(crate) chunked: bool,
+}
+
+/// Quotes and escapes `s` as a JSON string, for building `Response::json` bodies by hand.
+pub fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Adds `name` to the `Vary` header, keeping what other middleware put there.
+pub(crate) fn add_vary(response: &mut Response, name: &str) {
+    let vary = match response.headers.get("Vary") {
+        Some(vary) if vary.split(',').any(|v| v.trim().eq_ignore_ascii_case(name) || v.trim() == "*") => return,
+        Some(vary) => format!("{}, {}", vary, name),
+        None => name.to_string(),
+    };
+    response.headers.set("Vary", &vary);
+}
+
+const WEEKDAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+/// Formats a time the way HTTP wants it: `Sun, 06 Nov 1994 08:49:37 GMT` (the IMF-fixdate of
+/// RFC 9110).
+pub fn http_date(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let days = secs / 86_400;
+    let (year, month, day) = civil_from_days(days as i64);
+    let (hour, minute, second) = (secs % 86_400 / 3600, secs % 3600 / 60, secs % 60);
+    // 1970-01-01 was a Thursday
+    let weekday = WEEKDAYS[((days + 4) % 7) as usize];
+    format!("{}, {:02} {} {} {:02}:{:02}:{:02} GMT", weekday, day, MONTHS[month as usize - 1], year, hour, minute, second)
+}
+
+/// Formats a time the way access logs want it: `10/Oct/2000:13:55:36 +0000`.
+pub(crate) fn log_date(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let (year, month, day) = civil_from_days((secs / 86_400) as i64);
+    let (hour, minute, second) = (secs % 86_400 / 3600, secs % 3600 / 60, secs % 60);
+    format!("{:02}/{}/{}:{:02}:{:02}:{:02} +0000", day, MONTHS[month as usize - 1], year, hour, minute, second)
+}
+
+/// Parses an IMF-fixdate, as sent in `If-Modified-Since`. The two obsolete formats RFC 9110
+/// still mentions give `None`, which just means the header is ignored.
+///
+/// Note: the year is whatever digits the client sent, so only 1970..=9999 is accepted; a
+/// year like 300000000000 would otherwise overflow the day count or the `SystemTime`.
+pub fn parse_http_date(s: &str) -> Option<SystemTime> {
+    let (weekday, rest) = s.trim().split_once(", ")?;
+    let mut parts = rest.split(' ');
+    let (day, month, year, time, zone) = (parts.next()?, parts.next()?, parts.next()?, parts.next()?, parts.next()?);
+    if !WEEKDAYS.contains(&weekday) || zone != "GMT" || parts.next().is_some() || day.len() != 2 {
+        return None;
+    }
+    let day: u32 = day.parse().ok()?;
+    let month = MONTHS.iter().position(|m| *m == month)? as u32 + 1;
+    let year: i64 = year.parse().ok().filter(|year| (1970..=9999).contains(year))?;
+    let mut hms = time.split(':').map(|n| n.parse::<u64>().ok());
+    let (hour, minute, second) = (hms.next()??, hms.next()??, hms.next()??);
+    if hms.next().is_some() || !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+    let days = u64::try_from(days_from_civil(year, month, day)).ok()?;
+    let secs = days.checked_mul(86_400)?.checked_add(hour * 3600 + minute * 60 + second)?;
+    UNIX_EPOCH.checked_add(Duration::from_secs(secs))
+}
+
+/// Turns (year, month, day) into days since 1970-01-01, the inverse of `civil_from_days`.
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let month_from_march = (month as i64 + 9) % 12;
+    let day_of_year = (153 * month_from_march + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Turns days since 1970-01-01 into (year, month, day).
+///
+/// Note: Howard Hinnant's algorithm. Counting from 0000-03-01 puts the leap day at the end
+/// of the "year", and 400-year eras repeat exactly, so there is no special case at all.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_from_march + 2) / 5 + 1;
+    let month = if month_from_march < 10 { month_from_march + 3 } else { month_from_march - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month as u32, day as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn serialize(response: Response, head: bool) -> String {
+        let mut out = Vec::new();
+        response.with_header("Date", "today").write_to(&mut out, head).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_serialization() {
+        assert_eq!(
+            serialize(Response::text(200, "hello"), false),
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; charset=utf-8\r\nDate: today\r\nContent-Length: 5\r\n\r\nhello"
+        );
+        assert_eq!(
+            serialize(Response::text(StatusCode::NOT_FOUND, "missing"), true),
+            "HTTP/1.1 404 Not Found\r\nContent-Type: text/plain; charset=utf-8\r\nDate: today\r\nContent-Length: 7\r\n\r\n"
+        );
+        assert_eq!(serialize(Response::new(204), false), "HTTP/1.1 204 No Content\r\nDate: today\r\n\r\n");
+        let binary = serialize(Response::new(200).with_body(vec![b'x'; 3]), false);
+        assert!(binary.contains("Content-Type: application/octet-stream\r\n"), "{}", binary);
+
+        let mut out = Vec::new();
+        Response::json(201, "{}").write_to(&mut out, false).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("HTTP/1.1 201 Created\r\nContent-Type: application/json\r\nDate: "), "{}", out);
+    }
+
+    #[test]
+    fn test_streams() {
+        let sized = Response::stream(200, "text/plain", &b"abcdef"[..], Some(6));
+        assert!(serialize(sized, false).ends_with("Content-Length: 6\r\n\r\nabcdef"));
+
+        let unsized_stream = Response::stream(200, "text/plain", io::repeat(b'z').take(10_000), None);
+        let out = serialize(unsized_stream, false);
+        assert!(out.contains("Transfer-Encoding: chunked\r\n\r\n2000\r\n"), "{}", &out[..200]);
+        assert!(out.ends_with(&format!("\r\n{:X}\r\n{}\r\n0\r\n\r\n", 10_000 - 8192, "z".repeat(10_000 - 8192))));
+    }
+
+    #[test]
+    fn test_helpers() {
+        let response = Response::redirect(StatusCode::SEE_OTHER, "/next\r\nSet-Cookie: evil=1");
+        assert_eq!(response.status, 303);
+        assert_eq!(response.headers.get("Location"), Some("/nextSet-Cookie: evil=1"));
+        assert_eq!(Response::html(200, "<p>").headers.get("Content-Type"), Some("text/html; charset=utf-8"));
+        assert_eq!(json_string("say \"hi\"\n\u{1}"), r#""say \"hi\"\n\u0001""#);
+        let mut response = Response::text(200, "x").with_header("Vary", "Origin");
+        add_vary(&mut response, "Accept-Encoding");
+        add_vary(&mut response, "accept-encoding");
+        assert_eq!(response.headers.get("Vary"), Some("Origin, Accept-Encoding"));
+    }
+
+    #[test]
+    fn test_http_date() {
+        // the example in RFC 9110
+        assert_eq!(http_date(UNIX_EPOCH + Duration::from_secs(784_111_777)), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(http_date(UNIX_EPOCH), "Thu, 01 Jan 1970 00:00:00 GMT");
+        assert_eq!(http_date(UNIX_EPOCH + Duration::from_secs(951_782_400)), "Tue, 29 Feb 2000 00:00:00 GMT");
+
+        for secs in [0, 784_111_777, 951_782_400, 1_792_368_000] {
+            let time = UNIX_EPOCH + Duration::from_secs(secs);
+            assert_eq!(parse_http_date(&http_date(time)), Some(time));
+        }
+        assert_eq!(parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 25:49:37 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 06 Nov 9223372036854775807 08:49:37 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 06 Nov 300000000000 08:49:37 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 06 Nov 1969 08:49:37 GMT"), None);
+    }
+}
@@ -0,0 +1,339 @@
+//! Dispatching requests to handlers by method and path.
+//!
+//! ```ignore
+//! let mut router = Router::new();
+//! router.route(Method::Get, "/users/:id", |req: Request| {
+//!     Response::text(200, format!("user {}", req.param("id").unwrap()))
+//! });
+//! router.route(Method::Get, "/files/*path", serve_file);
+//! ```
+
+use crate::middleware::{Middleware, Wrapped};
+use crate::request::{Method, Request, percent_decode};
+use crate::response::Response;
+
+/// Something that turns a request into a response.
+///
+/// Why a trait instead of just closures?
+/// Closures get it for free (see the blanket impl below), but a type with its own state
+/// (a `Router`, a static file server) can implement it too, and they can all be nested.
+///
+/// Note: `Send + Sync` because one handler is shared by all the worker threads.
+pub trait Handler: Send + Sync + 'static {
+    fn call(&self, request: Request) -> Response;
+
+    /// This handler wrapped in `middleware`, which runs around it.
+    fn with<M: Middleware>(self, middleware: M) -> Wrapped<Self, M>
+    where
+        Self: Sized,
+    {
+        Wrapped { handler: self, middleware }
+    }
+}
+
+impl<F> Handler for F
+where
+    F: Fn(Request) -> Response + Send + Sync + 'static,
+{
+    fn call(&self, request: Request) -> Response {
+        self(request)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    /// `users`: must be equal.
+    Literal(String),
+    /// `:id`: any one segment.
+    Param(String),
+    /// `*path`: the rest of the path, possibly nothing. Only as the last segment.
+    Wildcard(String),
+}
+
+impl Segment {
+    /// When several routes match, the one with the most specific segments wins, from left to
+    /// right: `/users/new` before `/users/:id` before `/users/*rest`.
+    fn rank(&self) -> u8 {
+        match self {
+            Segment::Literal(_) => 2,
+            Segment::Param(_) => 1,
+            Segment::Wildcard(_) => 0,
+        }
+    }
+}
+
+/// Path parameters by name, as in `Request::params`.
+type Params = Vec<(String, String)>;
+
+struct Route<H: ?Sized> {
+    method: Method,
+    segments: Vec<Segment>,
+    handler: Box<H>,
+}
+
+impl<H: ?Sized> Route<H> {
+    /// The parameters if `path` matches this route's pattern, whatever the method.
+    fn matches(&self, path: &[String]) -> Option<Params> {
+        let mut params = Vec::new();
+        for (i, segment) in self.segments.iter().enumerate() {
+            match segment {
+                Segment::Wildcard(name) => {
+                    params.push((name.clone(), path.get(i..).unwrap_or_default().join("/")));
+                    return Some(params);
+                }
+                Segment::Literal(literal) if path.get(i) != Some(literal) => return None,
+                Segment::Literal(_) => {}
+                Segment::Param(name) => params.push((name.clone(), path.get(i)?.clone())),
+            }
+        }
+        (path.len() == self.segments.len()).then_some(params)
+    }
+
+    /// How well the route fits: most specific segments first, then an exact method over
+    /// HEAD answered by GET.
+    fn specificity(&self, method: Method) -> (Vec<u8>, bool) {
+        (self.segments.iter().map(Segment::rank).collect(), self.method == method)
+    }
+}
+
+/// The route table behind `Router` and `AsyncRouter`, generic over the kind of handler.
+pub(crate) struct Routes<H: ?Sized> {
+    routes: Vec<Route<H>>,
+}
+
+/// What `Routes::find` found for a request.
+pub(crate) enum Lookup<'a, H: ?Sized> {
+    Route(&'a H),
+    NotFound,
+    /// The path matches, but only for other methods: 405, with this `Allow` header.
+    MethodNotAllowed(String),
+}
+
+impl<H: ?Sized> Routes<H> {
+    pub(crate) fn new() -> Routes<H> {
+        Routes { routes: Vec::new() }
+    }
+
+    /// # Panics
+    ///
+    /// If the pattern doesn't start with `/` or has a `*wildcard` before the last segment.
+    pub(crate) fn add(&mut self, method: Method, pattern: &str, handler: Box<H>) {
+        assert!(pattern.starts_with('/'), "route patterns start with '/': {}", pattern);
+        let segments: Vec<Segment> = split(pattern)
+            .map(|s| match s.as_bytes()[0] {
+                b':' => Segment::Param(s[1..].to_string()),
+                b'*' => Segment::Wildcard(s[1..].to_string()),
+                _ => Segment::Literal(percent_decode(s)),
+            })
+            .collect();
+        let last = segments.len().saturating_sub(1);
+        assert!(
+            !segments[..last].iter().any(|s| matches!(s, Segment::Wildcard(_))),
+            "a wildcard must be the last segment: {}",
+            pattern
+        );
+        self.routes.push(Route { method, segments, handler });
+    }
+
+    /// Finds the route for the request, filling in `request.params` if there is one.
+    pub(crate) fn find(&self, request: &mut Request) -> Lookup<'_, H> {
+        // Note: segments are decoded one by one, so `%2F` stays inside its segment.
+        let path: Vec<String> = split(&request.path).map(percent_decode).collect();
+
+        // Note: HEAD is GET without the body (`Response::write_to` leaves it out), so a GET
+        // route answers HEAD requests unless there is a HEAD route for the same path.
+        let method = request.method;
+        let accepts = |route: &Route<H>| route.method == method || (method == Method::Head && route.method == Method::Get);
+        let mut best: Option<(&Route<H>, Params)> = None;
+        let mut allowed = Vec::new();
+        for route in &self.routes {
+            let Some(params) = route.matches(&path) else { continue };
+            if !accepts(route) {
+                allowed.push(route.method.as_str());
+                if route.method == Method::Get {
+                    allowed.push(Method::Head.as_str());
+                }
+                continue;
+            }
+            let more_specific = match &best {
+                Some((current, _)) => route.specificity(method) > current.specificity(method),
+                None => true,
+            };
+            if more_specific {
+                best = Some((route, params));
+            }
+        }
+
+        match best {
+            Some((route, params)) => {
+                request.params = params;
+                Lookup::Route(&route.handler)
+            }
+            None if !allowed.is_empty() => {
+                allowed.sort_unstable();
+                allowed.dedup();
+                Lookup::MethodNotAllowed(allowed.join(", "))
+            }
+            None => Lookup::NotFound,
+        }
+    }
+}
+
+pub(crate) fn not_found() -> Response {
+    Response::text(404, "404 Not Found")
+}
+
+pub(crate) fn method_not_allowed(allow: &str) -> Response {
+    Response::text(405, "405 Method Not Allowed").with_header("Allow", allow)
+}
+
+/// Routes registered with `route`, plus what to do when none matches.
+pub struct Router {
+    routes: Routes<dyn Handler>,
+    not_found: Box<dyn Handler>,
+}
+
+impl Router {
+    pub fn new() -> Router {
+        Router { routes: Routes::new(), not_found: Box::new(|_: Request| not_found()) }
+    }
+
+    /// Registers `handler` for `method` requests whose path matches `pattern`.
+    ///
+    /// # Panics
+    ///
+    /// If the pattern doesn't start with `/` or has a `*wildcard` before the last segment.
+    pub fn route(&mut self, method: Method, pattern: &str, handler: impl Handler) -> &mut Router {
+        self.routes.add(method, pattern, Box::new(handler));
+        self
+    }
+
+    /// Replaces the default `404 Not Found` handler.
+    pub fn not_found(&mut self, handler: impl Handler) -> &mut Router {
+        self.not_found = Box::new(handler);
+        self
+    }
+
+    /// Finds the route for the request and calls it.
+    ///
+    /// A path that no route matches gets a 404; a path that matches, but only for other
+    /// methods, gets a 405 with an `Allow` header listing them.
+    pub fn dispatch(&self, mut request: Request) -> Response {
+        match self.routes.find(&mut request) {
+            Lookup::Route(handler) => handler.call(request),
+            Lookup::MethodNotAllowed(allow) => method_not_allowed(&allow),
+            Lookup::NotFound => self.not_found.call(request),
+        }
+    }
+}
+
+impl Default for Router {
+    fn default() -> Router {
+        Router::new()
+    }
+}
+
+/// A router is a handler too, so routers can be mounted in other routers or wrapped.
+impl Handler for Router {
+    fn call(&self, request: Request) -> Response {
+        self.dispatch(request)
+    }
+}
+
+/// The non-empty segments of a path: `/users//42/` has the same two as `/users/42`.
+fn split(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|s| !s.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    fn request(method: &str, target: &str) -> Request {
+        let raw = format!("{} {} HTTP/1.1\r\nHost: x\r\n\r\n", method, target);
+        Request::read_from(&mut raw.as_bytes()).unwrap()
+    }
+
+    fn body(response: Response) -> String {
+        String::from_utf8(response.body.as_bytes().unwrap().to_vec()).unwrap()
+    }
+
+    fn echo(name: &'static str) -> impl Handler {
+        move |req: Request| {
+            let mut params: Vec<String> = req.params.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+            params.insert(0, name.to_string());
+            Response::text(200, params.join(" "))
+        }
+    }
+
+    #[test]
+    fn test_params_and_wildcards() {
+        let mut router = Router::new();
+        router
+            .route(Method::Get, "/users/:id", echo("user"))
+            .route(Method::Get, "/users/:id/posts/:post", echo("post"))
+            .route(Method::Get, "/files/*path", echo("file"));
+
+        assert_eq!(body(router.dispatch(request("GET", "/users/42"))), "user id=42");
+        assert_eq!(body(router.dispatch(request("GET", "/users/a%20b/"))), "user id=a b");
+        assert_eq!(body(router.dispatch(request("GET", "/users/7/posts/9?x=1"))), "post id=7 post=9");
+        assert_eq!(body(router.dispatch(request("GET", "/files/css/site.css"))), "file path=css/site.css");
+        assert_eq!(body(router.dispatch(request("GET", "/files"))), "file path=");
+        assert_eq!(body(router.dispatch(request("GET", "/files/a%2Fb"))), "file path=a/b");
+        assert_eq!(router.dispatch(request("GET", "/users")).status, 404);
+        assert_eq!(router.dispatch(request("GET", "/users/1/posts")).status, 404);
+    }
+
+    #[test]
+    fn test_most_specific_route_wins() {
+        let mut router = Router::new();
+        router
+            .route(Method::Get, "/users/*rest", echo("rest"))
+            .route(Method::Get, "/users/:id", echo("id"))
+            .route(Method::Get, "/users/new", echo("new"));
+
+        assert_eq!(body(router.dispatch(request("GET", "/users/new"))), "new");
+        assert_eq!(body(router.dispatch(request("GET", "/users/5"))), "id id=5");
+        assert_eq!(body(router.dispatch(request("GET", "/users/5/x"))), "rest rest=5/x");
+    }
+
+    #[test]
+    fn test_not_found_and_method_not_allowed() {
+        let mut router = Router::new();
+        router.route(Method::Get, "/items", echo("list")).route(Method::Post, "/items", echo("create"));
+
+        let response = router.dispatch(request("DELETE", "/items"));
+        assert_eq!(response.status, 405);
+        assert_eq!(response.headers.get("Allow"), Some("GET, HEAD, POST"));
+        assert_eq!(router.dispatch(request("GET", "/nothing")).status, 404);
+
+        let head = router.dispatch(request("HEAD", "/items"));
+        assert_eq!(body(head), "list");
+        router.route(Method::Head, "/items", echo("head"));
+        assert_eq!(body(router.dispatch(request("HEAD", "/items"))), "head");
+        let response = router.dispatch(request("PUT", "/items"));
+        assert_eq!(response.headers.get("Allow"), Some("GET, HEAD, POST"));
+
+        router.not_found(|req: Request| Response::text(404, format!("no {}", req.path)));
+        assert_eq!(body(router.dispatch(request("GET", "/nothing"))), "no /nothing");
+    }
+
+    #[test]
+    fn test_closures_with_state_and_nested_routers() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&hits);
+        let mut api = Router::new();
+        api.route(Method::Post, "/api/hit", move |_: Request| {
+            let n = counter.fetch_add(1, Ordering::SeqCst) + 1;
+            Response::text(200, n.to_string())
+        });
+        let mut router = Router::new();
+        router.route(Method::Post, "/api/*rest", api);
+
+        router.dispatch(request("POST", "/api/hit"));
+        assert_eq!(body(router.dispatch(request("POST", "/api/hit"))), "2");
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+    }
+}
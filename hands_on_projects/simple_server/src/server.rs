@@ -0,0 +1,235 @@
+//! Accepting connections and handing them to the thread pool, until told to stop.
+//!
+//! ```ignore
+//! let server = Server::builder().addr("127.0.0.1:7878").threads(8).bind(router)?;
+//! server.handle().shutdown_on_signals()?;
+//! server.run()?; // returns after Ctrl-C, once the open connections are done
+//! ```
+
+use crate::ThreadPool;
+use crate::connection::{ConnectionConfig, Upgrades, serve_connection};
+use crate::request::Limits;
+use crate::router::Handler;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+/// Settings for a `Server`, from `Server::builder()`.
+#[derive(Debug, Clone)]
+pub struct ServerBuilder {
+    pub(crate) addr: String,
+    threads: usize,
+    pub(crate) connection: ConnectionConfig,
+}
+
+impl ServerBuilder {
+    /// The address to listen on, `127.0.0.1:7878` by default. Port 0 picks a free port.
+    pub fn addr(mut self, addr: impl Into<String>) -> ServerBuilder {
+        self.addr = addr.into();
+        self
+    }
+
+    /// How many connections are served at the same time; more wait in line.
+    pub fn threads(mut self, threads: usize) -> ServerBuilder {
+        self.threads = threads;
+        self
+    }
+
+    pub fn keep_alive_timeout(mut self, timeout: Duration) -> ServerBuilder {
+        self.connection.keep_alive_timeout = timeout;
+        self
+    }
+
+    pub fn read_timeout(mut self, timeout: Duration) -> ServerBuilder {
+        self.connection.read_timeout = timeout;
+        self
+    }
+
+    pub fn max_requests_per_connection(mut self, max: usize) -> ServerBuilder {
+        self.connection.max_requests = max;
+        self
+    }
+
+    pub fn limits(mut self, limits: Limits) -> ServerBuilder {
+        self.connection.limits = limits;
+        self
+    }
+
+    /// Binds the address and starts the worker threads; `run` then accepts connections.
+    ///
+    /// # Panics
+    ///
+    /// If the number of threads is zero.
+    pub fn bind(self, handler: impl Handler) -> io::Result<Server> {
+        let listener = TcpListener::bind(&self.addr)?;
+        let addr = listener.local_addr()?;
+        Ok(Server {
+            listener,
+            pool: ThreadPool::new(self.threads),
+            handler: Arc::new(handler),
+            config: Arc::new(self.connection),
+            upgrades: Arc::new(Upgrades::default()),
+            handle: ShutdownHandle { shutdown: Arc::new(AtomicBool::new(false)), addr },
+        })
+    }
+}
+
+pub struct Server {
+    listener: TcpListener,
+    pool: ThreadPool,
+    handler: Arc<dyn Handler>,
+    config: Arc<ConnectionConfig>,
+    upgrades: Arc<Upgrades>,
+    handle: ShutdownHandle,
+}
+
+impl Server {
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder { addr: "127.0.0.1:7878".to_string(), threads: 4, connection: ConnectionConfig::default() }
+    }
+
+    /// The address actually bound, useful with port 0.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.handle.addr
+    }
+
+    /// A handle to stop the server from another thread.
+    pub fn handle(&self) -> ShutdownHandle {
+        self.handle.clone()
+    }
+
+    /// Accepts connections until `shutdown` is called on a handle, then waits for the
+    /// connections already accepted to finish.
+    pub fn run(self) -> io::Result<()> {
+        let shutdown = &self.handle.shutdown;
+        for stream in self.listener.incoming() {
+            if shutdown.load(Ordering::SeqCst) {
+                break;
+            }
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    eprintln!("Failed to establish a connection: {}", e);
+                    continue;
+                }
+            };
+            let handler = Arc::clone(&self.handler);
+            let config = Arc::clone(&self.config);
+            let shutdown = Arc::clone(shutdown);
+            let upgrades = Arc::clone(&self.upgrades);
+            self.pool.execute(move || {
+                if let Err(e) = serve_connection(stream, &*handler, &config, &shutdown, &upgrades) {
+                    eprintln!("Connection error: {}", e);
+                }
+            });
+        }
+        println!("Server is shutting down.");
+        // Note: the listener is closed first so new clients are refused instead of waiting.
+        // Dropping the pool then closes its channel and joins the workers, which finish the
+        // connections they have; idle keep-alive connections notice the shutdown and close.
+        // Upgraded connections, on threads of their own, notice it too (a WebSocket says
+        // "going away").
+        drop(self.listener);
+        drop(self.pool);
+        self.upgrades.join();
+        Ok(())
+    }
+}
+
+/// Stops a running `Server`; clones stop the same server.
+#[derive(Debug, Clone)]
+pub struct ShutdownHandle {
+    pub(crate) shutdown: Arc<AtomicBool>,
+    pub(crate) addr: SocketAddr,
+}
+
+impl ShutdownHandle {
+    /// Asks the server to stop. `run` returns once the open connections are done.
+    pub fn shutdown(&self) {
+        if self.shutdown.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        // Why connect to ourselves?
+        // `run` is blocked in `accept`, and std has no way to interrupt it. A connection
+        // wakes it up, and it sees the flag before serving it.
+        let ip = match self.addr.ip() {
+            IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+            IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+            ip => ip,
+        };
+        let _ = TcpStream::connect_timeout(&SocketAddr::new(ip, self.addr.port()), Duration::from_secs(1));
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        self.shutdown.load(Ordering::SeqCst)
+    }
+
+    /// Shuts the server down on SIGINT (Ctrl-C) or SIGTERM (`kill`, `docker stop`).
+    ///
+    /// Note: a process has one handler per signal, so this works once per process.
+    pub fn shutdown_on_signals(&self) -> Result<(), ctrlc::Error> {
+        let handle = self.clone();
+        ctrlc::set_handler(move || {
+            eprintln!("Received a shutdown signal.");
+            handle.shutdown();
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::{Method, Request};
+    use crate::response::Response;
+    use crate::router::Router;
+    use std::io::{Read, Write};
+    use std::thread;
+    use std::time::Instant;
+
+    #[test]
+    fn test_shutdown_drains_connections() {
+        let mut router = Router::new();
+        router
+            .route(Method::Get, "/", |_: Request| Response::text(200, "hi"))
+            .route(Method::Get, "/slow", |_: Request| {
+                thread::sleep(Duration::from_millis(300));
+                Response::text(200, "slow")
+            })
+            .route(Method::Get, "/panic", |_: Request| -> Response { panic!("handler bug") });
+        let server = Server::builder()
+            .addr("127.0.0.1:0")
+            .threads(2)
+            .keep_alive_timeout(Duration::from_secs(30))
+            .bind(router)
+            .unwrap();
+        let addr = server.local_addr();
+        let handle = server.handle();
+        let running = thread::spawn(move || server.run().unwrap());
+
+        // an idle keep-alive connection, and one in the middle of a slow request
+        let mut idle = TcpStream::connect(addr).unwrap();
+        idle.write_all(b"GET /panic HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+        let mut buf = [0; 1024];
+        let n = idle.read(&mut buf).unwrap();
+        assert!(String::from_utf8_lossy(&buf[..n]).starts_with("HTTP/1.1 500 "));
+        idle = TcpStream::connect(addr).unwrap();
+        idle.write_all(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+        let _ = idle.read(&mut buf).unwrap();
+        let mut slow = TcpStream::connect(addr).unwrap();
+        slow.write_all(b"GET /slow HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+        thread::sleep(Duration::from_millis(100));
+
+        let started = Instant::now();
+        handle.shutdown();
+        running.join().unwrap();
+        assert!(started.elapsed() < Duration::from_secs(2), "took {:?}", started.elapsed());
+
+        let mut out = String::new();
+        slow.read_to_string(&mut out).unwrap();
+        assert!(out.ends_with("slow") && out.contains("Connection: close"), "{}", out);
+        assert_eq!(idle.read(&mut buf).unwrap(), 0);
+        assert!(TcpStream::connect(addr).is_err());
+    }
+}
@@ -0,0 +1,395 @@
+//! Serving the files of a directory.
+//!
+//! ```ignore
+//! router.route(Method::Get, "/docs/*path", StaticFiles::new("docs").with_listing(true));
+//! ```
+
+use crate::request::{Request, percent_decode};
+use crate::response::{Response, StatusCode, http_date, parse_http_date};
+use crate::router::Handler;
+use std::fs::{self, File, Metadata};
+use std::io::{self, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A handler for GET and HEAD requests serving the files under `root`.
+///
+/// Mounted at a wildcard route such as `/docs/*path`, the wildcard named `path` is the file
+/// to serve; without one the whole request path is.
+pub struct StaticFiles {
+    root: PathBuf,
+    listing: bool,
+}
+
+impl StaticFiles {
+    pub fn new(root: impl Into<PathBuf>) -> StaticFiles {
+        StaticFiles { root: root.into(), listing: false }
+    }
+
+    /// List the contents of directories without an `index.html`, instead of a 404.
+    pub fn with_listing(mut self, listing: bool) -> StaticFiles {
+        self.listing = listing;
+        self
+    }
+
+    fn serve(&self, request: &Request) -> io::Result<Response> {
+        let relative = match request.param("path") {
+            Some(path) => path.to_string(),
+            None => percent_decode(&request.path),
+        };
+        let Some(path) = self.resolve(&relative)? else {
+            return Ok(Response::text(StatusCode::FORBIDDEN, "403 Forbidden"));
+        };
+        let metadata = fs::metadata(&path)?;
+        if !metadata.is_dir() {
+            return serve_file(request, &path, &metadata);
+        }
+
+        // Why redirect `/docs/guide` to `/docs/guide/`?
+        // The links inside `index.html` or the listing are relative, and a browser resolves
+        // `intro.html` on `/docs/guide` to `/docs/intro.html`.
+        if !request.path.ends_with('/') {
+            let mut location = format!("{}/", request.path);
+            if let Some(query) = &request.query {
+                location = format!("{}?{}", location, query);
+            }
+            return Ok(Response::redirect(StatusCode::MOVED_PERMANENTLY, &location));
+        }
+        let index = path.join("index.html");
+        if let Ok(metadata) = fs::metadata(&index)
+            && metadata.is_file()
+        {
+            return serve_file(request, &index, &metadata);
+        }
+        if !self.listing {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+        list_directory(&path, &percent_decode(&request.path))
+    }
+
+    /// The file for a path relative to the root, or `None` if it is outside the root.
+    ///
+    /// Note: `..` segments are rejected outright, decoded ones (`%2e%2e`) included since the
+    /// path is decoded by now. A symlink can still point out of the root, so the real path is
+    /// checked as well.
+    fn resolve(&self, relative: &str) -> io::Result<Option<PathBuf>> {
+        let mut path = self.root.clone();
+        for segment in relative.split('/').filter(|s| !s.is_empty() && *s != ".") {
+            if segment == ".." || segment.contains(['\\', '\0']) {
+                return Ok(None);
+            }
+            path.push(segment);
+        }
+        let root = self.root.canonicalize()?;
+        let real = path.canonicalize()?;
+        Ok(real.starts_with(&root).then_some(real))
+    }
+}
+
+impl Handler for StaticFiles {
+    fn call(&self, request: Request) -> Response {
+        match self.serve(&request) {
+            Ok(response) => response,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Response::text(StatusCode::NOT_FOUND, "404 Not Found"),
+            // e.g. a file name that isn't valid for this OS
+            Err(e) if e.kind() == io::ErrorKind::InvalidInput => Response::text(StatusCode::NOT_FOUND, "404 Not Found"),
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => Response::text(StatusCode::FORBIDDEN, "403 Forbidden"),
+            Err(e) => {
+                eprintln!("Failed to serve {}: {}", request.path, e);
+                Response::text(StatusCode::INTERNAL_SERVER_ERROR, "500 Internal Server Error")
+            }
+        }
+    }
+}
+
+fn serve_file(request: &Request, path: &Path, metadata: &Metadata) -> io::Result<Response> {
+    let len = metadata.len();
+    let modified = metadata.modified().ok().map(whole_seconds);
+    let etag = etag(len, metadata.modified().ok());
+
+    let headers = |response: Response| {
+        let response = response.with_header("ETag", &etag).with_header("Accept-Ranges", "bytes");
+        match modified {
+            Some(modified) => response.with_header("Last-Modified", &http_date(modified)),
+            None => response,
+        }
+    };
+
+    // Why does If-None-Match win over If-Modified-Since?
+    // The ETag changes with every write; a file written twice in the same second keeps its
+    // Last-Modified, which only has whole seconds.
+    let not_modified = match request.header("If-None-Match") {
+        Some(tags) => tags.split(',').any(|tag| tag.trim() == "*" || weak_eq(tag.trim(), &etag)),
+        None => match (request.header("If-Modified-Since").and_then(parse_http_date), modified) {
+            (Some(since), Some(modified)) => modified <= since,
+            _ => false,
+        },
+    };
+    if not_modified {
+        return Ok(headers(Response::new(StatusCode::NOT_MODIFIED)));
+    }
+
+    let content_type = mime_type(path);
+    let mut file = File::open(path)?;
+    // Note: If-Range asks for the range only if the file is still the one the client has
+    // part of; otherwise it wants the whole new file.
+    let range_applies = match request.header("If-Range") {
+        Some(validator) if validator.starts_with('"') => validator == etag,
+        Some(date) => parse_http_date(date).is_some_and(|date| Some(date) == modified),
+        None => true,
+    };
+    match request.header("Range").filter(|_| range_applies).map(|range| parse_range(range, len)) {
+        Some(Range::Satisfiable(start, end)) => {
+            file.seek(SeekFrom::Start(start))?;
+            let part = io::Read::take(file, end - start + 1);
+            let response = Response::stream(StatusCode::PARTIAL_CONTENT, content_type, part, Some(end - start + 1))
+                .with_header("Content-Range", &format!("bytes {}-{}/{}", start, end, len));
+            Ok(headers(response))
+        }
+        Some(Range::NotSatisfiable) => Ok(Response::text(StatusCode::RANGE_NOT_SATISFIABLE, "416 Range Not Satisfiable")
+            .with_header("Content-Range", &format!("bytes */{}", len))),
+        Some(Range::Ignored) | None => Ok(headers(Response::stream(StatusCode::OK, content_type, file, Some(len)))),
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Range {
+    /// First and last byte, inclusive.
+    Satisfiable(u64, u64),
+    NotSatisfiable,
+    /// Not understood, or several ranges: the whole file is sent, which RFC 9110 allows.
+    Ignored,
+}
+
+/// Parses `bytes=0-499` (the first 500 bytes), `bytes=500-` (from 500 on) or `bytes=-500`
+/// (the last 500 bytes) for a file of `len` bytes.
+fn parse_range(header: &str, len: u64) -> Range {
+    let Some(spec) = header.trim().strip_prefix("bytes=") else { return Range::Ignored };
+    if spec.contains(',') {
+        return Range::Ignored;
+    }
+    let Some((start, end)) = spec.trim().split_once('-') else { return Range::Ignored };
+    let number = |s: &str| s.parse::<u64>().ok().filter(|_| s.bytes().all(|b| b.is_ascii_digit()));
+    let (start, end) = match (start, end) {
+        ("", suffix) => match number(suffix) {
+            Some(0) => return Range::NotSatisfiable,
+            Some(suffix) => (len.saturating_sub(suffix), len.saturating_sub(1)),
+            None => return Range::Ignored,
+        },
+        (start, "") => match number(start) {
+            Some(start) => (start, len.saturating_sub(1)),
+            None => return Range::Ignored,
+        },
+        (start, end) => match (number(start), number(end)) {
+            (Some(start), Some(end)) if start <= end => (start, end.min(len.saturating_sub(1))),
+            _ => return Range::Ignored,
+        },
+    };
+    if start >= len {
+        return Range::NotSatisfiable;
+    }
+    Range::Satisfiable(start, end)
+}
+
+fn list_directory(dir: &Path, url_path: &str) -> io::Result<Response> {
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let is_dir = entry.file_type()?.is_dir();
+        entries.push((!is_dir, entry.file_name().to_string_lossy().into_owned()));
+    }
+    // directories first, then by name
+    entries.sort();
+
+    let title = html_escape(url_path);
+    let mut html = format!("<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>Index of {0}</title></head>\n<body>\n<h1>Index of {0}</h1>\n<ul>\n", title);
+    if url_path != "/" {
+        html.push_str("<li><a href=\"../\">../</a></li>\n");
+    }
+    for (is_file, name) in entries {
+        let slash = if is_file { "" } else { "/" };
+        html.push_str(&format!(
+            "<li><a href=\"{}{}\">{}{}</a></li>\n",
+            html_escape(&percent_encode(&name)),
+            slash,
+            html_escape(&name),
+            slash
+        ));
+    }
+    html.push_str("</ul>\n</body>\n</html>\n");
+    Ok(Response::html(StatusCode::OK, html))
+}
+
+/// The content type for a file, by its extension.
+pub fn mime_type(path: &Path) -> &'static str {
+    let extension = path.extension().and_then(|e| e.to_str()).unwrap_or_default().to_ascii_lowercase();
+    match extension.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "json" => "application/json",
+        "txt" | "md" | "rs" | "toml" => "text/plain; charset=utf-8",
+        "xml" => "application/xml",
+        "csv" => "text/csv; charset=utf-8",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "svg" => "image/svg+xml",
+        "ico" => "image/x-icon",
+        "webp" => "image/webp",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "pdf" => "application/pdf",
+        "wasm" => "application/wasm",
+        "zip" => "application/zip",
+        "gz" => "application/gzip",
+        "mp4" => "video/mp4",
+        "mp3" => "audio/mpeg",
+        _ => "application/octet-stream",
+    }
+}
+
+/// An ETag from the size and modification time: cheap, and it changes when the file does
+/// without reading it.
+fn etag(len: u64, modified: Option<SystemTime>) -> String {
+    let nanos = modified.and_then(|m| m.duration_since(UNIX_EPOCH).ok()).map_or(0, |d| d.as_nanos());
+    format!("\"{:x}-{:x}\"", len, nanos)
+}
+
+/// Compares ETags ignoring the `W/` (weak) prefix, as If-None-Match does.
+fn weak_eq(a: &str, b: &str) -> bool {
+    a.trim_start_matches("W/") == b.trim_start_matches("W/")
+}
+
+/// HTTP dates have whole seconds, so the modification time is compared without its fraction.
+fn whole_seconds(time: SystemTime) -> SystemTime {
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    UNIX_EPOCH + std::time::Duration::from_secs(secs)
+}
+
+fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;").replace('\'', "&#39;")
+}
+
+/// Escapes everything but unreserved characters, for a file name in a link.
+fn percent_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::Method;
+    use crate::router::Router;
+    use std::io::Read;
+
+    /// A fresh directory with a few files, mounted at `/docs/*path`.
+    fn setup(name: &str, listing: bool) -> (PathBuf, Router) {
+        let dir = std::env::temp_dir().join(format!("simple_server_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("guide")).unwrap();
+        fs::create_dir_all(dir.join("empty dir")).unwrap();
+        fs::write(dir.join("index.html"), "<h1>docs</h1>").unwrap();
+        fs::write(dir.join("guide/intro.md"), "0123456789").unwrap();
+        fs::write(dir.join("style.css"), "body {}").unwrap();
+        let mut router = Router::new();
+        router.route(Method::Get, "/docs/*path", StaticFiles::new(&dir).with_listing(listing));
+        (dir, router)
+    }
+
+    fn get(router: &Router, target: &str, headers: &str) -> (u16, Response, String) {
+        let raw = format!("GET {} HTTP/1.1\r\nHost: x\r\n{}\r\n", target, headers);
+        let mut response = router.dispatch(Request::read_from(&mut raw.as_bytes()).unwrap());
+        let body = match std::mem::replace(&mut response.body, Vec::new().into()) {
+            crate::response::Body::Bytes(bytes) => String::from_utf8(bytes).unwrap(),
+            crate::response::Body::Stream(mut reader, _) => {
+                let mut body = String::new();
+                reader.read_to_string(&mut body).unwrap();
+                body
+            }
+        };
+        (response.status.0, response, body)
+    }
+
+    #[test]
+    fn test_files_indexes_and_listings() {
+        let (dir, router) = setup("files", true);
+        let (status, response, body) = get(&router, "/docs/style.css", "");
+        assert_eq!((status, body.as_str()), (200, "body {}"));
+        assert_eq!(response.headers.get("Content-Type"), Some("text/css; charset=utf-8"));
+        assert!(response.headers.contains("Last-Modified") && response.headers.contains("ETag"));
+
+        assert_eq!(get(&router, "/docs/", "").2, "<h1>docs</h1>");
+        let (status, response, _) = get(&router, "/docs/guide?x=1", "");
+        assert_eq!((status, response.headers.get("Location")), (301, Some("/docs/guide/?x=1")));
+        let (status, _, listing) = get(&router, "/docs/guide/", "");
+        assert_eq!(status, 200);
+        assert!(listing.contains("<a href=\"intro.md\">intro.md</a>"), "{}", listing);
+        let (_, _, listing) = get(&router, "/docs/empty%20dir/", "");
+        assert!(listing.contains("Index of /docs/empty dir/"), "{}", listing);
+
+        assert_eq!(get(&router, "/docs/missing.txt", "").0, 404);
+        let (unlisted_dir, unlisted) = setup("unlisted", false);
+        assert_eq!(get(&unlisted, "/docs/guide/", "").0, 404);
+        fs::remove_dir_all(dir).unwrap();
+        fs::remove_dir_all(unlisted_dir).unwrap();
+    }
+
+    #[test]
+    fn test_path_traversal() {
+        let (dir, router) = setup("traversal", true);
+        let secret = dir.parent().unwrap().join("simple_server_secret.txt");
+        fs::write(&secret, "secret").unwrap();
+        for target in ["/docs/../simple_server_secret.txt", "/docs/%2e%2e/simple_server_secret.txt", "/docs/guide%2F..%2F..%2Fsimple_server_secret.txt"] {
+            let (status, _, body) = get(&router, target, "");
+            assert!(status == 403 || status == 404, "{} gave {}", target, status);
+            assert_ne!(body, "secret");
+        }
+        fs::remove_file(secret).unwrap();
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_conditional_requests() {
+        let (dir, router) = setup("conditional", true);
+        let (_, response, _) = get(&router, "/docs/style.css", "");
+        let etag = response.headers.get("ETag").unwrap().to_string();
+        let modified = response.headers.get("Last-Modified").unwrap().to_string();
+
+        assert_eq!(get(&router, "/docs/style.css", &format!("If-None-Match: \"x\", {}\r\n", etag)).0, 304);
+        assert_eq!(get(&router, "/docs/style.css", "If-None-Match: \"other\"\r\n").0, 200);
+        assert_eq!(get(&router, "/docs/style.css", &format!("If-Modified-Since: {}\r\n", modified)).0, 304);
+        assert_eq!(get(&router, "/docs/style.css", "If-Modified-Since: Thu, 01 Jan 1970 00:00:00 GMT\r\n").0, 200);
+        let huge = "If-Modified-Since: Sun, 06 Nov 300000000000 08:49:37 GMT\r\n";
+        assert_eq!(get(&router, "/docs/style.css", huge).0, 200);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_ranges() {
+        assert_eq!(parse_range("bytes=0-4", 10), Range::Satisfiable(0, 4));
+        assert_eq!(parse_range("bytes=5-", 10), Range::Satisfiable(5, 9));
+        assert_eq!(parse_range("bytes=-3", 10), Range::Satisfiable(7, 9));
+        assert_eq!(parse_range("bytes=8-100", 10), Range::Satisfiable(8, 9));
+        assert_eq!(parse_range("bytes=10-", 10), Range::NotSatisfiable);
+        assert_eq!(parse_range("bytes=0-1,3-4", 10), Range::Ignored);
+        assert_eq!(parse_range("lines=1-2", 10), Range::Ignored);
+
+        let (dir, router) = setup("ranges", true);
+        let (status, response, body) = get(&router, "/docs/guide/intro.md", "Range: bytes=2-5\r\n");
+        assert_eq!((status, body.as_str()), (206, "2345"));
+        assert_eq!(response.headers.get("Content-Range"), Some("bytes 2-5/10"));
+        let (status, response, _) = get(&router, "/docs/guide/intro.md", "Range: bytes=50-\r\n");
+        assert_eq!((status, response.headers.get("Content-Range")), (416, Some("bytes */10")));
+        let stale = "Range: bytes=2-5\r\nIf-Range: \"stale\"\r\n";
+        assert_eq!(get(&router, "/docs/guide/intro.md", stale).2, "0123456789");
+        let huge = "Range: bytes=2-5\r\nIf-Range: Sun, 06 Nov 9223372036854775807 08:49:37 GMT\r\n";
+        assert_eq!(get(&router, "/docs/guide/intro.md", huge).2, "0123456789");
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
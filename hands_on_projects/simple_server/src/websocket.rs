@@ -0,0 +1,791 @@
+//! WebSocket (RFC 6455): messages both ways over a connection that started as HTTP.
+//!
+//! ```text
+//! GET /ws HTTP/1.1
+//! Upgrade: websocket                                 <- "let's switch protocols"
+//! Connection: Upgrade
+//! Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==        <- 16 random bytes, base64
+//! Sec-WebSocket-Version: 13
+//!
+//! HTTP/1.1 101 Switching Protocols
+//! Upgrade: websocket
+//! Connection: Upgrade
+//! Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=  <- SHA-1 of the key and a fixed GUID
+//! ```
+//!
+//! After that both sides send frames whenever they like: text, binary, ping, pong, close.
+//!
+//! ```ignore
+//! router.route(Method::Get, "/ws/echo", WebSocketRoute::new(|_request, mut socket: WebSocket| {
+//!     while let Ok(Some(message)) = socket.recv() {
+//!         if socket.send(message).is_err() {
+//!             break;
+//!         }
+//!     }
+//! }));
+//! ```
+//!
+//! Note: an open WebSocket runs on a thread of its own (or of tokio's blocking pool) until it
+//! closes, so idle sockets don't take the workers that serve HTTP.
+
+use crate::connection::{IDLE_POLL, Upgraded, has_token};
+use crate::request::{Method, Request, Version};
+use crate::response::{Response, StatusCode};
+use crate::router::Handler;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use std::io::{self, BufRead, BufReader, Chain, Cursor, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Appended to the client's key before hashing, so that only a server that knows the
+/// protocol can answer the handshake.
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Messages we send are cut in frames of at most this size.
+const MAX_FRAME: usize = 64 * 1024;
+
+/// How long we wait for the client to answer our close frame before closing anyway.
+const CLOSE_WAIT: Duration = Duration::from_secs(1);
+
+// Close codes (RFC 6455, section 7.4.1).
+pub const CLOSE_NORMAL: u16 = 1000;
+pub const CLOSE_GOING_AWAY: u16 = 1001;
+pub const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+pub const CLOSE_INVALID_DATA: u16 = 1007;
+pub const CLOSE_TOO_BIG: u16 = 1009;
+
+/// The `Sec-WebSocket-Accept` value answering a `Sec-WebSocket-Key`.
+pub fn accept_key(key: &str) -> String {
+    let mut sha1 = sha1_smol::Sha1::new();
+    sha1.update(key.as_bytes());
+    sha1.update(GUID.as_bytes());
+    STANDARD.encode(sha1.digest().bytes())
+}
+
+/// A whole message, put back together from its frames.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+impl From<String> for Message {
+    fn from(text: String) -> Message {
+        Message::Text(text)
+    }
+}
+
+impl From<&str> for Message {
+    fn from(text: &str) -> Message {
+        Message::Text(text.to_string())
+    }
+}
+
+impl From<Vec<u8>> for Message {
+    fn from(bytes: Vec<u8>) -> Message {
+        Message::Binary(bytes)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Opcode {
+    /// The next part of a fragmented message.
+    Continuation,
+    Text,
+    Binary,
+    Close,
+    Ping,
+    Pong,
+}
+
+impl Opcode {
+    fn from_bits(bits: u8) -> Option<Opcode> {
+        match bits {
+            0x0 => Some(Opcode::Continuation),
+            0x1 => Some(Opcode::Text),
+            0x2 => Some(Opcode::Binary),
+            0x8 => Some(Opcode::Close),
+            0x9 => Some(Opcode::Ping),
+            0xA => Some(Opcode::Pong),
+            _ => None,
+        }
+    }
+
+    fn bits(self) -> u8 {
+        match self {
+            Opcode::Continuation => 0x0,
+            Opcode::Text => 0x1,
+            Opcode::Binary => 0x2,
+            Opcode::Close => 0x8,
+            Opcode::Ping => 0x9,
+            Opcode::Pong => 0xA,
+        }
+    }
+
+    /// Close, ping and pong manage the connection; they may come between the frames of a
+    /// fragmented message.
+    fn is_control(self) -> bool {
+        matches!(self, Opcode::Close | Opcode::Ping | Opcode::Pong)
+    }
+}
+
+/// One frame on the wire.
+///
+/// ```text
+///  0               1               2               3
+/// |F|R R R| opcode|M| length (7)  | extended length (16 or 64)... | masking key (32)...
+/// |I|S S S|       |A|             |  if length is 126 or 127      |  if MASK is set
+/// |N|V V V|       |S|             |                               |
+///                  K
+/// ```
+#[derive(Debug)]
+pub(crate) struct Frame {
+    pub(crate) fin: bool,
+    pub(crate) opcode: Opcode,
+    pub(crate) masked: bool,
+    pub(crate) payload: Vec<u8>,
+}
+
+/// Why reading a frame failed.
+#[derive(Debug)]
+pub(crate) enum FrameError {
+    Io(io::Error),
+    /// The peer broke the protocol; the connection is closed with this code.
+    Violation(u16, &'static str),
+}
+
+impl From<io::Error> for FrameError {
+    fn from(e: io::Error) -> FrameError {
+        FrameError::Io(e)
+    }
+}
+
+/// Reads a frame and unmasks its payload, refusing data payloads longer than `max_payload`.
+///
+/// Control frames only have the 125 bytes limit of the protocol: they don't count towards a
+/// message, so a ping may come between the fragments of one that used up its budget.
+pub(crate) fn read_frame(reader: &mut impl Read, max_payload: u64) -> Result<Frame, FrameError> {
+    let mut head = [0; 2];
+    reader.read_exact(&mut head)?;
+    let fin = head[0] & 0x80 != 0;
+    // the reserved bits are for extensions, and we agreed on none
+    if head[0] & 0x70 != 0 {
+        return Err(FrameError::Violation(CLOSE_PROTOCOL_ERROR, "reserved bits set"));
+    }
+    let opcode = Opcode::from_bits(head[0] & 0x0F).ok_or(FrameError::Violation(CLOSE_PROTOCOL_ERROR, "unknown opcode"))?;
+    let masked = head[1] & 0x80 != 0;
+    let len = match head[1] & 0x7F {
+        126 => {
+            let mut len = [0; 2];
+            reader.read_exact(&mut len)?;
+            u16::from_be_bytes(len) as u64
+        }
+        127 => {
+            let mut len = [0; 8];
+            reader.read_exact(&mut len)?;
+            u64::from_be_bytes(len)
+        }
+        len => len as u64,
+    };
+    if opcode.is_control() && (!fin || len > 125) {
+        return Err(FrameError::Violation(CLOSE_PROTOCOL_ERROR, "control frames are short and unfragmented"));
+    }
+    if !opcode.is_control() && len > max_payload {
+        return Err(FrameError::Violation(CLOSE_TOO_BIG, "message too big"));
+    }
+
+    let mut mask = [0; 4];
+    if masked {
+        reader.read_exact(&mut mask)?;
+    }
+    let mut payload = vec![0; len as usize];
+    reader.read_exact(&mut payload)?;
+    if masked {
+        apply_mask(&mut payload, mask);
+    }
+    Ok(Frame { fin, opcode, masked, payload })
+}
+
+/// Writes a frame in one piece. Clients mask their frames, servers don't.
+pub(crate) fn write_frame(
+    out: &mut impl Write,
+    fin: bool,
+    opcode: Opcode,
+    payload: &[u8],
+    mask: Option<[u8; 4]>,
+) -> io::Result<()> {
+    let mut frame = Vec::with_capacity(payload.len() + 14);
+    frame.push(if fin { 0x80 } else { 0 } | opcode.bits());
+    let mask_bit = if mask.is_some() { 0x80 } else { 0 };
+    match payload.len() {
+        len @ 0..=125 => frame.push(mask_bit | len as u8),
+        len @ 126..=0xFFFF => {
+            frame.push(mask_bit | 126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(mask_bit | 127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    let start = frame.len();
+    match mask {
+        Some(mask) => {
+            frame.extend_from_slice(&mask);
+            frame.extend_from_slice(payload);
+            apply_mask(&mut frame[start + 4..], mask);
+        }
+        None => frame.extend_from_slice(payload),
+    }
+    out.write_all(&frame)
+}
+
+/// XORs the payload with the 4-byte key, repeated. Masking twice unmasks.
+///
+/// Why do clients mask?
+/// So a script in a browser can't choose the bytes on the wire, and make a naive proxy on
+/// the way take them for an HTTP request and cache a poisoned answer.
+fn apply_mask(payload: &mut [u8], mask: [u8; 4]) {
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+}
+
+struct Shared {
+    stream: Mutex<TcpStream>,
+    close_sent: AtomicBool,
+}
+
+/// Sends on a `WebSocket`, from any thread: clone it to push messages from elsewhere, e.g.
+/// a thread broadcasting updates to every open dashboard.
+#[derive(Clone)]
+pub struct Sender(Arc<Shared>);
+
+impl Sender {
+    /// Sends a message, cut in frames if it is large.
+    pub fn send(&self, message: impl Into<Message>) -> io::Result<()> {
+        let (opcode, payload) = match message.into() {
+            Message::Text(text) => (Opcode::Text, text.into_bytes()),
+            Message::Binary(bytes) => (Opcode::Binary, bytes),
+        };
+        // Note: the lock is held for the whole message, as the frames of two messages can't
+        // be mixed.
+        let stream = self.0.stream.lock().unwrap();
+        if self.is_closed() {
+            return Err(io::Error::new(io::ErrorKind::NotConnected, "WebSocket closed"));
+        }
+        let mut out = &*stream;
+        let mut frames = payload.chunks(MAX_FRAME).peekable();
+        if frames.peek().is_none() {
+            return write_frame(&mut out, true, opcode, &[], None);
+        }
+        let mut opcode = opcode;
+        while let Some(frame) = frames.next() {
+            write_frame(&mut out, frames.peek().is_none(), opcode, frame, None)?;
+            opcode = Opcode::Continuation;
+        }
+        Ok(())
+    }
+
+    /// Starts the close handshake; `recv` then returns `None` once the client answers.
+    /// Nothing can be sent after it. Closing twice does nothing.
+    pub fn close(&self, code: u16, reason: &str) -> io::Result<()> {
+        let stream = self.0.stream.lock().unwrap();
+        if self.0.close_sent.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+        let mut payload = code.to_be_bytes().to_vec();
+        // a control frame carries 125 bytes at most, and the reason must stay valid UTF-8
+        let mut end = reason.len().min(123);
+        while !reason.is_char_boundary(end) {
+            end -= 1;
+        }
+        payload.extend_from_slice(&reason.as_bytes()[..end]);
+        write_frame(&mut &*stream, true, Opcode::Close, &payload, None)
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.0.close_sent.load(Ordering::SeqCst)
+    }
+
+    fn pong(&self, payload: &[u8]) -> io::Result<()> {
+        let stream = self.0.stream.lock().unwrap();
+        if self.is_closed() {
+            return Ok(());
+        }
+        write_frame(&mut &*stream, true, Opcode::Pong, payload, None)
+    }
+}
+
+/// An open WebSocket connection. `recv` returns text and binary messages; pings are answered
+/// and the close handshake is done along the way.
+pub struct WebSocket {
+    reader: BufReader<Chain<Cursor<Vec<u8>>, TcpStream>>,
+    stream: TcpStream,
+    sender: Sender,
+    shutdown: Arc<AtomicBool>,
+    read_timeout: Duration,
+    max_message_size: usize,
+    /// Whether the client's close frame arrived.
+    peer_closed: bool,
+}
+
+impl WebSocket {
+    /// A WebSocket on a connection upgraded after the handshake; `WebSocketRoute` does both.
+    pub fn from_upgraded(upgraded: Upgraded) -> io::Result<WebSocket> {
+        let Upgraded { stream, buffered, shutdown, read_timeout } = upgraded;
+        let reader = BufReader::new(Cursor::new(buffered).chain(stream.try_clone()?));
+        let sender = Sender(Arc::new(Shared { stream: Mutex::new(stream.try_clone()?), close_sent: AtomicBool::new(false) }));
+        Ok(WebSocket { reader, stream, sender, shutdown, read_timeout, max_message_size: 1 << 20, peer_closed: false })
+    }
+
+    /// Longer messages close the connection with code 1009. 1 MiB by default.
+    pub fn max_message_size(mut self, bytes: usize) -> WebSocket {
+        self.max_message_size = bytes;
+        self
+    }
+
+    /// A sender for this connection, to send from another thread.
+    pub fn sender(&self) -> Sender {
+        self.sender.clone()
+    }
+
+    pub fn send(&self, message: impl Into<Message>) -> io::Result<()> {
+        self.sender.send(message)
+    }
+
+    /// See `Sender::close`.
+    pub fn close(&self, code: u16, reason: &str) -> io::Result<()> {
+        self.sender.close(code, reason)
+    }
+
+    /// The next message, or `None` once the connection is closed: by the client, by `close`,
+    /// or because the server is shutting down (the client then gets code 1001).
+    ///
+    /// A client breaking the protocol gets a close frame with the reason, and this returns
+    /// an `InvalidData` error.
+    pub fn recv(&mut self) -> io::Result<Option<Message>> {
+        // a fragmented message being put together
+        let mut message: Option<(Opcode, Vec<u8>)> = None;
+        loop {
+            if self.peer_closed || !self.wait_for_frame()? {
+                return Ok(None);
+            }
+            let received = message.as_ref().map_or(0, |(_, data)| data.len());
+            let frame = match read_frame(&mut self.reader, self.max_message_size.saturating_sub(received) as u64) {
+                Ok(frame) => frame,
+                Err(FrameError::Io(e)) => return Err(e),
+                Err(FrameError::Violation(code, reason)) => return Err(self.fail(code, reason)),
+            };
+            if !frame.masked {
+                return Err(self.fail(CLOSE_PROTOCOL_ERROR, "client frames must be masked"));
+            }
+
+            match frame.opcode {
+                Opcode::Ping => self.sender.pong(&frame.payload)?,
+                Opcode::Pong => {}
+                Opcode::Close => return self.closed_by_peer(&frame.payload),
+                Opcode::Text | Opcode::Binary if message.is_some() => {
+                    return Err(self.fail(CLOSE_PROTOCOL_ERROR, "new message before the last one ended"));
+                }
+                Opcode::Text | Opcode::Binary => message = Some((frame.opcode, frame.payload)),
+                Opcode::Continuation => match &mut message {
+                    Some((_, data)) => data.extend_from_slice(&frame.payload),
+                    None => return Err(self.fail(CLOSE_PROTOCOL_ERROR, "continuation without a message")),
+                },
+            }
+            if !frame.fin || frame.opcode.is_control() {
+                continue;
+            }
+            let Some((opcode, data)) = message.take() else { continue };
+            // Note: after our close frame, the client's last messages are dropped.
+            if self.sender.is_closed() {
+                continue;
+            }
+            return match opcode {
+                Opcode::Text => match String::from_utf8(data) {
+                    Ok(text) => Ok(Some(Message::Text(text))),
+                    Err(_) => Err(self.fail(CLOSE_INVALID_DATA, "text message is not UTF-8")),
+                },
+                _ => Ok(Some(Message::Binary(data))),
+            };
+        }
+    }
+
+    /// Waits for the next frame to start, checking for a shutdown now and then like an idle
+    /// keep-alive connection. `false` if the connection ended instead.
+    fn wait_for_frame(&mut self) -> io::Result<bool> {
+        self.stream.set_read_timeout(Some(IDLE_POLL))?;
+        let ready = loop {
+            match self.reader.fill_buf() {
+                Ok(buf) => break !buf.is_empty(),
+                Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                    if self.shutdown.load(Ordering::SeqCst) {
+                        self.close(CLOSE_GOING_AWAY, "server shutting down")?;
+                        break false;
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        };
+        // a frame that has started must arrive in full
+        self.stream.set_read_timeout(Some(self.read_timeout))?;
+        Ok(ready)
+    }
+
+    /// Answers the client's close frame with the same code, as the handshake wants.
+    fn closed_by_peer(&mut self, payload: &[u8]) -> io::Result<Option<Message>> {
+        self.peer_closed = true;
+        let code = match payload {
+            [] => CLOSE_NORMAL,
+            [_] => return Err(self.fail(CLOSE_PROTOCOL_ERROR, "close frame with half a code")),
+            [high, low, reason @ ..] => {
+                let code = u16::from_be_bytes([*high, *low]);
+                // 1004-1006 and 1015 are never sent, only reported locally
+                let valid = matches!(code, 1000..=1003 | 1007..=1014 | 3000..=4999);
+                if !valid || std::str::from_utf8(reason).is_err() {
+                    return Err(self.fail(CLOSE_PROTOCOL_ERROR, "invalid close frame"));
+                }
+                code
+            }
+        };
+        self.close(code, "")?;
+        Ok(None)
+    }
+
+    /// Closes the connection with `code` and returns the error for `recv`.
+    fn fail(&mut self, code: u16, reason: &'static str) -> io::Error {
+        let _ = self.close(code, reason);
+        io::Error::new(io::ErrorKind::InvalidData, reason)
+    }
+}
+
+/// Dropping a `WebSocket` closes it: with code 1000 unless `close` was called, then waiting
+/// a moment for the client's close frame.
+impl Drop for WebSocket {
+    fn drop(&mut self) {
+        let _ = self.close(CLOSE_NORMAL, "");
+        if !self.peer_closed && self.stream.set_read_timeout(Some(CLOSE_WAIT)).is_ok() {
+            while let Ok(frame) = read_frame(&mut self.reader, self.max_message_size as u64) {
+                if frame.opcode == Opcode::Close {
+                    break;
+                }
+            }
+        }
+        // Note: the server closes the TCP connection first, so the client doesn't have to wait
+        // for it (RFC 6455, section 7.1.1).
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+}
+
+type OnOpen = dyn Fn(Request, WebSocket) + Send + Sync;
+
+/// A handler accepting WebSocket handshakes and calling `on_open` with each new connection,
+/// along with its request (for its path parameters, query or cookies).
+///
+/// Requests that aren't a valid handshake get `400 Bad Request`, or `426 Upgrade Required`
+/// when they don't ask for WebSocket (version 13) at all.
+pub struct WebSocketRoute {
+    on_open: Arc<OnOpen>,
+    max_message_size: usize,
+}
+
+impl WebSocketRoute {
+    pub fn new(on_open: impl Fn(Request, WebSocket) + Send + Sync + 'static) -> WebSocketRoute {
+        WebSocketRoute { on_open: Arc::new(on_open), max_message_size: 1 << 20 }
+    }
+
+    /// See `WebSocket::max_message_size`.
+    pub fn max_message_size(mut self, bytes: usize) -> WebSocketRoute {
+        self.max_message_size = bytes;
+        self
+    }
+}
+
+impl Handler for WebSocketRoute {
+    fn call(&self, request: Request) -> Response {
+        let accept = match handshake(&request) {
+            Ok(key) => accept_key(key),
+            Err(response) => return response,
+        };
+        let on_open = Arc::clone(&self.on_open);
+        let max_message_size = self.max_message_size;
+        Response::new(StatusCode::SWITCHING_PROTOCOLS)
+            .with_header("Upgrade", "websocket")
+            .with_header("Connection", "Upgrade")
+            .with_header("Sec-WebSocket-Accept", &accept)
+            .with_upgrade(move |upgraded| match WebSocket::from_upgraded(upgraded) {
+                Ok(socket) => on_open(request, socket.max_message_size(max_message_size)),
+                Err(e) => eprintln!("WebSocket error: {}", e),
+            })
+    }
+}
+
+/// Checks the handshake request and returns its `Sec-WebSocket-Key`, or the response
+/// refusing it.
+fn handshake(request: &Request) -> Result<&str, Response> {
+    if !has_token(request.header("Upgrade"), "websocket") {
+        return Err(Response::text(StatusCode::UPGRADE_REQUIRED, "This is a WebSocket endpoint.")
+            .with_header("Upgrade", "websocket"));
+    }
+    if request.method != Method::Get || request.version != Version::Http11 {
+        return Err(Response::text(StatusCode::BAD_REQUEST, "A WebSocket handshake is a GET over HTTP/1.1."));
+    }
+    if !has_token(request.header("Connection"), "upgrade") {
+        return Err(Response::text(StatusCode::BAD_REQUEST, "Missing Connection: Upgrade."));
+    }
+    if request.header("Sec-WebSocket-Version").map(str::trim) != Some("13") {
+        return Err(Response::text(StatusCode::UPGRADE_REQUIRED, "Unsupported WebSocket version.")
+            .with_header("Sec-WebSocket-Version", "13"));
+    }
+    match request.header("Sec-WebSocket-Key").map(str::trim) {
+        Some(key) if STANDARD.decode(key).is_ok_and(|nonce| nonce.len() == 16) => Ok(key),
+        _ => Err(Response::text(StatusCode::BAD_REQUEST, "Invalid Sec-WebSocket-Key.")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::router::Router;
+    use crate::server::{Server, ShutdownHandle};
+    use std::net::SocketAddr;
+    use std::thread;
+    use std::time::Instant;
+
+    const MASK: Option<[u8; 4]> = Some([0x12, 0x34, 0x56, 0x78]);
+
+    fn router() -> Router {
+        let mut router = Router::new();
+        router
+            .route(
+                Method::Get,
+                "/echo",
+                WebSocketRoute::new(|_, mut socket: WebSocket| {
+                    while let Ok(Some(message)) = socket.recv() {
+                        socket.send(message).unwrap();
+                    }
+                })
+                .max_message_size(100_000),
+            )
+            .route(
+                Method::Get,
+                "/push/:count",
+                WebSocketRoute::new(|request, mut socket: WebSocket| {
+                    let count: usize = request.param("count").unwrap().parse().unwrap();
+                    let sender = socket.sender();
+                    let pusher = thread::spawn(move || (0..count).for_each(|i| sender.send(i.to_string()).unwrap()));
+                    pusher.join().unwrap();
+                    socket.close(CLOSE_NORMAL, "done").unwrap();
+                    assert_eq!(socket.recv().unwrap(), None);
+                }),
+            );
+        router
+    }
+
+    fn start() -> (SocketAddr, ShutdownHandle, thread::JoinHandle<()>) {
+        let server = Server::builder().addr("127.0.0.1:0").threads(2).bind(router()).unwrap();
+        let (addr, handle) = (server.local_addr(), server.handle());
+        (addr, handle, thread::spawn(move || server.run().unwrap()))
+    }
+
+    /// Connects and does the handshake, returning the socket and the response head.
+    fn connect(addr: SocketAddr, path: &str, extra: &str) -> (BufReader<TcpStream>, String) {
+        let stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: x\r\nUpgrade: websocket\r\nConnection: keep-alive, Upgrade\r\n\
+             Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n{}\r\n",
+            path, extra
+        );
+        (&stream).write_all(request.as_bytes()).unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut head = String::new();
+        while !head.ends_with("\r\n\r\n") {
+            assert!(reader.read_line(&mut head).unwrap() > 0, "{}", head);
+        }
+        (reader, head)
+    }
+
+    fn send(client: &mut BufReader<TcpStream>, fin: bool, opcode: Opcode, payload: &[u8]) {
+        write_frame(client.get_mut(), fin, opcode, payload, MASK).unwrap();
+    }
+
+    fn next(client: &mut BufReader<TcpStream>) -> Frame {
+        let frame = read_frame(client, u64::MAX).unwrap();
+        assert!(!frame.masked);
+        frame
+    }
+
+    fn close_code(frame: &Frame) -> u16 {
+        assert_eq!(frame.opcode, Opcode::Close);
+        u16::from_be_bytes([frame.payload[0], frame.payload[1]])
+    }
+
+    #[test]
+    fn test_accept_key_and_frames() {
+        // the example of RFC 6455, section 1.3
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+
+        for len in [0, 125, 126, 65_535, 65_536] {
+            let payload: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let mut wire = Vec::new();
+            write_frame(&mut wire, true, Opcode::Binary, &payload, MASK).unwrap();
+            assert!(len == 0 || wire[wire.len() - len..] != payload[..], "masked on the wire");
+            let frame = read_frame(&mut &wire[..], u64::MAX).unwrap();
+            assert_eq!((frame.fin, frame.opcode, frame.masked, frame.payload), (true, Opcode::Binary, true, payload));
+        }
+
+        let mut wire = Vec::new();
+        write_frame(&mut wire, false, Opcode::Ping, b"", None).unwrap();
+        assert!(matches!(read_frame(&mut &wire[..], 100), Err(FrameError::Violation(CLOSE_PROTOCOL_ERROR, _))));
+        wire.clear();
+        write_frame(&mut wire, true, Opcode::Text, &[b'a'; 200], None).unwrap();
+        assert!(matches!(read_frame(&mut &wire[..], 100), Err(FrameError::Violation(CLOSE_TOO_BIG, _))));
+    }
+
+    #[test]
+    fn test_handshake_rejections() {
+        let (addr, handle, running) = start();
+        let (_, head) = connect(addr, "/echo", "Sec-WebSocket-Version: 13\r\n");
+        assert!(head.starts_with("HTTP/1.1 101 Switching Protocols\r\n"), "{}", head);
+        assert!(head.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"), "{}", head);
+        assert!(!head.contains("Content-Length"), "{}", head);
+
+        let (_, head) = connect(addr, "/echo", "Sec-WebSocket-Version: 8\r\n");
+        assert!(head.starts_with("HTTP/1.1 426 ") && head.contains("Sec-WebSocket-Version: 13"), "{}", head);
+        let mut plain = TcpStream::connect(addr).unwrap();
+        plain.write_all(b"GET /echo HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n").unwrap();
+        let mut out = String::new();
+        plain.read_to_string(&mut out).unwrap();
+        assert!(out.starts_with("HTTP/1.1 426 ") && out.contains("Upgrade: websocket"), "{}", out);
+
+        handle.shutdown();
+        running.join().unwrap();
+    }
+
+    #[test]
+    fn test_messages_fragments_pings_and_close() {
+        let (addr, handle, running) = start();
+        let (mut client, _) = connect(addr, "/echo", "Sec-WebSocket-Version: 13\r\n");
+
+        // a text message in two fragments, with a ping between them
+        send(&mut client, false, Opcode::Text, "héllo, ".as_bytes());
+        send(&mut client, true, Opcode::Ping, b"are you there?");
+        send(&mut client, true, Opcode::Continuation, b"world");
+        let pong = next(&mut client);
+        assert_eq!((pong.opcode, &pong.payload[..]), (Opcode::Pong, &b"are you there?"[..]));
+        let echo = next(&mut client);
+        assert_eq!((echo.fin, echo.opcode, &echo.payload[..]), (true, Opcode::Text, "héllo, world".as_bytes()));
+
+        // a ping is still fine once the fragments used up max_message_size
+        send(&mut client, false, Opcode::Binary, &vec![1; 100_000]);
+        send(&mut client, true, Opcode::Ping, b"still there?");
+        send(&mut client, true, Opcode::Continuation, b"");
+        assert_eq!(next(&mut client).opcode, Opcode::Pong);
+        let (first, second) = (next(&mut client), next(&mut client));
+        assert_eq!((first.payload.len() + second.payload.len(), second.fin), (100_000, true));
+
+        // large messages come back in frames of MAX_FRAME
+        let big = vec![7; MAX_FRAME + 10];
+        send(&mut client, true, Opcode::Binary, &big);
+        let (first, second) = (next(&mut client), next(&mut client));
+        assert_eq!((first.fin, first.opcode, first.payload.len()), (false, Opcode::Binary, MAX_FRAME));
+        assert_eq!((second.fin, second.opcode, second.payload.len()), (true, Opcode::Continuation, 10));
+
+        send(&mut client, true, Opcode::Close, &4000u16.to_be_bytes());
+        assert_eq!(close_code(&next(&mut client)), 4000);
+        assert_eq!(client.read(&mut [0; 16]).unwrap(), 0);
+
+        handle.shutdown();
+        running.join().unwrap();
+    }
+
+    #[test]
+    fn test_protocol_errors_close_the_connection() {
+        let (addr, handle, running) = start();
+        let cases: [(&[u8], u16); 4] = [
+            // not masked
+            (&[0x81, 0x01, b'a'], CLOSE_PROTOCOL_ERROR),
+            // continuation of nothing
+            (&[0x80, 0x80, 0, 0, 0, 0], CLOSE_PROTOCOL_ERROR),
+            // invalid UTF-8 in a text message
+            (&[0x81, 0x81, 0, 0, 0, 0, 0xFF], CLOSE_INVALID_DATA),
+            // over max_message_size
+            (&[0x82, 0xFF, 0, 0, 0, 0, 0, 0x10, 0, 0], CLOSE_TOO_BIG),
+        ];
+        for (frame, code) in cases {
+            let (mut client, _) = connect(addr, "/echo", "Sec-WebSocket-Version: 13\r\n");
+            client.get_mut().write_all(frame).unwrap();
+            assert_eq!(close_code(&next(&mut client)), code, "{:?}", frame);
+        }
+        handle.shutdown();
+        running.join().unwrap();
+    }
+
+    #[test]
+    fn test_push_from_another_thread_and_server_close() {
+        let (addr, handle, running) = start();
+        let (mut client, _) = connect(addr, "/push/3", "Sec-WebSocket-Version: 13\r\n");
+        for i in 0..3 {
+            assert_eq!(next(&mut client).payload, i.to_string().into_bytes());
+        }
+        let close = next(&mut client);
+        assert_eq!((close_code(&close), &close.payload[2..]), (CLOSE_NORMAL, &b"done"[..]));
+        send(&mut client, true, Opcode::Close, &close.payload[..2]);
+        assert_eq!(client.read(&mut [0; 16]).unwrap(), 0);
+
+        // an open WebSocket is told when the server shuts down
+        let (mut client, _) = connect(addr, "/echo", "Sec-WebSocket-Version: 13\r\n");
+        let started = Instant::now();
+        handle.shutdown();
+        assert_eq!(close_code(&next(&mut client)), CLOSE_GOING_AWAY);
+        send(&mut client, true, Opcode::Close, &CLOSE_GOING_AWAY.to_be_bytes());
+        running.join().unwrap();
+        assert!(started.elapsed() < Duration::from_secs(1), "took {:?}", started.elapsed());
+    }
+
+    #[test]
+    fn test_idle_websockets_leave_the_pool_free() {
+        // more idle sockets than the pool has threads
+        let (addr, handle, running) = start();
+        let clients: Vec<_> = (0..4).map(|_| connect(addr, "/echo", "Sec-WebSocket-Version: 13\r\n").0).collect();
+
+        let mut http = TcpStream::connect(addr).unwrap();
+        http.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        http.write_all(b"GET /nothing HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n").unwrap();
+        let mut out = String::new();
+        http.read_to_string(&mut out).unwrap();
+        assert!(out.starts_with("HTTP/1.1 404 "), "{}", out);
+
+        handle.shutdown();
+        for mut client in clients {
+            assert_eq!(close_code(&next(&mut client)), CLOSE_GOING_AWAY);
+            send(&mut client, true, Opcode::Close, &CLOSE_GOING_AWAY.to_be_bytes());
+        }
+        running.join().unwrap();
+    }
+
+    #[test]
+    fn test_async_server_upgrades_too() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let server = runtime
+            .block_on(Server::builder().addr("127.0.0.1:0").bind_async(crate::async_server::Blocking::new(router())))
+            .unwrap();
+        let (addr, handle) = (server.local_addr(), server.handle());
+        let running = runtime.spawn(server.run());
+
+        let (mut client, head) = connect(addr, "/echo", "Sec-WebSocket-Version: 13\r\n");
+        assert!(head.starts_with("HTTP/1.1 101 "), "{}", head);
+        send(&mut client, true, Opcode::Text, b"over tokio");
+        assert_eq!(next(&mut client).payload, b"over tokio");
+        send(&mut client, true, Opcode::Close, &[]);
+        assert_eq!(close_code(&next(&mut client)), CLOSE_NORMAL);
+
+        handle.shutdown();
+        runtime.block_on(running).unwrap().unwrap();
+    }
+}